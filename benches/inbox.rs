@@ -0,0 +1,73 @@
+//! Compares per-envelope sled inserts against a single batch for an inbox
+//! import of 10k envelopes.
+//!
+//! Run with `cargo bench --bench inbox`. Envelopes are unsigned; the import
+//! path under test runs after validation.
+
+use chrono::Utc;
+use openherd_cow::config::Config;
+use openherd_cow::state::AppState;
+use openherd_cow::types::{Envelope, Post};
+use std::time::Instant;
+
+const POSTS: usize = 10_000;
+const ROUNDS: u32 = 5;
+
+fn posts() -> Vec<(Envelope, Post)> {
+    (0..POSTS)
+        .map(|i| {
+            let post = Post {
+                id: format!("{:040x}", i),
+                text: format!("Inbox bench post {}", i),
+                latitude: 33.75,
+                longitude: -84.39,
+                date: Utc::now(),
+                parent: None,
+                quote: None,
+                nonce: None,
+            };
+            let envelope = Envelope {
+                signature: "-----BEGIN PGP SIGNATURE-----\n".repeat(8),
+                public_key: "-----BEGIN PGP PUBLIC KEY BLOCK-----\n".repeat(20),
+                id: post.id.clone(),
+                data: serde_json::to_string(&post).unwrap(),
+                received_at: None,
+                seq: None,
+            };
+            (envelope, post)
+        })
+        .collect()
+}
+
+fn fresh_state() -> AppState {
+    let db = sled::Config::new().temporary(true).open().unwrap();
+    AppState::new(db, Config::default())
+}
+
+fn main() {
+    let posts = posts();
+
+    for batched in [false, true] {
+        let mut total = std::time::Duration::ZERO;
+        for _ in 0..ROUNDS {
+            let mut state = fresh_state();
+            let input = posts.clone();
+            let start = Instant::now();
+            if batched {
+                state.import_batch(input).unwrap();
+            } else {
+                for (envelope, post) in input {
+                    state.import_envelope(envelope, &post).unwrap();
+                }
+            }
+            state.db.flush().unwrap();
+            total += start.elapsed();
+        }
+        println!(
+            "{:<10} {:>10.3?} per {} envelopes",
+            if batched { "batch" } else { "per-insert" },
+            total / ROUNDS,
+            POSTS
+        );
+    }
+}
@@ -0,0 +1,83 @@
+//! Compares the substring scan against the tantivy index.
+//!
+//! Run with `cargo bench --bench search`. Envelopes are unsigned; search
+//! never verifies them.
+
+use chrono::Utc;
+use openherd_cow::config::PostDateSource;
+use openherd_cow::search::{naive_search, SearchIndex};
+use openherd_cow::types::{Envelope, Post};
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+const POSTS: usize = 50_000;
+const ROUNDS: u32 = 20;
+const WORDS: &[&str] = &[
+    "market", "river", "concert", "traffic", "garden", "library", "storm", "bakery", "festival",
+    "bridge", "coffee", "parade", "museum", "harbor", "stadium", "orchard",
+];
+
+fn corpus() -> HashMap<String, Envelope> {
+    (0..POSTS)
+        .map(|i| {
+            let id = format!("{:040x}", i);
+            let text = format!(
+                "Post {} about the {} and the {} downtown",
+                i,
+                WORDS[i % WORDS.len()],
+                WORDS[(i * 7 + 3) % WORDS.len()]
+            );
+            let post = Post {
+                id: id.clone(),
+                text,
+                latitude: 33.75,
+                longitude: -84.39,
+                date: Utc::now(),
+                parent: None,
+                quote: None,
+                nonce: None,
+            };
+            let envelope = Envelope {
+                signature: String::new(),
+                public_key: String::new(),
+                id: id.clone(),
+                data: serde_json::to_string(&post).unwrap(),
+                received_at: None,
+                seq: None,
+            };
+            (id, envelope)
+        })
+        .collect()
+}
+
+fn time(label: &str, mut f: impl FnMut() -> usize) {
+    let mut total = Duration::ZERO;
+    let mut found = 0;
+    for _ in 0..ROUNDS {
+        let start = Instant::now();
+        found = f();
+        total += start.elapsed();
+    }
+    println!(
+        "{:<8} {:>10.3?} per query ({} results)",
+        label,
+        total / ROUNDS,
+        found
+    );
+}
+
+fn main() {
+    let memory = corpus();
+    let mut index = SearchIndex::in_memory().unwrap();
+    let start = Instant::now();
+    index.rebuild(&memory).unwrap();
+    println!("indexed {} posts in {:.3?}", POSTS, start.elapsed());
+
+    for term in ["festival", "orchard"] {
+        println!("query {:?}", term);
+        time("naive", || {
+            naive_search(&memory, term, 0, 100, PostDateSource::Signed).len()
+        });
+        time("indexed", || index.search(term, 0, 100).unwrap().len());
+    }
+}
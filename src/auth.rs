@@ -0,0 +1,158 @@
+use crate::config::{AdminBackendKind, Config};
+use argon2::password_hash::{
+    rand_core::OsRng, PasswordHash, PasswordHasher, PasswordVerifier, SaltString,
+};
+use argon2::Argon2;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+const SLED_PASSWORDS_KEY: &[u8] = b"__admin_passwords__";
+
+/// Where admin passwords are checked. Chosen with `--admin-backend`.
+pub trait AdminBackend: Send + Sync {
+    fn verify(&self, password: &str) -> bool;
+}
+
+pub fn backend_from_config(config: &Config, db: &sled::Db) -> Arc<dyn AdminBackend> {
+    match config.admin_backend {
+        AdminBackendKind::Sled => Arc::new(SledPasswords::new(db.clone())),
+        AdminBackendKind::Env => Arc::new(EnvPasswords {
+            var: config.admin_passwords_env.clone(),
+        }),
+        AdminBackendKind::File => Arc::new(FilePasswords {
+            path: config.admin_passwords_file.clone(),
+        }),
+    }
+}
+
+fn contains_password(candidates: impl IntoIterator<Item = String>, password: &str) -> bool {
+    !password.is_empty() && candidates.into_iter().any(|p| p == password)
+}
+
+/// Argon2 PHC string for `password`, as stored by `SledPasswords`.
+pub fn hash_password(password: &str) -> Result<String, argon2::password_hash::Error> {
+    let salt = SaltString::generate(&mut OsRng);
+    Ok(Argon2::default()
+        .hash_password(password.as_bytes(), &salt)?
+        .to_string())
+}
+
+/// Checks a stored entry, which is an Argon2 hash or, for stores written
+/// before hashing, the plaintext password.
+fn verify_stored(stored: &str, password: &str) -> bool {
+    if password.is_empty() {
+        return false;
+    }
+    match PasswordHash::new(stored) {
+        Ok(hash) => Argon2::default()
+            .verify_password(password.as_bytes(), &hash)
+            .is_ok(),
+        Err(_) => stored == password,
+    }
+}
+
+/// Passwords managed with `enroll-admin`/`denroll-admin`/`rotate-admin`,
+/// stored in sled as Argon2 hashes. Read on every check so enrolment changes
+/// apply without a restart.
+pub struct SledPasswords {
+    db: sled::Db,
+}
+
+impl SledPasswords {
+    pub fn new(db: sled::Db) -> Self {
+        Self { db }
+    }
+
+    pub fn load(&self) -> Vec<String> {
+        match self.db.get(SLED_PASSWORDS_KEY) {
+            Ok(Some(bytes)) => serde_json::from_slice(&bytes).unwrap_or_default(),
+            Ok(None) => Vec::new(),
+            Err(e) => {
+                eprintln!("Failed to read admin passwords: {}", e);
+                Vec::new()
+            }
+        }
+    }
+
+    pub(crate) fn save(&self, passwords: &[String]) -> sled::Result<()> {
+        let bytes = serde_json::to_vec(passwords)
+            .map_err(|e| sled::Error::Unsupported(format!("serialize admin passwords: {}", e)))?;
+        self.db.insert(SLED_PASSWORDS_KEY, bytes)?;
+        self.db.flush()?;
+        Ok(())
+    }
+
+    /// Adds a password, returning false if it was already enrolled.
+    pub fn enroll(&self, password: &str) -> sled::Result<bool> {
+        let mut passwords = self.load();
+        if passwords.iter().any(|p| verify_stored(p, password)) {
+            return Ok(false);
+        }
+        passwords.push(hash_for_store(password)?);
+        self.save(&passwords)?;
+        Ok(true)
+    }
+
+    pub fn denroll(&self, password: &str) -> sled::Result<()> {
+        let mut passwords = self.load();
+        passwords.retain(|p| !verify_stored(p, password));
+        self.save(&passwords)
+    }
+
+    /// Drops every stored credential and stores `passwords` in their place.
+    pub fn replace_all(&self, passwords: &[String]) -> sled::Result<()> {
+        let hashed = passwords
+            .iter()
+            .map(|p| hash_for_store(p))
+            .collect::<sled::Result<Vec<String>>>()?;
+        self.save(&hashed)
+    }
+}
+
+fn hash_for_store(password: &str) -> sled::Result<String> {
+    hash_password(password)
+        .map_err(|e| sled::Error::Unsupported(format!("hash admin password: {}", e)))
+}
+
+impl AdminBackend for SledPasswords {
+    fn verify(&self, password: &str) -> bool {
+        self.load().iter().any(|p| verify_stored(p, password))
+    }
+}
+
+/// Comma-separated passwords in an environment variable, for deployments
+/// that inject secrets through the environment.
+pub struct EnvPasswords {
+    pub var: String,
+}
+
+impl AdminBackend for EnvPasswords {
+    fn verify(&self, password: &str) -> bool {
+        let value = std::env::var(&self.var).unwrap_or_default();
+        contains_password(value.split(',').map(|p| p.trim().to_string()), password)
+    }
+}
+
+/// One password per line in a file, e.g. a mounted secret. Re-read on every
+/// check so rotated secrets apply immediately.
+pub struct FilePasswords {
+    pub path: PathBuf,
+}
+
+impl AdminBackend for FilePasswords {
+    fn verify(&self, password: &str) -> bool {
+        match std::fs::read_to_string(&self.path) {
+            Ok(contents) => {
+                contains_password(contents.lines().map(|l| l.trim().to_string()), password)
+            }
+            Err(e) => {
+                eprintln!(
+                    "Failed to read admin passwords from {}: {}",
+                    self.path.display(),
+                    e
+                );
+                false
+            }
+        }
+    }
+}
@@ -0,0 +1,299 @@
+//! Whole-node archives for `backup` and `restore`: every section this node
+//! persists in sled, as one versioned JSON document. Active reports and post
+//! labels live only in memory and `labels.json` is a plain file next to the
+//! database, so none of those are included, and neither are the `damaged:`
+//! entries `--repair` sets aside.
+
+use crate::auth::SledPasswords;
+use crate::federation::NODE_KEY_DB_KEY;
+use crate::state::{PeerStatus, SEQ_BASE_DB_KEY};
+use crate::store;
+use crate::types::{
+    ArchivedReport, DeadLetter, Envelope, KarmaCode, PinnedPost, WebSubSubscription,
+};
+use chrono::{DateTime, Utc};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::Path;
+
+/// Bumped whenever a section is added or changes shape. Version 2 added
+/// `reactions` and `pins`; older archives restore without them.
+pub const BACKUP_VERSION: u32 = 2;
+
+#[derive(Debug, thiserror::Error)]
+pub enum BackupError {
+    #[error("archive version {0} is not supported (expected at most {BACKUP_VERSION})")]
+    UnsupportedVersion(u64),
+    #[error("archive has no version")]
+    MissingVersion,
+    #[error("database is not empty; restore only into a fresh data directory")]
+    NotEmpty,
+    #[error("database error: {0}")]
+    Db(#[from] sled::Error),
+    #[error("I/O error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("JSON error: {0}")]
+    Json(#[from] serde_json::Error),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Backup {
+    pub version: u32,
+    pub created_at: DateTime<Utc>,
+    pub posts: Vec<Envelope>,
+    pub quarantined: Vec<Envelope>,
+    pub peers: BTreeMap<String, PeerStatus>,
+    /// Votes are not a section of their own: they are rebuilt from each
+    /// code's history at startup.
+    pub karma_codes: Vec<KarmaCode>,
+    pub archived_reports: Vec<ArchivedReport>,
+    pub dead_letters: Vec<DeadLetter>,
+    pub websub_subscriptions: Vec<WebSubSubscription>,
+    /// Signed reaction envelopes, keyed as under `reaction:`.
+    #[serde(default)]
+    pub reactions: BTreeMap<String, Envelope>,
+    #[serde(default)]
+    pub pins: Vec<PinnedPost>,
+    /// Armored secret key peers know this node by. Keep archives private.
+    pub node_key: Option<String>,
+    /// Argon2 hashes from the sled admin backend.
+    pub admin_passwords: Vec<String>,
+}
+
+fn values<T: DeserializeOwned>(db: &sled::Db, prefix: &str) -> sled::Result<Vec<T>> {
+    let mut out = Vec::new();
+    for entry in db.scan_prefix(prefix) {
+        let (key, value) = entry?;
+        match serde_json::from_slice(&value) {
+            Ok(item) => out.push(item),
+            Err(e) => eprintln!("Skipping {}: {}", String::from_utf8_lossy(&key), e),
+        }
+    }
+    Ok(out)
+}
+
+/// Reads every persisted section of `db`.
+pub fn export(db: &sled::Db) -> sled::Result<Backup> {
+    let mut posts = Vec::new();
+    for entry in db.scan_prefix("post:") {
+        let (key, value) = entry?;
+        match store::decode_envelope(&value) {
+            Ok(envelope) => posts.push(envelope),
+            Err(e) => eprintln!("Skipping {}: {}", String::from_utf8_lossy(&key), e),
+        }
+    }
+
+    let mut peers = BTreeMap::new();
+    for entry in db.scan_prefix("peer:") {
+        let (key, value) = entry?;
+        if let Ok(peer) = serde_json::from_slice(&value) {
+            let addr = String::from_utf8_lossy(&key["peer:".len()..]).into_owned();
+            peers.insert(addr, peer);
+        }
+    }
+
+    let mut reactions = BTreeMap::new();
+    for entry in db.scan_prefix("reaction:") {
+        let (key, value) = entry?;
+        match serde_json::from_slice(&value) {
+            Ok(envelope) => {
+                let key = String::from_utf8_lossy(&key["reaction:".len()..]).into_owned();
+                reactions.insert(key, envelope);
+            }
+            Err(e) => eprintln!("Skipping {}: {}", String::from_utf8_lossy(&key), e),
+        }
+    }
+
+    let node_key = db
+        .get(NODE_KEY_DB_KEY)?
+        .map(|bytes| String::from_utf8_lossy(&bytes).into_owned());
+
+    Ok(Backup {
+        version: BACKUP_VERSION,
+        created_at: Utc::now(),
+        posts,
+        quarantined: values(db, "quarantine:")?,
+        peers,
+        karma_codes: values(db, "karma_code:")?,
+        archived_reports: values(db, "archived_report:")?,
+        dead_letters: values(db, "deadletter:")?,
+        websub_subscriptions: values(db, "websub:")?,
+        reactions,
+        pins: values(db, "pin:")?,
+        node_key,
+        admin_passwords: SledPasswords::new(db.clone()).load(),
+    })
+}
+
+/// Writes the archive readable by its owner only, as it holds the node's
+/// secret key and the admin password hashes.
+pub fn write(db: &sled::Db, path: &Path) -> Result<Backup, BackupError> {
+    let backup = export(db)?;
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    let file = options.open(path)?;
+    // `mode` only applies to a new file; tighten one being overwritten.
+    #[cfg(unix)]
+    file.set_permissions(std::os::unix::fs::PermissionsExt::from_mode(0o600))?;
+    let file = std::io::BufWriter::new(file);
+    serde_json::to_writer(file, &backup)?;
+    Ok(backup)
+}
+
+/// Reads an archive, checking its version before anything else so an
+/// archive from a newer release fails with a clear error.
+pub fn read(path: &Path) -> Result<Backup, BackupError> {
+    let value: serde_json::Value =
+        serde_json::from_reader(std::io::BufReader::new(std::fs::File::open(path)?))?;
+    match value.get("version").and_then(serde_json::Value::as_u64) {
+        Some(v) if (1..=u64::from(BACKUP_VERSION)).contains(&v) => {
+            Ok(serde_json::from_value(value)?)
+        }
+        Some(v) => Err(BackupError::UnsupportedVersion(v)),
+        None => Err(BackupError::MissingVersion),
+    }
+}
+
+/// Writes `backup` into `db`, which must be empty.
+pub fn restore(db: &sled::Db, backup: &Backup, compress_posts: bool) -> Result<(), BackupError> {
+    if backup.version == 0 || backup.version > BACKUP_VERSION {
+        return Err(BackupError::UnsupportedVersion(u64::from(backup.version)));
+    }
+    if !db.is_empty() {
+        return Err(BackupError::NotEmpty);
+    }
+
+    // Posts keep their `seq` so peers' outbox cursors stay valid. Sequence
+    // numbers come from sled's id counter, which starts over in a fresh
+    // database, so new ones are offset past every restored one.
+    let max_seq = backup
+        .posts
+        .iter()
+        .chain(&backup.quarantined)
+        .filter_map(|env| env.seq)
+        .max()
+        .unwrap_or(0);
+    db.insert(SEQ_BASE_DB_KEY, &max_seq.to_be_bytes())?;
+
+    for envelope in &backup.posts {
+        db.insert(
+            store::post_key(&envelope.id),
+            store::encode_envelope(envelope, compress_posts)?,
+        )?;
+    }
+    for envelope in &backup.quarantined {
+        db.insert(
+            format!("quarantine:{}", envelope.id),
+            serde_json::to_vec(envelope)?,
+        )?;
+    }
+    for (addr, peer) in &backup.peers {
+        db.insert(format!("peer:{}", addr), serde_json::to_vec(peer)?)?;
+    }
+    for code in &backup.karma_codes {
+        db.insert(
+            format!("karma_code:{}", code.code),
+            serde_json::to_vec(code)?,
+        )?;
+    }
+    for report in &backup.archived_reports {
+        db.insert(
+            format!("archived_report:{}", report.id),
+            serde_json::to_vec(report)?,
+        )?;
+    }
+    for letter in &backup.dead_letters {
+        db.insert(
+            format!("deadletter:{:020}", db.generate_id()?),
+            serde_json::to_vec(letter)?,
+        )?;
+    }
+    for subscription in &backup.websub_subscriptions {
+        db.insert(
+            format!("websub:{}", subscription.callback),
+            serde_json::to_vec(subscription)?,
+        )?;
+    }
+    for (key, envelope) in &backup.reactions {
+        db.insert(format!("reaction:{}", key), serde_json::to_vec(envelope)?)?;
+    }
+    for pin in &backup.pins {
+        db.insert(format!("pin:{}", pin.post_id), serde_json::to_vec(pin)?)?;
+    }
+    if let Some(node_key) = &backup.node_key {
+        db.insert(NODE_KEY_DB_KEY, node_key.as_bytes())?;
+    }
+    if !backup.admin_passwords.is_empty() {
+        SledPasswords::new(db.clone()).save(&backup.admin_passwords)?;
+    }
+    db.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::AdminBackend;
+    use crate::config::Config;
+    use crate::state::AppState;
+    use crate::validation::{testing::signed_envelope, validate_envelope};
+
+    fn temp_db() -> sled::Db {
+        sled::Config::new().temporary(true).open().unwrap()
+    }
+
+    #[test]
+    fn test_backup_round_trips_into_fresh_db() {
+        let db = temp_db();
+        let mut state = AppState::new(db.clone(), Config::default());
+        for text in ["first", "second"] {
+            let envelope = signed_envelope(text);
+            let post = validate_envelope(&envelope).unwrap();
+            state.import_envelope(envelope, &post).unwrap();
+        }
+        db.insert(
+            "peer:peer.example",
+            serde_json::to_vec(&PeerStatus::default()).unwrap(),
+        )
+        .unwrap();
+        SledPasswords::new(db.clone()).enroll("admin").unwrap();
+
+        let dir = std::env::temp_dir().join(format!("openherd-backup-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("node.json");
+        write(&db, &path).unwrap();
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+
+        let restored = temp_db();
+        restore(&restored, &read(&path).unwrap(), true).unwrap();
+        let backup = export(&restored).unwrap();
+        assert_eq!(backup.posts.len(), 2);
+        assert!(backup.peers.contains_key("peer.example"));
+        assert!(SledPasswords::new(restored.clone()).verify("admin"));
+
+        let max_seq = backup.posts.iter().filter_map(|e| e.seq).max().unwrap();
+        let mut state = AppState::new(restored.clone(), Config::default());
+        let envelope = signed_envelope("after restore");
+        let post = validate_envelope(&envelope).unwrap();
+        state.import_envelope(envelope.clone(), &post).unwrap();
+        assert!(state.memory[&envelope.id].seq.unwrap() > max_seq);
+
+        let again = restore(&restored, &backup, false);
+        assert!(matches!(again, Err(BackupError::NotEmpty)));
+
+        std::fs::write(&path, r#"{"version": 999}"#).unwrap();
+        assert!(matches!(
+            read(&path),
+            Err(BackupError::UnsupportedVersion(999))
+        ));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
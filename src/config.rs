@@ -0,0 +1,540 @@
+use crate::sync::normalize_peer_address;
+use crate::types::{Envelope, Post, Submitter, SyncDirection};
+use crate::validation::EnvelopeLimits;
+use chrono::{DateTime, Utc};
+use clap::{ArgAction, CommandFactory, FromArgMatches, Parser, ValueEnum};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::PathBuf;
+
+/// Server settings, read from command-line flags or the matching environment
+/// variables.
+#[derive(Parser, Debug, Clone)]
+pub struct Config {
+    /// Port to listen on when `--bind` is not given.
+    #[arg(long, env = "PORT", default_value_t = 3000)]
+    pub port: u16,
+
+    /// Full socket address to listen on, e.g. `127.0.0.1:3000`.
+    ///
+    /// Without it the server binds every interface, which also exposes the
+    /// admin endpoints. When running behind a reverse proxy, bind to
+    /// localhost instead.
+    #[arg(long = "bind", env = "BIND_ADDR")]
+    pub bind: Option<SocketAddr>,
+
+    /// Origins allowed to call `/_openherd/admin/*` cross-origin, comma
+    /// separated. Empty means same-origin only. Federation routes always
+    /// allow any origin.
+    #[arg(long, env = "ADMIN_CORS_ORIGINS", value_delimiter = ',')]
+    pub admin_cors_origins: Vec<String>,
+
+    /// Posts a single signing key may submit per rate window. 0 disables the limit.
+    #[arg(long, env = "POST_RATE_LIMIT", default_value_t = 60)]
+    pub post_rate_limit: usize,
+
+    /// Posts a single signing key may submit per rate window through
+    /// `/_openherd/post`. Unset uses `--post-rate-limit`; 0 disables it.
+    #[arg(long, env = "CLIENT_POST_RATE_LIMIT")]
+    pub client_post_rate_limit: Option<usize>,
+
+    /// Length of the per-key post rate window, in seconds.
+    #[arg(long, env = "POST_RATE_WINDOW_SECS", default_value_t = 3600)]
+    pub post_rate_window_secs: u64,
+
+    /// Multiplier on `--post-rate-limit` for keys whose posts have negative
+    /// net karma.
+    #[arg(long, env = "KARMA_RATE_LOW_FACTOR", default_value_t = 0.5)]
+    pub karma_rate_low_factor: f64,
+
+    /// Net karma at which a key counts as trusted for rate limiting.
+    #[arg(long, env = "KARMA_RATE_HIGH_THRESHOLD", default_value_t = 10)]
+    pub karma_rate_high_threshold: i32,
+
+    /// Multiplier on `--post-rate-limit` for trusted keys. Set both factors
+    /// to 1 to rate limit every key the same.
+    #[arg(long, env = "KARMA_RATE_HIGH_FACTOR", default_value_t = 2.0)]
+    pub karma_rate_high_factor: f64,
+
+    /// Age in hours after which unresolved reports leave the active queue.
+    /// 0 keeps reports until an admin resolves them.
+    #[arg(long, env = "REPORT_MAX_AGE_HOURS", default_value_t = 720)]
+    pub report_max_age_hours: u64,
+
+    /// Most reports held for review at once; 0 means unlimited. What
+    /// happens past it is set by `--report-overflow`.
+    #[arg(long, env = "MAX_MODERATION_REPORTS", default_value_t = 10_000)]
+    pub max_moderation_reports: usize,
+
+    /// When the report queue is full: `reject` answers new reports with 429,
+    /// `evict-oldest` archives the oldest queued reports to make room.
+    #[arg(long, env = "REPORT_OVERFLOW", value_enum, default_value_t = ReportOverflow::Reject)]
+    pub report_overflow: ReportOverflow,
+
+    /// Distinct reporters a post needs before its report count is shown
+    /// publicly. Lower counts read as 0.
+    #[arg(long, env = "PUBLIC_REPORT_THRESHOLD", default_value_t = 3)]
+    pub public_report_threshold: usize,
+
+    /// Let anyone read a post's report categories, each shown once it has
+    /// `--public-report-threshold` reporters. Free-text reasons stay
+    /// admin-only either way.
+    #[arg(long, env = "PUBLIC_REPORT_CATEGORIES")]
+    pub public_report_categories: bool,
+
+    /// JSON file of rules that label matching posts as they arrive or are
+    /// reported. See `rules` for the format. Off unless set: labels
+    /// federate, so a loose rule mislabels posts on peers too.
+    #[arg(long, env = "LABEL_RULES")]
+    pub label_rules: Option<PathBuf>,
+
+    /// Drop expired reports instead of archiving them under `archived_report:`.
+    #[arg(long, env = "DROP_EXPIRED_REPORTS")]
+    pub drop_expired_reports: bool,
+
+    /// Decimal places post coordinates are rounded to in the outbox, search,
+    /// bbox, by-keys, detail and quotes responses; 2 is about 1 km. The
+    /// coordinates are inside the signed data, so those envelopes no longer
+    /// verify and the responses carry `X-OpenHerd-Geo-Precision`. Peers can
+    /// then not pull from this node; federate by pushing, which like WebSub
+    /// sends the exact stored envelopes. Unset serves exact coordinates.
+    #[arg(long, env = "GEO_PRECISION", value_parser = clap::value_parser!(u32).range(0..=8))]
+    pub geo_precision: Option<u32>,
+
+    /// Leading zero bits of proof of work required on incoming posts.
+    /// 0 disables the requirement.
+    #[arg(long, env = "POW_DIFFICULTY", default_value_t = 0)]
+    pub pow_difficulty: u32,
+
+    /// Proof of work required on posts clients submit to `/_openherd/post`.
+    /// Unset uses `--pow-difficulty`.
+    #[arg(long, env = "CLIENT_POW_DIFFICULTY")]
+    pub client_pow_difficulty: Option<u32>,
+
+    /// Deepest reply accepted, counting a reply to a top-level post as 1.
+    /// Only parents this node holds are counted. 0 allows any depth.
+    #[arg(long, env = "MAX_REPLY_DEPTH", default_value_t = 0)]
+    pub max_reply_depth: usize,
+
+    /// Largest armored public key accepted in an envelope, in bytes.
+    #[arg(long, env = "MAX_PUBLIC_KEY_BYTES", default_value_t = 16 * 1024)]
+    pub max_public_key_bytes: usize,
+
+    /// Largest armored signature accepted in an envelope, in bytes.
+    #[arg(long, env = "MAX_SIGNATURE_BYTES", default_value_t = 4 * 1024)]
+    pub max_signature_bytes: usize,
+
+    /// Largest signed post data accepted in an envelope, in bytes.
+    #[arg(long, env = "MAX_POST_DATA_BYTES", default_value_t = 16 * 1024)]
+    pub max_post_data_bytes: usize,
+
+    /// Headers a reverse proxy puts the client's address in, most trusted
+    /// first, e.g. `CF-Connecting-IP,X-Forwarded-For`. The first address in
+    /// the first one present is used; without any, the socket peer. Clients
+    /// can set these headers themselves, so when the node is not behind a
+    /// proxy that overwrites them, pass an empty value to use only the
+    /// socket peer.
+    #[arg(
+        long,
+        env = "CLIENT_IP_HEADERS",
+        value_delimiter = ',',
+        default_value = "X-Forwarded-For,X-Real-IP"
+    )]
+    pub client_ip_headers: Vec<String>,
+
+    /// Where admin passwords are checked: `sled` (managed with
+    /// `enroll-admin`), `env` or `file`.
+    #[arg(long, env = "ADMIN_BACKEND", value_enum, default_value_t = AdminBackendKind::Sled)]
+    pub admin_backend: AdminBackendKind,
+
+    /// Environment variable holding comma-separated admin passwords for the
+    /// `env` backend.
+    #[arg(
+        long,
+        env = "ADMIN_PASSWORDS_ENV",
+        default_value = "OPENHERD_ADMIN_PASSWORDS"
+    )]
+    pub admin_passwords_env: String,
+
+    /// File with one admin password per line for the `file` backend.
+    #[arg(
+        long,
+        env = "ADMIN_PASSWORDS_FILE",
+        default_value = "./admin-passwords"
+    )]
+    pub admin_passwords_file: PathBuf,
+
+    /// Shortest query accepted by `/_openherd/search`.
+    #[arg(long, env = "SEARCH_MIN_QUERY_LEN", default_value_t = 3)]
+    pub search_min_query_len: usize,
+
+    /// Most results a single search page may return.
+    #[arg(long, env = "SEARCH_MAX_RESULTS", default_value_t = 100)]
+    pub search_max_results: usize,
+
+    /// Lifetime of bearer tokens issued by `/_openherd/admin/login`, in seconds.
+    #[arg(long, env = "ADMIN_TOKEN_TTL_SECS", default_value_t = 3600)]
+    pub admin_token_ttl_secs: u64,
+
+    /// Most karma codes a single admin generation request may create.
+    #[arg(long, env = "MAX_KARMA_CODES_PER_REQUEST", default_value_t = 1000)]
+    pub max_karma_codes_per_request: u32,
+
+    /// Largest vote weight a generated karma code may carry.
+    #[arg(long, env = "MAX_KARMA_WEIGHT", default_value_t = 10)]
+    pub max_karma_weight: i32,
+
+    /// Serve the karma voting and code administration endpoints.
+    #[arg(long, env = "ENABLE_KARMA", default_value_t = true, action = ArgAction::Set)]
+    pub enable_karma: bool,
+
+    /// Reactions clients may leave on posts, comma separated, e.g.
+    /// `👍,❤️,😂`. Empty turns reactions off.
+    #[arg(long, env = "REACTIONS", value_delimiter = ',')]
+    pub reactions: Vec<String>,
+
+    /// Reactions a single client address may leave per post rate window.
+    /// 0 disables the limit.
+    #[arg(long, env = "REACTION_RATE_LIMIT", default_value_t = 120)]
+    pub reaction_rate_limit: usize,
+
+    /// Distinct reactions kept per post; further new ones are refused.
+    /// 0 means no cap.
+    #[arg(long, env = "MAX_REACTIONS_PER_POST", default_value_t = 1000)]
+    pub max_reactions_per_post: usize,
+
+    /// Accept moderation reports and serve the admin report queue.
+    #[arg(long, env = "ENABLE_REPORTS", default_value_t = true, action = ArgAction::Set)]
+    pub enable_reports: bool,
+
+    /// Serve the admin UI and API. Federation endpoints stay up either way.
+    #[arg(long, env = "ENABLE_ADMIN", default_value_t = true, action = ArgAction::Set)]
+    pub enable_admin: bool,
+
+    /// Ask peers for MessagePack when syncing, and push it to peers that
+    /// answer in it. Peers without support keep getting JSON.
+    #[arg(long, env = "SYNC_MSGPACK", default_value_t = true, action = ArgAction::Set)]
+    pub sync_msgpack: bool,
+
+    /// Skip envelopes we already hold unchanged when pulling a peer's outbox,
+    /// instead of verifying them again.
+    #[arg(long, env = "SYNC_SKIP_KNOWN", default_value_t = true, action = ArgAction::Set)]
+    pub sync_skip_known: bool,
+
+    /// Accept plain `http://` peer addresses. Turn off to federate over TLS
+    /// only.
+    #[arg(long, env = "ALLOW_INSECURE_PEERS", default_value_t = true, action = ArgAction::Set)]
+    pub allow_insecure_peers: bool,
+
+    /// Skip signature verification of envelopes pulled from peers an admin
+    /// has marked trusted, checking only their structure, size, proof of
+    /// work and this node's policies. Verification is the bulk of the cost
+    /// of a pull, but with it off a trusted peer can hand us posts under
+    /// any author's id: a compromised or buggy peer can forge posts that
+    /// this node then serves and pushes on as genuine. It cannot replace a
+    /// post we already hold with one under a different key. Only trust
+    /// peers run by the same operator. Off by default.
+    #[arg(long, env = "TRUST_PEER_VALIDATION")]
+    pub trust_peer_validation: bool,
+
+    /// Shortest time between two syncs with the same peer, in seconds.
+    /// Requests within it are refused unless an admin forces them, and the
+    /// background monitor skips the peer. 0 disables the cooldown.
+    #[arg(long, env = "PEER_SYNC_COOLDOWN_SECS", default_value_t = 60)]
+    pub peer_sync_cooldown_secs: u64,
+
+    /// Have the background monitor sync with every known peer each round,
+    /// instead of only checking that it is up. Off by default, so peers
+    /// sync when an admin or a peer asks.
+    #[arg(long, env = "PEER_MONITOR_SYNC")]
+    pub peer_monitor_sync: bool,
+
+    /// Sync direction for particular peers in the background monitor with
+    /// `--peer-monitor-sync`, as `address=direction` with direction `pull`,
+    /// `push` or `both`, e.g. `https://hub.example=push`. Unlisted peers
+    /// sync both ways.
+    #[arg(long, env = "PEER_SYNC_DIRECTIONS", value_delimiter = ',', value_parser = parse_peer_direction)]
+    pub peer_sync_directions: Vec<(String, SyncDirection)>,
+
+    /// How long a peer that keeps failing is kept after it is quarantined
+    /// for repeated failed syncs, in seconds. It is still synced meanwhile
+    /// and becomes healthy again on the first success. 0 removes it as soon
+    /// as it is quarantined.
+    #[arg(long, env = "PEER_REMOVAL_GRACE_SECS", default_value_t = 86_400)]
+    pub peer_removal_grace_secs: u64,
+
+    /// Most peers to track. When full, a new peer replaces the known peer
+    /// with the most failed syncs, or is refused if none is failing. 0 means
+    /// no limit.
+    #[arg(long, env = "MAX_PEERS", default_value_t = 100)]
+    pub max_peers: usize,
+
+    /// Serve the WebSub hub at `/_openherd/hub` and push new posts to its
+    /// subscribers. Every subscription request makes this node fetch the
+    /// callback URL, so it is off by default.
+    #[arg(long, env = "WEBSUB")]
+    pub websub: bool,
+
+    /// Most live WebSub subscriptions. Past it, new callbacks are refused
+    /// until one expires or unsubscribes. 0 means no limit.
+    #[arg(long, env = "WEBSUB_MAX_SUBSCRIPTIONS", default_value_t = 100)]
+    pub websub_max_subscriptions: usize,
+
+    /// Accept WebSub callbacks that resolve to loopback, private or
+    /// link-local addresses. Only for subscribers on the same host or
+    /// network; otherwise anyone could make this node reach internal
+    /// services.
+    #[arg(long, env = "WEBSUB_ALLOW_PRIVATE_CALLBACKS")]
+    pub websub_allow_private_callbacks: bool,
+
+    /// Longest WebSub subscription lease granted, in seconds. Subscribers
+    /// asking for longer, or not saying, get this.
+    #[arg(long, env = "WEBSUB_MAX_LEASE_SECS", default_value_t = 10 * 24 * 3600)]
+    pub websub_max_lease_secs: u64,
+
+    /// Run as a read-only mirror that pulls from peers but takes no writes
+    /// from clients. `POST /_openherd/inbox` is only served to pushes signed
+    /// by `--inbox-trusted-keys`, and not at all without any. Karma voting
+    /// (`PATCH .../upvote`, `PATCH .../downvote`, `DELETE /_openherd/karma/:code`)
+    /// and `POST /_openherd/moderation/report` are not served. Reads, `sync`,
+    /// the peer monitor and the admin API are unaffected.
+    #[arg(long, env = "MIRROR")]
+    pub mirror: bool,
+
+    /// Reject inbox pushes that are not signed by a trusted node key.
+    #[arg(long, env = "REQUIRE_SIGNED_INBOX")]
+    pub require_signed_inbox: bool,
+
+    /// Node key fingerprints allowed to push when signed inbox is required.
+    #[arg(long, env = "INBOX_TRUSTED_KEYS", value_delimiter = ',')]
+    pub inbox_trusted_keys: Vec<String>,
+
+    /// Admin key fingerprints whose signed requests the admin API accepts
+    /// in place of a password. See `federation` for the scheme.
+    #[arg(long, env = "ADMIN_SIGNING_KEYS", value_delimiter = ',')]
+    pub admin_signing_keys: Vec<String>,
+
+    /// Refuse admin requests not signed by an `--admin-signing-keys` key.
+    /// Signed requests then still need the password or a token as well.
+    #[arg(long, env = "REQUIRE_ADMIN_SIGNATURE")]
+    pub require_admin_signature: bool,
+
+    /// When sled is flushed to disk. `every-write` flushes before a write is
+    /// acknowledged and loses nothing on a crash. `periodic` flushes every
+    /// `--flush-interval-ms` and may lose that much on a crash; `on-shutdown`
+    /// only flushes on a clean exit and relies on sled's own background
+    /// flushing otherwise. Both are much faster under heavy import load.
+    #[arg(long, env = "FLUSH_POLICY", value_enum, default_value_t = FlushPolicy::EveryWrite)]
+    pub flush_policy: FlushPolicy,
+
+    /// Interval for `--flush-policy periodic`.
+    #[arg(long, env = "FLUSH_INTERVAL_MS", default_value_t = 1000)]
+    pub flush_interval_ms: u64,
+
+    /// Compress stored envelopes with zstd. Existing entries are read either
+    /// way and are rewritten in the new form when next updated.
+    #[arg(long, env = "COMPRESS_POSTS")]
+    pub compress_posts: bool,
+
+    /// Write each inbox request as a single atomic sled batch instead of one
+    /// insert per envelope.
+    #[arg(long, env = "INBOX_BATCH_WRITES")]
+    pub inbox_batch_writes: bool,
+
+    /// Log requests that take at least this long, in milliseconds. 0 turns
+    /// slow-request logging off; timings are still collected for
+    /// `/_openherd/metrics`.
+    #[arg(long, env = "SLOW_REQUEST_MS", default_value_t = 1000)]
+    pub slow_request_ms: u64,
+
+    /// Log one in this many new or changed posts as they are stored: id,
+    /// position, date and text length, never the text. 0 turns it off.
+    #[arg(long, env = "POST_LOG_SAMPLE", default_value_t = 0)]
+    pub post_log_sample: u64,
+
+    /// Requests handled at once across all routes except `/_openherd/health`
+    /// and `/_openherd/metrics`; past it new requests get 503 straight away
+    /// instead of queueing on the state lock. 0 means
+    /// unlimited. Shed requests never reach the per-key post rate limit or
+    /// body parsing, so this caps work before either of those applies.
+    #[arg(long, env = "MAX_CONCURRENT_REQUESTS", default_value_t = 256)]
+    pub max_concurrent_requests: usize,
+
+    /// Answer stored posts on `/_openherd/post` and `/_openherd/inbox` with
+    /// a receipt signed by the node key. A receipt is only as durable as
+    /// `--flush-policy` makes the post. See `federation` for the format.
+    #[arg(long, env = "POST_RECEIPTS")]
+    pub post_receipts: bool,
+
+    /// Rebuild the full-text search index from stored posts at startup.
+    #[arg(long)]
+    pub reindex: bool,
+
+    /// Fix what the startup integrity check finds instead of only logging
+    /// it: unreadable posts and posts stored under another id move under
+    /// `damaged:`, posts sharing a `seq` are renumbered, and a search index
+    /// that disagrees with the stored posts is rebuilt.
+    #[arg(long)]
+    pub repair: bool,
+
+    /// Re-verify every stored post's signature in the background at startup,
+    /// quarantining any that fail.
+    #[arg(long)]
+    pub verify_on_start: bool,
+
+    /// Keep envelopes rejected by the inbox or sync, with the reason, for
+    /// inspection under `/_openherd/admin/dead-letters`. Off by default as it
+    /// stores untrusted data.
+    #[arg(long, env = "DEAD_LETTERS")]
+    pub dead_letters: bool,
+
+    /// Armored public key file, or directory of them. When set, only posts
+    /// signed by one of these keys are accepted, making the node part of a
+    /// closed network. Unset accepts any self-signed post.
+    #[arg(long, env = "TRUSTED_KEYRING")]
+    pub trusted_keyring: Option<PathBuf>,
+
+    /// Denylist of terms and `re:` patterns; posts whose text matches one
+    /// are rejected. See `filter` for the format. Reloaded by
+    /// `/_openherd/admin/content-filter/reload`.
+    #[arg(long, env = "CONTENT_FILTER")]
+    pub content_filter: Option<PathBuf>,
+
+    /// Most rejected envelopes kept; the oldest are dropped first.
+    #[arg(long, env = "DEAD_LETTER_MAX", default_value_t = 1000)]
+    pub dead_letter_max: usize,
+
+    /// What happens when a karma code is used on a post outside the code's
+    /// region: `enforce` rejects the vote, `warn` allows it but logs and
+    /// counts it, `off` skips the check.
+    #[arg(long, env = "REGION_MODE", value_enum, default_value_t = RegionMode::Warn)]
+    pub region_mode: RegionMode,
+
+    /// Which date orders posts in search and map results: the author's
+    /// signed `date`, or the time this node received the post, which
+    /// clients cannot backdate.
+    #[arg(long, env = "POST_DATE_SOURCE", value_enum, default_value_t = PostDateSource::Signed)]
+    pub post_date_source: PostDateSource,
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AdminBackendKind {
+    Sled,
+    Env,
+    File,
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PostDateSource {
+    /// The `date` in the signed post, chosen by its author.
+    Signed,
+    /// When this node first stored the post.
+    Received,
+}
+
+impl PostDateSource {
+    /// The date a post is ordered by. Envelopes stored before `received_at`
+    /// existed fall back to the signed date.
+    pub fn date_of(self, envelope: &Envelope, post: &Post) -> DateTime<Utc> {
+        match self {
+            PostDateSource::Signed => post.date,
+            PostDateSource::Received => envelope.received_at.unwrap_or(post.date),
+        }
+    }
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RegionMode {
+    Enforce,
+    Warn,
+    Off,
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReportOverflow {
+    Reject,
+    EvictOldest,
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FlushPolicy {
+    EveryWrite,
+    Periodic,
+    OnShutdown,
+}
+
+fn parse_peer_direction(value: &str) -> Result<(String, SyncDirection), String> {
+    let (address, direction) = value
+        .rsplit_once('=')
+        .ok_or_else(|| format!("expected address=direction, got {}", value))?;
+    let address = normalize_peer_address(address)
+        .ok_or_else(|| format!("invalid peer address {}", address))?;
+    let direction = SyncDirection::from_str(direction.trim(), true)?;
+    Ok((address, direction))
+}
+
+impl Config {
+    /// How the background monitor syncs with `addr`.
+    pub fn sync_direction_for(&self, addr: &str) -> SyncDirection {
+        self.peer_sync_directions
+            .iter()
+            .find(|(peer, _)| peer == addr)
+            .map_or(SyncDirection::Both, |(_, direction)| *direction)
+    }
+
+    pub fn pow_difficulty_for(&self, from: Submitter) -> u32 {
+        match from {
+            Submitter::Peer => self.pow_difficulty,
+            Submitter::Client => self.client_pow_difficulty.unwrap_or(self.pow_difficulty),
+        }
+    }
+
+    pub fn post_rate_limit_for(&self, from: Submitter) -> usize {
+        match from {
+            Submitter::Peer => self.post_rate_limit,
+            Submitter::Client => self.client_post_rate_limit.unwrap_or(self.post_rate_limit),
+        }
+    }
+
+    pub fn envelope_limits(&self) -> EnvelopeLimits {
+        EnvelopeLimits {
+            public_key: self.max_public_key_bytes,
+            signature: self.max_signature_bytes,
+            data: self.max_post_data_bytes,
+        }
+    }
+
+    pub fn listen_addr(&self) -> SocketAddr {
+        self.bind.unwrap_or(SocketAddr::new(
+            IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            self.port,
+        ))
+    }
+}
+
+impl Default for Config {
+    /// The built-in defaults, ignoring the environment so tests and embedders
+    /// get the same settings wherever they run.
+    fn default() -> Self {
+        let matches = defaults_command().get_matches_from(["openherd-cow"]);
+        Self::from_arg_matches(&matches).expect("defaults parse")
+    }
+}
+
+/// The `Config` command with no argument reading an environment variable.
+fn defaults_command() -> clap::Command {
+    Config::command().mut_args(|arg| arg.env(None))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_default_ignores_the_environment() {
+        let reads_env =
+            |command: clap::Command| command.get_arguments().any(|arg| arg.get_env().is_some());
+        assert!(reads_env(Config::command()));
+        assert!(!reads_env(defaults_command()));
+        assert_eq!(Config::default().max_concurrent_requests, 256);
+    }
+}
@@ -0,0 +1,113 @@
+use crate::types::ValidationError;
+use axum::{
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde::{Deserialize, Serialize};
+use std::sync::PoisonError;
+
+/// Error returned by handlers. Rendered as its status code with a JSON
+/// `ErrorBody` so clients can tell failures apart.
+#[derive(Debug, thiserror::Error)]
+pub enum ApiError {
+    /// A bare status; the message is the status's canonical reason.
+    #[error("{}", .0.canonical_reason().unwrap_or("error"))]
+    Status(StatusCode),
+    #[error("{1}")]
+    WithMessage(StatusCode, String),
+    #[error("invalid envelope: {0}")]
+    Validation(#[from] ValidationError),
+    #[error("server state is unavailable")]
+    LockPoisoned,
+    #[error("database error: {0}")]
+    Db(#[from] sled::Error),
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ErrorBody {
+    pub code: String,
+    pub message: String,
+}
+
+impl ApiError {
+    pub fn new(status: StatusCode, message: impl Into<String>) -> Self {
+        ApiError::WithMessage(status, message.into())
+    }
+
+    pub fn bad_request(message: impl Into<String>) -> Self {
+        Self::new(StatusCode::BAD_REQUEST, message)
+    }
+
+    pub fn unauthorized(message: impl Into<String>) -> Self {
+        Self::new(StatusCode::UNAUTHORIZED, message)
+    }
+
+    pub fn not_found(message: impl Into<String>) -> Self {
+        Self::new(StatusCode::NOT_FOUND, message)
+    }
+
+    pub fn status(&self) -> StatusCode {
+        match self {
+            ApiError::Status(status) | ApiError::WithMessage(status, _) => *status,
+            ApiError::Validation(ValidationError::TooLarge { .. }) => StatusCode::PAYLOAD_TOO_LARGE,
+            ApiError::Validation(ValidationError::UntrustedKey) => StatusCode::FORBIDDEN,
+            ApiError::Validation(_) => StatusCode::BAD_REQUEST,
+            ApiError::LockPoisoned | ApiError::Db(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    /// Stable machine-readable code for the `code` field.
+    pub fn code(&self) -> &'static str {
+        match self {
+            ApiError::Validation(_) => "invalid_envelope",
+            ApiError::LockPoisoned => "state_unavailable",
+            ApiError::Db(_) => "database_error",
+            ApiError::Status(status) | ApiError::WithMessage(status, _) => match *status {
+                StatusCode::BAD_REQUEST => "bad_request",
+                StatusCode::UNAUTHORIZED => "unauthorized",
+                StatusCode::FORBIDDEN => "forbidden",
+                StatusCode::NOT_FOUND => "not_found",
+                StatusCode::CONFLICT => "conflict",
+                StatusCode::GONE => "gone",
+                StatusCode::PAYLOAD_TOO_LARGE => "payload_too_large",
+                StatusCode::TOO_MANY_REQUESTS => "too_many_requests",
+                StatusCode::SERVICE_UNAVAILABLE => "unavailable",
+                s if s.is_server_error() => "internal_error",
+                _ => "error",
+            },
+        }
+    }
+}
+
+impl From<StatusCode> for ApiError {
+    fn from(status: StatusCode) -> Self {
+        ApiError::Status(status)
+    }
+}
+
+impl<T> From<PoisonError<T>> for ApiError {
+    fn from(_: PoisonError<T>) -> Self {
+        ApiError::LockPoisoned
+    }
+}
+
+/// Lets tests compare a handler's error straight against a status code.
+impl PartialEq<StatusCode> for ApiError {
+    fn eq(&self, other: &StatusCode) -> bool {
+        self.status() == *other
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        if self.status().is_server_error() {
+            eprintln!("Request failed: {}", self);
+        }
+        let body = ErrorBody {
+            code: self.code().to_string(),
+            message: self.to_string(),
+        };
+        (self.status(), Json(body)).into_response()
+    }
+}
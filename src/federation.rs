@@ -0,0 +1,293 @@
+//! Signed inbox pushes between nodes.
+//!
+//! Every node has a long-lived OpenPGP node key, generated on first start and
+//! kept in sled. When pushing to a peer's `/_openherd/inbox` a node sends:
+//!
+//! - `X-OpenHerd-Node-Key`: base64 of its ASCII-armored public key
+//! - `X-OpenHerd-Date`: the current time, RFC 3339
+//! - `X-OpenHerd-Signature`: base64 of an ASCII-armored detached signature
+//!   over the bytes of the date header, a `\n`, then the raw request body
+//!
+//! With `--require-signed-inbox` the receiver only accepts pushes whose key
+//! fingerprint (lowercase hex) is listed in `--inbox-trusted-keys`, whose date
+//! is within `MAX_CLOCK_SKEW` of its own clock and whose signature verifies.
+//! Nodes can read each other's key from `/_openherd/node`.
+//!
+//! Admin requests may be signed the same way, with the admin's key in
+//! `X-OpenHerd-Admin-Key` and the signature covering the date, the method
+//! and the path with its query, each followed by `\n`, then the body. See
+//! `admin_signed_bytes`. Keys listed in `--admin-signing-keys` are trusted.
+//!
+//! With `--post-receipts` the node also signs a `PostReceipt` for every post
+//! it stores from `/_openherd/post` or `/_openherd/inbox`, once the post has
+//! been flushed under `--flush-policy`. The receipt's `signature` is an
+//! ASCII-armored detached signature, by the node key whose fingerprint is
+//! `node`, over the UTF-8 bytes of
+//!
+//! ```text
+//! openherd-receipt\n{id}\n{data_sha256}\n{received_at}\n{node}
+//! ```
+//!
+//! where `data_sha256` is the lowercase hex SHA-256 of the envelope's `data`,
+//! so the receipt vouches for one version of the post, and `received_at` is
+//! exactly as serialized in the receipt: RFC 3339 in UTC with a `Z` suffix.
+//! Anyone holding the node's public key (from `/_openherd/node`) can check a
+//! receipt with `verify_receipt`.
+
+use crate::types::{PostReceipt, ValidationError};
+use crate::validation::{generate_signing_key, sign_detached, verify_detached};
+use axum::http::HeaderMap;
+use base64::{engine::general_purpose::STANDARD, Engine};
+use chrono::{DateTime, SecondsFormat, Utc};
+use pgp::types::{KeyTrait, SecretKeyTrait};
+use pgp::{Deserializable, SignedPublicKey, SignedSecretKey};
+use sha2::{Digest, Sha256};
+
+pub const NODE_KEY_HEADER: &str = "x-openherd-node-key";
+pub const DATE_HEADER: &str = "x-openherd-date";
+pub const SIGNATURE_HEADER: &str = "x-openherd-signature";
+pub const ADMIN_KEY_HEADER: &str = "x-openherd-admin-key";
+
+pub(crate) const NODE_KEY_DB_KEY: &[u8] = b"__node_key__";
+pub(crate) const MAX_CLOCK_SKEW: chrono::Duration = chrono::Duration::minutes(5);
+
+/// Why a signed push was refused.
+#[derive(Debug, thiserror::Error)]
+pub enum PushAuthError {
+    #[error("missing or malformed signature headers")]
+    Missing,
+    #[error("request date is outside the allowed clock skew")]
+    Stale,
+    #[error("node key {0} is not trusted")]
+    Untrusted(String),
+    #[error("signature does not verify: {0}")]
+    BadSignature(#[from] ValidationError),
+}
+
+pub struct NodeKey {
+    secret: SignedSecretKey,
+    public_key: String,
+}
+
+impl NodeKey {
+    pub fn generate() -> Result<Self, ValidationError> {
+        let (secret, public_key) = generate_signing_key("openherd node")?;
+        Ok(Self { secret, public_key })
+    }
+
+    /// Loads the node key from sled, generating and storing one on first use.
+    pub fn load_or_generate(db: &sled::Db) -> Result<Self, String> {
+        if let Some(bytes) = db.get(NODE_KEY_DB_KEY).map_err(|e| e.to_string())? {
+            let armored = String::from_utf8(bytes.to_vec()).map_err(|e| e.to_string())?;
+            let (secret, _) = SignedSecretKey::from_string(&armored).map_err(|e| e.to_string())?;
+            let public_key = secret
+                .public_key()
+                .sign(&secret, String::new)
+                .and_then(|k| k.to_armored_string(None.into()))
+                .map_err(|e| e.to_string())?;
+            return Ok(Self { secret, public_key });
+        }
+        let key = Self::generate().map_err(|e| e.to_string())?;
+        let armored = key
+            .secret
+            .to_armored_string(None.into())
+            .map_err(|e| e.to_string())?;
+        db.insert(NODE_KEY_DB_KEY, armored.as_bytes())
+            .map_err(|e| e.to_string())?;
+        db.flush().map_err(|e| e.to_string())?;
+        Ok(key)
+    }
+
+    pub fn fingerprint(&self) -> String {
+        hex::encode(self.secret.fingerprint())
+    }
+
+    pub fn public_key(&self) -> &str {
+        &self.public_key
+    }
+
+    /// Headers authenticating a push of `body`, dated now.
+    pub fn sign_push(&self, body: &[u8]) -> Result<Vec<(&'static str, String)>, ValidationError> {
+        let date = Utc::now().to_rfc3339();
+        let signature = sign_detached(&self.secret, &signed_bytes(&date, body))?;
+        Ok(vec![
+            (NODE_KEY_HEADER, STANDARD.encode(&self.public_key)),
+            (DATE_HEADER, date),
+            (SIGNATURE_HEADER, STANDARD.encode(signature)),
+        ])
+    }
+
+    /// Headers authenticating an admin request, dated now. Any key can be
+    /// an admin key; this one need not be a node's.
+    pub fn sign_admin_request(
+        &self,
+        method: &str,
+        path_and_query: &str,
+        body: &[u8],
+    ) -> Result<Vec<(&'static str, String)>, ValidationError> {
+        let date = Utc::now().to_rfc3339();
+        let data = admin_signed_bytes(&date, method, path_and_query, body);
+        let signature = sign_detached(&self.secret, &data)?;
+        Ok(vec![
+            (ADMIN_KEY_HEADER, STANDARD.encode(&self.public_key)),
+            (DATE_HEADER, date),
+            (SIGNATURE_HEADER, STANDARD.encode(signature)),
+        ])
+    }
+
+    /// This node's acknowledgement that it stored post `id`, with envelope
+    /// `data`, at `received_at`.
+    pub fn sign_receipt(
+        &self,
+        id: &str,
+        data: &str,
+        received_at: DateTime<Utc>,
+    ) -> Result<PostReceipt, ValidationError> {
+        let node = self.fingerprint();
+        let data_sha256 = hex::encode(Sha256::digest(data.as_bytes()));
+        let signed = receipt_signed_bytes(id, &data_sha256, received_at, &node);
+        let signature = sign_detached(&self.secret, &signed)?;
+        Ok(PostReceipt {
+            id: id.to_string(),
+            data_sha256,
+            received_at,
+            node,
+            signature,
+        })
+    }
+}
+
+/// What a node signs for a receipt; see the module docs.
+pub fn receipt_signed_bytes(
+    id: &str,
+    data_sha256: &str,
+    received_at: DateTime<Utc>,
+    node: &str,
+) -> Vec<u8> {
+    format!(
+        "openherd-receipt\n{}\n{}\n{}\n{}",
+        id,
+        data_sha256,
+        received_at.to_rfc3339_opts(SecondsFormat::AutoSi, true),
+        node
+    )
+    .into_bytes()
+}
+
+/// Checks that `receipt` was signed by the node whose armored public key is
+/// `node_public_key`, and that it names that node.
+pub fn verify_receipt(receipt: &PostReceipt, node_public_key: &str) -> Result<(), ValidationError> {
+    let (key, _) = SignedPublicKey::from_string(node_public_key)?;
+    if !hex::encode(key.fingerprint()).eq_ignore_ascii_case(&receipt.node) {
+        return Err(ValidationError::InvalidSignature);
+    }
+    let data = receipt_signed_bytes(
+        &receipt.id,
+        &receipt.data_sha256,
+        receipt.received_at,
+        &receipt.node,
+    );
+    verify_detached(&receipt.signature, &data, &key)
+}
+
+fn signed_bytes(date: &str, body: &[u8]) -> Vec<u8> {
+    let mut data = Vec::with_capacity(date.len() + 1 + body.len());
+    data.extend_from_slice(date.as_bytes());
+    data.push(b'\n');
+    data.extend_from_slice(body);
+    data
+}
+
+fn decoded_header(headers: &HeaderMap, name: &str) -> Result<String, PushAuthError> {
+    let value = headers
+        .get(name)
+        .and_then(|v| v.to_str().ok())
+        .ok_or(PushAuthError::Missing)?;
+    let bytes = STANDARD.decode(value).map_err(|_| PushAuthError::Missing)?;
+    String::from_utf8(bytes).map_err(|_| PushAuthError::Missing)
+}
+
+/// Checks a signed push against the trusted fingerprints, returning the
+/// sender's fingerprint.
+pub fn verify_push(
+    headers: &HeaderMap,
+    body: &[u8],
+    trusted: &[String],
+) -> Result<String, PushAuthError> {
+    verify_signed(headers, NODE_KEY_HEADER, trusted, |date| {
+        signed_bytes(date, body)
+    })
+}
+
+/// What an admin client signs for a request: the `X-OpenHerd-Date` value,
+/// the method and the path with query, each followed by `\n`, then the body.
+pub fn admin_signed_bytes(date: &str, method: &str, path_and_query: &str, body: &[u8]) -> Vec<u8> {
+    let head = format!("{}\n{}", method, path_and_query);
+    signed_bytes(date, &signed_bytes(&head, body))
+}
+
+/// What a signed admin request is remembered by so it is accepted once:
+/// a SHA-256 over the signer and the signed content. Unlike the signature
+/// itself, which can be re-encoded without invalidating it, this is the
+/// same for every form of the same signed request.
+pub fn admin_replay_key(
+    fingerprint: &str,
+    date: &str,
+    method: &str,
+    path_and_query: &str,
+    body: &[u8],
+) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(signed_bytes(
+        fingerprint,
+        &admin_signed_bytes(date, method, path_and_query, body),
+    ));
+    hex::encode(hasher.finalize())
+}
+
+/// Checks a signed admin request against the trusted admin fingerprints,
+/// returning the signer's fingerprint.
+pub fn verify_admin_request(
+    headers: &HeaderMap,
+    method: &str,
+    path_and_query: &str,
+    body: &[u8],
+    trusted: &[String],
+) -> Result<String, PushAuthError> {
+    verify_signed(headers, ADMIN_KEY_HEADER, trusted, |date| {
+        admin_signed_bytes(date, method, path_and_query, body)
+    })
+}
+
+fn verify_signed(
+    headers: &HeaderMap,
+    key_header: &str,
+    trusted: &[String],
+    signed: impl FnOnce(&str) -> Vec<u8>,
+) -> Result<String, PushAuthError> {
+    let public_key = decoded_header(headers, key_header)?;
+    let signature = decoded_header(headers, SIGNATURE_HEADER)?;
+    let date = headers
+        .get(DATE_HEADER)
+        .and_then(|v| v.to_str().ok())
+        .ok_or(PushAuthError::Missing)?;
+
+    let sent: DateTime<Utc> = DateTime::parse_from_rfc3339(date)
+        .map_err(|_| PushAuthError::Missing)?
+        .with_timezone(&Utc);
+    if (Utc::now() - sent).abs() > MAX_CLOCK_SKEW {
+        return Err(PushAuthError::Stale);
+    }
+
+    let (key, _) = SignedPublicKey::from_string(&public_key).map_err(ValidationError::from)?;
+    let fingerprint = hex::encode(key.fingerprint());
+    if !trusted
+        .iter()
+        .any(|t| t.trim().eq_ignore_ascii_case(&fingerprint))
+    {
+        return Err(PushAuthError::Untrusted(fingerprint));
+    }
+
+    verify_detached(&signature, &signed(date), &key)?;
+    Ok(fingerprint)
+}
@@ -0,0 +1,140 @@
+//! Content denylist from `--content-filter`: one entry per line, checked
+//! against the text of every incoming post.
+//!
+//! ```text
+//! # comments and blank lines are ignored
+//! buy followers
+//! re:https?://spam\.example
+//! ```
+//!
+//! Plain lines match as substrings; lines starting with `re:` are regexes.
+//! The post text and plain entries are NFKC-normalized and lowercased
+//! first, so full-width letters, ligatures and case changes do not slip
+//! past an entry. Regexes are compiled as written, case-insensitively;
+//! lowercasing them would turn classes like `\S` into `\s`.
+
+use crate::types::{Post, ValidationError};
+use regex::Regex;
+use std::path::Path;
+use unicode_normalization::UnicodeNormalization;
+
+#[derive(Debug, Clone, Default)]
+pub struct ContentFilter {
+    terms: Vec<String>,
+    patterns: Vec<Regex>,
+}
+
+fn normalize(text: &str) -> String {
+    text.nfkc().collect::<String>().to_lowercase()
+}
+
+impl ContentFilter {
+    pub fn parse(contents: &str) -> Result<Self, String> {
+        let mut filter = Self::default();
+        for (n, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            match line.strip_prefix("re:") {
+                Some(pattern) => {
+                    let re = Regex::new(&format!("(?i){}", pattern))
+                        .map_err(|e| format!("line {}: {}", n + 1, e))?;
+                    filter.patterns.push(re);
+                }
+                None => filter.terms.push(normalize(line)),
+            }
+        }
+        Ok(filter)
+    }
+
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let contents =
+            std::fs::read_to_string(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+        Self::parse(&contents).map_err(|e| format!("{}: {}", path.display(), e))
+    }
+
+    pub fn terms(&self) -> usize {
+        self.terms.len()
+    }
+
+    pub fn patterns(&self) -> usize {
+        self.patterns.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.terms.is_empty() && self.patterns.is_empty()
+    }
+
+    /// Fails with `BlockedContent` if the post text matches any entry.
+    pub fn check(&self, post: &Post) -> Result<(), ValidationError> {
+        if self.is_empty() {
+            return Ok(());
+        }
+        let text = normalize(&post.text);
+        let blocked = self.terms.iter().any(|term| text.contains(term.as_str()))
+            || self.patterns.iter().any(|re| re.is_match(&text));
+        if blocked {
+            return Err(ValidationError::BlockedContent);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::validation::testing::TestKey;
+
+    fn post(text: &str) -> Post {
+        TestKey::generate().post(text)
+    }
+
+    #[test]
+    fn test_filter_matches_normalized_terms_and_patterns() {
+        let filter =
+            ContentFilter::parse("# spam\nBuy Followers\n\nre:https?://spam\\.example\n").unwrap();
+        assert_eq!((filter.terms(), filter.patterns()), (1, 1));
+
+        for text in [
+            "cheap: buy followers today",
+            "BUY FOLLOWERS",
+            // Full-width letters normalize to ASCII under NFKC.
+            "ｂｕｙ ｆｏｌｌｏｗｅｒｓ",
+            "see HTTP://SPAM.example/x",
+        ] {
+            assert!(
+                matches!(
+                    filter.check(&post(text)),
+                    Err(ValidationError::BlockedContent)
+                ),
+                "{text}"
+            );
+        }
+        assert!(filter.check(&post("followers who buy nothing")).is_ok());
+        assert!(filter.check(&post("https://example.org")).is_ok());
+    }
+
+    #[test]
+    fn test_patterns_keep_their_escapes() {
+        let filter = ContentFilter::parse("re:^\\S+$\nre:\\bCASH\\d\n").unwrap();
+        assert!(matches!(
+            filter.check(&post("oneword")),
+            Err(ValidationError::BlockedContent)
+        ));
+        assert!(matches!(
+            filter.check(&post("free cash4u")),
+            Err(ValidationError::BlockedContent)
+        ));
+        assert!(filter.check(&post("two words")).is_ok());
+        assert!(filter.check(&post("cash d")).is_ok());
+    }
+
+    #[test]
+    fn test_empty_filter_allows_everything() {
+        let filter = ContentFilter::parse("# nothing yet\n").unwrap();
+        assert!(filter.is_empty());
+        assert!(filter.check(&post("anything at all")).is_ok());
+        assert!(ContentFilter::parse("re:(unclosed").is_err());
+    }
+}
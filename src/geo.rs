@@ -0,0 +1,173 @@
+use crate::types::{BoundingBox, Post};
+use chrono::{DateTime, Utc};
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+/// Size of a grid cell in degrees.
+const CELL_DEGREES: f64 = 1.0;
+
+#[derive(Debug, Clone, Copy)]
+struct GeoPoint {
+    lat: f64,
+    lon: f64,
+    date: DateTime<Utc>,
+}
+
+type Cell = (i32, i32);
+
+const GEOHASH_ALPHABET: &[u8; 32] = b"0123456789bcdefghjkmnpqrstuvwxyz";
+
+/// Standard base-32 geohash of a point, `precision` characters long.
+pub fn geohash(lat: f64, lon: f64, precision: usize) -> String {
+    let (mut lat_range, mut lon_range) = ((-90.0, 90.0), (-180.0, 180.0));
+    let mut hash = String::with_capacity(precision);
+    let mut even_bit = true;
+    let (mut bits, mut value) = (0, 0usize);
+    while hash.len() < precision {
+        let (range, coord): (&mut (f64, f64), f64) = if even_bit {
+            (&mut lon_range, lon)
+        } else {
+            (&mut lat_range, lat)
+        };
+        let mid = (range.0 + range.1) / 2.0;
+        value <<= 1;
+        if coord >= mid {
+            value |= 1;
+            range.0 = mid;
+        } else {
+            range.1 = mid;
+        }
+        even_bit = !even_bit;
+        bits += 1;
+        if bits == 5 {
+            hash.push(GEOHASH_ALPHABET[value] as char);
+            bits = 0;
+            value = 0;
+        }
+    }
+    hash
+}
+
+fn cell_of(lat: f64, lon: f64) -> Cell {
+    (
+        (lat / CELL_DEGREES).floor() as i32,
+        (lon / CELL_DEGREES).floor() as i32,
+    )
+}
+
+/// Post coordinates bucketed into a fixed lat/lon grid, kept in step with
+/// `memory` by the import and removal paths on `AppState`. It also counts
+/// posts per date, which gives the time bounds of the whole node cheaply.
+#[derive(Default)]
+pub struct GeoIndex {
+    cells: HashMap<Cell, HashSet<String>>,
+    points: HashMap<String, GeoPoint>,
+    dates: BTreeMap<DateTime<Utc>, usize>,
+}
+
+impl GeoIndex {
+    /// Indexes `post`, ordered by `date` in results.
+    pub fn insert(&mut self, post: &Post, date: DateTime<Utc>) {
+        self.remove(&post.id);
+        let point = GeoPoint {
+            lat: post.latitude,
+            lon: post.longitude,
+            date,
+        };
+        self.cells
+            .entry(cell_of(point.lat, point.lon))
+            .or_default()
+            .insert(post.id.clone());
+        *self.dates.entry(date).or_insert(0) += 1;
+        self.points.insert(post.id.clone(), point);
+    }
+
+    pub fn remove(&mut self, id: &str) {
+        let Some(point) = self.points.remove(id) else {
+            return;
+        };
+        let cell = cell_of(point.lat, point.lon);
+        if let Some(ids) = self.cells.get_mut(&cell) {
+            ids.remove(id);
+            if ids.is_empty() {
+                self.cells.remove(&cell);
+            }
+        }
+        if let Some(count) = self.dates.get_mut(&point.date) {
+            *count -= 1;
+            if *count == 0 {
+                self.dates.remove(&point.date);
+            }
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.cells.clear();
+        self.points.clear();
+        self.dates.clear();
+    }
+
+    /// Dates of the oldest and newest indexed posts.
+    pub fn timespan(&self) -> Option<(DateTime<Utc>, DateTime<Utc>)> {
+        let (oldest, _) = self.dates.first_key_value()?;
+        let (newest, _) = self.dates.last_key_value()?;
+        Some((*oldest, *newest))
+    }
+
+    pub fn len(&self) -> usize {
+        self.points.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.points.is_empty()
+    }
+
+    /// Number of posts per geohash cell of `precision` characters.
+    pub fn density(&self, precision: usize) -> BTreeMap<String, usize> {
+        let mut counts = BTreeMap::new();
+        for point in self.points.values() {
+            *counts
+                .entry(geohash(point.lat, point.lon, precision))
+                .or_insert(0) += 1;
+        }
+        counts
+    }
+
+    /// Ids of posts inside `bbox`, newest first. A box whose `min_lon` is
+    /// greater than its `max_lon` crosses the antimeridian.
+    pub fn within(&self, bbox: &BoundingBox) -> Vec<String> {
+        let lon_ranges = if bbox.min_lon > bbox.max_lon {
+            vec![(bbox.min_lon, 180.0), (-180.0, bbox.max_lon)]
+        } else {
+            vec![(bbox.min_lon, bbox.max_lon)]
+        };
+        let (lat_lo, _) = cell_of(bbox.min_lat, 0.0);
+        let (lat_hi, _) = cell_of(bbox.max_lat, 0.0);
+
+        let mut hits: Vec<(DateTime<Utc>, &String)> = Vec::new();
+        let mut seen: HashSet<&String> = HashSet::new();
+        for (lon_min, lon_max) in lon_ranges {
+            let (_, lon_lo) = cell_of(0.0, lon_min);
+            let (_, lon_hi) = cell_of(0.0, lon_max);
+            for lat_cell in lat_lo..=lat_hi {
+                for lon_cell in lon_lo..=lon_hi {
+                    let Some(ids) = self.cells.get(&(lat_cell, lon_cell)) else {
+                        continue;
+                    };
+                    for id in ids {
+                        let point = &self.points[id];
+                        if point.lat >= bbox.min_lat
+                            && point.lat <= bbox.max_lat
+                            && point.lon >= lon_min
+                            && point.lon <= lon_max
+                            && seen.insert(id)
+                        {
+                            hits.push((point.date, id));
+                        }
+                    }
+                }
+            }
+        }
+        hits.sort_by(|a, b| b.0.cmp(&a.0).then_with(|| a.1.cmp(b.1)));
+        hits.into_iter().map(|(_, id)| id.clone()).collect()
+    }
+}
@@ -1,234 +1,1011 @@
 use crate::{
-    state::{PeerStatus, SharedState},
+    config::{Config, RegionMode},
+    error::ApiError,
+    federation::{self, PushAuthError},
+    filter::ContentFilter,
+    search::naive_search,
+    state::{AppState, PeerStatus, SharedState},
+    store,
+    sync::{is_insecure_peer, normalize_peer_address},
     types::{
-        AdminAuth, ApiResponse, Envelope, KarmaCode, KarmaGenerateRequest, KarmaMetadata,
-        ModerationAction, ModerationLabel, ModerationReport, SyncRequest, SyncResponse,
+        AdminAuth, AdminToken, ApiResponse, AppliedLabel, ArchivedReport, BoundingBoxQuery,
+        Capabilities, ContentFilterStatus, DeadLetter, DensityQuery, DryRunQuery, Envelope,
+        HealthResponse, HubRequest, InboxResponse, IssuerQuery, IssuerStats, IssuerStatsRequest,
+        IssuerVote, KarmaCode, KarmaCodeStatus, KarmaDrift, KarmaGenerateRequest, KarmaMetadata,
+        KarmaRecompute, KarmaRecomputeQuery, KarmaRedemption, KarmaStatusRequest, KeyPosts,
+        LabelImportRequest, LabelImportResponse, LabelProposal, LabelsQuery, ModerationAction,
+        ModerationImpact, ModerationLabel, ModerationReport, NodeInfo, OutboxId, OutboxIdPage,
+        OutboxPage, OutboxQuery, PartialOutbox, PinnedPost, Post, PostDetail, PostQuotes,
+        PostReactions, PostReceipt, PostStatus, PostsByKeysQuery, QuotesQuery, ReasonCount,
+        ReportCategory, ReportReasons, SearchHit, SearchQuery, Submitter, SyncRequest,
+        SyncResponse, Timespan, ValidationError,
     },
-    validation::validate_envelope,
+    validation::{validate_envelope, validate_reaction},
+    websub,
+    wire::{Format, Negotiated, SyncBody, CHANGE_SEQ_HEADER},
 };
 use axum::{
-    extract::{Path, State},
-    http::{HeaderMap, StatusCode},
-    response::{Html, Json},
+    body::Body,
+    extract::{ConnectInfo, Form, Path, Query, Request, State},
+    http::{
+        header::{AUTHORIZATION, CONTENT_DISPOSITION, CONTENT_TYPE},
+        HeaderMap, HeaderName, Method, StatusCode, Uri,
+    },
+    middleware::Next,
+    response::{Html, IntoResponse, Json, Response},
 };
-use chrono::Utc;
+use chrono::{DateTime, Utc};
+use clap::ValueEnum;
 use rand::{distributions::Alphanumeric, Rng};
-use reqwest::StatusCode as HttpStatus;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::net::SocketAddr;
+use std::sync::Arc;
 use std::time::Duration;
-use url::Url;
 
-pub async fn outbox(State(state): State<SharedState>) -> Result<Json<Vec<Envelope>>, StatusCode> {
-    let state = state
-        .lock()
-        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
-    let envelopes: Vec<Envelope> = state.memory.values().cloned().collect();
-    Ok(Json(envelopes))
+/// Liveness: the process is up and the state lock is not poisoned.
+pub async fn health(State(state): State<SharedState>) -> Result<Response, ApiError> {
+    let s = state.lock()?;
+    Ok((change_seq_header(&s), Json(ApiResponse { ok: true })).into_response())
 }
 
-pub async fn inbox(
+/// `X-OpenHerd-Seq`, so peers can skip syncing when nothing changed.
+fn change_seq_header(s: &AppState) -> [(HeaderName, String); 1] {
+    [(
+        HeaderName::from_static(CHANGE_SEQ_HEADER),
+        s.change_seq.to_string(),
+    )]
+}
+
+/// Request latency histograms in the Prometheus text format.
+pub async fn metrics(
     State(state): State<SharedState>,
-    Json(envelopes): Json<Vec<Envelope>>,
-) -> Result<Json<ApiResponse>, StatusCode> {
-    let mut s = state
-        .lock()
-        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+) -> Result<([(HeaderName, &'static str); 1], String), ApiError> {
+    let (metrics, gauges) = {
+        let s = state.lock()?;
+        let mut gauges = crate::metrics::render_peer_lag(&s.peers);
+        gauges += &crate::metrics::render_gauge(
+            "openherd_moderation_reports",
+            "Reports waiting for review.",
+            s.moderation_reports.len(),
+        );
+        gauges += &crate::metrics::render_gauge(
+            "openherd_moderation_reports_max",
+            "Report queue cap; 0 means unlimited.",
+            s.config.max_moderation_reports,
+        );
+        (s.request_metrics.clone(), gauges)
+    };
+    Ok((
+        [(CONTENT_TYPE, "text/plain; version=0.0.4")],
+        metrics.render() + &gauges,
+    ))
+}
 
-    let mut imported_count = 0;
-    let mut errors = Vec::new();
+/// Answers requests shed because `--max-concurrent-requests` were already
+/// in flight.
+pub async fn overloaded(_: tower::BoxError) -> ApiError {
+    ApiError::new(
+        StatusCode::SERVICE_UNAVAILABLE,
+        "node is overloaded, retry shortly",
+    )
+}
 
-    for envelope in envelopes {
-        match validate_envelope(&envelope) {
-            Ok(_post) => {
-                let id = envelope.id.clone();
-
-                match serde_json::to_vec(&envelope) {
-                    Ok(bytes) => {
-                        if let Err(e) = s.db.insert(id.as_bytes(), bytes) {
-                            eprintln!("DB insert error for {}: {}", id, e);
-                        }
+/// Fallback for paths no route matches, so a mistyped endpoint gets the
+/// same JSON error body as any other failure instead of an empty 404.
+pub async fn not_found(method: Method, uri: Uri) -> ApiError {
+    ApiError::not_found(format!("no route for {} {}", method, uri.path()))
+}
+
+/// Readiness: sled accepts a write/read/delete round-trip.
+pub async fn health_ready(
+    State(state): State<SharedState>,
+) -> Result<(StatusCode, Json<HealthResponse>), ApiError> {
+    let s = state.lock()?;
+
+    let probe = s.probe_db();
+    let status = if probe.is_ok() {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    Ok((
+        status,
+        Json(HealthResponse {
+            ok: probe.is_ok(),
+            db_writable: probe.is_ok(),
+            db_write_failures: s.db_write_failures,
+            region_violations: s.region_violations,
+            message: probe.err().map(|e| e.to_string()),
+        }),
+    ))
+}
+
+/// Most envelopes in one cursor page of the outbox.
+const MAX_OUTBOX_PAGE: usize = 1000;
+
+/// Every post we hold, or with `after_seq`, an `OutboxPage` of the posts
+/// this node stored after that cursor, oldest first. Sequence numbers are
+/// assigned by this node as posts arrive, so unlike post dates they cannot
+/// be backdated. They increase but are not contiguous, and a post imported
+/// again with changed data gets a new number and appears again. Start from
+/// 0 and pass back each page's `cursor` until a page comes back empty.
+/// `since` filters either form by post date, and `fields` turns either
+/// into a `PartialOutbox` of just those post fields.
+pub async fn outbox(
+    State(state): State<SharedState>,
+    headers: HeaderMap,
+    Query(query): Query<OutboxQuery>,
+) -> Result<Response, ApiError> {
+    let s = state.lock()?;
+    let format = Format::accepted(&headers);
+    let seq_header = change_seq_header(&s);
+    let geo_precision = s.config.geo_precision;
+    let fields = query.fields.as_deref().map(post_fields).transpose()?;
+    let (page, cursor): (Vec<&Envelope>, _) = match query.after_seq {
+        Some(after) => {
+            let limit = query.limit.unwrap_or(MAX_OUTBOX_PAGE).min(MAX_OUTBOX_PAGE);
+            let (page, cursor) = outbox_after(&s, after, limit, query.since);
+            (page, Some(cursor))
+        }
+        None => {
+            let all = s
+                .memory
+                .values()
+                .filter(|env| dated_since(&s, env, query.since))
+                .collect();
+            (all, None)
+        }
+    };
+
+    if let Some(fields) = fields {
+        let posts = page
+            .into_iter()
+            .filter_map(|env| select_fields(env, &fields, geo_precision))
+            .collect();
+        let partial = PartialOutbox {
+            unverified: true,
+            posts,
+            geo_precision,
+            cursor,
+        };
+        return Ok((seq_header, Negotiated(format, partial)).into_response());
+    }
+    let envelopes: Vec<Envelope> = page
+        .into_iter()
+        .map(|env| served(env, geo_precision))
+        .collect();
+    Ok(match cursor {
+        Some(cursor) => (
+            seq_header,
+            Negotiated(format, OutboxPage { envelopes, cursor }),
+        )
+            .into_response(),
+        None => (seq_header, Negotiated(format, envelopes)).into_response(),
+    })
+}
+
+const POST_FIELDS: [&str; 8] = [
+    "id",
+    "text",
+    "latitude",
+    "longitude",
+    "date",
+    "parent",
+    "quote",
+    "nonce",
+];
+
+/// Parses a `fields` list, rejecting names that are not `Post` fields.
+fn post_fields(spec: &str) -> Result<Vec<String>, ApiError> {
+    let fields: Vec<String> = spec
+        .split(',')
+        .map(str::trim)
+        .filter(|f| !f.is_empty())
+        .map(str::to_string)
+        .collect();
+    if fields.is_empty() {
+        return Err(ApiError::bad_request("fields is empty"));
+    }
+    if let Some(unknown) = fields.iter().find(|f| !POST_FIELDS.contains(&f.as_str())) {
+        return Err(ApiError::bad_request(format!(
+            "unknown field {}; expected some of {}",
+            unknown,
+            POST_FIELDS.join(",")
+        )));
+    }
+    Ok(fields)
+}
+
+/// Rounds rather than adding random noise: noise averaged over repeated
+/// requests would give the exact position back.
+fn round_position(post: &mut Post, decimals: u32) {
+    let scale = 10f64.powi(decimals as i32);
+    post.latitude = (post.latitude * scale).round() / scale;
+    post.longitude = (post.longitude * scale).round() / scale;
+}
+
+/// `envelope` as the read endpoints serve it. With `--geo-precision` its
+/// post's coordinates are rounded, so it no longer verifies; the stored
+/// envelope keeps the exact signed position.
+fn served(envelope: &Envelope, geo_precision: Option<u32>) -> Envelope {
+    let mut served = envelope.clone();
+    let Some(decimals) = geo_precision else {
+        return served;
+    };
+    if let Ok(mut post) = serde_json::from_str::<Post>(&envelope.data) {
+        round_position(&mut post, decimals);
+        if let Ok(data) = serde_json::to_string(&post) {
+            served.data = data;
+        }
+    }
+    served
+}
+
+/// The requested fields of the post in `envelope`, with coordinates rounded
+/// to `geo_precision` decimals if set, or None if its data does not parse.
+fn select_fields(
+    envelope: &Envelope,
+    fields: &[String],
+    geo_precision: Option<u32>,
+) -> Option<serde_json::Map<String, serde_json::Value>> {
+    let mut post: Post = serde_json::from_str(&envelope.data).ok()?;
+    if let Some(decimals) = geo_precision {
+        round_position(&mut post, decimals);
+    }
+    let serde_json::Value::Object(mut all) = serde_json::to_value(post).ok()? else {
+        return None;
+    };
+    all.retain(|key, _| fields.contains(key));
+    Some(all)
+}
+
+/// Most ids in one page of `outbox_ids`.
+const MAX_OUTBOX_ID_PAGE: usize = 10_000;
+
+/// The outbox as ids only, paged by sequence cursor like `outbox` with
+/// `after_seq` (which defaults to 0 here). With `dates`, each id carries
+/// its post date. Fetch the envelopes you lack with `outbox_by_ids`.
+pub async fn outbox_ids(
+    State(state): State<SharedState>,
+    Query(query): Query<OutboxQuery>,
+) -> Result<Json<OutboxIdPage>, ApiError> {
+    let s = state.lock()?;
+    let limit = query
+        .limit
+        .unwrap_or(MAX_OUTBOX_ID_PAGE)
+        .min(MAX_OUTBOX_ID_PAGE);
+    let (page, cursor) = outbox_after(&s, query.after_seq.unwrap_or(0), limit, query.since);
+    let ids = page
+        .into_iter()
+        .map(|env| OutboxId {
+            id: env.id.clone(),
+            date: query.dates.then(|| post_date(&s, env)).flatten(),
+        })
+        .collect();
+    Ok(Json(OutboxIdPage { ids, cursor }))
+}
+
+/// Full envelopes for the requested ids, skipping ids we do not hold.
+pub async fn outbox_by_ids(
+    State(state): State<SharedState>,
+    headers: HeaderMap,
+    Json(ids): Json<Vec<String>>,
+) -> Result<Negotiated<Vec<Envelope>>, ApiError> {
+    if ids.len() > MAX_OUTBOX_PAGE {
+        return Err(ApiError::bad_request(format!(
+            "at most {} ids per request",
+            MAX_OUTBOX_PAGE
+        )));
+    }
+    let s = state.lock()?;
+    let envelopes = ids
+        .iter()
+        .filter_map(|id| s.memory.get(id))
+        .map(|env| served(env, s.config.geo_precision))
+        .collect();
+    Ok(Negotiated(Format::accepted(&headers), envelopes))
+}
+
+/// A post's date by `--post-date-source`, or `None` if its data is unreadable.
+fn post_date(s: &AppState, envelope: &Envelope) -> Option<DateTime<Utc>> {
+    let post: Post = serde_json::from_str(&envelope.data).ok()?;
+    Some(s.config.post_date_source.date_of(envelope, &post))
+}
+
+fn dated_since(s: &AppState, envelope: &Envelope, since: Option<DateTime<Utc>>) -> bool {
+    since.is_none_or(|since| post_date(s, envelope).is_some_and(|date| date >= since))
+}
+
+/// Up to `limit` posts stored after sequence number `after`, oldest first,
+/// skipping posts dated before `since`, with the sequence number of the last
+/// post looked at as the next cursor.
+fn outbox_after(
+    s: &AppState,
+    after: u64,
+    limit: usize,
+    since: Option<DateTime<Utc>>,
+) -> (Vec<&Envelope>, u64) {
+    let mut page = Vec::new();
+    let mut cursor = after;
+    for (&seq, id) in s.seq_index.range(after.saturating_add(1)..) {
+        if page.len() >= limit {
+            break;
+        }
+        cursor = seq;
+        match s.memory.get(id) {
+            Some(envelope) if dated_since(s, envelope, since) => page.push(envelope),
+            _ => {}
+        }
+    }
+    (page, cursor)
+}
+
+/// The outbox as newline-delimited JSON, one envelope per line, read from
+/// sled as the response is sent so neither side holds the whole set.
+pub async fn outbox_ndjson(State(state): State<SharedState>) -> Result<Response, ApiError> {
+    let (db, geo_precision) = {
+        let s = state.lock()?;
+        (s.db.clone(), s.config.geo_precision)
+    };
+    let lines = futures_util::stream::iter(db.scan_prefix(b"post:").values().map(move |value| {
+        let envelope = store::decode_envelope(&value?)
+            .map_err(|e| sled::Error::Unsupported(format!("unreadable post: {}", e)))?;
+        let mut line = serde_json::to_vec(&served(&envelope, geo_precision))
+            .map_err(|e| sled::Error::Unsupported(format!("serialize post: {}", e)))?;
+        line.push(b'\n');
+        Ok::<_, sled::Error>(line)
+    }));
+    Ok((
+        [(CONTENT_TYPE, "application/x-ndjson")],
+        Body::from_stream(lines),
+    )
+        .into_response())
+}
+
+/// WebSub hub for the outbox topic. Takes `subscribe` and `unsubscribe`
+/// requests and answers `202 Accepted`; the intent is then verified with the
+/// callback in the background, as the spec requires.
+pub async fn websub_hub(
+    State(state): State<SharedState>,
+    Form(req): Form<HubRequest>,
+) -> Result<StatusCode, ApiError> {
+    if req.mode != "subscribe" && req.mode != "unsubscribe" {
+        return Err(ApiError::bad_request(
+            "hub.mode must be subscribe or unsubscribe",
+        ));
+    }
+    let known_topic = url::Url::parse(&req.topic)
+        .is_ok_and(|topic| topic.path().trim_end_matches('/') == websub::TOPIC_PATH);
+    if !known_topic {
+        return Err(ApiError::bad_request(format!(
+            "unknown hub.topic; this hub only serves {}",
+            websub::TOPIC_PATH
+        )));
+    }
+    if normalize_peer_address(&req.callback).is_none() {
+        return Err(ApiError::bad_request("hub.callback must be an http(s) URL"));
+    }
+    if req
+        .secret
+        .as_ref()
+        .is_some_and(|secret| secret.len() >= 200)
+    {
+        return Err(ApiError::bad_request(
+            "hub.secret must be shorter than 200 bytes",
+        ));
+    }
+
+    let (max_lease, allow_private) = {
+        let s = state.lock()?;
+        if req.mode == "subscribe" && !s.websub_has_room(&req.callback) {
+            return Err(ApiError::new(
+                StatusCode::SERVICE_UNAVAILABLE,
+                "this hub has no room for more subscriptions",
+            ));
+        }
+        (
+            s.config.websub_max_lease_secs,
+            s.config.websub_allow_private_callbacks,
+        )
+    };
+    let addr = websub::resolve_callback(&req.callback, allow_private)
+        .await
+        .map_err(ApiError::bad_request)?;
+    let lease = req.lease_seconds.unwrap_or(max_lease).min(max_lease);
+    tokio::spawn(websub::verify_intent(state, req, lease, addr));
+    Ok(StatusCode::ACCEPTED)
+}
+
+/// A client submitting one post. It passes the same checks as the inbox
+/// (signature, proof of work, per-key rate limit), under the client limits
+/// where those are set, and is flushed before the answer, which is the
+/// stored post's detail: `201 Created` for a new or changed post, `200 OK`
+/// if we already held it. Peers push batches to `inbox` instead.
+pub async fn submit_post(
+    State(state): State<SharedState>,
+    Json(envelope): Json<Envelope>,
+) -> Result<(StatusCode, Json<PostDetail>), ApiError> {
+    let mut s = state.lock()?;
+    let held = s
+        .memory
+        .get(&envelope.id)
+        .is_some_and(|existing| existing.data == envelope.data);
+    let post = s.admit_client_envelope(&envelope)?;
+    let id = envelope.id.clone();
+    s.import_envelope(envelope, &post)?;
+    s.flush_writes()?;
+    s.commit_search_index();
+
+    let status = if held {
+        StatusCode::OK
+    } else {
+        StatusCode::CREATED
+    };
+    let mut detail = post_detail_of(&s, id)?;
+    let receipts = PendingReceipts::of(&s, std::slice::from_ref(&detail.envelope.id));
+    drop(s);
+    detail.receipt = receipts.sign().pop();
+    Ok((status, Json(detail)))
+}
+
+/// Stored posts to sign receipts for, with `--post-receipts` and a node
+/// key. Copied out of the state so that signing happens after the lock is
+/// released.
+#[derive(Default)]
+struct PendingReceipts {
+    key: Option<Arc<federation::NodeKey>>,
+    posts: Vec<Envelope>,
+}
+
+impl PendingReceipts {
+    /// Only call once the posts are flushed: a receipt vouches that the
+    /// post was stored.
+    fn of(s: &AppState, ids: &[String]) -> Self {
+        let Some(key) = s.node_key.clone().filter(|_| s.config.post_receipts) else {
+            return Self::default();
+        };
+        Self {
+            key: Some(key),
+            posts: ids
+                .iter()
+                .filter_map(|id| s.memory.get(id).cloned())
+                .collect(),
+        }
+    }
+
+    fn sign(self) -> Vec<PostReceipt> {
+        let Some(key) = self.key else {
+            return Vec::new();
+        };
+        self.posts
+            .iter()
+            .filter_map(|envelope| {
+                let received_at = envelope.received_at?;
+                match key.sign_receipt(&envelope.id, &envelope.data, received_at) {
+                    Ok(receipt) => Some(receipt),
+                    Err(e) => {
+                        eprintln!("Failed to sign receipt for {}: {}", envelope.id, e);
+                        None
                     }
-                    Err(e) => eprintln!("Serialization error for {}: {}", id, e),
                 }
+            })
+            .collect()
+    }
+}
 
-                s.memory.insert(id, envelope);
-                imported_count += 1;
-            }
+pub async fn inbox(
+    State(state): State<SharedState>,
+    SyncBody(envelopes): SyncBody<Vec<Envelope>>,
+) -> Result<(StatusCode, Json<InboxResponse>), ApiError> {
+    let mut s = state.lock()?;
+
+    let mut stored = Vec::new();
+    let mut errors = Vec::new();
+    let mut failed = Vec::new();
+    let mut valid = Vec::new();
+
+    for envelope in envelopes {
+        match s.admit_envelope(&envelope) {
+            Ok(post) => valid.push((envelope, post)),
             Err(e) => {
                 errors.push(format!("Error validating post {}: {}", envelope.id, e));
             }
         }
     }
 
-    if imported_count == 0 && !errors.is_empty() {
+    if s.config.inbox_batch_writes {
+        let ids: Vec<String> = valid.iter().map(|(env, _)| env.id.clone()).collect();
+        match s.import_batch(valid) {
+            Ok(()) => stored = ids,
+            Err(_) => failed = ids,
+        }
+    } else {
+        for (envelope, post) in valid {
+            let id = envelope.id.clone();
+            match s.import_envelope(envelope, &post) {
+                Ok(()) => stored.push(id),
+                Err(_) => failed.push(id),
+            }
+        }
+    }
+    let imported_count = stored.len();
+
+    if imported_count == 0 && failed.is_empty() && !errors.is_empty() {
         eprintln!("All posts failed validation: {:?}", errors);
-        return Err(StatusCode::BAD_REQUEST);
+        return Err(ApiError::bad_request(errors.join("; ")));
     }
 
     if !errors.is_empty() {
         eprintln!("Some posts failed validation: {:?}", errors);
     }
 
-    println!("Successfully imported {} posts", imported_count);
+    let flushed = match s.flush_writes() {
+        Ok(()) => true,
+        Err(e) => {
+            eprintln!("DB flush error: {}", e);
+            false
+        }
+    };
+    let durable = flushed && failed.is_empty();
+    s.commit_search_index();
+    let receipts = if flushed {
+        PendingReceipts::of(&s, &stored)
+    } else {
+        PendingReceipts::default()
+    };
+    drop(s);
+    let receipts = receipts.sign();
 
-    if let Err(e) = s.db.flush() {
-        eprintln!("DB flush error: {}", e);
+    if durable {
+        println!("Successfully imported {} posts", imported_count);
+    } else {
+        eprintln!(
+            "Inbox import not durable: {} stored, {} failed to persist",
+            imported_count,
+            failed.len()
+        );
     }
 
-    Ok(Json(ApiResponse { ok: true }))
+    let status = if durable {
+        StatusCode::OK
+    } else {
+        StatusCode::INTERNAL_SERVER_ERROR
+    };
+    Ok((
+        status,
+        Json(InboxResponse {
+            ok: durable,
+            imported: imported_count,
+            rejected: errors.len(),
+            failed,
+            receipts,
+        }),
+    ))
 }
 
-pub async fn peers(State(state): State<SharedState>) -> Result<Json<Vec<String>>, StatusCode> {
-    let s = state
-        .lock()
-        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
-    let list: Vec<String> = s.peers.keys().cloned().collect();
-    Ok(Json(list))
+/// Ranked full-text search over post text. Falls back to a case-insensitive
+/// substring scan, newest first, when the search index is unavailable.
+pub async fn search(
+    State(state): State<SharedState>,
+    Query(query): Query<SearchQuery>,
+) -> Result<Json<Vec<SearchHit>>, ApiError> {
+    let s = state.lock()?;
+
+    let needle = query.q.trim();
+    if needle.chars().count() < s.config.search_min_query_len {
+        return Err(ApiError::bad_request(format!(
+            "query must be at least {} characters",
+            s.config.search_min_query_len
+        )));
+    }
+    let limit = query
+        .limit
+        .unwrap_or(s.config.search_max_results)
+        .min(s.config.search_max_results);
+
+    let hits = match &s.search_index {
+        Some(index) => index
+            .search(needle, query.offset, limit)
+            .map_err(|e| {
+                ApiError::new(
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    format!("search failed: {}", e),
+                )
+            })?
+            .into_iter()
+            .filter_map(|hit| {
+                Some(SearchHit {
+                    envelope: served(s.memory.get(&hit.id)?, s.config.geo_precision),
+                    score: Some(hit.score),
+                    snippet: Some(hit.snippet),
+                })
+            })
+            .collect(),
+        None => naive_search(
+            &s.memory,
+            needle,
+            query.offset,
+            limit,
+            s.config.post_date_source,
+        )
+        .into_iter()
+        .map(|env| SearchHit {
+            envelope: served(env, s.config.geo_precision),
+            score: None,
+            snippet: None,
+        })
+        .collect(),
+    };
+    Ok(Json(hits))
 }
 
-pub async fn sync(
+/// Largest page `posts_in_bbox` returns.
+const MAX_BBOX_RESULTS: usize = 500;
+
+/// Posts inside a bounding box, newest first, for map viewports.
+pub async fn posts_in_bbox(
     State(state): State<SharedState>,
-    Json(body): Json<SyncRequest>,
-) -> Result<Json<SyncResponse>, StatusCode> {
-    let base = match Url::parse(&body.address) {
-        Ok(url) if url.scheme() == "http" || url.scheme() == "https" => {
-            url.as_str().trim_end_matches('/').to_string()
+    Json(query): Json<BoundingBoxQuery>,
+) -> Result<Json<Vec<Envelope>>, ApiError> {
+    let bbox = &query.bbox;
+    let lat_ok = |lat: f64| (-90.0..=90.0).contains(&lat);
+    let lon_ok = |lon: f64| (-180.0..=180.0).contains(&lon);
+    if !lat_ok(bbox.min_lat)
+        || !lat_ok(bbox.max_lat)
+        || !lon_ok(bbox.min_lon)
+        || !lon_ok(bbox.max_lon)
+        || bbox.min_lat > bbox.max_lat
+    {
+        return Err(ApiError::bad_request("invalid bounding box"));
+    }
+
+    let s = state.lock()?;
+    let limit = query
+        .limit
+        .unwrap_or(MAX_BBOX_RESULTS)
+        .min(MAX_BBOX_RESULTS);
+    let envelopes = s
+        .geo_index
+        .within(bbox)
+        .into_iter()
+        .filter_map(|id| s.memory.get(&id))
+        .skip(query.offset)
+        .take(limit)
+        .map(|env| served(env, s.config.geo_precision))
+        .collect();
+    Ok(Json(envelopes))
+}
+
+/// Most fingerprints one `posts_by_keys` request may name.
+const MAX_KEYS_PER_REQUEST: usize = 200;
+
+/// Largest page `posts_by_keys` returns, counted in envelopes.
+const MAX_BY_KEYS_RESULTS: usize = 500;
+
+/// Posts by each of several signing keys, for following feeds: one group
+/// per key in request order, leaving out keys we hold nothing for, each
+/// sorted by post date. An envelope's id is its key's fingerprint, so
+/// `memory` is itself the key index and a group never holds more than one
+/// post today. `offset` and `limit` count envelopes across all groups.
+pub async fn posts_by_keys(
+    State(state): State<SharedState>,
+    Json(query): Json<PostsByKeysQuery>,
+) -> Result<Json<Vec<KeyPosts>>, ApiError> {
+    if query.keys.len() > MAX_KEYS_PER_REQUEST {
+        return Err(ApiError::bad_request(format!(
+            "at most {} keys per request",
+            MAX_KEYS_PER_REQUEST
+        )));
+    }
+    let s = state.lock()?;
+    let mut remaining = query
+        .limit
+        .unwrap_or(MAX_BY_KEYS_RESULTS)
+        .min(MAX_BY_KEYS_RESULTS);
+    let mut skip = query.offset;
+    let mut seen = HashSet::new();
+    let mut groups = Vec::new();
+    for key in &query.keys {
+        if remaining == 0 {
+            break;
         }
-        _ => {
-            return Ok(Json(SyncResponse {
-                ok: false,
-                message: "Invalid URL format".to_string(),
-            }));
+        if !seen.insert(key.as_str()) {
+            continue;
         }
-    };
+        let mut envelopes: Vec<&Envelope> = s.memory.get(key).into_iter().collect();
+        envelopes.sort_by_key(|envelope| post_date(&s, envelope));
+        let skipped = skip.min(envelopes.len());
+        skip -= skipped;
+        let envelopes: Vec<Envelope> = envelopes
+            .into_iter()
+            .skip(skipped)
+            .take(remaining)
+            .map(|env| served(env, s.config.geo_precision))
+            .collect();
+        if envelopes.is_empty() {
+            continue;
+        }
+        remaining -= envelopes.len();
+        groups.push(KeyPosts {
+            key: key.clone(),
+            envelopes,
+        });
+    }
+    Ok(Json(groups))
+}
 
-    let client = reqwest::Client::builder()
-        .timeout(Duration::from_secs(15))
-        .build()
-        .map_err(|e| {
-            eprintln!("Failed to build HTTP client: {}", e);
-            StatusCode::INTERNAL_SERVER_ERROR
-        })?;
+/// Finest geohash precision `density` serves; 6 characters is roughly a
+/// 1.2 km by 0.6 km cell.
+const MAX_DENSITY_PRECISION: usize = 6;
 
-    let outbox_url = format!("{}/_openherd/outbox", base);
-    let resp = match client.get(&outbox_url).send().await {
-        Ok(r) => r,
-        Err(e) => {
-            return Ok(Json(SyncResponse {
-                ok: false,
-                message: format!("Failed to fetch remote outbox: {}", e),
-            }));
-        }
-    };
+/// Post counts per geohash prefix, for heatmaps. `precision` is clamped to
+/// `1..=MAX_DENSITY_PRECISION`.
+pub async fn density(
+    State(state): State<SharedState>,
+    Query(query): Query<DensityQuery>,
+) -> Result<Json<BTreeMap<String, usize>>, ApiError> {
+    let precision = query.precision.clamp(1, MAX_DENSITY_PRECISION);
+    Ok(Json(state.lock()?.geo_index.density(precision)))
+}
+
+/// Oldest and newest post dates and the post count, for timeline UIs.
+/// Read from the geo index's date counts rather than by scanning posts.
+pub async fn timespan(State(state): State<SharedState>) -> Result<Json<Timespan>, ApiError> {
+    let s = state.lock()?;
+    let bounds = s.geo_index.timespan();
+    Ok(Json(Timespan {
+        oldest: bounds.map(|(oldest, _)| oldest),
+        newest: bounds.map(|(_, newest)| newest),
+        count: s.geo_index.len(),
+    }))
+}
+
+pub async fn peers(State(state): State<SharedState>) -> Result<Json<Vec<String>>, ApiError> {
+    let s = state.lock()?;
+    let list: Vec<String> = s.peers.keys().cloned().collect();
+    Ok(Json(list))
+}
+
+/// Per-peer status, including the outcome of the last sync with each peer.
+pub async fn admin_peers(
+    State(state): State<SharedState>,
+    headers: HeaderMap,
+) -> Result<Json<HashMap<String, PeerStatus>>, ApiError> {
+    require_admin(&state, &headers).await?;
+    let s = state.lock()?;
+    Ok(Json(s.peers.clone()))
+}
+
+pub async fn admin_pinned_posts(
+    State(state): State<SharedState>,
+    headers: HeaderMap,
+) -> Result<Json<Vec<PinnedPost>>, ApiError> {
+    require_admin(&state, &headers).await?;
+    let s = state.lock()?;
+    Ok(Json(s.pinned_posts()))
+}
 
-    if resp.status() != HttpStatus::OK {
-        return Ok(Json(SyncResponse {
-            ok: false,
-            message: format!("Remote outbox returned status {}", resp.status()),
-        }));
+/// Pins a post we hold so it is kept however posts are otherwise expired.
+pub async fn admin_pin_post(
+    State(state): State<SharedState>,
+    headers: HeaderMap,
+    Path(id): Path<String>,
+) -> Result<Json<ApiResponse>, ApiError> {
+    require_admin(&state, &headers).await?;
+    let s = state.lock()?;
+    if !s.memory.contains_key(&id) {
+        return Err(ApiError::not_found("unknown post"));
     }
+    s.pin_post(&id)?;
+    Ok(Json(ApiResponse { ok: true }))
+}
 
-    let incoming: Vec<Envelope> = match resp.json().await {
-        Ok(data) => data,
-        Err(e) => {
-            return Ok(Json(SyncResponse {
-                ok: false,
-                message: format!("Failed to parse remote outbox: {}", e),
-            }));
-        }
-    };
+pub async fn admin_unpin_post(
+    State(state): State<SharedState>,
+    headers: HeaderMap,
+    Path(id): Path<String>,
+) -> Result<Json<ApiResponse>, ApiError> {
+    require_admin(&state, &headers).await?;
+    let s = state.lock()?;
+    if !s.unpin_post(&id)? {
+        return Err(ApiError::not_found("post is not pinned"));
+    }
+    Ok(Json(ApiResponse { ok: true }))
+}
 
-    {
-        let mut s = state
-            .lock()
-            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
-        for env in incoming.into_iter() {
-            if let Ok(_p) = validate_envelope(&env) {
-                let id = env.id.clone();
-                if let Ok(bytes) = serde_json::to_vec(&env) {
-                    let _ = s.db.insert(id.as_bytes(), bytes);
-                }
-                s.memory.insert(id, env);
-            }
-        }
-        let _ = s.db.flush();
+/// Marks a known peer trusted; see `--trust-peer-validation`.
+pub async fn admin_trust_peer(
+    State(state): State<SharedState>,
+    headers: HeaderMap,
+    Path(address): Path<String>,
+) -> Result<Json<ApiResponse>, ApiError> {
+    set_peer_trust(&state, &headers, &address, true).await
+}
+
+pub async fn admin_untrust_peer(
+    State(state): State<SharedState>,
+    headers: HeaderMap,
+    Path(address): Path<String>,
+) -> Result<Json<ApiResponse>, ApiError> {
+    set_peer_trust(&state, &headers, &address, false).await
+}
+
+async fn set_peer_trust(
+    state: &SharedState,
+    headers: &HeaderMap,
+    address: &str,
+    trusted: bool,
+) -> Result<Json<ApiResponse>, ApiError> {
+    require_admin(state, headers).await?;
+    let mut s = state.lock()?;
+    let address = normalize_peer_address(address)
+        .ok_or_else(|| ApiError::bad_request("invalid peer address"))?;
+    if !s.set_peer_trusted(&address, trusted) {
+        return Err(ApiError::not_found("unknown peer"));
     }
+    Ok(Json(ApiResponse { ok: true }))
+}
 
-    let posts_to_send: Vec<Envelope> = {
-        let s = state
-            .lock()
-            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
-        s.memory.values().take(10_000).cloned().collect()
-    };
+/// Drops a peer right away instead of waiting for the failure threshold.
+pub async fn admin_remove_peer(
+    State(state): State<SharedState>,
+    headers: HeaderMap,
+    Path(address): Path<String>,
+) -> Result<Json<ApiResponse>, ApiError> {
+    require_admin(&state, &headers).await?;
+    let mut s = state.lock()?;
 
-    let inbox_url = format!("{}/_openherd/inbox", base);
-    let post_resp = match client.post(&inbox_url).json(&posts_to_send).send().await {
-        Ok(r) => r,
-        Err(e) => {
+    let address = normalize_peer_address(&address)
+        .ok_or_else(|| ApiError::bad_request("invalid peer address"))?;
+    s.remove_peer(&address)
+        .ok_or_else(|| ApiError::not_found("unknown peer"))?;
+    Ok(Json(ApiResponse { ok: true }))
+}
+
+pub async fn sync(
+    State(state): State<SharedState>,
+    headers: HeaderMap,
+    Json(body): Json<SyncRequest>,
+) -> Result<Json<SyncResponse>, ApiError> {
+    let base = match normalize_peer_address(&body.address) {
+        Some(base) => base,
+        None => {
             return Ok(Json(SyncResponse {
                 ok: false,
-                message: format!("Failed to push to remote inbox: {}", e),
+                message: "Invalid URL format".to_string(),
+                imported: 0,
+                skipped: 0,
             }));
         }
     };
 
-    if post_resp.status() != HttpStatus::OK {
-        return Ok(Json(SyncResponse {
-            ok: false,
-            message: format!("Remote inbox returned status {}", post_resp.status()),
-        }));
+    if body.force {
+        require_admin(&state, &headers).await?;
     }
-
     {
-        let mut s = state
-            .lock()
-            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
-        s.peers
-            .entry(base.clone())
-            .and_modify(|p| {
-                p.failures = 0;
-                p.last_ok = Some(Utc::now());
+        let s = state.lock()?;
+        let refusal = if !s.config.allow_insecure_peers && is_insecure_peer(&base) {
+            Some("Insecure peer address: this node only syncs with https:// peers".to_string())
+        } else if !s.can_add_peer(&base) {
+            Some("Peer limit reached".to_string())
+        } else if body.force {
+            None
+        } else {
+            s.sync_cooldown(&base).map(|last| {
+                format!(
+                    "Too soon: last synced with this peer at {}",
+                    last.to_rfc3339()
+                )
             })
-            .or_insert(PeerStatus {
-                failures: 0,
-                last_ok: Some(Utc::now()),
-            });
+        };
+        if let Some(message) = refusal {
+            return Ok(Json(SyncResponse {
+                ok: false,
+                message,
+                imported: 0,
+                skipped: 0,
+            }));
+        }
     }
 
+    let client = reqwest::Client::builder()
+        .timeout(Duration::from_secs(15))
+        .build()
+        .map_err(|e| {
+            ApiError::new(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("failed to build HTTP client: {}", e),
+            )
+        })?;
+
+    let outcome = crate::sync::sync_peer(&state, &client, &base, body.direction).await;
+    let message = outcome
+        .error
+        .clone()
+        .unwrap_or_else(|| "Sync complete".to_string());
+    let ok = outcome.error.is_none();
+    let (imported, skipped) = (outcome.imported, outcome.skipped);
+    state.lock()?.record_sync(&base, outcome);
+
     Ok(Json(SyncResponse {
-        ok: true,
-        message: "Sync complete".to_string(),
+        ok,
+        message,
+        imported,
+        skipped,
     }))
 }
 
+/// Applies one vote with `code` to `post`. Every check runs against the
+/// live entry in `karma_codes` under the caller's lock, so of two requests
+/// racing for the same code only the first is applied; the other gets
+/// `CONFLICT`.
 fn apply_karma_internal(
     s: &mut crate::state::AppState,
-    karma_code: KarmaCode,
     code: &str,
-    envelope: &Envelope,
+    post: &Post,
     direction: &str,
-) -> Result<(), StatusCode> {
-    if karma_code.expires < Utc::now() {
-        return Err(StatusCode::GONE);
+) -> Result<(), ApiError> {
+    let region_mode = s.config.region_mode;
+    let kc = s
+        .karma_codes
+        .get_mut(code)
+        .ok_or_else(|| ApiError::not_found("unknown karma code"))?;
+    if kc.expires < Utc::now() {
+        return Err(ApiError::new(StatusCode::GONE, "karma code has expired"));
     }
-    if karma_code.current_post.is_some() {
-        return Err(StatusCode::CONFLICT);
+    if kc.current_post.is_some() {
+        return Err(ApiError::new(
+            StatusCode::CONFLICT,
+            "karma code is already applied to a post",
+        ));
     }
 
-    if let Some(ref vt) = karma_code.vote_type {
+    if let Some(ref vt) = kc.vote_type {
         if vt != direction {
-            return Err(StatusCode::BAD_REQUEST);
+            return Err(ApiError::bad_request(format!(
+                "karma code can only be used to {}",
+                vt
+            )));
         }
     }
-    let post_id = envelope.id.clone();
-    let delta = if direction == "upvote" { 1 } else { -1 };
-    if let Some(kc) = s.karma_codes.get_mut(code) {
-        kc.current_post = Some(post_id.clone());
-        kc.used_direction = Some(direction.to_string());
-        if kc.vote_type.is_none() {
-            kc.vote_type = Some(direction.to_string());
-        }
+    let outside_region = region_mode != RegionMode::Off
+        && kc
+            .region
+            .as_ref()
+            .is_some_and(|region| !region.contains(post.latitude, post.longitude));
+    if outside_region && region_mode == RegionMode::Enforce {
+        return Err(ApiError::bad_request(
+            "post is outside the karma code's region",
+        ));
+    }
+
+    let post_id = post.id.clone();
+    let sign = if direction == "upvote" { 1 } else { -1 };
+    let delta = sign * kc.weight;
+    kc.current_post = Some(post_id.clone());
+    kc.used_direction = Some(direction.to_string());
+    kc.history.push(KarmaRedemption {
+        post: post_id.clone(),
+        direction: direction.to_string(),
+        at: Utc::now(),
+        revoked_at: None,
+    });
+    if kc.vote_type.is_none() {
+        kc.vote_type = Some(direction.to_string());
+    }
+
+    if outside_region {
+        s.region_violations += 1;
+        eprintln!(
+            "Karma code {} used on post {} outside its region",
+            code, post.id
+        );
+    }
+    *s.karma_votes.entry(post_id.to_lowercase()).or_insert(0) += delta;
+    s.persist_karma_code(code);
+    if let Err(e) = s.flush_writes() {
+        eprintln!("DB flush error: {}", e);
     }
-    *s.karma_votes.entry(post_id).or_insert(0) += delta;
     Ok(())
 }
 
@@ -236,17 +1013,10 @@ pub async fn karma_upvote(
     State(state): State<SharedState>,
     Path(code): Path<String>,
     Json(envelope): Json<Envelope>,
-) -> Result<Json<ApiResponse>, StatusCode> {
-    let mut s = state
-        .lock()
-        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
-    let karma_code = s
-        .karma_codes
-        .get(&code)
-        .ok_or(StatusCode::NOT_FOUND)?
-        .clone();
-    validate_envelope(&envelope).map_err(|_| StatusCode::BAD_REQUEST)?;
-    apply_karma_internal(&mut s, karma_code, &code, &envelope, "upvote")?;
+) -> Result<Json<ApiResponse>, ApiError> {
+    let post = validate_envelope(&envelope)?;
+    let mut s = state.lock()?;
+    apply_karma_internal(&mut s, &code, &post, "upvote")?;
     Ok(Json(ApiResponse { ok: true }))
 }
 
@@ -254,49 +1024,49 @@ pub async fn karma_downvote(
     State(state): State<SharedState>,
     Path(code): Path<String>,
     Json(envelope): Json<Envelope>,
-) -> Result<Json<ApiResponse>, StatusCode> {
-    let mut s = state
-        .lock()
-        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
-    let karma_code = s
-        .karma_codes
-        .get(&code)
-        .ok_or(StatusCode::NOT_FOUND)?
-        .clone();
-    validate_envelope(&envelope).map_err(|_| StatusCode::BAD_REQUEST)?;
-    apply_karma_internal(&mut s, karma_code, &code, &envelope, "downvote")?;
+) -> Result<Json<ApiResponse>, ApiError> {
+    let post = validate_envelope(&envelope)?;
+    let mut s = state.lock()?;
+    apply_karma_internal(&mut s, &code, &post, "downvote")?;
     Ok(Json(ApiResponse { ok: true }))
 }
 
+/// Takes back a code's current vote. The code can then be used again, on any
+/// post, but only in its direction: a code issued with a type, or locked to
+/// one by its first vote, keeps that type after a revoke.
 pub async fn karma_revoke(
     State(state): State<SharedState>,
     Path(code): Path<String>,
-) -> Result<Json<ApiResponse>, StatusCode> {
-    let mut s = state
-        .lock()
-        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+) -> Result<Json<ApiResponse>, ApiError> {
+    let mut s = state.lock()?;
 
     let karma_code = s
         .karma_codes
         .get(&code)
-        .ok_or(StatusCode::NOT_FOUND)?
+        .ok_or_else(|| ApiError::not_found("unknown karma code"))?
         .clone();
-    if let Some(post_id) = &karma_code.current_post {
-        let direction = karma_code
-            .used_direction
-            .as_deref()
-            .or(karma_code.vote_type.as_deref())
-            .unwrap_or("upvote");
-        let delta = if direction == "upvote" { -1 } else { 1 };
-        if let Some(score) = s.karma_votes.get_mut(post_id) {
+    if let (Some(post_id), Some(direction)) =
+        (&karma_code.current_post, karma_code.applied_direction())
+    {
+        let sign = if direction == "upvote" { -1 } else { 1 };
+        let delta = sign * karma_code.weight;
+        if let Some(score) = s.karma_votes.get_mut(&post_id.to_lowercase()) {
             *score += delta;
         }
     }
 
     if let Some(kc) = s.karma_codes.get_mut(&code) {
+        if kc.current_post.is_some() {
+            if let Some(last) = kc.history.last_mut() {
+                last.revoked_at = Some(Utc::now());
+            }
+        }
         kc.current_post = None;
         kc.used_direction = None;
-        if kc.vote_type.is_some() { /* keep constraint */ }
+    }
+    s.persist_karma_code(&code);
+    if let Err(e) = s.flush_writes() {
+        eprintln!("DB flush error: {}", e);
     }
 
     Ok(Json(ApiResponse { ok: true }))
@@ -305,12 +1075,13 @@ pub async fn karma_revoke(
 pub async fn karma_metadata(
     State(state): State<SharedState>,
     Path(code): Path<String>,
-) -> Result<Json<KarmaMetadata>, StatusCode> {
-    let s = state
-        .lock()
-        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+) -> Result<Json<KarmaMetadata>, ApiError> {
+    let s = state.lock()?;
 
-    let karma_code = s.karma_codes.get(&code).ok_or(StatusCode::NOT_FOUND)?;
+    let karma_code = s
+        .karma_codes
+        .get(&code)
+        .ok_or_else(|| ApiError::not_found("unknown karma code"))?;
 
     Ok(Json(KarmaMetadata {
         code: karma_code.code.clone(),
@@ -322,15 +1093,10 @@ pub async fn karma_metadata(
 pub async fn karma_lookup(
     State(state): State<SharedState>,
     Json(post_ids): Json<Vec<String>>,
-) -> Result<Json<Vec<i32>>, StatusCode> {
-    let s = state
-        .lock()
-        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+) -> Result<Json<Vec<i32>>, ApiError> {
+    let s = state.lock()?;
 
-    let scores: Vec<i32> = post_ids
-        .iter()
-        .map(|id| s.karma_votes.get(id).copied().unwrap_or(0))
-        .collect();
+    let scores: Vec<i32> = post_ids.iter().map(|id| s.karma_of(id)).collect();
 
     Ok(Json(scores))
 }
@@ -338,236 +1104,1257 @@ pub async fn karma_lookup(
 pub async fn moderation_lookup(
     State(state): State<SharedState>,
     Json(post_ids): Json<Vec<String>>,
-) -> Result<Json<Vec<Option<String>>>, StatusCode> {
-    let s = state
-        .lock()
-        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+) -> Result<Json<Vec<Option<String>>>, ApiError> {
+    let s = state.lock()?;
 
     let labels: Vec<Option<String>> = post_ids
         .iter()
-        .map(|id| s.post_labels.get(id).cloned())
+        .map(|id| s.applied_label(id).cloned())
+        .collect();
+
+    Ok(Json(labels))
+}
+
+/// Like `moderation_lookup`, with each label's name and description so a
+/// client can render the notice without fetching the label list.
+pub async fn moderation_lookup_definitions(
+    State(state): State<SharedState>,
+    Json(post_ids): Json<Vec<String>>,
+) -> Result<Json<Vec<Option<AppliedLabel>>>, ApiError> {
+    let s = state.lock()?;
+
+    let labels = post_ids
+        .iter()
+        .map(|id| {
+            let slug = s.applied_label(id)?;
+            let definition = s.label_definitions.get(slug);
+            Some(AppliedLabel {
+                id: slug.clone(),
+                label: definition.map(|d| d.label.clone()),
+                description: definition.map(|d| d.description.clone()),
+            })
+        })
         .collect();
 
     Ok(Json(labels))
 }
 
+/// Karma and label for each post id, aligned with the request order.
+pub async fn lookup(
+    State(state): State<SharedState>,
+    Json(post_ids): Json<Vec<String>>,
+) -> Result<Json<Vec<PostStatus>>, ApiError> {
+    let s = state.lock()?;
+
+    let statuses: Vec<PostStatus> = post_ids
+        .iter()
+        .map(|id| PostStatus {
+            karma: s.karma_of(id),
+            label: s.applied_label(id).cloned(),
+        })
+        .collect();
+
+    Ok(Json(statuses))
+}
+
+/// Number of distinct reporters per post id, aligned with the request
+/// order. Counts below the public threshold are reported as 0 so a single
+/// reporter cannot be singled out; reasons and addresses are never exposed.
+pub async fn moderation_report_counts(
+    State(state): State<SharedState>,
+    Json(post_ids): Json<Vec<String>>,
+) -> Result<Json<Vec<usize>>, ApiError> {
+    let s = state.lock()?;
+    Ok(Json(public_report_counts(&s, &post_ids)))
+}
+
+fn public_report_counts(s: &AppState, post_ids: &[String]) -> Vec<usize> {
+    let wanted: HashSet<&str> = post_ids.iter().map(String::as_str).collect();
+    let mut reporters: HashMap<&str, HashSet<&str>> = HashMap::new();
+    for report in &s.moderation_reports {
+        let post = report.post.id.as_str();
+        if wanted.contains(post) {
+            let reporter = report.reporter_ip.as_deref().unwrap_or(report.id.as_str());
+            reporters.entry(post).or_default().insert(reporter);
+        }
+    }
+
+    let threshold = s.config.public_report_threshold.max(1);
+    post_ids
+        .iter()
+        .map(|id| {
+            let count = reporters.get(id.as_str()).map_or(0, HashSet::len);
+            if count >= threshold {
+                count
+            } else {
+                0
+            }
+        })
+        .collect()
+}
+
+/// Why a post was reported: distinct reporters per category, plus the
+/// free-text reasons for admins. Without `--public-report-categories` only
+/// admins may ask. A post with no reports, or none visible to the caller,
+/// is a 404.
+pub async fn moderation_report_reasons(
+    State(state): State<SharedState>,
+    headers: HeaderMap,
+    Path(id): Path<String>,
+) -> Result<Json<ReportReasons>, ApiError> {
+    let admin = require_admin(&state, &headers).await;
+    let s = state.lock()?;
+    let admin = match admin {
+        Ok(()) => true,
+        Err(e) if !s.config.public_report_categories => return Err(e),
+        Err(_) => false,
+    };
+
+    let mut reporters = HashSet::new();
+    let mut categories: HashMap<&str, HashSet<&str>> = HashMap::new();
+    let mut reasons: HashMap<&str, HashSet<&str>> = HashMap::new();
+    for report in s.moderation_reports.iter().filter(|r| r.post.id == id) {
+        let reporter = report.reporter_ip.as_deref().unwrap_or(report.id.as_str());
+        let category = report.suggested_label.as_deref().unwrap_or("uncategorized");
+        reporters.insert(reporter);
+        categories.entry(category).or_default().insert(reporter);
+        reasons
+            .entry(report.reason.trim())
+            .or_default()
+            .insert(reporter);
+    }
+
+    let threshold = if admin {
+        1
+    } else {
+        s.config.public_report_threshold.max(1)
+    };
+    let categories = reason_counts(categories, threshold);
+    if categories.is_empty() {
+        return Err(ApiError::not_found("no reports for this post"));
+    }
+    Ok(Json(ReportReasons {
+        post_id: id,
+        reporters: reporters.len(),
+        categories,
+        reasons: admin.then(|| reason_counts(reasons, 1)),
+    }))
+}
+
+/// Groups with at least `min` reporters, largest first.
+fn reason_counts(groups: HashMap<&str, HashSet<&str>>, min: usize) -> Vec<ReasonCount> {
+    let mut counts: Vec<ReasonCount> = groups
+        .into_iter()
+        .filter(|(_, reporters)| reporters.len() >= min)
+        .map(|(reason, reporters)| ReasonCount {
+            reason: reason.to_string(),
+            count: reporters.len(),
+        })
+        .collect();
+    counts.sort_by(|a, b| b.count.cmp(&a.count).then_with(|| a.reason.cmp(&b.reason)));
+    counts
+}
+
+/// Everything about one post for a detail view. `report_count` follows the
+/// same public threshold as `moderation_report_counts`.
+pub async fn post_detail(
+    State(state): State<SharedState>,
+    Path(id): Path<String>,
+) -> Result<Json<PostDetail>, ApiError> {
+    let s = state.lock()?;
+    let mut detail = post_detail_of(&s, id)?;
+    if let Some(decimals) = s.config.geo_precision {
+        detail.envelope = served(&detail.envelope, Some(decimals));
+        round_position(&mut detail.post, decimals);
+    }
+    Ok(Json(detail))
+}
+
+pub async fn post_reactions(
+    State(state): State<SharedState>,
+    Path(id): Path<String>,
+) -> Result<Json<PostReactions>, ApiError> {
+    let s = state.lock()?;
+    if !s.memory.contains_key(&id) {
+        return Err(ApiError::not_found("unknown post"));
+    }
+    let counts = s.reaction_counts(&id)?;
+    Ok(Json(PostReactions {
+        post_id: id,
+        counts,
+    }))
+}
+
+/// The posts we hold that quote `id`, a page at a time after `after_seq`.
+/// The quoted post itself need not be held here.
+pub async fn post_quotes(
+    State(state): State<SharedState>,
+    Path(id): Path<String>,
+    Query(query): Query<QuotesQuery>,
+) -> Result<Json<PostQuotes>, ApiError> {
+    let s = state.lock()?;
+    let after = query.after_seq.unwrap_or(0);
+    let limit = query.limit.unwrap_or(MAX_OUTBOX_PAGE).min(MAX_OUTBOX_PAGE);
+    let quoting = s.quote_index.get(&id);
+    let mut later: Vec<(u64, &Envelope)> = quoting
+        .into_iter()
+        .flatten()
+        .filter_map(|quoting| s.memory.get(quoting))
+        .filter_map(|env| Some((env.seq?, env)))
+        .filter(|(seq, _)| *seq > after)
+        .collect();
+    later.sort_unstable_by_key(|(seq, _)| *seq);
+    later.truncate(limit);
+    Ok(Json(PostQuotes {
+        post_id: id,
+        count: quoting.map_or(0, BTreeSet::len),
+        cursor: later.last().map_or(after, |(seq, _)| *seq),
+        quotes: later
+            .into_iter()
+            .map(|(_, env)| served(env, s.config.geo_precision))
+            .collect(),
+    }))
+}
+
+/// Records a signed reaction envelope on post `id`. Answers `201 Created`
+/// for a new reaction and `200 OK` if this key had already left it.
+pub async fn add_reaction(
+    State(state): State<SharedState>,
+    Path(id): Path<String>,
+    connect_info: Option<ConnectInfo<SocketAddr>>,
+    headers: HeaderMap,
+    Json(envelope): Json<Envelope>,
+) -> Result<(StatusCode, Json<PostReactions>), ApiError> {
+    let mut s = state.lock()?;
+    let client = client_ip(&headers, connect_info.as_ref(), &s.config)
+        .unwrap_or_else(|| "unknown".to_string());
+    if s.check_reaction_rate(&client).is_err() {
+        return Err(ApiError::new(
+            StatusCode::TOO_MANY_REQUESTS,
+            "reaction rate limit exceeded",
+        ));
+    }
+    let reaction = validate_reaction(&envelope, &s.config.envelope_limits())?;
+    if s.keyring
+        .as_ref()
+        .is_some_and(|k| !k.contains(&reaction.id.to_lowercase()))
+    {
+        return Err(ValidationError::UntrustedKey.into());
+    }
+    if reaction.post != id {
+        return Err(ApiError::bad_request("reaction is for a different post"));
+    }
+    if !s.config.reactions.contains(&reaction.reaction) {
+        return Err(ApiError::bad_request(format!(
+            "reaction {} is not allowed here",
+            reaction.reaction
+        )));
+    }
+    if !s.memory.contains_key(&id) {
+        return Err(ApiError::not_found("unknown post"));
+    }
+    if !s.has_room_for_reaction(&reaction)? {
+        return Err(ApiError::new(
+            StatusCode::TOO_MANY_REQUESTS,
+            "post has the most reactions this node keeps",
+        ));
+    }
+
+    let created = s.add_reaction(&envelope, &reaction)?;
+    let counts = s.reaction_counts(&id)?;
+    let status = if created {
+        StatusCode::CREATED
+    } else {
+        StatusCode::OK
+    };
+    Ok((
+        status,
+        Json(PostReactions {
+            post_id: id,
+            counts,
+        }),
+    ))
+}
+
+fn post_detail_of(s: &AppState, id: String) -> Result<PostDetail, ApiError> {
+    let envelope = s
+        .memory
+        .get(&id)
+        .cloned()
+        .ok_or_else(|| ApiError::not_found("unknown post"))?;
+    let post: Post = serde_json::from_str(&envelope.data).map_err(|e| {
+        ApiError::new(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("stored post is unreadable: {}", e),
+        )
+    })?;
+    Ok(PostDetail {
+        karma: s.karma_of(&id),
+        labels: s.applied_label(&id).cloned().into_iter().collect(),
+        reply_count: s.reply_count(&id),
+        quote_count: s.quote_count(&id),
+        report_count: public_report_counts(s, std::slice::from_ref(&id))[0],
+        label_source: s.post_label_sources.get(&id).cloned(),
+        receipt: None,
+        envelope,
+        post,
+    })
+}
+
+/// Every label definition, or with `lat` and `lon`, those that apply at
+/// that point: the global labels and any whose region covers it.
 pub async fn moderation_labels(
     State(state): State<SharedState>,
-) -> Result<Json<Vec<ModerationLabel>>, StatusCode> {
-    let s = state
-        .lock()
-        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
-    let list = s
+    Query(query): Query<LabelsQuery>,
+) -> Result<Json<Vec<ModerationLabel>>, ApiError> {
+    let s = state.lock()?;
+    let mut list: Vec<ModerationLabel> = s
         .label_definitions
-        .iter()
-        .map(|(k, v)| ModerationLabel {
-            label: k.clone(),
-            description: v.clone(),
+        .values()
+        .filter(|label| match (query.lat, query.lon) {
+            (Some(lat), Some(lon)) => label.applies_at(lat, lon),
+            _ => true,
         })
+        .cloned()
         .collect();
+    list.sort_by(|a, b| a.id.cmp(&b.id));
     Ok(Json(list))
 }
 
+/// Every labelled post on this node, so peers can review our labels.
+pub async fn moderation_post_labels(
+    State(state): State<SharedState>,
+) -> Result<Json<BTreeMap<String, String>>, ApiError> {
+    let s = state.lock()?;
+    Ok(Json(s.post_labels.clone().into_iter().collect()))
+}
+
+/// The client's address: the first address in the first of
+/// `--client-ip-headers` present, else the socket peer. `None` when neither
+/// is known, as when the router is called without `ConnectInfo`.
+pub fn client_ip(
+    headers: &HeaderMap,
+    connect_info: Option<&ConnectInfo<SocketAddr>>,
+    config: &Config,
+) -> Option<String> {
+    config
+        .client_ip_headers
+        .iter()
+        .map(|name| name.trim())
+        .filter(|name| !name.is_empty())
+        .find_map(|name| {
+            let value = headers.get(name)?.to_str().ok()?;
+            let first = value.split(',').next()?.trim();
+            (!first.is_empty()).then(|| first.to_string())
+        })
+        .or_else(|| connect_info.map(|ConnectInfo(addr)| addr.ip().to_string()))
+}
+
 pub async fn moderation_report(
     State(state): State<SharedState>,
+    connect_info: Option<ConnectInfo<SocketAddr>>,
     headers: HeaderMap,
     Json(reports): Json<Vec<ModerationReport>>,
-) -> Result<Json<ApiResponse>, StatusCode> {
-    let mut s = state
-        .lock()
-        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+) -> Result<Json<ApiResponse>, ApiError> {
+    let mut s = state.lock()?;
 
-    let reporter_ip = headers
-        .get("X-Forwarded-For")
-        .and_then(|v| v.to_str().ok())
-        .map(|s| s.split(',').next().unwrap_or(s).trim().to_string())
-        .or_else(|| {
-            headers
-                .get("X-Real-IP")
-                .and_then(|v| v.to_str().ok())
-                .map(|s| s.to_string())
-        })
+    let reporter_ip = client_ip(&headers, connect_info.as_ref(), &s.config)
         .unwrap_or_else(|| "unknown".to_string());
 
+    let reports: Vec<ModerationReport> = reports
+        .into_iter()
+        .filter(|report| !report.reason.trim().is_empty())
+        .collect();
+    if !s.make_room_for_reports(reports.len()) {
+        return Err(ApiError::new(
+            StatusCode::TOO_MANY_REQUESTS,
+            "moderation report queue is full",
+        ));
+    }
+
     for mut report in reports {
         report.reported_at = Utc::now();
         report.reporter_ip = Some(reporter_ip.clone());
         report.id = uuid::Uuid::new_v4().to_string();
+        report.suggested_label = report
+            .suggested_label
+            .as_deref()
+            .and_then(|label| s.resolve_label(label));
 
-        if report.reason.trim().is_empty() {
-            continue;
-        }
-
+        let post_id = report.post.id.clone();
         s.moderation_reports.push(report);
+        let held = s
+            .memory
+            .get(&post_id)
+            .and_then(|env| serde_json::from_str::<Post>(&env.data).ok());
+        if let Some(post) = held {
+            s.apply_label_rules(&post);
+        }
     }
 
     Ok(Json(ApiResponse { ok: true }))
 }
 
+fn bearer_token(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get(AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+        .map(str::trim)
+}
+
+/// Accepts either a bearer token from `admin_login` or the
+/// `X-Admin-Password` header. Takes the state lock itself, so call it
+/// before locking.
+async fn require_admin(state: &SharedState, headers: &HeaderMap) -> Result<(), ApiError> {
+    if let Some(token) = bearer_token(headers) {
+        return if state.lock()?.is_admin_token(token) {
+            Ok(())
+        } else {
+            Err(ApiError::unauthorized("invalid or expired admin token"))
+        };
+    }
+
+    let password = headers
+        .get("X-Admin-Password")
+        .and_then(|v| v.to_str().ok())
+        .ok_or_else(|| ApiError::unauthorized("admin credentials required"))?;
+    if !is_admin(state, password).await? {
+        return Err(ApiError::unauthorized("invalid admin password"));
+    }
+    Ok(())
+}
+
+/// For admin endpoints that take the password in the JSON body: a bearer
+/// token, if present, is checked instead.
+async fn require_admin_or_password(
+    state: &SharedState,
+    headers: &HeaderMap,
+    password: &str,
+) -> Result<(), ApiError> {
+    if bearer_token(headers).is_some() {
+        require_admin(state, headers).await
+    } else if is_admin(state, password).await? {
+        Ok(())
+    } else {
+        Err(ApiError::unauthorized("invalid admin password"))
+    }
+}
+
+/// Checks `password` with the admin backend on a blocking thread, outside
+/// the state lock: Argon2 is slow on purpose, and every other request
+/// would wait on it.
+async fn is_admin(state: &SharedState, password: &str) -> Result<bool, ApiError> {
+    let backend = state.lock()?.admin_backend.clone();
+    let password = password.to_string();
+    tokio::task::spawn_blocking(move || backend.verify(&password))
+        .await
+        .map_err(|e| {
+            ApiError::new(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("admin check failed: {}", e),
+            )
+        })
+}
+
+/// Rejects admin requests a cross-site HTML form could forge. Forms cannot
+/// set custom headers or a JSON content type, so any state-changing admin
+/// call must carry `Authorization`/`X-Admin-Password` or a JSON body (which
+/// forces a CORS preflight). Credentials are never read from cookies.
+pub async fn admin_csrf_guard(req: Request, next: Next) -> Result<Response, ApiError> {
+    let headers = req.headers();
+    let safe = matches!(*req.method(), Method::GET | Method::HEAD | Method::OPTIONS);
+    let has_credential =
+        headers.contains_key(AUTHORIZATION) || headers.contains_key("X-Admin-Password");
+    let is_json = headers
+        .get(CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|ct| ct.starts_with("application/json"));
+
+    if safe || has_credential || is_json {
+        Ok(next.run(req).await)
+    } else {
+        Err(ApiError::new(
+            StatusCode::FORBIDDEN,
+            "admin requests need credentials or a JSON body",
+        ))
+    }
+}
+
+/// Largest admin request body buffered for signature verification.
+const MAX_SIGNED_ADMIN_BYTES: usize = 16 * 1024 * 1024;
+
+/// Checks admin requests signed with an `--admin-signing-keys` key; see
+/// `federation` for the scheme. A valid signature stands in for the
+/// password through a bearer token that lives for this request only, unless
+/// `--require-admin-signature` asks for both. With that set, unsigned
+/// requests are refused. Each signature is accepted once.
+pub async fn admin_signature_guard(
+    State(state): State<SharedState>,
+    req: Request,
+    next: Next,
+) -> Result<Response, ApiError> {
+    let (trusted, required) = {
+        let s = state.lock()?;
+        (
+            s.config.admin_signing_keys.clone(),
+            s.config.require_admin_signature,
+        )
+    };
+    if !req.headers().contains_key(federation::ADMIN_KEY_HEADER) {
+        if required {
+            return Err(ApiError::unauthorized("admin requests must be signed"));
+        }
+        return Ok(next.run(req).await);
+    }
+
+    let (mut parts, body) = req.into_parts();
+    let bytes = axum::body::to_bytes(body, MAX_SIGNED_ADMIN_BYTES)
+        .await
+        .map_err(|_| ApiError::new(StatusCode::PAYLOAD_TOO_LARGE, "admin request is too large"))?;
+    let path = parts
+        .uri
+        .path_and_query()
+        .map_or_else(|| parts.uri.path().to_string(), |p| p.as_str().to_string());
+    let method = parts.method.to_string();
+    let fingerprint =
+        match federation::verify_admin_request(&parts.headers, &method, &path, &bytes, &trusted) {
+            Ok(fingerprint) => fingerprint,
+            Err(PushAuthError::Untrusted(fingerprint)) => {
+                eprintln!(
+                    "Rejected admin request signed by untrusted key {}",
+                    fingerprint
+                );
+                return Err(ApiError::new(
+                    StatusCode::FORBIDDEN,
+                    format!("admin key {} is not trusted", fingerprint),
+                ));
+            }
+            Err(e) => return Err(ApiError::unauthorized(e.to_string())),
+        };
+    let date = parts
+        .headers
+        .get(federation::DATE_HEADER)
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or_default();
+    let replay_key = federation::admin_replay_key(&fingerprint, date, &method, &path, &bytes);
+
+    let token = {
+        let mut s = state.lock()?;
+        if !s.note_admin_request(&replay_key) {
+            return Err(ApiError::unauthorized(
+                "admin request signature was already used",
+            ));
+        }
+        (!required).then(|| s.issue_admin_token().0)
+    };
+    println!(
+        "Admin request {} {} signed by {}",
+        method, path, fingerprint
+    );
+    if let Some(token) = &token {
+        let bearer = format!("Bearer {}", token)
+            .parse()
+            .map_err(|_| ApiError::from(StatusCode::INTERNAL_SERVER_ERROR))?;
+        parts.headers.insert(AUTHORIZATION, bearer);
+    }
+
+    let response = next
+        .run(Request::from_parts(parts, axum::body::Body::from(bytes)))
+        .await;
+    if let Some(token) = token {
+        if let Ok(mut s) = state.lock() {
+            s.admin_tokens.remove(&token);
+        }
+    }
+    Ok(response)
+}
+
+/// Largest inbox body buffered for signature verification.
+const MAX_SIGNED_PUSH_BYTES: usize = 64 * 1024 * 1024;
+
+/// With `require_signed_inbox` on, only lets through pushes signed by a
+/// trusted node key. See `federation` for the scheme.
+pub async fn inbox_signature_guard(
+    State(state): State<SharedState>,
+    req: Request,
+    next: Next,
+) -> Result<Response, ApiError> {
+    let trusted = state.lock()?.config.inbox_trusted_keys.clone();
+
+    let (parts, body) = req.into_parts();
+    let bytes = axum::body::to_bytes(body, MAX_SIGNED_PUSH_BYTES)
+        .await
+        .map_err(|_| ApiError::new(StatusCode::PAYLOAD_TOO_LARGE, "inbox push is too large"))?;
+    match federation::verify_push(&parts.headers, &bytes, &trusted) {
+        Ok(_) => {}
+        Err(PushAuthError::Untrusted(fingerprint)) => {
+            eprintln!(
+                "Rejected inbox push from untrusted node key {}",
+                fingerprint
+            );
+            return Err(ApiError::new(
+                StatusCode::FORBIDDEN,
+                format!("node key {} is not trusted", fingerprint),
+            ));
+        }
+        Err(e) => {
+            eprintln!("Rejected unsigned or invalid inbox push: {}", e);
+            return Err(ApiError::unauthorized(e.to_string()));
+        }
+    }
+    Ok(next
+        .run(Request::from_parts(parts, axum::body::Body::from(bytes)))
+        .await)
+}
+
+/// Node identity and capabilities. Peers use the id to detect syncing with
+/// themselves and the public key to authenticate our pushes.
+/// Named features this node supports, for `NodeInfo` and `Capabilities`.
+fn capability_names(s: &AppState) -> Vec<String> {
+    let config = &s.config;
+    let mut capabilities = vec!["search".to_string()];
+    if config.sync_msgpack {
+        capabilities.push("msgpack".to_string());
+    }
+    if s.node_key.is_some() {
+        capabilities.push("signed-push".to_string());
+    }
+    if config.require_signed_inbox {
+        capabilities.push("signed-inbox-required".to_string());
+    }
+    if config.enable_karma {
+        capabilities.push("karma".to_string());
+    }
+    if config.enable_reports {
+        capabilities.push("reports".to_string());
+    }
+    if config.post_receipts && s.node_key.is_some() {
+        capabilities.push("receipts".to_string());
+    }
+    capabilities
+}
+
+pub async fn node_info(State(state): State<SharedState>) -> Result<Json<NodeInfo>, ApiError> {
+    let s = state.lock()?;
+    let config = &s.config;
+    let capabilities = capability_names(&s);
+
+    Ok(Json(NodeInfo {
+        id: s.node_key.as_ref().map(|k| k.fingerprint()),
+        public_key: s.node_key.as_ref().map(|k| k.public_key().to_string()),
+        version: env!("CARGO_PKG_VERSION").to_string(),
+        capabilities,
+        pow_difficulty: config.pow_difficulty,
+        peers: s.peers.len(),
+        max_peers: config.max_peers,
+    }))
+}
+
+/// Configured limits for clients to adapt to. Reads only configuration,
+/// so it is cheap enough to call on every client start.
+pub async fn capabilities(
+    State(state): State<SharedState>,
+) -> Result<Json<Capabilities>, ApiError> {
+    let s = state.lock()?;
+    let config = &s.config;
+    let mut content_types = vec!["application/json".to_string()];
+    if config.sync_msgpack {
+        content_types.push(crate::wire::MSGPACK.to_string());
+    }
+    Ok(Json(Capabilities {
+        version: env!("CARGO_PKG_VERSION").to_string(),
+        capabilities: capability_names(&s),
+        content_types,
+        mirror: config.mirror,
+        pow_difficulty: config.pow_difficulty,
+        region_mode: config
+            .region_mode
+            .to_possible_value()
+            .map_or_else(String::new, |v| v.get_name().to_string()),
+        reactions: config.reactions.clone(),
+        max_reply_depth: config.max_reply_depth,
+        max_post_data_bytes: config.max_post_data_bytes,
+        max_public_key_bytes: config.max_public_key_bytes,
+        max_signature_bytes: config.max_signature_bytes,
+        post_rate_limit: config.post_rate_limit,
+        post_rate_window_secs: config.post_rate_window_secs,
+        client_pow_difficulty: config.pow_difficulty_for(Submitter::Client),
+        client_post_rate_limit: config.post_rate_limit_for(Submitter::Client),
+        reaction_rate_limit: config.reaction_rate_limit,
+        max_reactions_per_post: config.max_reactions_per_post,
+        max_outbox_page: MAX_OUTBOX_PAGE,
+        max_outbox_id_page: MAX_OUTBOX_ID_PAGE,
+        max_bbox_results: MAX_BBOX_RESULTS,
+        max_keys_per_request: MAX_KEYS_PER_REQUEST,
+        max_by_keys_results: MAX_BY_KEYS_RESULTS,
+        search_min_query_len: config.search_min_query_len,
+        search_max_results: config.search_max_results,
+        geo_precision: config.geo_precision,
+    }))
+}
+
+#[cfg(feature = "admin-ui")]
+const ADMIN_PAGE: &str = include_str!("../static/admin.html");
+
+#[cfg(not(feature = "admin-ui"))]
+const ADMIN_PAGE: &str = "<!doctype html>
+<html><head><meta charset=\"utf-8\"><title>OpenHerd admin</title></head>
+<body><p>This node was built without the admin UI. The admin API under
+<code>/_openherd/admin/</code> is still available.</p></body></html>
+";
+
 pub async fn admin_ui() -> Html<&'static str> {
-    Html(include_str!("../static/admin.html"))
+    Html(ADMIN_PAGE)
 }
 
-pub async fn admin_reports(
+pub async fn admin_login(
     State(state): State<SharedState>,
     Json(auth): Json<AdminAuth>,
-) -> Result<Json<Vec<ModerationReport>>, StatusCode> {
-    let s = state
-        .lock()
-        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+) -> Result<Json<AdminToken>, ApiError> {
+    if !is_admin(&state, &auth.password).await? {
+        return Err(ApiError::unauthorized("invalid admin password"));
+    }
+    let mut s = state.lock()?;
+
+    let (token, expires) = s.issue_admin_token();
+    Ok(Json(AdminToken { token, expires }))
+}
 
-    if !s.is_admin(&auth.password) {
-        return Err(StatusCode::UNAUTHORIZED);
+pub async fn admin_logout(
+    State(state): State<SharedState>,
+    headers: HeaderMap,
+) -> Result<Json<ApiResponse>, ApiError> {
+    let mut s = state.lock()?;
+
+    let token =
+        bearer_token(&headers).ok_or_else(|| ApiError::unauthorized("bearer token required"))?;
+    if s.admin_tokens.remove(token).is_none() {
+        return Err(ApiError::unauthorized("invalid or expired admin token"));
     }
 
+    Ok(Json(ApiResponse { ok: true }))
+}
+
+pub async fn admin_reports(
+    State(state): State<SharedState>,
+    headers: HeaderMap,
+    Json(auth): Json<AdminAuth>,
+) -> Result<Json<Vec<ModerationReport>>, ApiError> {
+    require_admin_or_password(&state, &headers, &auth.password).await?;
+    let s = state.lock()?;
+
     Ok(Json(s.moderation_reports.clone()))
 }
 
-pub async fn admin_accept_report(
+/// The report queue grouped by suggested label, largest group first, so
+/// moderators can handle e.g. all spam reports together.
+pub async fn admin_reports_by_category(
     State(state): State<SharedState>,
     headers: HeaderMap,
-    Json(action): Json<ModerationAction>,
-) -> Result<Json<ApiResponse>, StatusCode> {
-    let mut s = state
-        .lock()
-        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    Json(auth): Json<AdminAuth>,
+) -> Result<Json<Vec<ReportCategory>>, ApiError> {
+    require_admin_or_password(&state, &headers, &auth.password).await?;
+    let s = state.lock()?;
 
-    let password = headers
-        .get("X-Admin-Password")
-        .and_then(|v| v.to_str().ok())
-        .ok_or(StatusCode::UNAUTHORIZED)?;
+    let mut groups: BTreeMap<String, Vec<ModerationReport>> = BTreeMap::new();
+    for report in &s.moderation_reports {
+        let category = report
+            .suggested_label
+            .clone()
+            .unwrap_or_else(|| "uncategorized".to_string());
+        groups.entry(category).or_default().push(report.clone());
+    }
+    let mut categories: Vec<ReportCategory> = groups
+        .into_iter()
+        .map(|(category, reports)| ReportCategory {
+            category,
+            count: reports.len(),
+            reports,
+        })
+        .collect();
+    categories.sort_by_key(|c| std::cmp::Reverse(c.count));
+    Ok(Json(categories))
+}
+
+pub async fn admin_archived_reports(
+    State(state): State<SharedState>,
+    headers: HeaderMap,
+) -> Result<Json<Vec<ArchivedReport>>, ApiError> {
+    require_admin(&state, &headers).await?;
+    let s = state.lock()?;
+    Ok(Json(s.archived_reports()))
+}
+
+pub async fn admin_dead_letters(
+    State(state): State<SharedState>,
+    headers: HeaderMap,
+) -> Result<Json<Vec<DeadLetter>>, ApiError> {
+    require_admin(&state, &headers).await?;
+    let s = state.lock()?;
+    Ok(Json(s.dead_letters()))
+}
+
+pub async fn admin_clear_dead_letters(
+    State(state): State<SharedState>,
+    headers: HeaderMap,
+) -> Result<Json<ApiResponse>, ApiError> {
+    require_admin(&state, &headers).await?;
+    let mut s = state.lock()?;
+    s.clear_dead_letters()?;
+    Ok(Json(ApiResponse { ok: true }))
+}
 
-    if !s.is_admin(password) {
-        return Err(StatusCode::UNAUTHORIZED);
+/// What labelling or unlabelling `posts` and dropping the reports with ids
+/// in `dropped` does. Sibling reports are counted against both `posts` and
+/// the posts of the dropped reports.
+fn moderation_impact(
+    s: &AppState,
+    mut posts: Vec<String>,
+    dropped: &[&str],
+    applied: bool,
+) -> ModerationImpact {
+    posts.sort();
+    let reported: HashSet<&str> = s
+        .moderation_reports
+        .iter()
+        .filter(|r| dropped.contains(&r.id.as_str()))
+        .map(|r| r.post.id.as_str())
+        .chain(posts.iter().map(String::as_str))
+        .collect();
+    let sibling_reports = s
+        .moderation_reports
+        .iter()
+        .filter(|r| reported.contains(r.post.id.as_str()) && !dropped.contains(&r.id.as_str()))
+        .count();
+    let karma_votes = s
+        .karma_codes
+        .values()
+        .filter(|kc| kc.current_post.as_ref().is_some_and(|p| posts.contains(p)))
+        .count();
+    ModerationImpact {
+        reports: s
+            .moderation_reports
+            .iter()
+            .filter(|r| dropped.contains(&r.id.as_str()))
+            .count(),
+        posts,
+        sibling_reports,
+        karma_votes,
+        applied,
     }
+}
+
+/// Closes a report, labelling its post if a label is given. With
+/// `dry_run` only reports the impact.
+pub async fn admin_accept_report(
+    State(state): State<SharedState>,
+    headers: HeaderMap,
+    Query(query): Query<DryRunQuery>,
+    Json(action): Json<ModerationAction>,
+) -> Result<Json<ModerationImpact>, ApiError> {
+    require_admin(&state, &headers).await?;
+    let mut s = state.lock()?;
 
     let report = s
         .moderation_reports
         .iter()
         .find(|r| r.id == action.report_id)
-        .ok_or(StatusCode::NOT_FOUND)?;
+        .ok_or_else(|| ApiError::not_found("unknown report"))?;
 
     let post_id = report.post.id.clone();
+    let slug = action
+        .label
+        .map(|label| {
+            s.resolve_label(&label)
+                .ok_or_else(|| ApiError::bad_request(format!("unknown label {}", label)))
+        })
+        .transpose()?;
+    let labelled = slug.iter().map(|_| post_id.clone()).collect();
+    let impact = moderation_impact(&s, labelled, &[&action.report_id], !query.dry_run);
+    if query.dry_run {
+        return Ok(Json(impact));
+    }
 
-    if let Some(label) = action.label {
-        s.post_labels.insert(post_id, label);
+    if let Some(slug) = slug {
+        s.post_label_sources.remove(&post_id);
+        s.post_labels.insert(post_id, slug);
     }
 
     s.moderation_reports.retain(|r| r.id != action.report_id);
 
-    Ok(Json(ApiResponse { ok: true }))
+    Ok(Json(impact))
 }
 
+/// Dismisses a report. With `dry_run` only reports the impact.
 pub async fn admin_delete_report(
     State(state): State<SharedState>,
     Path(report_id): Path<String>,
     headers: HeaderMap,
-) -> Result<Json<ApiResponse>, StatusCode> {
-    let mut s = state
-        .lock()
-        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
-
-    let password = headers
-        .get("X-Admin-Password")
-        .and_then(|v| v.to_str().ok())
-        .ok_or(StatusCode::UNAUTHORIZED)?;
+    Query(query): Query<DryRunQuery>,
+) -> Result<Json<ModerationImpact>, ApiError> {
+    require_admin(&state, &headers).await?;
+    let mut s = state.lock()?;
 
-    if !s.is_admin(password) {
-        return Err(StatusCode::UNAUTHORIZED);
+    let impact = moderation_impact(&s, Vec::new(), &[&report_id], !query.dry_run);
+    if !query.dry_run {
+        s.moderation_reports.retain(|r| r.id != report_id);
     }
 
-    s.moderation_reports.retain(|r| r.id != report_id);
-
-    Ok(Json(ApiResponse { ok: true }))
+    Ok(Json(impact))
 }
 
 pub async fn admin_add_label(
     State(state): State<SharedState>,
     headers: HeaderMap,
     Json(label): Json<ModerationLabel>,
-) -> Result<Json<ApiResponse>, StatusCode> {
-    let mut s = state
-        .lock()
-        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
-    let password = headers
-        .get("X-Admin-Password")
-        .and_then(|v| v.to_str().ok())
-        .ok_or(StatusCode::UNAUTHORIZED)?;
-    if !s.is_admin(password) {
-        return Err(StatusCode::UNAUTHORIZED);
+) -> Result<Json<ApiResponse>, ApiError> {
+    require_admin(&state, &headers).await?;
+    let mut s = state.lock()?;
+    let label = label.with_id();
+    if label.id.is_empty() {
+        return Err(ApiError::bad_request("label needs an id or name"));
     }
-    if s.label_definitions.contains_key(&label.label) {
-        return Err(StatusCode::BAD_REQUEST);
+    if s.label_definitions.contains_key(&label.id) {
+        return Err(ApiError::bad_request(format!(
+            "label {} already exists",
+            label.id
+        )));
     }
-    s.label_definitions
-        .insert(label.label.clone(), label.description.clone());
+    s.label_definitions.insert(label.id.clone(), label);
 
-    let labels_vec: Vec<ModerationLabel> = s
-        .label_definitions
-        .iter()
-        .map(|(l, d)| ModerationLabel {
-            label: l.clone(),
-            description: d.clone(),
-        })
-        .collect();
-    if let Ok(json) = serde_json::to_string_pretty(&labels_vec) {
-        let _ = std::fs::write("./labels.json", json);
+    if let Err(e) = s.save_label_definitions() {
+        eprintln!("Failed to write labels.json: {}", e);
     }
 
     Ok(Json(ApiResponse { ok: true }))
 }
 
+/// Deletes a label definition and clears it from every post carrying it.
+/// With `dry_run` only reports the impact.
 pub async fn admin_delete_label(
     State(state): State<SharedState>,
     headers: HeaderMap,
     Path(label): Path<String>,
-) -> Result<Json<ApiResponse>, StatusCode> {
-    let mut s = state
-        .lock()
-        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
-    let password = headers
-        .get("X-Admin-Password")
-        .and_then(|v| v.to_str().ok())
-        .ok_or(StatusCode::UNAUTHORIZED)?;
-    if !s.is_admin(password) {
-        return Err(StatusCode::UNAUTHORIZED);
-    }
-    s.label_definitions.remove(&label);
-    s.post_labels.retain(|_, l| l != &label);
-
-    let labels_vec: Vec<ModerationLabel> = s
-        .label_definitions
+    Query(query): Query<DryRunQuery>,
+) -> Result<Json<ModerationImpact>, ApiError> {
+    require_admin(&state, &headers).await?;
+    let mut s = state.lock()?;
+    let slug = s
+        .resolve_label(&label)
+        .ok_or_else(|| ApiError::not_found(format!("unknown label {}", label)))?;
+    let labelled = s
+        .post_labels
         .iter()
-        .map(|(l, d)| ModerationLabel {
-            label: l.clone(),
-            description: d.clone(),
-        })
+        .filter(|(_, l)| **l == slug)
+        .map(|(id, _)| id.clone())
         .collect();
-    if let Ok(json) = serde_json::to_string_pretty(&labels_vec) {
-        let _ = std::fs::write("./labels.json", json);
+    let impact = moderation_impact(&s, labelled, &[], !query.dry_run);
+    if query.dry_run {
+        return Ok(Json(impact));
     }
+    s.label_definitions.remove(&slug);
+    s.post_labels.retain(|_, l| l != &slug);
+    let s = &mut *s;
+    s.post_label_sources
+        .retain(|id, _| s.post_labels.contains_key(id));
 
-    Ok(Json(ApiResponse { ok: true }))
+    if let Err(e) = s.save_label_definitions() {
+        eprintln!("Failed to write labels.json: {}", e);
+    }
+
+    Ok(Json(impact))
 }
 
-pub async fn admin_generate_karma_codes(
+/// Fetches a peer's labels for posts we hold and lists those that differ
+/// from ours. Only the post ids in `accept` are merged, attributed to the
+/// peer; a label definition we lack is copied from the peer.
+pub async fn admin_import_labels(
     State(state): State<SharedState>,
     headers: HeaderMap,
-    Json(req): Json<KarmaGenerateRequest>,
-) -> Result<Json<Vec<KarmaCode>>, StatusCode> {
-    let mut s = state
-        .lock()
-        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    Json(req): Json<LabelImportRequest>,
+) -> Result<Json<LabelImportResponse>, ApiError> {
+    let base = normalize_peer_address(&req.address)
+        .ok_or_else(|| ApiError::bad_request("invalid peer address"))?;
+    {
+        require_admin(&state, &headers).await?;
+        let s = state.lock()?;
+        if !s.config.allow_insecure_peers && is_insecure_peer(&base) {
+            return Err(ApiError::bad_request(
+                "insecure peer address: this node only talks to https:// peers",
+            ));
+        }
+    }
 
-    let password = headers
-        .get("X-Admin-Password")
-        .and_then(|v| v.to_str().ok())
-        .ok_or(StatusCode::UNAUTHORIZED)?;
+    let client = reqwest::Client::builder()
+        .timeout(Duration::from_secs(15))
+        .build()
+        .map_err(|e| {
+            ApiError::new(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("failed to build HTTP client: {}", e),
+            )
+        })?;
+    let (definitions, peer_labels) = crate::sync::fetch_peer_labels(&client, &base)
+        .await
+        .map_err(|e| ApiError::new(StatusCode::BAD_GATEWAY, e))?;
+    let definitions: HashMap<String, ModerationLabel> = definitions
+        .into_iter()
+        .map(|l| {
+            let l = l.with_id();
+            (l.id.clone(), l)
+        })
+        .collect();
+
+    let mut s = state.lock()?;
+    let mut proposals: Vec<LabelProposal> = peer_labels
+        .into_iter()
+        .filter(|(post_id, slug)| {
+            s.memory.contains_key(post_id) && s.post_labels.get(post_id) != Some(slug)
+        })
+        .filter_map(|(post_id, slug)| {
+            Some(LabelProposal {
+                current: s.post_labels.get(&post_id).cloned(),
+                label: definitions.get(&slug)?.clone(),
+                post_id,
+            })
+        })
+        .collect();
+    proposals.sort_by(|a, b| a.post_id.cmp(&b.post_id));
+
+    let accept: HashSet<&str> = req.accept.iter().map(String::as_str).collect();
+    let mut merged = 0;
+    let mut new_definitions = false;
+    for proposal in proposals
+        .iter()
+        .filter(|p| accept.contains(p.post_id.as_str()))
+    {
+        if !s.label_definitions.contains_key(&proposal.label.id) {
+            s.label_definitions
+                .insert(proposal.label.id.clone(), proposal.label.clone());
+            new_definitions = true;
+        }
+        s.post_labels
+            .insert(proposal.post_id.clone(), proposal.label.id.clone());
+        s.post_label_sources
+            .insert(proposal.post_id.clone(), base.clone());
+        merged += 1;
+    }
+    if new_definitions {
+        if let Err(e) = s.save_label_definitions() {
+            eprintln!("Failed to write labels.json: {}", e);
+        }
+    }
+
+    Ok(Json(LabelImportResponse {
+        peer: base,
+        proposals,
+        merged,
+    }))
+}
+
+pub async fn admin_karma_history(
+    State(state): State<SharedState>,
+    headers: HeaderMap,
+    Path(code): Path<String>,
+) -> Result<Json<Vec<KarmaRedemption>>, ApiError> {
+    require_admin(&state, &headers).await?;
+    let s = state.lock()?;
+
+    let karma_code = s
+        .karma_codes
+        .get(&code)
+        .ok_or_else(|| ApiError::not_found("unknown karma code"))?;
+    Ok(Json(karma_code.history.clone()))
+}
+
+/// Posts currently voted on by codes from one issuer, to help spot
+/// coordinated voting from a batch of codes. Codes whose direction cannot
+/// be recovered add nothing to a tally and are left out.
+pub async fn admin_issuer_votes(
+    State(state): State<SharedState>,
+    headers: HeaderMap,
+    Query(query): Query<IssuerQuery>,
+) -> Result<Json<Vec<IssuerVote>>, ApiError> {
+    require_admin(&state, &headers).await?;
+    let s = state.lock()?;
+
+    let mut votes: Vec<IssuerVote> = s
+        .karma_codes
+        .values()
+        .filter(|kc| kc.issuer == query.issuer)
+        .filter_map(|kc| {
+            Some(IssuerVote {
+                code: kc.code.clone(),
+                post: kc.current_post.clone()?,
+                direction: kc.applied_direction()?.to_string(),
+            })
+        })
+        .collect();
+    votes.sort_by(|a, b| a.post.cmp(&b.post).then_with(|| a.code.cmp(&b.code)));
+    Ok(Json(votes))
+}
+
+/// Rebuilds `karma_votes` from the karma codes, the source of truth, and
+/// lists every post whose tally changed. With `dry_run` only reports.
+pub async fn admin_recompute_karma(
+    State(state): State<SharedState>,
+    headers: HeaderMap,
+    Query(query): Query<KarmaRecomputeQuery>,
+) -> Result<Json<KarmaRecompute>, ApiError> {
+    require_admin(&state, &headers).await?;
+    let mut s = state.lock()?;
+
+    let tallies = s.karma_tallies();
+    let post_ids: BTreeSet<&String> = s.karma_votes.keys().chain(tallies.keys()).collect();
+    let drift: Vec<KarmaDrift> = post_ids
+        .into_iter()
+        .filter_map(|id| {
+            let before = s.karma_votes.get(id).copied().unwrap_or(0);
+            let after = tallies.get(id).copied().unwrap_or(0);
+            (before != after).then(|| KarmaDrift {
+                post_id: id.clone(),
+                before,
+                after,
+            })
+        })
+        .collect();
+    if !drift.is_empty() {
+        eprintln!("Karma tally drifted on {} posts", drift.len());
+    }
+
+    let posts = tallies.values().filter(|&&karma| karma != 0).count();
+    if !query.dry_run {
+        s.karma_votes = tallies;
+    }
+    Ok(Json(KarmaRecompute {
+        posts,
+        drift,
+        applied: !query.dry_run,
+    }))
+}
+
+/// Re-reads the `--content-filter` file. A file that fails to parse leaves
+/// the current filter in place.
+pub async fn admin_reload_content_filter(
+    State(state): State<SharedState>,
+    headers: HeaderMap,
+) -> Result<Json<ContentFilterStatus>, ApiError> {
+    require_admin(&state, &headers).await?;
+    let mut s = state.lock()?;
+    let path = s
+        .config
+        .content_filter
+        .clone()
+        .ok_or_else(|| ApiError::bad_request("no content filter file is configured"))?;
+    let filter = ContentFilter::load(&path).map_err(ApiError::bad_request)?;
+    let status = ContentFilterStatus {
+        terms: filter.terms(),
+        patterns: filter.patterns(),
+    };
+    s.content_filter = filter;
+    Ok(Json(status))
+}
+
+/// Most issuers in one page of `admin_issuer_stats`.
+const MAX_ISSUER_STATS: usize = 100;
+
+/// Per-issuer code usage and net karma, highest net karma first.
+pub async fn admin_issuer_stats(
+    State(state): State<SharedState>,
+    headers: HeaderMap,
+    Json(req): Json<IssuerStatsRequest>,
+) -> Result<Json<Vec<IssuerStats>>, ApiError> {
+    require_admin_or_password(&state, &headers, &req.password).await?;
+    let s = state.lock()?;
 
-    if !s.is_admin(password) {
-        return Err(StatusCode::UNAUTHORIZED);
+    let now = Utc::now();
+    let mut by_issuer: BTreeMap<&str, IssuerStats> = BTreeMap::new();
+    for kc in s.karma_codes.values() {
+        let stats = by_issuer
+            .entry(kc.issuer.as_str())
+            .or_insert_with(|| IssuerStats {
+                issuer: kc.issuer.clone(),
+                issued: 0,
+                used: 0,
+                expired_unused: 0,
+                net_karma: 0,
+            });
+        stats.issued += 1;
+        if kc.is_used() {
+            stats.used += 1;
+        } else if kc.expires < now {
+            stats.expired_unused += 1;
+        }
+        stats.net_karma += kc.applied_karma();
+    }
+
+    let mut stats: Vec<IssuerStats> = by_issuer.into_values().collect();
+    stats.sort_by_key(|st| std::cmp::Reverse(st.net_karma));
+    let limit = req.limit.unwrap_or(MAX_ISSUER_STATS).min(MAX_ISSUER_STATS);
+    Ok(Json(
+        stats.into_iter().skip(req.offset).take(limit).collect(),
+    ))
+}
+
+/// Most codes in one `admin_karma_status` request.
+const MAX_KARMA_STATUS_CODES: usize = 1_000;
+
+/// `karma_metadata` for many codes at once, e.g. to reconcile a printed
+/// batch against redemptions. Answers one entry per requested code, in
+/// order, with `null` for codes this node does not know.
+pub async fn admin_karma_status(
+    State(state): State<SharedState>,
+    headers: HeaderMap,
+    Json(req): Json<KarmaStatusRequest>,
+) -> Result<Json<Vec<Option<KarmaCodeStatus>>>, ApiError> {
+    require_admin_or_password(&state, &headers, &req.password).await?;
+    let s = state.lock()?;
+    if req.codes.len() > MAX_KARMA_STATUS_CODES {
+        return Err(ApiError::bad_request(format!(
+            "at most {} codes per request",
+            MAX_KARMA_STATUS_CODES
+        )));
+    }
+
+    let now = Utc::now();
+    Ok(Json(
+        req.codes
+            .iter()
+            .map(|code| {
+                let kc = s.karma_codes.get(code)?;
+                Some(KarmaCodeStatus {
+                    code: kc.code.clone(),
+                    used: kc.is_used(),
+                    expired: kc.expires < now,
+                    current_post: kc.current_post.clone(),
+                })
+            })
+            .collect(),
+    ))
+}
+
+/// Checks admin auth and the configured batch cap before any codes are made,
+/// and clamps the requested weight to the configured range.
+async fn authorize_code_generation(
+    state: &SharedState,
+    headers: &HeaderMap,
+    req: &mut KarmaGenerateRequest,
+) -> Result<(), ApiError> {
+    require_admin(state, headers).await?;
+    let s = state.lock()?;
+    if req.count > s.config.max_karma_codes_per_request {
+        return Err(ApiError::bad_request(format!(
+            "at most {} codes can be generated per request",
+            s.config.max_karma_codes_per_request
+        )));
     }
+    req.weight = req.weight.clamp(1, s.config.max_karma_weight.max(1));
+    Ok(())
+}
 
+/// Builds the requested batch of codes. Called without the state lock held.
+fn generate_karma_codes(req: &KarmaGenerateRequest) -> Vec<KarmaCode> {
     let vt_opt: Option<String> = None;
 
     let mut created = Vec::new();
@@ -580,59 +2367,3333 @@ pub async fn admin_generate_karma_codes(
             .to_uppercase();
         let code = format!("{}-{}", &raw[0..5], &raw[5..10]);
 
-        let kc = KarmaCode {
-            code: code.clone(),
+        created.push(KarmaCode {
+            code,
             issuer: req.issuer.clone(),
             vote_type: vt_opt.clone(),
             expires: req.expires,
             region: req.region.clone(),
             current_post: None,
             used_direction: None,
-        };
-        s.karma_codes.insert(code.clone(), kc.clone());
-        created.push(kc);
+            history: Vec::new(),
+            weight: req.weight,
+        });
     }
+    created
+}
+
+fn store_karma_codes(state: &SharedState, codes: &[KarmaCode]) -> Result<(), ApiError> {
+    state.lock()?.store_karma_codes(codes)?;
+    Ok(())
+}
 
+pub async fn admin_generate_karma_codes(
+    State(state): State<SharedState>,
+    headers: HeaderMap,
+    Json(mut req): Json<KarmaGenerateRequest>,
+) -> Result<Json<Vec<KarmaCode>>, ApiError> {
+    authorize_code_generation(&state, &headers, &mut req).await?;
+    let created = generate_karma_codes(&req);
+    store_karma_codes(&state, &created)?;
     Ok(Json(created))
 }
 
 pub async fn admin_generate_karma_codes_text(
     State(state): State<SharedState>,
     headers: HeaderMap,
-    Json(req): Json<KarmaGenerateRequest>,
-) -> Result<String, StatusCode> {
-    let mut s = state
-        .lock()
-        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
-    let password = headers
-        .get("X-Admin-Password")
-        .and_then(|v| v.to_str().ok())
-        .ok_or(StatusCode::UNAUTHORIZED)?;
-    if !s.is_admin(password) {
-        return Err(StatusCode::UNAUTHORIZED);
-    }
+    Json(mut req): Json<KarmaGenerateRequest>,
+) -> Result<String, ApiError> {
+    authorize_code_generation(&state, &headers, &mut req).await?;
+    let created = generate_karma_codes(&req);
+    store_karma_codes(&state, &created)?;
 
-    let vt_opt: Option<String> = None;
     let mut lines = vec![req.issuer.clone()];
-    for _ in 0..req.count.max(1) {
-        let raw: String = rand::thread_rng()
-            .sample_iter(&Alphanumeric)
-            .take(10)
-            .map(char::from)
-            .collect::<String>()
-            .to_uppercase();
-        let code = format!("{}-{}", &raw[0..5], &raw[5..10]);
-        let kc = KarmaCode {
-            code: code.clone(),
-            issuer: req.issuer.clone(),
-            vote_type: vt_opt.clone(),
-            expires: req.expires,
-            region: req.region.clone(),
-            current_post: None,
-            used_direction: None,
+    lines.extend(created.into_iter().map(|kc| kc.code));
+    Ok(lines.join("\n"))
+}
+
+/// Quotes a CSV field when it contains a separator, quote or line break.
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+/// One row per code, for printing codes on labels or cards. When the issuer
+/// is a node URL, `verify_url` points at the code's metadata there.
+fn karma_codes_csv(codes: &[KarmaCode]) -> String {
+    let mut out = String::from("code,issuer,type,weight,expires,region,verify_url\n");
+    for kc in codes {
+        let region = kc
+            .region
+            .as_ref()
+            .map(|r| format!("{:.5} {:.5} {}km", r.lat, r.lon, r.radius_km))
+            .unwrap_or_default();
+        let verify_url = normalize_peer_address(&kc.issuer)
+            .map(|base| format!("{}/_openherd/karma/{}", base, kc.code))
+            .unwrap_or_default();
+        let row = [
+            kc.code.clone(),
+            kc.issuer.clone(),
+            kc.vote_type.clone().unwrap_or_else(|| "any".to_string()),
+            kc.weight.to_string(),
+            kc.expires.format("%Y-%m-%d %H:%M UTC").to_string(),
+            region,
+            verify_url,
+        ];
+        let row: Vec<String> = row.iter().map(|f| csv_field(f)).collect();
+        out.push_str(&row.join(","));
+        out.push('\n');
+    }
+    out
+}
+
+pub async fn admin_generate_karma_codes_csv(
+    State(state): State<SharedState>,
+    headers: HeaderMap,
+    Json(mut req): Json<KarmaGenerateRequest>,
+) -> Result<([(HeaderName, &'static str); 2], String), ApiError> {
+    authorize_code_generation(&state, &headers, &mut req).await?;
+    let created = generate_karma_codes(&req);
+    store_karma_codes(&state, &created)?;
+    Ok((
+        [
+            (CONTENT_TYPE, "text/csv; charset=utf-8"),
+            (
+                CONTENT_DISPOSITION,
+                "attachment; filename=\"karma-codes.csv\"",
+            ),
+        ],
+        karma_codes_csv(&created),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::state::AppState;
+    use crate::validation::testing::signed_envelope;
+    use axum::extract::FromRequest;
+    use axum::response::IntoResponse;
+    use sha2::Digest;
+    use std::sync::Mutex;
+
+    fn test_state() -> SharedState {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        Arc::new(Mutex::new(AppState::new(db, Config::default())))
+    }
+
+    /// Serves `state` on a local port, returning its base URL.
+    async fn spawn_remote(state: SharedState) -> String {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            axum::serve(listener, crate::routes::router(state))
+                .await
+                .unwrap();
+        });
+        address
+    }
+
+    fn import(state: &SharedState, envelope: Envelope) {
+        let post = crate::validation::validate_envelope(&envelope).unwrap();
+        state
+            .lock()
+            .unwrap()
+            .import_envelope(envelope, &post)
+            .unwrap();
+    }
+
+    fn enroll(state: &SharedState, password: &str) {
+        let db = state.lock().unwrap().db.clone();
+        crate::auth::SledPasswords::new(db)
+            .enroll(password)
+            .unwrap();
+    }
+
+    fn admin_headers(state: &SharedState) -> HeaderMap {
+        enroll(state, "admin");
+        let mut headers = HeaderMap::new();
+        headers.insert("X-Admin-Password", "admin".parse().unwrap());
+        headers
+    }
+
+    fn add_code(state: &SharedState, code: &str) {
+        state.lock().unwrap().karma_codes.insert(
+            code.to_string(),
+            KarmaCode {
+                code: code.to_string(),
+                issuer: "test".to_string(),
+                vote_type: None,
+                expires: Utc::now() + chrono::Duration::days(1),
+                region: None,
+                current_post: None,
+                used_direction: None,
+                history: Vec::new(),
+                weight: 1,
+            },
+        );
+    }
+
+    fn search_query(q: &str) -> Query<SearchQuery> {
+        Query(SearchQuery {
+            q: q.to_string(),
+            offset: 0,
+            limit: None,
+        })
+    }
+
+    #[tokio::test]
+    async fn test_search_matches_case_insensitively() {
+        let state = test_state();
+        let hit = signed_envelope("Lost DOG near the park");
+        import(&state, hit.clone());
+        import(&state, signed_envelope("Farmers market today"));
+
+        let Json(results) = search(State(state.clone()), search_query("dog"))
+            .await
+            .unwrap();
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].envelope.id, hit.id);
+        assert!(results[0].snippet.is_none());
+
+        let short = search(State(state), search_query("do")).await;
+        assert_eq!(short.unwrap_err(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_search_uses_index_when_present() {
+        let state = test_state();
+        state.lock().unwrap().search_index = Some(crate::search::SearchIndex::in_memory().unwrap());
+        let hit = signed_envelope("Lost dog near the park");
+        import(&state, hit.clone());
+        import(&state, signed_envelope("Farmers market today"));
+        state.lock().unwrap().commit_search_index();
+
+        let Json(results) = search(State(state.clone()), search_query("dog"))
+            .await
+            .unwrap();
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].envelope.id, hit.id);
+        assert!(results[0]
+            .snippet
+            .as_deref()
+            .unwrap()
+            .contains("<b>dog</b>"));
+
+        state.lock().unwrap().remove_post(&hit.id);
+        state.lock().unwrap().commit_search_index();
+        let Json(results) = search(State(state), search_query("dog")).await.unwrap();
+        assert!(results.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_admin_login_issues_bearer_token() {
+        let state = test_state();
+        enroll(&state, "hunter2");
+
+        let wrong = admin_login(
+            State(state.clone()),
+            Json(AdminAuth {
+                password: "nope".to_string(),
+            }),
+        )
+        .await;
+        assert_eq!(wrong.unwrap_err(), StatusCode::UNAUTHORIZED);
+
+        let Json(issued) = admin_login(
+            State(state.clone()),
+            Json(AdminAuth {
+                password: "hunter2".to_string(),
+            }),
+        )
+        .await
+        .unwrap();
+
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            AUTHORIZATION,
+            format!("Bearer {}", issued.token).parse().unwrap(),
+        );
+        let reports = admin_reports(
+            State(state.clone()),
+            headers.clone(),
+            Json(AdminAuth {
+                password: String::new(),
+            }),
+        )
+        .await;
+        assert!(reports.is_ok());
+
+        assert!(admin_logout(State(state.clone()), headers.clone())
+            .await
+            .is_ok());
+        assert_eq!(
+            require_admin(&state, &headers).await.unwrap_err(),
+            StatusCode::UNAUTHORIZED
+        );
+    }
+
+    #[tokio::test]
+    async fn test_karma_history_records_redemptions() {
+        let state = test_state();
+        let headers = admin_headers(&state);
+        add_code(&state, "AAAAA-BBBBB");
+        let envelope = signed_envelope("Vote on me");
+
+        assert!(karma_upvote(
+            State(state.clone()),
+            Path("AAAAA-BBBBB".to_string()),
+            Json(envelope.clone()),
+        )
+        .await
+        .is_ok());
+        assert!(
+            karma_revoke(State(state.clone()), Path("AAAAA-BBBBB".to_string()))
+                .await
+                .is_ok()
+        );
+
+        let Json(history) = admin_karma_history(
+            State(state.clone()),
+            headers,
+            Path("AAAAA-BBBBB".to_string()),
+        )
+        .await
+        .unwrap();
+        assert_eq!(history.len(), 1);
+        assert_eq!(history[0].post, envelope.id);
+        assert_eq!(history[0].direction, "upvote");
+        assert!(history[0].revoked_at.is_some());
+
+        let unauthorized = admin_karma_history(
+            State(state),
+            HeaderMap::new(),
+            Path("AAAAA-BBBBB".to_string()),
+        )
+        .await;
+        assert_eq!(unauthorized.unwrap_err(), StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn test_generate_karma_codes_enforces_max_count() {
+        let state = test_state();
+        let headers = admin_headers(&state);
+        state.lock().unwrap().config.max_karma_codes_per_request = 5;
+        let request = |count| KarmaGenerateRequest {
+            count,
+            issuer: "https://example.org".to_string(),
+            vote_type: None,
+            expires: Utc::now() + chrono::Duration::days(1),
+            region: None,
+            weight: 1,
         };
-        s.karma_codes.insert(code.clone(), kc);
-        lines.push(code);
+
+        let too_many =
+            admin_generate_karma_codes(State(state.clone()), headers.clone(), Json(request(6)))
+                .await;
+        assert_eq!(too_many.unwrap_err(), StatusCode::BAD_REQUEST);
+        assert!(state.lock().unwrap().karma_codes.is_empty());
+
+        let Json(codes) =
+            admin_generate_karma_codes(State(state.clone()), headers, Json(request(5)))
+                .await
+                .unwrap();
+        assert_eq!(codes.len(), 5);
+        assert_eq!(state.lock().unwrap().karma_codes.len(), 5);
+    }
+
+    #[tokio::test]
+    async fn test_health_ready_probes_db() {
+        let state = test_state();
+        let (status, Json(health)) = health_ready(State(state.clone())).await.unwrap();
+        assert_eq!(status, StatusCode::OK);
+        assert!(health.db_writable);
+        assert!(state
+            .lock()
+            .unwrap()
+            .db
+            .get(b"__health_probe__")
+            .unwrap()
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn test_inbox_reports_imported_and_rejected() {
+        let state = test_state();
+        let good = signed_envelope("Durable post");
+        let mut bad = signed_envelope("Tampered post");
+        bad.data = bad.data.replace("Tampered", "Altered");
+
+        let (status, Json(resp)) = inbox(State(state.clone()), SyncBody(vec![good.clone(), bad]))
+            .await
+            .unwrap();
+        assert_eq!(status, StatusCode::OK);
+        assert!(resp.ok);
+        assert_eq!(resp.imported, 1);
+        assert_eq!(resp.rejected, 1);
+
+        let s = state.lock().unwrap();
+        assert!(s.memory.contains_key(&good.id));
+        assert!(s
+            .db
+            .get(format!("post:{}", good.id).as_bytes())
+            .unwrap()
+            .is_some());
+    }
+
+    #[tokio::test]
+    async fn test_inbox_batch_writes() {
+        let state = test_state();
+        state.lock().unwrap().config.inbox_batch_writes = true;
+        let posts = vec![signed_envelope("First"), signed_envelope("Second")];
+
+        let (status, Json(resp)) = inbox(State(state.clone()), SyncBody(posts.clone()))
+            .await
+            .unwrap();
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(resp.imported, 2);
+
+        let s = state.lock().unwrap();
+        for envelope in &posts {
+            assert!(s.memory.contains_key(&envelope.id));
+            assert!(s
+                .db
+                .get(format!("post:{}", envelope.id).as_bytes())
+                .unwrap()
+                .is_some());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_label_rules_on_batch_import() {
+        let state = test_state();
+        {
+            let mut s = state.lock().unwrap();
+            s.config.inbox_batch_writes = true;
+            s.label_rules = serde_json::from_value(serde_json::json!([
+                {"name": "link-spam", "label": "spam", "text": "(?i)buy now"},
+            ]))
+            .unwrap();
+        }
+        let spam = signed_envelope("BUY NOW at my shop");
+        let fine = signed_envelope("nice weather today");
+
+        let (status, _) = inbox(
+            State(state.clone()),
+            SyncBody(vec![spam.clone(), fine.clone()]),
+        )
+        .await
+        .unwrap();
+        assert_eq!(status, StatusCode::OK);
+
+        let s = state.lock().unwrap();
+        assert_eq!(
+            s.post_label_sources.get(&spam.id).map(String::as_str),
+            Some("rule:link-spam")
+        );
+        assert!(!s.post_labels.contains_key(&fine.id));
+    }
+
+    #[tokio::test]
+    async fn test_post_rate_limit_per_key() {
+        let state = test_state();
+        state.lock().unwrap().config.post_rate_limit = 2;
+        let key = crate::validation::testing::TestKey::generate();
+        let posts: Vec<Envelope> = ["one", "two", "three"]
+            .iter()
+            .map(|text| key.envelope(&key.post(text)))
+            .collect();
+
+        let (_, Json(resp)) = inbox(State(state.clone()), SyncBody(posts[..2].to_vec()))
+            .await
+            .unwrap();
+        assert_eq!(resp.imported, 2);
+
+        let over = inbox(State(state.clone()), SyncBody(vec![posts[2].clone()])).await;
+        assert_eq!(over.unwrap_err(), StatusCode::BAD_REQUEST);
+
+        // Re-sending a post the node already holds does not count.
+        let (_, Json(resp)) = inbox(State(state), SyncBody(vec![posts[1].clone()]))
+            .await
+            .unwrap();
+        assert_eq!(resp.imported, 1);
+    }
+
+    #[tokio::test]
+    async fn test_admin_peers_reports_last_sync() {
+        let state = test_state();
+        let headers = admin_headers(&state);
+        {
+            let mut s = state.lock().unwrap();
+            s.record_sync(
+                "https://peer.example",
+                crate::state::SyncOutcome {
+                    at: Utc::now(),
+                    imported: 40,
+                    skipped: 0,
+                    pushed: 12,
+                    error: None,
+                    position: None,
+                    change_seq: None,
+                    pushed_change_seq: None,
+                },
+            );
+            s.record_sync(
+                "https://peer.example",
+                crate::state::SyncOutcome {
+                    at: Utc::now(),
+                    imported: 0,
+                    skipped: 0,
+                    pushed: 0,
+                    error: Some("Remote outbox returned status 502".to_string()),
+                    position: None,
+                    change_seq: None,
+                    pushed_change_seq: None,
+                },
+            );
+        }
+
+        let Json(peers) = admin_peers(State(state), headers).await.unwrap();
+        let peer = &peers["https://peer.example"];
+        assert_eq!(peer.failures, 1);
+        assert!(peer.last_ok.is_some());
+        let last = peer.last_sync.as_ref().unwrap();
+        assert!(last.error.is_some());
+    }
+
+    #[tokio::test]
+    async fn test_expired_reports_are_archived() {
+        let state = test_state();
+        let headers = admin_headers(&state);
+        {
+            let mut s = state.lock().unwrap();
+            s.config.report_max_age_hours = 24;
+            for (id, age) in [("old", 48), ("new", 1)] {
+                s.moderation_reports.push(ModerationReport {
+                    post: crate::validation::testing::signed_envelope("reported"),
+                    reason: "spam".to_string(),
+                    suggested_label: None,
+                    reported_at: Utc::now() - chrono::Duration::hours(age),
+                    reporter_ip: None,
+                    id: id.to_string(),
+                });
+            }
+            assert_eq!(s.expire_reports(), 1);
+            assert_eq!(s.moderation_reports.len(), 1);
+            assert_eq!(s.moderation_reports[0].id, "new");
+        }
+
+        let Json(archived) = admin_archived_reports(State(state), headers).await.unwrap();
+        assert_eq!(archived.len(), 1);
+        assert_eq!(archived[0].id, "old");
+    }
+
+    #[tokio::test]
+    async fn test_weighted_karma_vote_and_revoke() {
+        let state = test_state();
+        let headers = admin_headers(&state);
+        state.lock().unwrap().config.max_karma_weight = 3;
+        let Json(codes) = admin_generate_karma_codes(
+            State(state.clone()),
+            headers,
+            Json(KarmaGenerateRequest {
+                count: 1,
+                issuer: "https://example.org".to_string(),
+                vote_type: None,
+                expires: Utc::now() + chrono::Duration::days(1),
+                region: None,
+                weight: 5,
+            }),
+        )
+        .await
+        .unwrap();
+        assert_eq!(codes[0].weight, 3);
+
+        let envelope = crate::validation::testing::signed_envelope("weighted");
+        import(&state, envelope.clone());
+        let code = codes[0].code.clone();
+        let voted = karma_upvote(
+            State(state.clone()),
+            Path(code.clone()),
+            Json(envelope.clone()),
+        )
+        .await;
+        assert!(voted.is_ok());
+        assert_eq!(state.lock().unwrap().karma_votes[&envelope.id], 3);
+
+        let revoked = karma_revoke(State(state.clone()), Path(code)).await;
+        assert!(revoked.is_ok());
+        assert_eq!(state.lock().unwrap().karma_votes[&envelope.id], 0);
+    }
+
+    #[tokio::test]
+    async fn test_combined_lookup_is_aligned() {
+        let state = test_state();
+        {
+            let mut s = state.lock().unwrap();
+            s.karma_votes.insert("a".to_string(), 4);
+            s.post_labels.insert("b".to_string(), "spam".to_string());
+        }
+        let ids = vec!["b".to_string(), "missing".to_string(), "a".to_string()];
+        let Json(statuses) = lookup(State(state), Json(ids)).await.unwrap();
+        let pairs: Vec<(i32, Option<&str>)> = statuses
+            .iter()
+            .map(|p| (p.karma, p.label.as_deref()))
+            .collect();
+        assert_eq!(pairs, vec![(0, Some("spam")), (0, None), (4, None)]);
+    }
+
+    #[tokio::test]
+    async fn test_admin_issuer_votes() {
+        let state = test_state();
+        let headers = admin_headers(&state);
+        add_code(&state, "AAAAA-11111");
+        add_code(&state, "AAAAA-22222");
+        add_code(&state, "AAAAA-33333");
+        let envelope = crate::validation::testing::signed_envelope("voted on");
+        import(&state, envelope.clone());
+        {
+            let mut s = state.lock().unwrap();
+            s.karma_codes.get_mut("AAAAA-33333").unwrap().issuer = "other".to_string();
+        }
+        for code in ["AAAAA-11111", "AAAAA-33333"] {
+            let voted = karma_downvote(
+                State(state.clone()),
+                Path(code.to_string()),
+                Json(envelope.clone()),
+            )
+            .await;
+            assert!(voted.is_ok());
+        }
+        {
+            // A legacy record of the vote, without `used_direction`, and a
+            // code applied with no direction recorded anywhere.
+            let mut s = state.lock().unwrap();
+            s.karma_codes.get_mut("AAAAA-11111").unwrap().used_direction = None;
+            s.karma_codes.get_mut("AAAAA-22222").unwrap().current_post = Some(envelope.id.clone());
+        }
+
+        let Json(votes) = admin_issuer_votes(
+            State(state),
+            headers,
+            Query(IssuerQuery {
+                issuer: "test".to_string(),
+            }),
+        )
+        .await
+        .unwrap();
+        assert_eq!(votes.len(), 1);
+        assert_eq!(votes[0].code, "AAAAA-11111");
+        assert_eq!(votes[0].post, envelope.id);
+        assert_eq!(votes[0].direction, "downvote");
+    }
+
+    #[tokio::test]
+    async fn test_outbox_and_inbox_speak_msgpack() {
+        let source = test_state();
+        import(&source, signed_envelope("Packed post"));
+        let mut headers = HeaderMap::new();
+        headers.insert("Accept", crate::wire::MSGPACK.parse().unwrap());
+        let resp = outbox(State(source), headers, Query(OutboxQuery::default()))
+            .await
+            .unwrap()
+            .into_response();
+        assert_eq!(resp.headers()[CONTENT_TYPE], crate::wire::MSGPACK);
+        let bytes = axum::body::to_bytes(resp.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let envelopes: Vec<Envelope> = Format::MsgPack.decode(&bytes).unwrap();
+        assert_eq!(envelopes.len(), 1);
+
+        let target = test_state();
+        let push = Request::builder()
+            .method("POST")
+            .header(CONTENT_TYPE, crate::wire::MSGPACK)
+            .body(axum::body::Body::from(bytes))
+            .unwrap();
+        let body = SyncBody::<Vec<Envelope>>::from_request(push, &())
+            .await
+            .unwrap();
+        let (status, Json(resp)) = inbox(State(target), body).await.unwrap();
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(resp.imported, 1);
+    }
+
+    #[tokio::test]
+    async fn test_admin_remove_peer() {
+        let state = test_state();
+        let headers = admin_headers(&state);
+        state.lock().unwrap().record_sync(
+            "https://peer.example",
+            crate::state::SyncOutcome {
+                at: Utc::now(),
+                imported: 0,
+                skipped: 0,
+                pushed: 0,
+                error: None,
+                position: None,
+                change_seq: None,
+                pushed_change_seq: None,
+            },
+        );
+        assert!(state
+            .lock()
+            .unwrap()
+            .db
+            .contains_key("peer:https://peer.example")
+            .unwrap());
+
+        let removed = admin_remove_peer(
+            State(state.clone()),
+            headers.clone(),
+            Path("https://peer.example/".to_string()),
+        )
+        .await;
+        assert!(removed.is_ok());
+        {
+            let s = state.lock().unwrap();
+            assert!(s.peers.is_empty());
+            assert!(!s.db.contains_key("peer:https://peer.example").unwrap());
+        }
+
+        let missing = admin_remove_peer(
+            State(state),
+            headers,
+            Path("https://peer.example".to_string()),
+        )
+        .await;
+        assert_eq!(missing.unwrap_err(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_node_info() {
+        let state = test_state();
+        let Json(anonymous) = node_info(State(state.clone())).await.unwrap();
+        assert!(anonymous.id.is_none());
+        assert!(anonymous.capabilities.contains(&"karma".to_string()));
+
+        let key = crate::federation::NodeKey::generate().unwrap();
+        let fingerprint = key.fingerprint();
+        state.lock().unwrap().node_key = Some(Arc::new(key));
+        let Json(info) = node_info(State(state)).await.unwrap();
+        assert_eq!(info.id.as_deref(), Some(fingerprint.as_str()));
+        assert!(info
+            .public_key
+            .unwrap()
+            .contains("BEGIN PGP PUBLIC KEY BLOCK"));
+        assert!(info.capabilities.contains(&"signed-push".to_string()));
+        assert_eq!(info.version, env!("CARGO_PKG_VERSION"));
+    }
+
+    #[tokio::test]
+    async fn test_capabilities_reflect_config() {
+        let state = test_state();
+        {
+            let mut s = state.lock().unwrap();
+            s.config.pow_difficulty = 8;
+            s.config.region_mode = RegionMode::Enforce;
+            s.config.max_post_data_bytes = 2048;
+            s.config.reactions = vec!["+1".to_string()];
+            s.config.sync_msgpack = false;
+        }
+        let Json(caps) = capabilities(State(state)).await.unwrap();
+        assert_eq!(caps.pow_difficulty, 8);
+        assert_eq!(caps.region_mode, "enforce");
+        assert_eq!(caps.max_post_data_bytes, 2048);
+        assert_eq!(caps.reactions, ["+1"]);
+        assert_eq!(caps.content_types, ["application/json"]);
+        assert_eq!(caps.max_outbox_page, MAX_OUTBOX_PAGE);
+        assert!(caps.capabilities.contains(&"karma".to_string()));
+    }
+
+    #[tokio::test]
+    async fn test_report_counts_respect_threshold() {
+        let state = test_state();
+        let busy = signed_envelope("reported a lot");
+        let quiet = signed_envelope("reported once");
+        {
+            let mut s = state.lock().unwrap();
+            s.config.public_report_threshold = 2;
+            let reports = [
+                (&busy, "1.1.1.1"),
+                (&busy, "2.2.2.2"),
+                (&busy, "2.2.2.2"),
+                (&quiet, "3.3.3.3"),
+            ];
+            for (i, (post, ip)) in reports.into_iter().enumerate() {
+                s.moderation_reports.push(ModerationReport {
+                    post: post.clone(),
+                    reason: "spam".to_string(),
+                    suggested_label: None,
+                    reported_at: Utc::now(),
+                    reporter_ip: Some(ip.to_string()),
+                    id: i.to_string(),
+                });
+            }
+        }
+
+        let ids = vec![quiet.id.clone(), busy.id.clone(), "unknown".to_string()];
+        let Json(counts) = moderation_report_counts(State(state), Json(ids))
+            .await
+            .unwrap();
+        assert_eq!(counts, vec![0, 2, 0]);
+    }
+
+    #[tokio::test]
+    async fn test_file_admin_backend() {
+        let state = test_state();
+        let path = std::env::temp_dir().join(format!("openherd-admins-{}", uuid::Uuid::new_v4()));
+        std::fs::write(&path, "first\n  second  \n").unwrap();
+        state.lock().unwrap().admin_backend =
+            Arc::new(crate::auth::FilePasswords { path: path.clone() });
+
+        let login = |password: &str| {
+            admin_login(
+                State(state.clone()),
+                Json(AdminAuth {
+                    password: password.to_string(),
+                }),
+            )
+        };
+        assert!(login("second").await.is_ok());
+        assert_eq!(login("third").await.unwrap_err(), StatusCode::UNAUTHORIZED);
+        assert_eq!(login("").await.unwrap_err(), StatusCode::UNAUTHORIZED);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_admin_passwords_are_checked_outside_the_lock() {
+        struct LockProbe(SharedState);
+        impl crate::auth::AdminBackend for LockProbe {
+            fn verify(&self, _password: &str) -> bool {
+                self.0.try_lock().is_ok()
+            }
+        }
+
+        let state = test_state();
+        state.lock().unwrap().admin_backend = Arc::new(LockProbe(state.clone()));
+        let Json(peers) = admin_peers(State(state.clone()), admin_headers(&state))
+            .await
+            .unwrap();
+        assert!(peers.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_sled_admin_passwords_are_hashed_and_rotate() {
+        use crate::auth::{AdminBackend, SledPasswords};
+
+        let state = test_state();
+        enroll(&state, "old-secret");
+        let store = SledPasswords::new(state.lock().unwrap().db.clone());
+        assert!(store.load().iter().all(|p| p.starts_with("$argon2")));
+        assert!(store.verify("old-secret"));
+
+        store.replace_all(&["new-secret".to_string()]).unwrap();
+        assert!(!store.verify("old-secret"));
+        assert!(is_admin(&state, "new-secret").await.unwrap());
+    }
+
+    async fn vote(state: &SharedState, code: &str, envelope: &Envelope, direction: &str) {
+        let path = Path(code.to_string());
+        let body = Json(envelope.clone());
+        let voted = if direction == "upvote" {
+            karma_upvote(State(state.clone()), path, body).await
+        } else {
+            karma_downvote(State(state.clone()), path, body).await
+        };
+        assert!(voted.is_ok());
+    }
+
+    async fn revoke(state: &SharedState, code: &str) {
+        let revoked = karma_revoke(State(state.clone()), Path(code.to_string())).await;
+        assert!(revoked.is_ok());
+    }
+
+    fn score(state: &SharedState, post: &str) -> i32 {
+        state
+            .lock()
+            .unwrap()
+            .karma_votes
+            .get(post)
+            .copied()
+            .unwrap_or(0)
+    }
+
+    #[tokio::test]
+    async fn test_revoke_reverses_upvote_and_downvote() {
+        let state = test_state();
+        let envelope = signed_envelope("revoke me");
+        add_code(&state, "UPUPU-UPUPU");
+        add_code(&state, "DOWND-DOWND");
+
+        vote(&state, "UPUPU-UPUPU", &envelope, "upvote").await;
+        assert_eq!(score(&state, &envelope.id), 1);
+        revoke(&state, "UPUPU-UPUPU").await;
+        assert_eq!(score(&state, &envelope.id), 0);
+
+        vote(&state, "DOWND-DOWND", &envelope, "downvote").await;
+        assert_eq!(score(&state, &envelope.id), -1);
+        revoke(&state, "DOWND-DOWND").await;
+        assert_eq!(score(&state, &envelope.id), 0);
+    }
+
+    #[tokio::test]
+    async fn test_revoke_of_unused_code_is_noop() {
+        let state = test_state();
+        let envelope = signed_envelope("untouched");
+        add_code(&state, "USEDD-USEDD");
+        add_code(&state, "IDLEE-IDLEE");
+        vote(&state, "USEDD-USEDD", &envelope, "downvote").await;
+
+        revoke(&state, "IDLEE-IDLEE").await;
+        revoke(&state, "IDLEE-IDLEE").await;
+        assert_eq!(score(&state, &envelope.id), -1);
+        let s = state.lock().unwrap();
+        assert!(s.karma_codes["IDLEE-IDLEE"].current_post.is_none());
+        assert!(s.karma_codes["IDLEE-IDLEE"].history.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_revoke_without_used_direction_uses_history() {
+        let state = test_state();
+        let envelope = signed_envelope("legacy vote");
+        add_code(&state, "LEGCY-LEGCY");
+        vote(&state, "LEGCY-LEGCY", &envelope, "downvote").await;
+        {
+            let mut s = state.lock().unwrap();
+            let kc = s.karma_codes.get_mut("LEGCY-LEGCY").unwrap();
+            kc.used_direction = None;
+            kc.vote_type = None;
+        }
+
+        revoke(&state, "LEGCY-LEGCY").await;
+        assert_eq!(score(&state, &envelope.id), 0);
+    }
+
+    #[tokio::test]
+    async fn test_revoked_code_is_persisted_and_reusable() {
+        let state = test_state();
+        let first = signed_envelope("first target");
+        let second = signed_envelope("second target");
+        add_code(&state, "REUSE-REUSE");
+        vote(&state, "REUSE-REUSE", &first, "upvote").await;
+        revoke(&state, "REUSE-REUSE").await;
+
+        let stored: KarmaCode = {
+            let s = state.lock().unwrap();
+            let bytes = s.db.get(b"karma_code:REUSE-REUSE").unwrap().unwrap();
+            serde_json::from_slice(&bytes).unwrap()
+        };
+        assert!(stored.current_post.is_none());
+        assert!(stored.used_direction.is_none());
+        assert!(stored.history[0].revoked_at.is_some());
+
+        vote(&state, "REUSE-REUSE", &second, "upvote").await;
+        assert_eq!(score(&state, &first.id), 0);
+        assert_eq!(score(&state, &second.id), 1);
+        {
+            let mut s = state.lock().unwrap();
+            s.karma_votes.clear();
+            s.rebuild_karma_votes();
+        }
+        assert_eq!(score(&state, &second.id), 1);
+
+        revoke(&state, "REUSE-REUSE").await;
+        let wrong_way = karma_downvote(
+            State(state.clone()),
+            Path("REUSE-REUSE".to_string()),
+            Json(second.clone()),
+        )
+        .await;
+        assert_eq!(wrong_way.unwrap_err(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_sync_skips_envelopes_already_held() {
+        let remote = test_state();
+        import(&remote, signed_envelope("held remotely"));
+        let address = spawn_remote(remote).await;
+
+        let local = test_state();
+        local.lock().unwrap().config.peer_sync_cooldown_secs = 0;
+        let request = || {
+            Json(SyncRequest {
+                address: address.clone(),
+                force: false,
+                direction: Default::default(),
+            })
+        };
+        let Json(first) = sync(State(local.clone()), HeaderMap::new(), request())
+            .await
+            .unwrap();
+        assert!(first.ok, "{}", first.message);
+        assert_eq!((first.imported, first.skipped), (1, 0));
+
+        let Json(second) = sync(State(local.clone()), HeaderMap::new(), request())
+            .await
+            .unwrap();
+        assert!(second.ok, "{}", second.message);
+        assert_eq!((second.imported, second.skipped), (0, 1));
+    }
+
+    #[tokio::test]
+    async fn test_generate_karma_codes_csv() {
+        let state = test_state();
+        let headers = admin_headers(&state);
+        let (_, csv) = admin_generate_karma_codes_csv(
+            State(state.clone()),
+            headers,
+            Json(KarmaGenerateRequest {
+                count: 2,
+                issuer: "https://example.org/".to_string(),
+                vote_type: None,
+                expires: Utc::now() + chrono::Duration::days(1),
+                region: Some(crate::types::GeoRegion {
+                    lat: 52.52,
+                    lon: 13.405,
+                    radius_km: 5.0,
+                }),
+                weight: 1,
+            }),
+        )
+        .await
+        .unwrap();
+
+        let lines: Vec<&str> = csv.lines().collect();
+        assert_eq!(lines.len(), 3);
+        assert!(lines[0].starts_with("code,issuer,"));
+        let fields: Vec<&str> = lines[1].split(',').collect();
+        assert_eq!(fields.len(), 7);
+        assert!(state.lock().unwrap().karma_codes.contains_key(fields[0]));
+        assert_eq!(fields[5], "52.52000 13.40500 5km");
+        assert_eq!(
+            fields[6],
+            format!("https://example.org/_openherd/karma/{}", fields[0])
+        );
+        assert_eq!(csv_field("a,\"b\""), "\"a,\"\"b\"\"\"");
+    }
+
+    #[tokio::test]
+    async fn test_region_mode_controls_out_of_region_votes() {
+        let state = test_state();
+        let envelope = signed_envelope("in Atlanta");
+        let berlin = crate::types::GeoRegion {
+            lat: 52.52,
+            lon: 13.405,
+            radius_km: 50.0,
+        };
+        assert!(!berlin.contains(33.7501, -84.3885));
+        for code in ["ENFRC-ENFRC", "WARNN-WARNN", "OFFFF-OFFFF"] {
+            add_code(&state, code);
+            state
+                .lock()
+                .unwrap()
+                .karma_codes
+                .get_mut(code)
+                .unwrap()
+                .region = Some(berlin.clone());
+        }
+
+        state.lock().unwrap().config.region_mode = RegionMode::Enforce;
+        let rejected = karma_upvote(
+            State(state.clone()),
+            Path("ENFRC-ENFRC".to_string()),
+            Json(envelope.clone()),
+        )
+        .await;
+        assert_eq!(rejected.unwrap_err(), StatusCode::BAD_REQUEST);
+        assert_eq!(score(&state, &envelope.id), 0);
+
+        state.lock().unwrap().config.region_mode = RegionMode::Warn;
+        vote(&state, "WARNN-WARNN", &envelope, "upvote").await;
+        assert_eq!(score(&state, &envelope.id), 1);
+        assert_eq!(state.lock().unwrap().region_violations, 1);
+
+        state.lock().unwrap().config.region_mode = RegionMode::Off;
+        vote(&state, "OFFFF-OFFFF", &envelope, "upvote").await;
+        assert_eq!(score(&state, &envelope.id), 2);
+        assert_eq!(state.lock().unwrap().region_violations, 1);
+    }
+
+    fn envelope_at(text: &str, latitude: f64, longitude: f64, age_mins: i64) -> Envelope {
+        let key = crate::validation::testing::TestKey::generate();
+        let mut post = key.post(text);
+        post.latitude = latitude;
+        post.longitude = longitude;
+        post.date = Utc::now() - chrono::Duration::minutes(age_mins);
+        key.envelope(&post)
+    }
+
+    fn bbox_query(
+        min_lat: f64,
+        min_lon: f64,
+        max_lat: f64,
+        max_lon: f64,
+    ) -> Json<BoundingBoxQuery> {
+        Json(BoundingBoxQuery {
+            bbox: crate::types::BoundingBox {
+                min_lat,
+                min_lon,
+                max_lat,
+                max_lon,
+            },
+            offset: 0,
+            limit: None,
+        })
+    }
+
+    #[tokio::test]
+    async fn test_geo_precision_rounds_served_coordinates() {
+        let state = test_state();
+        state.lock().unwrap().config.geo_precision = Some(2);
+        let envelope = envelope_at("somewhere exact", 33.749_987, -84.388_123, 0);
+        import(&state, envelope.clone());
+        let position = |env: &Envelope| {
+            let post: Post = serde_json::from_str(&env.data).unwrap();
+            (post.latitude, post.longitude)
+        };
+
+        let partial = outbox(
+            State(state.clone()),
+            HeaderMap::new(),
+            Query(OutboxQuery {
+                fields: Some("latitude,longitude".to_string()),
+                ..Default::default()
+            }),
+        )
+        .await
+        .unwrap();
+        let bytes = axum::body::to_bytes(partial.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let partial: PartialOutbox = serde_json::from_slice(&bytes).unwrap();
+        assert!(partial.unverified);
+        assert_eq!(partial.geo_precision, Some(2));
+        assert_eq!(partial.posts[0]["latitude"], 33.75);
+        assert_eq!(partial.posts[0]["longitude"], -84.39);
+
+        let Json(in_box) =
+            posts_in_bbox(State(state.clone()), bbox_query(33.0, -85.0, 34.0, -84.0))
+                .await
+                .unwrap();
+        assert_eq!(position(&in_box[0]), (33.75, -84.39));
+        assert!(validate_envelope(&in_box[0]).is_err());
+
+        let Json(detail) = post_detail(State(state.clone()), Path(envelope.id.clone()))
+            .await
+            .unwrap();
+        assert_eq!(position(&detail.envelope), (33.75, -84.39));
+        assert_eq!(
+            (detail.post.latitude, detail.post.longitude),
+            (33.75, -84.39)
+        );
+
+        // The stored, signed envelope keeps the exact position.
+        let s = state.lock().unwrap();
+        assert_eq!(position(&s.memory[&envelope.id]), (33.749_987, -84.388_123));
+    }
+
+    #[tokio::test]
+    async fn test_posts_in_bbox_handles_antimeridian() {
+        let state = test_state();
+        let east = envelope_at("east of the line", 10.0, 179.5, 2);
+        let west = envelope_at("west of the line", 10.5, -179.5, 1);
+        let greenwich = envelope_at("greenwich", 10.0, 0.0, 0);
+        for env in [&east, &west, &greenwich] {
+            import(&state, env.clone());
+        }
+
+        let Json(crossing) =
+            posts_in_bbox(State(state.clone()), bbox_query(0.0, 179.0, 20.0, -179.0))
+                .await
+                .unwrap();
+        let ids: Vec<&str> = crossing.iter().map(|e| e.id.as_str()).collect();
+        assert_eq!(ids, [west.id.as_str(), east.id.as_str()]);
+
+        let Json(plain) = posts_in_bbox(State(state.clone()), bbox_query(0.0, -1.0, 20.0, 1.0))
+            .await
+            .unwrap();
+        assert_eq!(plain.len(), 1);
+        assert_eq!(plain[0].id, greenwich.id);
+
+        state.lock().unwrap().remove_post(&greenwich.id);
+        let Json(removed) = posts_in_bbox(State(state.clone()), bbox_query(0.0, -1.0, 20.0, 1.0))
+            .await
+            .unwrap();
+        assert!(removed.is_empty());
+
+        let invalid = posts_in_bbox(State(state), bbox_query(20.0, 0.0, 10.0, 1.0)).await;
+        assert_eq!(invalid.unwrap_err(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_density_counts_posts_per_geohash() {
+        assert_eq!(crate::geo::geohash(57.64911, 10.40744, 6), "u4pruy");
+
+        let state = test_state();
+        import(&state, envelope_at("one", 57.64911, 10.40744, 0));
+        import(&state, envelope_at("two", 57.6492, 10.4075, 0));
+        import(&state, envelope_at("far", -33.8688, 151.2093, 0));
+
+        let Json(counts) = density(State(state.clone()), Query(DensityQuery { precision: 2 }))
+            .await
+            .unwrap();
+        assert_eq!(counts.get("u4"), Some(&2));
+        assert_eq!(counts.values().sum::<usize>(), 3);
+
+        let Json(capped) = density(State(state), Query(DensityQuery { precision: 50 }))
+            .await
+            .unwrap();
+        assert!(capped.keys().all(|k| k.len() == MAX_DENSITY_PRECISION));
+    }
+
+    async fn accepted_posts(state: &SharedState, karma: i32) -> usize {
+        let key = crate::validation::testing::TestKey::generate();
+        state
+            .lock()
+            .unwrap()
+            .karma_votes
+            .insert(key.fingerprint(), karma);
+        let mut accepted = 0;
+        for i in 0..6 {
+            let envelope = key.envelope(&key.post(&format!("post {}", i)));
+            if inbox(State(state.clone()), SyncBody(vec![envelope]))
+                .await
+                .is_ok()
+            {
+                accepted += 1;
+            }
+        }
+        accepted
+    }
+
+    #[tokio::test]
+    async fn test_reputation_ignores_the_case_of_post_ids() {
+        let state = test_state();
+        let key = crate::validation::testing::TestKey::generate();
+        let mut post = key.post("shouted id");
+        post.id = key.fingerprint().to_uppercase();
+        let mut envelope = key.envelope(&post);
+        envelope.id = post.id.clone();
+        envelope.data = serde_json::to_string(&post).unwrap();
+        envelope.signature = key.sign(&envelope.data);
+        import(&state, envelope.clone());
+        add_code(&state, "CASE-VOTE");
+        vote(&state, "CASE-VOTE", &envelope, "upvote").await;
+
+        let s = state.lock().unwrap();
+        assert_eq!(s.key_reputation(&key.fingerprint()), 1);
+        assert_eq!(s.karma_of(&envelope.id), 1);
+        assert_eq!(s.karma_tallies(), s.karma_votes);
+    }
+
+    #[tokio::test]
+    async fn test_post_rate_limit_scales_with_karma() {
+        let state = test_state();
+        state.lock().unwrap().config.post_rate_limit = 2;
+
+        assert_eq!(accepted_posts(&state, -3).await, 1);
+        assert_eq!(accepted_posts(&state, 0).await, 2);
+        assert_eq!(accepted_posts(&state, 10).await, 4);
+    }
+
+    #[tokio::test]
+    async fn test_reports_grouped_by_suggested_label() {
+        let state = test_state();
+        let headers = admin_headers(&state);
+        state.lock().unwrap().label_definitions.insert(
+            "spam".to_string(),
+            ModerationLabel {
+                id: "spam".to_string(),
+                label: "Spam".to_string(),
+                description: "Unsolicited advertising".to_string(),
+                region: None,
+            },
+        );
+        let report = |suggested: Option<&str>| ModerationReport {
+            post: signed_envelope("reported"),
+            reason: "looks bad".to_string(),
+            suggested_label: suggested.map(str::to_string),
+            reported_at: Utc::now(),
+            reporter_ip: None,
+            id: String::new(),
+        };
+        let submitted = moderation_report(
+            State(state.clone()),
+            None,
+            HeaderMap::new(),
+            Json(vec![
+                report(Some("SPAM")),
+                report(Some("spam")),
+                report(Some("not-a-label")),
+                report(None),
+            ]),
+        )
+        .await;
+        assert!(submitted.is_ok());
+
+        let Json(categories) = admin_reports_by_category(
+            State(state),
+            headers,
+            Json(AdminAuth {
+                password: "admin".to_string(),
+            }),
+        )
+        .await
+        .unwrap();
+        let summary: Vec<(&str, usize)> = categories
+            .iter()
+            .map(|c| (c.category.as_str(), c.count))
+            .collect();
+        assert_eq!(summary, [("spam", 2), ("uncategorized", 2)]);
+        assert_eq!(categories[0].reports.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_outbox_ndjson_streams_one_envelope_per_line() {
+        let state = test_state();
+        let envelopes = [signed_envelope("first"), signed_envelope("second")];
+        for env in &envelopes {
+            import(&state, env.clone());
+        }
+
+        let resp = outbox_ndjson(State(state)).await.unwrap();
+        assert_eq!(resp.headers()[CONTENT_TYPE], "application/x-ndjson");
+        let bytes = axum::body::to_bytes(resp.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let text = String::from_utf8(bytes.to_vec()).unwrap();
+        let mut ids: Vec<String> = text
+            .lines()
+            .map(|line| serde_json::from_str::<Envelope>(line).unwrap().id)
+            .collect();
+        ids.sort();
+        let mut expected: Vec<String> = envelopes.iter().map(|e| e.id.clone()).collect();
+        expected.sort();
+        assert_eq!(ids, expected);
+        assert!(text.ends_with('\n'));
+    }
+
+    fn outcome(error: Option<&str>) -> crate::state::SyncOutcome {
+        crate::state::SyncOutcome {
+            at: Utc::now(),
+            imported: 0,
+            skipped: 0,
+            pushed: 0,
+            error: error.map(str::to_string),
+            position: None,
+            change_seq: None,
+            pushed_change_seq: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_max_peers_evicts_only_failing_peers() {
+        let state = test_state();
+        let mut s = state.lock().unwrap();
+        s.config.max_peers = 2;
+        s.record_sync("https://a.example", outcome(None));
+        s.record_sync("https://b.example", outcome(None));
+
+        assert!(!s.can_add_peer("https://c.example"));
+        s.record_sync("https://c.example", outcome(None));
+        assert!(!s.peers.contains_key("https://c.example"));
+
+        s.record_sync("https://b.example", outcome(Some("timeout")));
+        assert!(s.can_add_peer("https://c.example"));
+        s.record_sync("https://c.example", outcome(None));
+        let mut peers: Vec<&String> = s.peers.keys().collect();
+        peers.sort();
+        assert_eq!(peers, ["https://a.example", "https://c.example"]);
+        assert!(!s.db.contains_key("peer:https://b.example").unwrap());
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_concurrent_votes_on_one_code() {
+        let state = test_state();
+        add_code(&state, "RACEE-RACEE");
+        let votes: Vec<_> = ["first racer", "second racer"]
+            .into_iter()
+            .map(|text| {
+                let state = state.clone();
+                let envelope = signed_envelope(text);
+                tokio::spawn(async move {
+                    karma_upvote(
+                        State(state),
+                        Path("RACEE-RACEE".to_string()),
+                        Json(envelope),
+                    )
+                    .await
+                })
+            })
+            .collect();
+
+        let mut applied = 0;
+        for vote in votes {
+            match vote.await.unwrap() {
+                Ok(_) => applied += 1,
+                Err(e) => assert_eq!(e, StatusCode::CONFLICT),
+            }
+        }
+        assert_eq!(applied, 1);
+        let s = state.lock().unwrap();
+        assert_eq!(s.karma_votes.values().sum::<i32>(), 1);
+        assert_eq!(s.karma_codes["RACEE-RACEE"].history.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_dead_letters_keep_rejected_envelopes() {
+        let state = test_state();
+        let headers = admin_headers(&state);
+        let bad = |text: &str| {
+            let mut envelope = signed_envelope(text);
+            envelope.data = envelope.data.replace(text, "tampered");
+            envelope
+        };
+
+        let rejected = inbox(State(state.clone()), SyncBody(vec![bad("off")])).await;
+        assert!(rejected.is_err());
+        assert!(state.lock().unwrap().dead_letters().is_empty());
+
+        {
+            let mut s = state.lock().unwrap();
+            s.config.dead_letters = true;
+            s.config.dead_letter_max = 2;
+        }
+        let batch = vec![
+            bad("one"),
+            bad("two"),
+            bad("three"),
+            signed_envelope("fine"),
+        ];
+        let (_, Json(resp)) = inbox(State(state.clone()), SyncBody(batch.clone()))
+            .await
+            .unwrap();
+        assert_eq!(resp.rejected, 3);
+
+        let Json(letters) = admin_dead_letters(State(state.clone()), headers.clone())
+            .await
+            .unwrap();
+        let ids: Vec<&str> = letters.iter().map(|l| l.envelope.id.as_str()).collect();
+        assert_eq!(ids, [batch[1].id.as_str(), batch[2].id.as_str()]);
+        assert!(!letters[0].error.is_empty());
+
+        let cleared = admin_clear_dead_letters(State(state.clone()), headers).await;
+        assert!(cleared.is_ok());
+        assert!(state.lock().unwrap().dead_letters().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_received_date_orders_results_when_configured() {
+        let state = test_state();
+        let backdated = envelope_at("herd news, backdated", 10.0, 10.0, 120);
+        let honest = envelope_at("herd news, honest", 10.0, 10.0, 60);
+        import(&state, honest.clone());
+        tokio::time::sleep(Duration::from_millis(5)).await;
+        import(&state, backdated.clone());
+        {
+            let s = state.lock().unwrap();
+            let first = s.memory[&honest.id].received_at.unwrap();
+            let second = s.memory[&backdated.id].received_at.unwrap();
+            assert!(first < second);
+        }
+
+        let ids = |hits: Vec<SearchHit>| -> Vec<String> {
+            hits.into_iter().map(|h| h.envelope.id).collect()
+        };
+        let Json(by_signed) = search(State(state.clone()), search_query("herd news"))
+            .await
+            .unwrap();
+        assert_eq!(ids(by_signed), [honest.id.clone(), backdated.id.clone()]);
+
+        {
+            let mut s = state.lock().unwrap();
+            s.config.post_date_source = crate::config::PostDateSource::Received;
+            s.rebuild_geo_index();
+        }
+        let Json(by_received) = search(State(state.clone()), search_query("herd news"))
+            .await
+            .unwrap();
+        assert_eq!(ids(by_received), [backdated.id.clone(), honest.id.clone()]);
+        let Json(in_box) = posts_in_bbox(State(state.clone()), bbox_query(0.0, 0.0, 20.0, 20.0))
+            .await
+            .unwrap();
+        assert_eq!(in_box[0].id, backdated.id);
+
+        // A peer's receivedAt is not trusted, and a re-import keeps ours.
+        let original = state.lock().unwrap().memory[&honest.id].received_at;
+        let mut resent = honest.clone();
+        resent.received_at = Some(Utc::now() + chrono::Duration::days(1));
+        import(&state, resent);
+        assert_eq!(
+            state.lock().unwrap().memory[&honest.id].received_at,
+            original
+        );
+    }
+
+    #[tokio::test]
+    async fn test_post_detail() {
+        let state = test_state();
+        let parent = crate::validation::testing::signed_envelope("parent");
+        import(&state, parent.clone());
+        let key = crate::validation::testing::TestKey::generate();
+        let mut reply = key.post("reply");
+        reply.parent = Some(parent.id.clone());
+        import(&state, key.envelope(&reply));
+        {
+            let mut s = state.lock().unwrap();
+            s.config.public_report_threshold = 1;
+            s.karma_votes.insert(parent.id.clone(), 4);
+            s.post_labels.insert(parent.id.clone(), "spam".to_string());
+            s.moderation_reports.push(ModerationReport {
+                post: parent.clone(),
+                reason: "spam".to_string(),
+                suggested_label: None,
+                reported_at: Utc::now(),
+                reporter_ip: Some("10.0.0.1".to_string()),
+                id: "r1".to_string(),
+            });
+        }
+
+        let Json(detail) = post_detail(State(state.clone()), Path(parent.id.clone()))
+            .await
+            .unwrap();
+        assert_eq!(detail.post.text, "parent");
+        assert_eq!(detail.envelope.id, parent.id);
+        assert_eq!(detail.karma, 4);
+        assert_eq!(detail.labels, ["spam"]);
+        assert_eq!(detail.reply_count, 1);
+        assert_eq!(detail.report_count, 1);
+        {
+            let mut s = state.lock().unwrap();
+            s.rebuild_geo_index();
+            assert_eq!(s.reply_count(&parent.id), 1);
+            s.remove_post(&reply.id);
+            assert_eq!(s.reply_count(&parent.id), 0);
+        }
+
+        let missing = post_detail(State(state), Path("nope".to_string())).await;
+        assert_eq!(missing.unwrap_err(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_sync_refuses_insecure_peers_when_disabled() {
+        let state = test_state();
+        state.lock().unwrap().config.allow_insecure_peers = false;
+        let Json(response) = sync(
+            State(state.clone()),
+            HeaderMap::new(),
+            Json(SyncRequest {
+                address: " HTTP://peer.example/ ".to_string(),
+                force: false,
+                direction: Default::default(),
+            }),
+        )
+        .await
+        .unwrap();
+        assert!(!response.ok);
+        assert!(
+            response.message.contains("https://"),
+            "{}",
+            response.message
+        );
+        assert!(state.lock().unwrap().peers.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_import_labels_needs_approval() {
+        let envelope = signed_envelope("labelled remotely");
+        let spam = ModerationLabel {
+            id: "spam".to_string(),
+            label: "Spam".to_string(),
+            description: "Junk".to_string(),
+            region: None,
+        };
+        let remote = test_state();
+        import(&remote, envelope.clone());
+        {
+            let mut r = remote.lock().unwrap();
+            r.label_definitions.insert("spam".to_string(), spam.clone());
+            r.post_labels
+                .insert(envelope.id.clone(), "spam".to_string());
+            r.post_labels
+                .insert("not-held-here".to_string(), "spam".to_string());
+        }
+        let address = spawn_remote(remote).await;
+
+        let local = test_state();
+        let headers = admin_headers(&local);
+        import(&local, envelope.clone());
+        local
+            .lock()
+            .unwrap()
+            .label_definitions
+            .insert("spam".to_string(), spam);
+        let request = |accept: Vec<String>| {
+            Json(LabelImportRequest {
+                address: address.clone(),
+                accept,
+            })
+        };
+
+        let Json(preview) =
+            admin_import_labels(State(local.clone()), headers.clone(), request(vec![]))
+                .await
+                .unwrap();
+        assert_eq!(preview.merged, 0);
+        assert_eq!(preview.proposals.len(), 1);
+        assert_eq!(preview.proposals[0].post_id, envelope.id);
+        assert!(local.lock().unwrap().post_labels.is_empty());
+
+        let Json(applied) = admin_import_labels(
+            State(local.clone()),
+            headers,
+            request(vec![envelope.id.clone()]),
+        )
+        .await
+        .unwrap();
+        assert_eq!(applied.merged, 1);
+        let Json(detail) = post_detail(State(local), Path(envelope.id.clone()))
+            .await
+            .unwrap();
+        assert_eq!(detail.labels, ["spam"]);
+        assert_eq!(detail.label_source.as_deref(), Some(address.as_str()));
+    }
+
+    async fn outbox_page(state: &SharedState, after_seq: u64, limit: usize) -> OutboxPage {
+        let resp = outbox(
+            State(state.clone()),
+            HeaderMap::new(),
+            Query(OutboxQuery {
+                after_seq: Some(after_seq),
+                limit: Some(limit),
+                ..Default::default()
+            }),
+        )
+        .await
+        .unwrap();
+        let bytes = axum::body::to_bytes(resp.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        serde_json::from_slice(&bytes).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_outbox_cursor_follows_arrival_not_post_date() {
+        let state = test_state();
+        let recent = envelope_at("written recently", 10.0, 10.0, 1);
+        let backdated = envelope_at("arrives later, backdated", 10.0, 10.0, 600);
+        let mut spoofed = recent.clone();
+        spoofed.seq = Some(u64::MAX);
+        import(&state, spoofed);
+        import(&state, backdated.clone());
+
+        let first = outbox_page(&state, 0, 1).await;
+        assert_eq!(first.envelopes.len(), 1);
+        assert_eq!(first.envelopes[0].id, recent.id);
+        assert!(first.cursor < u64::MAX);
+
+        let second = outbox_page(&state, first.cursor, 10).await;
+        assert_eq!(second.envelopes.len(), 1);
+        assert_eq!(second.envelopes[0].id, backdated.id);
+
+        let later = signed_envelope("after the cursor");
+        import(&state, later.clone());
+        import(&state, recent.clone());
+        let third = outbox_page(&state, second.cursor, 10).await;
+        let ids: Vec<&str> = third.envelopes.iter().map(|e| e.id.as_str()).collect();
+        assert_eq!(ids, [later.id.as_str()]);
+
+        let empty = outbox_page(&state, third.cursor, 10).await;
+        assert!(empty.envelopes.is_empty());
+        assert_eq!(empty.cursor, third.cursor);
+    }
+
+    #[tokio::test]
+    async fn test_websub_subscribe_verifies_and_pushes() {
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel::<(Option<String>, Vec<u8>)>();
+        let callback_app = axum::Router::new().route(
+            "/cb",
+            axum::routing::get(|Query(q): Query<HashMap<String, String>>| async move {
+                q.get("hub.challenge").cloned().unwrap_or_default()
+            })
+            .post(
+                move |headers: HeaderMap, body: axum::body::Bytes| async move {
+                    let signature = headers
+                        .get("X-Hub-Signature")
+                        .and_then(|v| v.to_str().ok())
+                        .map(String::from);
+                    let _ = tx.send((signature, body.to_vec()));
+                },
+            ),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let callback = format!("http://{}/cb", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, callback_app).await.unwrap() });
+
+        let state = test_state();
+        let request = |topic: &str| HubRequest {
+            mode: "subscribe".to_string(),
+            topic: topic.to_string(),
+            callback: callback.clone(),
+            lease_seconds: Some(60),
+            secret: Some("s3cret".to_string()),
+        };
+        let loopback = websub_hub(
+            State(state.clone()),
+            Form(request("https://node.example/_openherd/outbox")),
+        )
+        .await;
+        assert_eq!(loopback.unwrap_err(), StatusCode::BAD_REQUEST);
+        {
+            let mut s = state.lock().unwrap();
+            s.config.websub_allow_private_callbacks = true;
+            s.config.websub_max_subscriptions = 1;
+        }
+        let wrong_topic = websub_hub(
+            State(state.clone()),
+            Form(request("https://node.example/_openherd/search")),
+        )
+        .await;
+        assert_eq!(wrong_topic.unwrap_err(), StatusCode::BAD_REQUEST);
+
+        let status = websub_hub(
+            State(state.clone()),
+            Form(request("https://node.example/_openherd/outbox")),
+        )
+        .await
+        .unwrap();
+        assert_eq!(status, StatusCode::ACCEPTED);
+        for _ in 0..100 {
+            if !state.lock().unwrap().websub_subscriptions().is_empty() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        assert_eq!(state.lock().unwrap().websub_subscriptions().len(), 1);
+
+        let mut other = request("https://node.example/_openherd/outbox");
+        other.callback = format!("{}/other", callback);
+        let full = websub_hub(State(state.clone()), Form(other)).await;
+        assert_eq!(full.unwrap_err(), StatusCode::SERVICE_UNAVAILABLE);
+
+        websub::spawn_notifier(&state).unwrap();
+        let envelope = signed_envelope("pushed to subscribers");
+        import(&state, envelope.clone());
+        let (signature, body) = tokio::time::timeout(Duration::from_secs(5), rx.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(signature.unwrap(), websub::signature("s3cret", &body));
+        let pushed: Vec<Envelope> = serde_json::from_slice(&body).unwrap();
+        assert_eq!(pushed[0].id, envelope.id);
+    }
+
+    #[tokio::test]
+    async fn test_compressed_and_plain_posts_are_both_exported() {
+        let state = test_state();
+        let plain = signed_envelope("stored as json");
+        import(&state, plain.clone());
+        state.lock().unwrap().config.compress_posts = true;
+        let packed = signed_envelope("stored compressed");
+        import(&state, packed.clone());
+
+        {
+            let s = state.lock().unwrap();
+            let raw = |id: &str| s.db.get(store::post_key(id)).unwrap().unwrap();
+            assert_eq!(raw(&plain.id)[0], b'{');
+            let compressed = raw(&packed.id);
+            assert_ne!(compressed[0], b'{');
+            let json = serde_json::to_vec(&s.memory[&packed.id]).unwrap();
+            assert!(compressed.len() < json.len());
+            assert_eq!(
+                store::decode_envelope(&compressed).unwrap().data,
+                packed.data
+            );
+        }
+
+        let resp = outbox_ndjson(State(state)).await.unwrap();
+        let bytes = axum::body::to_bytes(resp.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let mut ids: Vec<String> = String::from_utf8(bytes.to_vec())
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str::<Envelope>(line).unwrap().id)
+            .collect();
+        ids.sort();
+        let mut expected = vec![plain.id, packed.id];
+        expected.sort();
+        assert_eq!(ids, expected);
+    }
+
+    #[tokio::test]
+    async fn test_issuer_stats() {
+        let state = test_state();
+        enroll(&state, "admin");
+        for code in ["STATS-AAAAA", "STATS-BBBBB", "STATS-CCCCC", "STATS-DDDDD"] {
+            add_code(&state, code);
+        }
+        {
+            let mut s = state.lock().unwrap();
+            let stale = s.karma_codes.get_mut("STATS-DDDDD").unwrap();
+            stale.issuer = "https://stale.example".to_string();
+            stale.expires = Utc::now() - chrono::Duration::days(1);
+        }
+        let post = signed_envelope("campaign target");
+        vote(&state, "STATS-AAAAA", &post, "upvote").await;
+        vote(&state, "STATS-BBBBB", &post, "upvote").await;
+
+        let request = |offset, limit| {
+            Json(IssuerStatsRequest {
+                password: "admin".to_string(),
+                offset,
+                limit,
+            })
+        };
+        let Json(stats) =
+            admin_issuer_stats(State(state.clone()), HeaderMap::new(), request(0, None))
+                .await
+                .unwrap();
+        let summary: Vec<(&str, usize, usize, usize, i32)> = stats
+            .iter()
+            .map(|st| {
+                (
+                    st.issuer.as_str(),
+                    st.issued,
+                    st.used,
+                    st.expired_unused,
+                    st.net_karma,
+                )
+            })
+            .collect();
+        assert_eq!(
+            summary,
+            [("test", 3, 2, 0, 2), ("https://stale.example", 1, 0, 1, 0)]
+        );
+
+        let Json(page) =
+            admin_issuer_stats(State(state.clone()), HeaderMap::new(), request(1, Some(1)))
+                .await
+                .unwrap();
+        assert_eq!(page.len(), 1);
+        assert_eq!(page[0].issuer, "https://stale.example");
+
+        let wrong = Json(IssuerStatsRequest {
+            password: "wrong".to_string(),
+            offset: 0,
+            limit: None,
+        });
+        let denied = admin_issuer_stats(State(state), HeaderMap::new(), wrong).await;
+        assert_eq!(denied.unwrap_err(), StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn test_sync_cooldown_unless_admin_forces() {
+        let remote = test_state();
+        let address = spawn_remote(remote).await;
+
+        let local = test_state();
+        let request = |force| {
+            Json(SyncRequest {
+                address: address.clone(),
+                force,
+                direction: Default::default(),
+            })
+        };
+        let Json(first) = sync(State(local.clone()), HeaderMap::new(), request(false))
+            .await
+            .unwrap();
+        assert!(first.ok, "{}", first.message);
+
+        let Json(again) = sync(State(local.clone()), HeaderMap::new(), request(false))
+            .await
+            .unwrap();
+        assert!(!again.ok);
+        assert!(again.message.starts_with("Too soon"), "{}", again.message);
+
+        let unauthorized = sync(State(local.clone()), HeaderMap::new(), request(true)).await;
+        assert_eq!(unauthorized.unwrap_err(), StatusCode::UNAUTHORIZED);
+
+        let headers = admin_headers(&local);
+        let Json(forced) = sync(State(local.clone()), headers, request(true))
+            .await
+            .unwrap();
+        assert!(forced.ok, "{}", forced.message);
+    }
+
+    #[tokio::test]
+    async fn test_outbox_ids_pages_and_filters_by_date() {
+        let state = test_state();
+        let old = envelope_at("old news", 10.0, 10.0, 600);
+        let fresh = envelope_at("fresh news", 10.0, 10.0, 5);
+        let newest = envelope_at("newest news", 10.0, 10.0, 1);
+        for env in [&old, &fresh, &newest] {
+            import(&state, env.clone());
+        }
+        let ids_query = |after_seq, limit, since| {
+            Query(OutboxQuery {
+                after_seq,
+                limit,
+                since,
+                dates: true,
+                ..Default::default()
+            })
+        };
+
+        let Json(all) = outbox_ids(State(state.clone()), ids_query(None, None, None))
+            .await
+            .unwrap();
+        let ids: Vec<&str> = all.ids.iter().map(|i| i.id.as_str()).collect();
+        assert_eq!(
+            ids,
+            [old.id.as_str(), fresh.id.as_str(), newest.id.as_str()]
+        );
+        assert!(all.ids.iter().all(|i| i.date.is_some()));
+
+        let since = Some(Utc::now() - chrono::Duration::minutes(60));
+        let Json(first) = outbox_ids(State(state.clone()), ids_query(None, Some(1), since))
+            .await
+            .unwrap();
+        assert_eq!(first.ids.len(), 1);
+        assert_eq!(first.ids[0].id, fresh.id);
+        let Json(second) = outbox_ids(
+            State(state.clone()),
+            ids_query(Some(first.cursor), Some(1), since),
+        )
+        .await
+        .unwrap();
+        assert_eq!(second.ids[0].id, newest.id);
+
+        let wanted = vec![old.id.clone(), "unknown".to_string()];
+        let Negotiated(_, envelopes) = outbox_by_ids(State(state), HeaderMap::new(), Json(wanted))
+            .await
+            .unwrap();
+        assert_eq!(envelopes.len(), 1);
+        assert_eq!(envelopes[0].id, old.id);
+    }
+
+    #[tokio::test]
+    async fn test_id_claimed_by_another_key_fails_fingerprint_check() {
+        let state = test_state();
+        let owner = crate::validation::testing::TestKey::generate();
+        let intruder = crate::validation::testing::TestKey::generate();
+        let original = owner.envelope(&owner.post("the original"));
+        import(&state, original.clone());
+
+        // The intruder signs a post naming the owner's id.
+        let mut forged_post = intruder.post("the forgery");
+        forged_post.id = original.id.clone();
+        let mut forged = intruder.envelope(&forged_post);
+        forged.id = original.id.clone();
+
+        let pushed = inbox(State(state.clone()), SyncBody(vec![forged.clone()])).await;
+        assert_eq!(pushed.unwrap_err(), StatusCode::BAD_REQUEST);
+        assert!(matches!(
+            state.lock().unwrap().admit_envelope(&forged),
+            Err(ValidationError::PostKeyMismatch)
+        ));
+        assert_eq!(
+            state.lock().unwrap().memory[&original.id].data,
+            original.data
+        );
+
+        // A stored entry whose key is not the one its id names is corrupt,
+        // and the owner's correctly signed post replaces it.
+        let mut planted = original.clone();
+        planted.public_key = forged.public_key.clone();
+        planted.data = forged.data.clone();
+        state
+            .lock()
+            .unwrap()
+            .memory
+            .insert(planted.id.clone(), planted);
+        let (status, _) = inbox(State(state.clone()), SyncBody(vec![original.clone()]))
+            .await
+            .unwrap();
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(
+            state.lock().unwrap().memory[&original.id].public_key,
+            original.public_key
+        );
+    }
+
+    #[tokio::test]
+    async fn test_trusted_keyring_rejects_outside_keys() {
+        use crate::validation::{testing::TestKey, Keyring};
+        let state = test_state();
+        let member = TestKey::generate();
+        state.lock().unwrap().keyring = Some(Keyring::from_armored(member.public_key()).unwrap());
+
+        let inside = member.envelope(&member.post("from the keyring"));
+        let (status, _) = inbox(State(state.clone()), SyncBody(vec![inside]))
+            .await
+            .unwrap();
+        assert_eq!(status, StatusCode::OK);
+
+        let outside = signed_envelope("from anyone");
+        let err = inbox(State(state.clone()), SyncBody(vec![outside.clone()]))
+            .await
+            .unwrap_err();
+        assert_eq!(err, StatusCode::BAD_REQUEST);
+        assert!(!state.lock().unwrap().memory.contains_key(&outside.id));
+    }
+
+    #[tokio::test]
+    async fn test_report_reasons_distribution() {
+        let state = test_state();
+        enroll(&state, "admin");
+        let post = signed_envelope("reported for several things");
+        let unreported = signed_envelope("nobody minds this one");
+        {
+            let mut s = state.lock().unwrap();
+            s.config.public_report_threshold = 2;
+            let reports = [
+                ("1.1.1.1", "spam", Some("spam")),
+                ("2.2.2.2", "buy now links", Some("spam")),
+                ("2.2.2.2", "spam", Some("spam")),
+                ("3.3.3.3", "rude", None),
+            ];
+            for (i, (ip, reason, label)) in reports.into_iter().enumerate() {
+                s.moderation_reports.push(ModerationReport {
+                    post: post.clone(),
+                    reason: reason.to_string(),
+                    suggested_label: label.map(str::to_string),
+                    reported_at: Utc::now(),
+                    reporter_ip: Some(ip.to_string()),
+                    id: i.to_string(),
+                });
+            }
+        }
+        let reasons = |id: &str, headers: HeaderMap| {
+            moderation_report_reasons(State(state.clone()), headers, Path(id.to_string()))
+        };
+        let count = |reason: &str, count| ReasonCount {
+            reason: reason.to_string(),
+            count,
+        };
+
+        let Json(full) = reasons(&post.id, admin_headers(&state)).await.unwrap();
+        assert_eq!(full.reporters, 3);
+        assert_eq!(
+            full.categories,
+            vec![count("spam", 2), count("uncategorized", 1)]
+        );
+        assert_eq!(
+            full.reasons.unwrap(),
+            vec![
+                count("spam", 2),
+                count("buy now links", 1),
+                count("rude", 1)
+            ]
+        );
+
+        let denied = reasons(&post.id, HeaderMap::new()).await.unwrap_err();
+        assert_eq!(denied, StatusCode::UNAUTHORIZED);
+
+        state.lock().unwrap().config.public_report_categories = true;
+        let Json(public) = reasons(&post.id, HeaderMap::new()).await.unwrap();
+        assert_eq!(public.categories, vec![count("spam", 2)]);
+        assert!(public.reasons.is_none());
+
+        let missing = reasons(&unreported.id, admin_headers(&state))
+            .await
+            .unwrap_err();
+        assert_eq!(missing, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_label_rules_on_import_and_report() {
+        let state = test_state();
+        state.lock().unwrap().label_rules = serde_json::from_value(serde_json::json!([
+            {"name": "link-spam", "label": "spam", "text": "(?i)buy now"},
+            {"name": "pile-on", "label": "abuse", "min_reports": 2},
+        ]))
+        .unwrap();
+
+        let spam = signed_envelope("BUY NOW at my shop");
+        let fine = signed_envelope("nice weather today");
+        import(&state, spam.clone());
+        import(&state, fine.clone());
+        {
+            let s = state.lock().unwrap();
+            assert_eq!(
+                s.post_labels.get(&spam.id).map(String::as_str),
+                Some("spam")
+            );
+            assert_eq!(
+                s.post_label_sources.get(&spam.id).map(String::as_str),
+                Some("rule:link-spam")
+            );
+            assert!(!s.post_labels.contains_key(&fine.id));
+        }
+
+        for ip in ["1.1.1.1", "2.2.2.2"] {
+            let mut headers = HeaderMap::new();
+            headers.insert("X-Forwarded-For", ip.parse().unwrap());
+            let report = ModerationReport {
+                post: fine.clone(),
+                reason: "rude".to_string(),
+                suggested_label: None,
+                reported_at: Utc::now(),
+                reporter_ip: None,
+                id: String::new(),
+            };
+            let Json(resp) =
+                moderation_report(State(state.clone()), None, headers, Json(vec![report]))
+                    .await
+                    .unwrap();
+            assert!(resp.ok);
+        }
+        let s = state.lock().unwrap();
+        assert_eq!(
+            s.post_labels.get(&fine.id).map(String::as_str),
+            Some("abuse")
+        );
+        assert_eq!(
+            s.post_label_sources.get(&fine.id).map(String::as_str),
+            Some("rule:pile-on")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_max_reply_depth() {
+        use crate::validation::testing::TestKey;
+        let state = test_state();
+        state.lock().unwrap().config.max_reply_depth = 2;
+
+        let reply_to = |parent: Option<&str>| {
+            let key = TestKey::generate();
+            let mut post = key.post("reply");
+            post.parent = parent.map(str::to_string);
+            key.envelope(&post)
+        };
+        let push = |envelope: Envelope| inbox(State(state.clone()), SyncBody(vec![envelope]));
+
+        let root = reply_to(None);
+        let first = reply_to(Some(&root.id));
+        let second = reply_to(Some(&first.id));
+        let third = reply_to(Some(&second.id));
+        for envelope in [&root, &first, &second] {
+            let (status, _) = push(envelope.clone()).await.unwrap();
+            assert_eq!(status, StatusCode::OK);
+        }
+        let err = push(third.clone()).await.unwrap_err();
+        assert_eq!(err, StatusCode::BAD_REQUEST);
+        assert!(!state.lock().unwrap().memory.contains_key(&third.id));
+
+        // Only held parents count: a reply under an unknown post is depth 1.
+        let orphan = reply_to(Some("0000000000000000000000000000000000000000"));
+        let (status, _) = push(orphan).await.unwrap();
+        assert_eq!(status, StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_outbox_field_selection() {
+        let state = test_state();
+        let envelope = signed_envelope("just the text please");
+        import(&state, envelope.clone());
+
+        let fetch =
+            |query: OutboxQuery| outbox(State(state.clone()), HeaderMap::new(), Query(query));
+        let response = fetch(OutboxQuery {
+            fields: Some("id, text".to_string()),
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+        let bytes = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let partial: PartialOutbox = serde_json::from_slice(&bytes).unwrap();
+        assert!(partial.unverified);
+        assert!(partial.cursor.is_none());
+        assert_eq!(partial.posts.len(), 1);
+        let post = &partial.posts[0];
+        assert_eq!(post.len(), 2);
+        assert_eq!(post["id"], envelope.id.as_str());
+        assert_eq!(post["text"], "just the text please");
+
+        let response = fetch(OutboxQuery {
+            after_seq: Some(0),
+            fields: Some("date".to_string()),
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+        let bytes = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let paged: PartialOutbox = serde_json::from_slice(&bytes).unwrap();
+        assert!(paged.cursor.is_some());
+        assert!(paged.posts[0].contains_key("date"));
+
+        let err = fetch(OutboxQuery {
+            fields: Some("text,signature".to_string()),
+            ..Default::default()
+        })
+        .await
+        .unwrap_err();
+        assert_eq!(err, StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_liveness_probes_count_without_recording_a_sync() {
+        let state = test_state();
+        let up = spawn_remote(state.clone()).await;
+        let client = reqwest::Client::new();
+        assert!(crate::sync::probe_peer(&client, &up).await);
+        assert!(!crate::sync::probe_peer(&client, "http://127.0.0.1:1").await);
+
+        let mut s = state.lock().unwrap();
+        let addr = "https://peer.example";
+        s.record_probe(addr, false);
+        assert!(!s.peers.contains_key(addr));
+        s.record_probe(addr, true);
+        for _ in 0..crate::state::PEER_FAILURE_LIMIT {
+            s.record_probe(addr, false);
+        }
+        assert!(s.peers[addr].quarantined_since.is_some());
+        s.record_probe(addr, true);
+        let peer = &s.peers[addr];
+        assert_eq!(peer.failures, 0);
+        assert!(peer.quarantined_since.is_none());
+        assert!(peer.last_ok.is_some());
+        assert!(peer.last_sync.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_failing_peer_is_quarantined_before_removal() {
+        let state = test_state();
+        let mut s = state.lock().unwrap();
+        s.config.peer_removal_grace_secs = 3600;
+        let addr = "https://peer.example";
+        let outcome = |error: Option<&str>| crate::state::SyncOutcome {
+            at: Utc::now(),
+            imported: 0,
+            skipped: 0,
+            pushed: 0,
+            error: error.map(str::to_string),
+            position: None,
+            change_seq: None,
+            pushed_change_seq: None,
+        };
+
+        s.record_sync(addr, outcome(None));
+        for _ in 0..crate::state::PEER_FAILURE_LIMIT {
+            s.record_sync(addr, outcome(Some("connection refused")));
+        }
+        assert!(s.peers[addr].quarantined_since.is_some());
+        assert!(!s.peer_expired(addr));
+
+        s.record_sync(addr, outcome(None));
+        assert!(s.peers[addr].quarantined_since.is_none());
+        assert_eq!(s.peers[addr].failures, 0);
+
+        s.config.peer_removal_grace_secs = 0;
+        for _ in 0..crate::state::PEER_FAILURE_LIMIT - 1 {
+            s.record_sync(addr, outcome(Some("connection refused")));
+        }
+        assert!(!s.peer_expired(addr));
+        s.record_sync(addr, outcome(Some("connection refused")));
+        assert!(s.peer_expired(addr));
+    }
+
+    #[tokio::test]
+    async fn test_submit_single_post() {
+        let state = test_state();
+        state.lock().unwrap().config.post_rate_limit = 1;
+        let envelope = signed_envelope("hello from a client");
+
+        let (status, Json(detail)) = submit_post(State(state.clone()), Json(envelope.clone()))
+            .await
+            .unwrap();
+        assert_eq!(status, StatusCode::CREATED);
+        assert_eq!(detail.post.text, "hello from a client");
+        assert!(detail.envelope.seq.is_some());
+
+        // Resubmitting the same post is not a new post and not rate limited.
+        let (status, _) = submit_post(State(state.clone()), Json(envelope.clone()))
+            .await
+            .unwrap();
+        assert_eq!(status, StatusCode::OK);
+
+        let mut tampered = envelope;
+        tampered.data = tampered.data.replace("hello", "howdy");
+        let err = submit_post(State(state), Json(tampered)).await.unwrap_err();
+        assert_eq!(err, StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_client_submissions_have_their_own_limits() {
+        let state = test_state();
+        {
+            let mut s = state.lock().unwrap();
+            s.config.post_rate_limit = 1;
+            s.config.client_post_rate_limit = Some(2);
+        }
+        let key = crate::validation::testing::TestKey::generate();
+        let mut post = key.post("version 0");
+        let mut next = |text: &str| {
+            post.text = text.to_string();
+            post.date += chrono::Duration::seconds(1);
+            key.envelope(&post)
+        };
+
+        for text in ["version 1", "version 2"] {
+            let (status, _) = submit_post(State(state.clone()), Json(next(text)))
+                .await
+                .unwrap();
+            assert_eq!(status, StatusCode::CREATED);
+        }
+        let limited = submit_post(State(state.clone()), Json(next("version 3"))).await;
+        assert_eq!(limited.unwrap_err(), StatusCode::BAD_REQUEST);
+        // Peers pushing the same key are counted separately.
+        assert!(
+            inbox(State(state.clone()), SyncBody(vec![next("version 4")]))
+                .await
+                .is_ok()
+        );
+
+        {
+            let mut s = state.lock().unwrap();
+            s.config.pow_difficulty = 30;
+            s.config.client_pow_difficulty = Some(0);
+        }
+        let envelope = signed_envelope("no work done");
+        let err = inbox(State(state.clone()), SyncBody(vec![envelope.clone()]))
+            .await
+            .unwrap_err();
+        assert_eq!(err, StatusCode::BAD_REQUEST);
+        let (status, _) = submit_post(State(state.clone()), Json(envelope))
+            .await
+            .unwrap();
+        assert_eq!(status, StatusCode::CREATED);
+
+        let Json(limits) = capabilities(State(state)).await.unwrap();
+        assert_eq!(
+            (limits.pow_difficulty, limits.client_pow_difficulty),
+            (30, 0)
+        );
+        assert_eq!(
+            (limits.post_rate_limit, limits.client_post_rate_limit),
+            (1, 2)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_sync_direction_skips_a_half() {
+        let remote = test_state();
+        let theirs = signed_envelope("held remotely");
+        import(&remote, theirs.clone());
+        let address = spawn_remote(remote.clone()).await;
+
+        let local = test_state();
+        local.lock().unwrap().config.peer_sync_cooldown_secs = 0;
+        let ours = signed_envelope("held locally");
+        import(&local, ours.clone());
+        let request = |direction| {
+            Json(SyncRequest {
+                address: address.clone(),
+                force: false,
+                direction,
+            })
+        };
+
+        let Json(pushed) = sync(
+            State(local.clone()),
+            HeaderMap::new(),
+            request(crate::types::SyncDirection::Push),
+        )
+        .await
+        .unwrap();
+        assert!(pushed.ok, "{}", pushed.message);
+        assert!(!local.lock().unwrap().memory.contains_key(&theirs.id));
+        assert!(remote.lock().unwrap().memory.contains_key(&ours.id));
+
+        remote.lock().unwrap().memory.remove(&ours.id);
+        let Json(pulled) = sync(
+            State(local.clone()),
+            HeaderMap::new(),
+            request(crate::types::SyncDirection::Pull),
+        )
+        .await
+        .unwrap();
+        assert!(pulled.ok, "{}", pulled.message);
+        assert_eq!(pulled.imported, 1);
+        assert!(local.lock().unwrap().memory.contains_key(&theirs.id));
+        assert!(!remote.lock().unwrap().memory.contains_key(&ours.id));
+    }
+
+    #[tokio::test]
+    async fn test_recompute_karma_reports_and_repairs_drift() {
+        let state = test_state();
+        let post = signed_envelope("voted on");
+        import(&state, post.clone());
+        add_code(&state, "AAAAA-BBBBB");
+        vote(&state, "AAAAA-BBBBB", &post, "upvote").await;
+        {
+            let mut s = state.lock().unwrap();
+            s.karma_votes.insert(post.id.clone(), 7);
+            s.karma_votes.insert("ghost".to_string(), -2);
+        }
+        let recompute = |dry_run| {
+            admin_recompute_karma(
+                State(state.clone()),
+                admin_headers(&state),
+                Query(KarmaRecomputeQuery { dry_run }),
+            )
+        };
+
+        let Json(check) = recompute(true).await.unwrap();
+        assert!(!check.applied);
+        assert_eq!(
+            check.drift,
+            vec![
+                KarmaDrift {
+                    post_id: post.id.clone(),
+                    before: 7,
+                    after: 1,
+                },
+                KarmaDrift {
+                    post_id: "ghost".to_string(),
+                    before: -2,
+                    after: 0,
+                },
+            ]
+        );
+        assert_eq!(state.lock().unwrap().karma_votes[&post.id], 7);
+
+        let Json(repair) = recompute(false).await.unwrap();
+        assert!(repair.applied);
+        assert_eq!(repair.posts, 1);
+        assert_eq!(state.lock().unwrap().karma_votes[&post.id], 1);
+
+        let Json(clean) = recompute(false).await.unwrap();
+        assert!(clean.drift.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_content_filter_blocks_posts_and_reloads() {
+        let dir = std::env::temp_dir().join(format!("openherd-filter-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("denylist.txt");
+        std::fs::write(&path, "casino\n").unwrap();
+
+        let state = test_state();
+        enroll(&state, "admin");
+        state.lock().unwrap().config.content_filter = Some(path.clone());
+        let reload = || admin_reload_content_filter(State(state.clone()), admin_headers(&state));
+        let Json(status) = reload().await.unwrap();
+        assert_eq!((status.terms, status.patterns), (1, 0));
+
+        let blocked = state
+            .lock()
+            .unwrap()
+            .admit_envelope(&signed_envelope("Best CASINO odds"));
+        assert!(matches!(blocked, Err(ValidationError::BlockedContent)));
+        let err = ApiError::from(ValidationError::BlockedContent);
+        assert_eq!(err, StatusCode::BAD_REQUEST);
+
+        std::fs::write(&path, "re:(broken\n").unwrap();
+        assert_eq!(reload().await.unwrap_err(), StatusCode::BAD_REQUEST);
+        assert_eq!(state.lock().unwrap().content_filter.terms(), 1);
+
+        std::fs::write(&path, "# cleared\n").unwrap();
+        let Json(status) = reload().await.unwrap();
+        assert_eq!((status.terms, status.patterns), (0, 0));
+        let allowed = state
+            .lock()
+            .unwrap()
+            .admit_envelope(&signed_envelope("Best casino odds"));
+        assert!(allowed.is_ok());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_sync_records_lag_behind_peer() {
+        let remote = test_state();
+        for text in ["old news", "breaking casino news"] {
+            import(&remote, signed_envelope(text));
+        }
+        let remote_seqs: Vec<u64> = remote.lock().unwrap().seq_index.keys().copied().collect();
+        let address = spawn_remote(remote.clone()).await;
+
+        // Rejecting the newer post leaves us one sequence number behind.
+        let local = test_state();
+        enroll(&local, "admin");
+        local.lock().unwrap().content_filter =
+            crate::filter::ContentFilter::parse("casino").unwrap();
+        let Json(resp) = sync(
+            State(local.clone()),
+            HeaderMap::new(),
+            Json(SyncRequest {
+                address: address.clone(),
+                force: false,
+                direction: crate::types::SyncDirection::Pull,
+            }),
+        )
+        .await
+        .unwrap();
+        assert!(resp.ok, "{}", resp.message);
+
+        let Json(peers) = admin_peers(State(local.clone()), admin_headers(&local))
+            .await
+            .unwrap();
+        let position = peers[&address].position.unwrap();
+        assert_eq!(
+            (position.pulled_seq, position.remote_seq),
+            (remote_seqs[0], remote_seqs[1])
+        );
+        assert_eq!(position.lag, remote_seqs[1] - remote_seqs[0]);
+
+        let (_, body) = metrics(State(local)).await.unwrap();
+        let gauge = format!("openherd_peer_lag{{peer=\"{}\"}} {}", address, position.lag);
+        assert!(body.contains(&gauge), "{body}");
+    }
+
+    #[tokio::test]
+    async fn test_removing_a_post_releases_its_karma_votes() {
+        let state = test_state();
+        let doomed = signed_envelope("about to go");
+        let kept = signed_envelope("staying");
+        import(&state, doomed.clone());
+        import(&state, kept.clone());
+        add_code(&state, "gone-vote");
+        add_code(&state, "kept-vote");
+        vote(&state, "gone-vote", &doomed, "upvote").await;
+        vote(&state, "kept-vote", &kept, "downvote").await;
+
+        state.lock().unwrap().quarantine_post(&doomed.id).unwrap();
+        {
+            let s = state.lock().unwrap();
+            assert!(!s.karma_votes.contains_key(&doomed.id));
+            assert_eq!(s.karma_votes[&kept.id], -1);
+            let code = &s.karma_codes["gone-vote"];
+            assert_eq!(code.current_post, None);
+            assert_eq!(code.used_direction, None);
+            assert!(code.history[0].revoked_at.is_some());
+            assert_eq!(
+                s.karma_codes["kept-vote"].current_post.as_ref(),
+                Some(&kept.id)
+            );
+            assert!(!s.karma_tallies().contains_key(&doomed.id));
+        }
+
+        // The freed code can vote again, and revoking it later touches no
+        // phantom tally.
+        vote(&state, "gone-vote", &kept, "upvote").await;
+        revoke(&state, "gone-vote").await;
+        let s = state.lock().unwrap();
+        assert_eq!(s.karma_votes[&kept.id], -1);
+        assert!(!s.karma_votes.contains_key(&doomed.id));
+    }
+
+    fn reaction_envelope(
+        key: &crate::validation::testing::TestKey,
+        post_id: &str,
+        reaction: &str,
+    ) -> Envelope {
+        let data = serde_json::to_string(&crate::types::Reaction {
+            id: key.fingerprint(),
+            post: post_id.to_string(),
+            reaction: reaction.to_string(),
+            date: Utc::now(),
+        })
+        .unwrap();
+        Envelope {
+            signature: key.sign(&data),
+            public_key: key.public_key().to_string(),
+            id: key.fingerprint(),
+            data,
+            received_at: None,
+            seq: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_reactions_are_counted_per_key() {
+        use crate::validation::testing::TestKey;
+
+        let state = test_state();
+        state.lock().unwrap().config.reactions = vec!["👍".to_string(), "❤️".to_string()];
+        let target = signed_envelope("react to me");
+        import(&state, target.clone());
+        let react = |envelope: Envelope, path: &str| {
+            add_reaction(
+                State(state.clone()),
+                Path(path.to_string()),
+                None,
+                HeaderMap::new(),
+                Json(envelope),
+            )
+        };
+
+        let (alice, bob) = (TestKey::generate(), TestKey::generate());
+        for (key, reaction) in [(&alice, "👍"), (&bob, "👍"), (&alice, "❤️")] {
+            let (status, _) = react(reaction_envelope(key, &target.id, reaction), &target.id)
+                .await
+                .unwrap();
+            assert_eq!(status, StatusCode::CREATED);
+        }
+        let (status, Json(again)) = react(reaction_envelope(&bob, &target.id, "👍"), &target.id)
+            .await
+            .unwrap();
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(again.counts["👍"], 2);
+
+        let not_allowed = react(reaction_envelope(&bob, &target.id, "🔥"), &target.id).await;
+        assert_eq!(not_allowed.unwrap_err(), StatusCode::BAD_REQUEST);
+        let elsewhere = react(reaction_envelope(&bob, "ghost", "👍"), &target.id).await;
+        assert_eq!(elsewhere.unwrap_err(), StatusCode::BAD_REQUEST);
+        let mut forged = reaction_envelope(&bob, &target.id, "❤️");
+        forged.id = alice.fingerprint();
+        let forged = react(forged, &target.id).await;
+        assert_eq!(forged.unwrap_err(), StatusCode::BAD_REQUEST);
+
+        let Json(reactions) = post_reactions(State(state.clone()), Path(target.id.clone()))
+            .await
+            .unwrap();
+        assert_eq!(
+            reactions.counts,
+            BTreeMap::from([("👍".to_string(), 2), ("❤️".to_string(), 1)])
+        );
+        // Karma is untouched by reactions.
+        assert!(!state.lock().unwrap().karma_votes.contains_key(&target.id));
+
+        state.lock().unwrap().remove_post(&target.id);
+        assert!(state
+            .lock()
+            .unwrap()
+            .reaction_counts(&target.id)
+            .unwrap()
+            .is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_reactions_are_rate_limited_and_capped() {
+        use crate::validation::testing::TestKey;
+
+        let state = test_state();
+        {
+            let mut s = state.lock().unwrap();
+            s.config.reactions = vec!["👍".to_string(), "❤️".to_string()];
+            s.config.reaction_rate_limit = 2;
+            s.config.max_reactions_per_post = 2;
+        }
+        let (first, second) = (signed_envelope("first"), signed_envelope("second"));
+        import(&state, first.clone());
+        import(&state, second.clone());
+        let react = |envelope: Envelope, post: &str, ip: &str| {
+            let mut headers = HeaderMap::new();
+            headers.insert("x-forwarded-for", ip.parse().unwrap());
+            add_reaction(
+                State(state.clone()),
+                Path(post.to_string()),
+                None,
+                headers,
+                Json(envelope),
+            )
+        };
+
+        let (alice, bob, carol) = (
+            TestKey::generate(),
+            TestKey::generate(),
+            TestKey::generate(),
+        );
+        for key in [&alice, &bob] {
+            let (status, _) = react(
+                reaction_envelope(key, &first.id, "👍"),
+                &first.id,
+                "10.0.0.1",
+            )
+            .await
+            .unwrap();
+            assert_eq!(status, StatusCode::CREATED);
+        }
+        // The post is full for new reactions, but a held one may be resent.
+        let full = react(
+            reaction_envelope(&carol, &first.id, "👍"),
+            &first.id,
+            "10.0.0.2",
+        )
+        .await;
+        assert_eq!(full.unwrap_err(), StatusCode::TOO_MANY_REQUESTS);
+        let (status, _) = react(
+            reaction_envelope(&bob, &first.id, "👍"),
+            &first.id,
+            "10.0.0.2",
+        )
+        .await
+        .unwrap();
+        assert_eq!(status, StatusCode::OK);
+
+        // A fresh key does not get the first address past its limit.
+        let limited = react(
+            reaction_envelope(&carol, &second.id, "👍"),
+            &second.id,
+            "10.0.0.1",
+        )
+        .await;
+        assert_eq!(limited.unwrap_err(), StatusCode::TOO_MANY_REQUESTS);
+        let (status, _) = react(
+            reaction_envelope(&carol, &second.id, "👍"),
+            &second.id,
+            "10.0.0.3",
+        )
+        .await
+        .unwrap();
+        assert_eq!(status, StatusCode::CREATED);
+    }
+
+    #[tokio::test]
+    async fn test_lookup_joins_label_definitions() {
+        let state = test_state();
+        {
+            let mut s = state.lock().unwrap();
+            s.label_definitions.insert(
+                "spam".to_string(),
+                ModerationLabel {
+                    id: "spam".to_string(),
+                    label: "Spam".to_string(),
+                    description: "Unsolicited advertising".to_string(),
+                    region: None,
+                },
+            );
+            s.post_labels.insert("a".to_string(), "spam".to_string());
+            // Imported from a peer whose definition we never copied.
+            s.post_labels.insert("b".to_string(), "retired".to_string());
+        }
+
+        let ids = ["a", "b", "c"].map(str::to_string).to_vec();
+        let Json(labels) = moderation_lookup_definitions(State(state), Json(ids))
+            .await
+            .unwrap();
+        assert_eq!(
+            labels,
+            vec![
+                Some(AppliedLabel {
+                    id: "spam".to_string(),
+                    label: Some("Spam".to_string()),
+                    description: Some("Unsolicited advertising".to_string()),
+                }),
+                Some(AppliedLabel {
+                    id: "retired".to_string(),
+                    label: None,
+                    description: None,
+                }),
+                None,
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_report_queue_cap_rejects_or_evicts() {
+        use crate::config::ReportOverflow;
+
+        let state = test_state();
+        state.lock().unwrap().config.max_moderation_reports = 2;
+        let report = |reason: &str| ModerationReport {
+            post: signed_envelope("reported"),
+            reason: reason.to_string(),
+            suggested_label: None,
+            reported_at: Utc::now(),
+            reporter_ip: None,
+            id: String::new(),
+        };
+        let submit = |reports: Vec<ModerationReport>| {
+            moderation_report(State(state.clone()), None, HeaderMap::new(), Json(reports))
+        };
+
+        let Json(resp) = submit(vec![report("first"), report("second")])
+            .await
+            .unwrap();
+        assert!(resp.ok);
+        let full = submit(vec![report("third")]).await.unwrap_err();
+        assert_eq!(full, StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(state.lock().unwrap().moderation_reports.len(), 2);
+
+        state.lock().unwrap().config.report_overflow = ReportOverflow::EvictOldest;
+        let Json(resp) = submit(vec![report("third")]).await.unwrap();
+        assert!(resp.ok);
+        let too_big = submit(vec![report("a"), report("b"), report("c")]).await;
+        assert_eq!(too_big.unwrap_err(), StatusCode::TOO_MANY_REQUESTS);
+
+        let s = state.lock().unwrap();
+        let reasons: Vec<&str> = s
+            .moderation_reports
+            .iter()
+            .map(|r| r.reason.as_str())
+            .collect();
+        assert_eq!(reasons, ["second", "third"]);
+        let archived = s.archived_reports();
+        assert_eq!(archived.len(), 1);
+        assert_eq!(archived[0].reason, "first");
+    }
+
+    #[tokio::test]
+    async fn test_pin_and_unpin_posts() {
+        let state = test_state();
+        enroll(&state, "admin");
+        let announcement = signed_envelope("meetup on friday");
+        import(&state, announcement.clone());
+        let pin = |id: &str| {
+            admin_pin_post(
+                State(state.clone()),
+                admin_headers(&state),
+                Path(id.to_string()),
+            )
+        };
+        let unpin = |id: &str| {
+            admin_unpin_post(
+                State(state.clone()),
+                admin_headers(&state),
+                Path(id.to_string()),
+            )
+        };
+
+        assert_eq!(pin("ghost").await.unwrap_err(), StatusCode::NOT_FOUND);
+        let Json(resp) = pin(&announcement.id).await.unwrap();
+        assert!(resp.ok);
+        assert!(state.lock().unwrap().is_pinned(&announcement.id));
+        let Json(pins) = admin_pinned_posts(State(state.clone()), admin_headers(&state))
+            .await
+            .unwrap();
+        assert_eq!(pins.len(), 1);
+        assert_eq!(pins[0].post_id, announcement.id);
+
+        let Json(resp) = unpin(&announcement.id).await.unwrap();
+        assert!(resp.ok);
+        assert!(!state.lock().unwrap().is_pinned(&announcement.id));
+        assert_eq!(
+            unpin(&announcement.id).await.unwrap_err(),
+            StatusCode::NOT_FOUND
+        );
+    }
+
+    #[tokio::test]
+    async fn test_posts_by_keys_groups_and_pages() {
+        let state = test_state();
+        let envelopes: Vec<Envelope> = ["one", "two", "three"]
+            .into_iter()
+            .map(signed_envelope)
+            .collect();
+        for env in &envelopes {
+            import(&state, env.clone());
+        }
+        let query = |keys: Vec<String>, offset, limit| {
+            Json(PostsByKeysQuery {
+                keys,
+                offset,
+                limit,
+            })
+        };
+        let mut keys: Vec<String> = envelopes.iter().map(|e| e.id.clone()).collect();
+        keys.insert(1, "0".repeat(40));
+        keys.push(envelopes[0].id.clone());
+
+        let Json(all) = posts_by_keys(State(state.clone()), query(keys.clone(), 0, None))
+            .await
+            .unwrap();
+        let grouped: Vec<&str> = all.iter().map(|g| g.key.as_str()).collect();
+        assert_eq!(
+            grouped,
+            [&envelopes[0].id, &envelopes[1].id, &envelopes[2].id]
+        );
+        assert!(all.iter().all(|g| g.envelopes.len() == 1));
+
+        let Json(page) = posts_by_keys(State(state.clone()), query(keys, 1, Some(1)))
+            .await
+            .unwrap();
+        assert_eq!(page.len(), 1);
+        assert_eq!(page[0].key, envelopes[1].id);
+
+        let too_many = vec![String::new(); MAX_KEYS_PER_REQUEST + 1];
+        let err = posts_by_keys(State(state), query(too_many, 0, None))
+            .await
+            .unwrap_err();
+        assert_eq!(err, StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_dry_run_previews_moderation_without_changes() {
+        let state = test_state();
+        let headers = admin_headers(&state);
+        let envelope = signed_envelope("reported twice");
+        import(&state, envelope.clone());
+        add_code(&state, "voter");
+        vote(&state, "voter", &envelope, "upvote").await;
+        {
+            let mut s = state.lock().unwrap();
+            s.label_definitions.insert(
+                "spam".to_string(),
+                ModerationLabel {
+                    id: "spam".to_string(),
+                    label: "Spam".to_string(),
+                    description: "Junk".to_string(),
+                    region: None,
+                },
+            );
+            for id in ["first", "second"] {
+                s.moderation_reports.push(ModerationReport {
+                    post: envelope.clone(),
+                    reason: "spam".to_string(),
+                    suggested_label: None,
+                    reported_at: Utc::now(),
+                    reporter_ip: None,
+                    id: id.to_string(),
+                });
+            }
+        }
+        let accept = || {
+            Json(ModerationAction {
+                report_id: "first".to_string(),
+                label: Some("spam".to_string()),
+            })
+        };
+        let expected = ModerationImpact {
+            posts: vec![envelope.id.clone()],
+            reports: 1,
+            sibling_reports: 1,
+            karma_votes: 1,
+            applied: false,
+        };
+
+        let Json(preview) = admin_accept_report(
+            State(state.clone()),
+            headers.clone(),
+            Query(DryRunQuery { dry_run: true }),
+            accept(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(preview, expected);
+        {
+            let s = state.lock().unwrap();
+            assert_eq!(s.moderation_reports.len(), 2);
+            assert!(s.post_labels.is_empty());
+        }
+
+        let Json(applied) = admin_accept_report(
+            State(state.clone()),
+            headers.clone(),
+            Query(DryRunQuery::default()),
+            accept(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            applied,
+            ModerationImpact {
+                applied: true,
+                ..expected
+            }
+        );
+        assert_eq!(state.lock().unwrap().moderation_reports.len(), 1);
+
+        let Json(preview) = admin_delete_label(
+            State(state.clone()),
+            headers,
+            Path("spam".to_string()),
+            Query(DryRunQuery { dry_run: true }),
+        )
+        .await
+        .unwrap();
+        assert_eq!(preview.posts, vec![envelope.id.clone()]);
+        assert_eq!(preview.reports, 0);
+        assert_eq!(preview.sibling_reports, 1);
+        let s = state.lock().unwrap();
+        assert!(s.label_definitions.contains_key("spam"));
+        assert_eq!(
+            s.post_labels.get(&envelope.id).map(String::as_str),
+            Some("spam")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_stored_posts_get_verifiable_receipts() {
+        let state = test_state();
+        let envelope = signed_envelope("keep this safe");
+        let Json(unsigned) = submit_post(State(state.clone()), Json(envelope.clone()))
+            .await
+            .unwrap()
+            .1;
+        assert!(unsigned.receipt.is_none());
+
+        let key = crate::federation::NodeKey::generate().unwrap();
+        let public_key = key.public_key().to_string();
+        {
+            let mut s = state.lock().unwrap();
+            s.node_key = Some(Arc::new(key));
+            s.config.post_receipts = true;
+        }
+        let (_, Json(detail)) = submit_post(State(state.clone()), Json(envelope.clone()))
+            .await
+            .unwrap();
+        let receipt = detail.receipt.unwrap();
+        assert_eq!(receipt.id, envelope.id);
+        assert_eq!(Some(receipt.received_at), detail.envelope.received_at);
+        assert_eq!(
+            receipt.data_sha256,
+            hex::encode(sha2::Sha256::digest(envelope.data.as_bytes()))
+        );
+        crate::federation::verify_receipt(&receipt, &public_key).unwrap();
+
+        let mut forged = receipt.clone();
+        forged.received_at += chrono::Duration::seconds(1);
+        assert!(crate::federation::verify_receipt(&forged, &public_key).is_err());
+        let mut forged = receipt.clone();
+        forged.data_sha256 = hex::encode(sha2::Sha256::digest(b"another version"));
+        assert!(crate::federation::verify_receipt(&forged, &public_key).is_err());
+
+        let other = signed_envelope("pushed by a peer");
+        let (_, Json(resp)) = inbox(State(state), SyncBody(vec![other.clone()]))
+            .await
+            .unwrap();
+        assert_eq!(resp.receipts.len(), 1);
+        assert_eq!(resp.receipts[0].id, other.id);
+        crate::federation::verify_receipt(&resp.receipts[0], &public_key).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_region_scoped_labels_only_apply_inside_region() {
+        let state = test_state();
+        let berlin = envelope_at("in berlin", 52.52, 13.40, 0);
+        let lagos = envelope_at("in lagos", 6.45, 3.39, 0);
+        let label = |id: &str, region| ModerationLabel {
+            id: id.to_string(),
+            label: id.to_string(),
+            description: String::new(),
+            region,
+        };
+        {
+            let mut s = state.lock().unwrap();
+            s.label_definitions
+                .insert("spam".to_string(), label("spam", None));
+            let eu = crate::types::GeoRegion {
+                lat: 50.0,
+                lon: 10.0,
+                radius_km: 1000.0,
+            };
+            s.label_definitions
+                .insert("eu-only".to_string(), label("eu-only", Some(eu)));
+            s.label_rules = serde_json::from_str(
+                r#"[{"name": "everything", "label": "eu-only", "text": "in"}]"#,
+            )
+            .unwrap();
+        }
+        for env in [&berlin, &lagos] {
+            import(&state, env.clone());
+        }
+        {
+            let mut s = state.lock().unwrap();
+            assert_eq!(
+                s.post_labels.get(&berlin.id).map(String::as_str),
+                Some("eu-only")
+            );
+            assert!(!s.post_labels.contains_key(&lagos.id));
+            s.post_labels
+                .insert(lagos.id.clone(), "eu-only".to_string());
+        }
+        let ids = vec![berlin.id.clone(), lagos.id.clone()];
+        let Json(labels) = moderation_lookup(State(state.clone()), Json(ids))
+            .await
+            .unwrap();
+        assert_eq!(labels, [Some("eu-only".to_string()), None]);
+
+        let at = |lat, lon| {
+            Query(LabelsQuery {
+                lat: Some(lat),
+                lon: Some(lon),
+            })
+        };
+        let Json(in_berlin) = moderation_labels(State(state.clone()), at(52.52, 13.40))
+            .await
+            .unwrap();
+        assert_eq!(in_berlin.len(), 2);
+        let Json(in_lagos) = moderation_labels(State(state.clone()), at(6.45, 3.39))
+            .await
+            .unwrap();
+        let ids: Vec<&str> = in_lagos.iter().map(|l| l.id.as_str()).collect();
+        assert_eq!(ids, ["spam"]);
+        let Json(all) = moderation_labels(State(state), Query(LabelsQuery::default()))
+            .await
+            .unwrap();
+        assert_eq!(all.len(), 2);
+    }
+
+    #[test]
+    fn test_client_ip_follows_configured_headers() {
+        use clap::Parser;
+        let peer = ConnectInfo(SocketAddr::from(([10, 0, 0, 7], 4000)));
+        let mut headers = HeaderMap::new();
+        headers.insert("X-Forwarded-For", "1.1.1.1, 10.0.0.1".parse().unwrap());
+        headers.insert("CF-Connecting-IP", "2.2.2.2".parse().unwrap());
+
+        let default = Config::default();
+        assert_eq!(
+            client_ip(&headers, Some(&peer), &default).as_deref(),
+            Some("1.1.1.1")
+        );
+
+        let cloudflare = Config::parse_from([
+            "openherd-cow",
+            "--client-ip-headers",
+            "CF-Connecting-IP,X-Forwarded-For",
+        ]);
+        assert_eq!(
+            client_ip(&headers, Some(&peer), &cloudflare).as_deref(),
+            Some("2.2.2.2")
+        );
+
+        let direct = Config::parse_from(["openherd-cow", "--client-ip-headers", ""]);
+        assert_eq!(
+            client_ip(&headers, Some(&peer), &direct).as_deref(),
+            Some("10.0.0.7")
+        );
+        assert_eq!(client_ip(&HeaderMap::new(), None, &default), None);
+    }
+
+    #[tokio::test]
+    async fn test_timespan_tracks_imports_and_removals() {
+        let state = test_state();
+        let Json(empty) = timespan(State(state.clone())).await.unwrap();
+        assert_eq!((empty.oldest, empty.newest, empty.count), (None, None, 0));
+
+        let oldest = envelope_at("oldest", 0.0, 0.0, 30);
+        let middle = envelope_at("middle", 0.0, 0.0, 20);
+        let newest = envelope_at("newest", 0.0, 0.0, 10);
+        for env in [&oldest, &middle, &newest] {
+            import(&state, env.clone());
+        }
+        let date = |env: &Envelope| serde_json::from_str::<Post>(&env.data).unwrap().date;
+
+        let Json(span) = timespan(State(state.clone())).await.unwrap();
+        assert_eq!(span.oldest, Some(date(&oldest)));
+        assert_eq!(span.newest, Some(date(&newest)));
+        assert_eq!(span.count, 3);
+
+        state.lock().unwrap().remove_post(&oldest.id);
+        let Json(span) = timespan(State(state)).await.unwrap();
+        assert_eq!(span.oldest, Some(date(&middle)));
+        assert_eq!(span.count, 2);
+    }
+
+    #[tokio::test]
+    async fn test_trusted_peer_posts_skip_verification() {
+        let original = signed_envelope("as signed");
+        let mut post: Post = serde_json::from_str(&original.data).unwrap();
+        post.text = "altered after signing".to_string();
+        let tampered = Envelope {
+            data: serde_json::to_string(&post).unwrap(),
+            ..original
+        };
+        let remote = test_state();
+        remote
+            .lock()
+            .unwrap()
+            .memory
+            .insert(tampered.id.clone(), tampered.clone());
+        let address = spawn_remote(remote).await;
+
+        let local = test_state();
+        let client = reqwest::Client::new();
+        let pull =
+            || crate::sync::sync_peer(&local, &client, &address, crate::types::SyncDirection::Pull);
+        let untrusted = pull().await;
+        assert_eq!(untrusted.imported, 0);
+        local.lock().unwrap().record_sync(&address, untrusted);
+
+        {
+            let mut s = local.lock().unwrap();
+            assert!(s.set_peer_trusted(&address, true));
+            assert!(!s.skips_verification(&address));
+            s.config.trust_peer_validation = true;
+        }
+        let trusted = pull().await;
+        assert_eq!(trusted.imported, 1, "{:?}", trusted.error);
+        let s = local.lock().unwrap();
+        assert_eq!(s.memory[&tampered.id].data, tampered.data);
+        assert!(s.peers[&address].trusted);
+    }
+
+    #[test]
+    fn test_unverified_envelope_cannot_change_the_key_of_a_held_post() {
+        let state = test_state();
+        let original = signed_envelope("the original");
+        import(&state, original.clone());
+
+        let swapped = Envelope {
+            public_key: signed_envelope("another key").public_key,
+            ..original.clone()
+        };
+        let mut s = state.lock().unwrap();
+        assert!(matches!(
+            s.admit_unverified_envelope(&swapped),
+            Err(ValidationError::KeyConflict)
+        ));
+        assert!(s.admit_unverified_envelope(&original).is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_post_quotes() {
+        use crate::validation::testing::TestKey;
+        let state = test_state();
+        let quoting = |quote: &str| {
+            let key = TestKey::generate();
+            let mut post = key.post("quoting");
+            post.quote = Some(quote.to_string());
+            (key, post)
+        };
+
+        let quoted = signed_envelope("quoted");
+        import(&state, quoted.clone());
+        let (first_key, mut first) = quoting(&quoted.id);
+        import(&state, first_key.envelope(&first));
+        let (second_key, second) = quoting(&quoted.id);
+        import(&state, second_key.envelope(&second));
+
+        let page = |after_seq| {
+            post_quotes(
+                State(state.clone()),
+                Path(quoted.id.clone()),
+                Query(QuotesQuery {
+                    after_seq,
+                    limit: Some(1),
+                }),
+            )
+        };
+        let Json(quotes) = page(None).await.unwrap();
+        assert_eq!(quotes.count, 2);
+        assert_eq!(quotes.quotes.len(), 1);
+        assert_eq!(quotes.quotes[0].id, first.id);
+        let Json(rest) = page(Some(quotes.cursor)).await.unwrap();
+        assert_eq!(rest.quotes.len(), 1);
+        assert_eq!(rest.quotes[0].id, second.id);
+        let Json(done) = page(Some(rest.cursor)).await.unwrap();
+        assert!(done.quotes.is_empty());
+        assert_eq!(done.cursor, rest.cursor);
+        let Json(detail) = post_detail(State(state.clone()), Path(quoted.id.clone()))
+            .await
+            .unwrap();
+        assert_eq!(detail.quote_count, 2);
+
+        // Editing a post to quote something else moves it in the index.
+        first.quote = Some(second.id.clone());
+        first.date += chrono::Duration::seconds(1);
+        import(&state, first_key.envelope(&first));
+        state.lock().unwrap().remove_post(&second.id);
+        let s = state.lock().unwrap();
+        assert_eq!(s.quote_count(&quoted.id), 0);
+        assert_eq!(s.quote_count(&second.id), 1);
+        drop(s);
+
+        let (bad_key, bad) = quoting("not-a-fingerprint");
+        let err = crate::validation::validate_envelope(&bad_key.envelope(&bad)).unwrap_err();
+        assert!(matches!(err, ValidationError::InvalidPostData(_)));
+    }
+
+    #[tokio::test]
+    async fn test_admin_karma_status() {
+        let state = test_state();
+        enroll(&state, "admin");
+        for code in ["STATUS-AAAAA", "STATUS-BBBBB", "STATUS-CCCCC"] {
+            add_code(&state, code);
+        }
+        state
+            .lock()
+            .unwrap()
+            .karma_codes
+            .get_mut("STATUS-CCCCC")
+            .unwrap()
+            .expires = Utc::now() - chrono::Duration::days(1);
+        let post = signed_envelope("redeemed");
+        vote(&state, "STATUS-AAAAA", &post, "upvote").await;
+
+        let request = |password: &str| {
+            Json(KarmaStatusRequest {
+                password: password.to_string(),
+                codes: ["STATUS-AAAAA", "UNKNOWN", "STATUS-BBBBB", "STATUS-CCCCC"]
+                    .map(str::to_string)
+                    .to_vec(),
+            })
+        };
+        let Json(statuses) =
+            admin_karma_status(State(state.clone()), HeaderMap::new(), request("admin"))
+                .await
+                .unwrap();
+        let summary: Vec<Option<(&str, bool, bool)>> = statuses
+            .iter()
+            .map(|st| {
+                st.as_ref()
+                    .map(|st| (st.code.as_str(), st.used, st.expired))
+            })
+            .collect();
+        assert_eq!(
+            summary,
+            [
+                Some(("STATUS-AAAAA", true, false)),
+                None,
+                Some(("STATUS-BBBBB", false, false)),
+                Some(("STATUS-CCCCC", false, true)),
+            ]
+        );
+        assert_eq!(statuses[0].as_ref().unwrap().current_post, Some(post.id));
+
+        let denied = admin_karma_status(State(state), HeaderMap::new(), request("wrong")).await;
+        assert_eq!(denied.unwrap_err(), StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn test_rate_limited_pull_is_retried() {
+        let remote = test_state();
+        let envelope = signed_envelope("held back");
+        import(&remote, envelope.clone());
+        let address = spawn_remote(remote.clone()).await;
+
+        let local = test_state();
+        enroll(&local, "admin");
+        {
+            let mut s = local.lock().unwrap();
+            s.config.post_rate_limit = 1;
+            s.post_times
+                .insert(envelope.id.to_lowercase(), [Utc::now()].into());
+        }
+        let pull = || {
+            sync(
+                State(local.clone()),
+                admin_headers(&local),
+                Json(SyncRequest {
+                    address: address.clone(),
+                    force: true,
+                    direction: crate::types::SyncDirection::Pull,
+                }),
+            )
+        };
+        let Json(resp) = pull().await.unwrap();
+        assert!(resp.ok, "{}", resp.message);
+        {
+            let s = local.lock().unwrap();
+            assert!(!s.memory.contains_key(&envelope.id));
+            assert_eq!(s.peers[&address].remote_change_seq, None);
+            assert_eq!(
+                s.pending_sync(&address, crate::types::SyncDirection::Pull, Some(1)),
+                Some(crate::types::SyncDirection::Pull)
+            );
+        }
+
+        local.lock().unwrap().post_times.clear();
+        let Json(resp) = pull().await.unwrap();
+        assert!(resp.ok, "{}", resp.message);
+        let s = local.lock().unwrap();
+        assert!(s.memory.contains_key(&envelope.id));
+        assert_eq!(
+            s.peers[&address].remote_change_seq,
+            Some(remote.lock().unwrap().change_seq)
+        );
     }
-    Ok(lines.join("\n"))
 }
@@ -1,13 +1,27 @@
+pub mod auth;
+pub mod backup;
+pub mod config;
+pub mod error;
+pub mod federation;
+pub mod filter;
+pub mod geo;
 pub mod handlers;
+pub mod metrics;
+pub mod routes;
+pub mod rules;
+pub mod search;
 pub mod state;
+pub mod store;
+pub mod sync;
 pub mod types;
 pub mod validation;
+pub mod websub;
+pub mod wire;
 
 #[cfg(test)]
 mod tests {
-    use crate::types::{Envelope, Post};
+    use crate::types::{Envelope, ModerationLabel, Post};
     use chrono::Utc;
-    use serde_json;
 
     #[test]
     fn test_envelope_serialization() {
@@ -16,6 +30,8 @@ mod tests {
             public_key: "-----BEGIN PGP PUBLIC KEY BLOCK-----\ntest_key\n-----END PGP PUBLIC KEY BLOCK-----".to_string(),
             id: "2fef8ec4334abede9aeb1d40293f2d6dbcc1edd0".to_string(),
             data: r#"{"id":"2fef8ec4334abede9aeb1d40293f2d6dbcc1edd0","text":"test","latitude":33.5583,"longitude":-84.2541,"date":"2025-06-03T02:06:56.465Z"}"#.to_string(),
+            received_at: None,
+            seq: None,
         };
 
         let json = serde_json::to_string(&envelope).unwrap();
@@ -34,6 +50,8 @@ mod tests {
             longitude: -84.3885,
             date: Utc::now(),
             parent: Some("8558e99c353bbac709e470b6342241c315fe352a".to_string()),
+            quote: None,
+            nonce: None,
         };
 
         let json = serde_json::to_string(&post).unwrap();
@@ -43,4 +61,51 @@ mod tests {
         assert_eq!(post.id, deserialized.id);
         assert_eq!(post.text, deserialized.text);
     }
+
+    #[test]
+    fn test_signed_envelope_validates() {
+        let envelope = crate::validation::testing::signed_envelope("Hello herd");
+        let post = crate::validation::validate_envelope(&envelope).unwrap();
+        assert_eq!(post.id, envelope.id);
+        assert_eq!(post.text, "Hello herd");
+    }
+
+    #[test]
+    fn test_label_slug() {
+        assert_eq!(ModerationLabel::slug("Hate Speech"), "hate-speech");
+        assert_eq!(ModerationLabel::slug("  NSFW! "), "nsfw");
+
+        let label: ModerationLabel =
+            serde_json::from_str(r#"{"label":"Spam","description":"Junk"}"#).unwrap();
+        assert_eq!(label.with_id().id, "spam");
+    }
+
+    #[test]
+    fn test_proof_of_work_difficulty() {
+        use crate::types::ValidationError;
+        use crate::validation::{proof_of_work_bits, validate_envelope_with_pow};
+
+        let key = crate::validation::testing::TestKey::generate();
+        let mut post = key.post("Costly post");
+        assert_eq!(proof_of_work_bits(&post), 0);
+        let unworked = key.envelope(&post);
+        assert!(matches!(
+            validate_envelope_with_pow(&unworked, 8),
+            Err(ValidationError::InsufficientWork { required: 8 })
+        ));
+        assert!(validate_envelope_with_pow(&unworked, 0).is_ok());
+
+        post.nonce = Some(0);
+        while proof_of_work_bits(&post) < 8 {
+            post.nonce = post.nonce.map(|n| n + 1);
+        }
+        let worked = key.envelope(&post);
+        assert!(validate_envelope_with_pow(&worked, 8).is_ok());
+
+        let bits = proof_of_work_bits(&post);
+        assert!(matches!(
+            validate_envelope_with_pow(&worked, bits + 1),
+            Err(ValidationError::InsufficientWork { .. })
+        ));
+    }
 }
@@ -1,32 +1,68 @@
-mod handlers;
-mod state;
-mod types;
-mod validation;
-
-use axum::{
-    routing::{delete, get, patch, post},
-    Router,
-};
-use chrono::Utc;
 use clap::{Parser, Subcommand};
-use state::{AppState as CoreState, PeerStatus, SharedState};
+use openherd_cow::auth::{AdminBackend, SledPasswords};
+use openherd_cow::backup;
+use openherd_cow::config::{AdminBackendKind, Config, FlushPolicy};
+use openherd_cow::federation::NodeKey;
+use openherd_cow::filter::ContentFilter;
+use openherd_cow::routes;
+use openherd_cow::rules;
+use openherd_cow::search::SearchIndex;
+use openherd_cow::state::{AppState as CoreState, PeerStatus, SharedState};
+use openherd_cow::store;
+use openherd_cow::sync;
+use openherd_cow::types::{self, SyncDirection};
+use openherd_cow::validation::{validate_envelope, Keyring};
+use openherd_cow::websub;
+use std::path::PathBuf;
 use std::sync::{Arc, Mutex};
 use std::time::Duration;
-use tower_http::cors::CorsLayer;
 
 #[derive(Parser)]
 #[command(name = "openherd-cow")]
 #[command(about = "OpenHerd Cow", long_about = None)]
 struct Cli {
+    #[command(flatten)]
+    config: Config,
+
     #[command(subcommand)]
     command: Option<Commands>,
 }
 
 #[derive(Subcommand)]
 enum Commands {
-    EnrollAdmin { password: String },
+    EnrollAdmin {
+        password: String,
+    },
+
+    DenrollAdmin {
+        password: String,
+    },
+
+    /// Replace every sled-stored admin password after a leak. Run with the
+    /// server stopped; bearer tokens only live in the server's memory, so
+    /// none survive the restart.
+    RotateAdmin {
+        /// A currently valid admin password.
+        current_password: String,
+        /// New passwords. Prompted for interactively when none are given.
+        #[arg(long = "new")]
+        new_passwords: Vec<String>,
+    },
 
-    DenrollAdmin { password: String },
+    /// Write every persisted section of the node to a versioned archive.
+    /// Run with the server stopped.
+    Backup {
+        path: PathBuf,
+    },
+
+    /// Read every entry in the database and report damage without serving.
+    /// Exits non-zero if anything is unreadable.
+    CheckDb,
+
+    /// Load an archive written by `backup` into an empty data directory.
+    Restore {
+        path: PathBuf,
+    },
 
     Serve,
 }
@@ -36,187 +72,607 @@ async fn main() {
     let cli = Cli::parse();
 
     let db = sled::open("./data").expect("failed to open sled DB");
-    let state: SharedState = Arc::new(Mutex::new(CoreState::new(db.clone())));
-
-    {
-        let mut s = state.lock().unwrap();
-        if let Ok(Some(admin_bytes)) = db.get(b"__admin_passwords__") {
-            if let Ok(passwords) = serde_json::from_slice::<Vec<String>>(&admin_bytes) {
-                s.admin_passwords = passwords;
-            }
-        }
-    }
+    let state: SharedState = Arc::new(Mutex::new(CoreState::new(db.clone(), cli.config.clone())));
 
     match cli.command.unwrap_or(Commands::Serve) {
         Commands::EnrollAdmin { password } => {
-            let mut s = state.lock().unwrap();
-            if !s.admin_passwords.contains(&password) {
-                s.admin_passwords.push(password.clone());
-                let bytes = serde_json::to_vec(&s.admin_passwords).unwrap();
-                db.insert(b"__admin_passwords__", bytes).unwrap();
-                db.flush().unwrap();
-                println!("Admin enrolled successfully");
-            } else {
-                println!("Admin already exists");
+            warn_if_not_sled_backend(&cli.config);
+            match SledPasswords::new(db.clone()).enroll(&password) {
+                Ok(true) => println!("Admin enrolled successfully"),
+                Ok(false) => println!("Admin already exists"),
+                Err(e) => eprintln!("Failed to enroll admin: {}", e),
             }
             return;
         }
         Commands::DenrollAdmin { password } => {
-            let mut s = state.lock().unwrap();
-            s.admin_passwords.retain(|p| p != &password);
-            let bytes = serde_json::to_vec(&s.admin_passwords).unwrap();
-            db.insert(b"__admin_passwords__", bytes).unwrap();
-            db.flush().unwrap();
-            println!("Admin denrolled successfully");
+            warn_if_not_sled_backend(&cli.config);
+            match SledPasswords::new(db.clone()).denroll(&password) {
+                Ok(()) => println!("Admin denrolled successfully"),
+                Err(e) => eprintln!("Failed to denroll admin: {}", e),
+            }
+            return;
+        }
+        Commands::RotateAdmin {
+            current_password,
+            new_passwords,
+        } => {
+            warn_if_not_sled_backend(&cli.config);
+            let store = SledPasswords::new(db.clone());
+            if !store.verify(&current_password) {
+                eprintln!("Current password is not a valid admin password");
+                std::process::exit(1);
+            }
+            let new_passwords = if new_passwords.is_empty() {
+                prompt_new_passwords()
+            } else {
+                new_passwords
+            };
+            if new_passwords.iter().all(|p| p.is_empty()) {
+                eprintln!("No new passwords given, leaving credentials unchanged");
+                std::process::exit(1);
+            }
+            let new_passwords: Vec<String> = new_passwords
+                .into_iter()
+                .filter(|p| !p.is_empty())
+                .collect();
+            match store.replace_all(&new_passwords) {
+                Ok(()) => println!(
+                    "Admin credentials rotated: {} password(s) stored",
+                    new_passwords.len()
+                ),
+                Err(e) => {
+                    eprintln!("Failed to rotate admin credentials: {}", e);
+                    std::process::exit(1);
+                }
+            }
+            return;
+        }
+        Commands::Backup { path } => {
+            match backup::write(&db, &path) {
+                Ok(archive) => println!(
+                    "Backed up {} posts, {} karma codes and {} peers to {}",
+                    archive.posts.len(),
+                    archive.karma_codes.len(),
+                    archive.peers.len(),
+                    path.display()
+                ),
+                Err(e) => {
+                    eprintln!("Backup failed: {}", e);
+                    std::process::exit(1);
+                }
+            }
+            return;
+        }
+        Commands::Restore { path } => {
+            let restored = backup::read(&path).and_then(|archive| {
+                backup::restore(&db, &archive, cli.config.compress_posts)?;
+                Ok(archive)
+            });
+            match restored {
+                Ok(archive) => println!(
+                    "Restored {} posts, {} karma codes and {} peers from {}",
+                    archive.posts.len(),
+                    archive.karma_codes.len(),
+                    archive.peers.len(),
+                    path.display()
+                ),
+                Err(e) => {
+                    eprintln!("Restore failed: {}", e);
+                    std::process::exit(1);
+                }
+            }
+            return;
+        }
+        Commands::CheckDb => {
+            if !check_db(&db) {
+                std::process::exit(1);
+            }
             return;
         }
         Commands::Serve => {}
     }
 
     {
+        match store::check_posts(&db) {
+            Ok(report) => {
+                log_integrity(&report, cli.config.repair);
+                if cli.config.repair && !report.is_clean() {
+                    match store::repair_posts(&db, &report, cli.config.compress_posts) {
+                        Ok(count) => println!("✓ Repaired {} stored posts", count),
+                        Err(e) => {
+                            eprintln!("Repair failed: {}", e);
+                            std::process::exit(1);
+                        }
+                    }
+                }
+            }
+            Err(e) => eprintln!("Integrity check failed: {}", e),
+        }
+
         {
             let mut s = state.lock().unwrap();
-            for item in s.db.iter() {
-                if let Ok((k, v)) = item {
-                    if k.starts_with(b"post:") {
-                        if let Ok(env) = serde_json::from_slice::<types::Envelope>(&v) {
+            let scan_db = s.db.clone();
+            let scan = store::scan(&scan_db, |k, v| {
+                if k.starts_with(b"post:") {
+                    // Left in place so check-db and --repair can find it.
+                    match store::decode_envelope(v) {
+                        Ok(env) => {
                             s.memory.insert(env.id.clone(), env);
-                        } else {
-                            let _ = s.db.remove(k);
                         }
+                        Err(e) => eprintln!(
+                            "Skipping undecodable post {}: {}",
+                            String::from_utf8_lossy(k),
+                            e
+                        ),
+                    }
+                } else if let Some(addr) = k.strip_prefix(b"peer:") {
+                    if let Ok(peer) = serde_json::from_slice::<PeerStatus>(v) {
+                        let addr = String::from_utf8_lossy(addr).into_owned();
+                        s.peers.insert(addr, peer);
+                    }
+                } else if k.starts_with(b"karma_code:") {
+                    if let Ok(kc) = serde_json::from_slice::<types::KarmaCode>(v) {
+                        s.karma_codes.insert(kc.code.clone(), kc);
                     }
                 }
+            });
+            if !scan.is_clean() {
+                eprintln!(
+                    "⚠ Database is damaged: {} unreadable entries{}. Serving what could be read; run check-db for details",
+                    scan.read_errors,
+                    if scan.aborted.is_some() {
+                        ", and the scan stopped early"
+                    } else {
+                        ""
+                    }
+                );
+            }
+            s.rebuild_karma_votes();
+            s.rebuild_geo_index();
+            if let Err(e) = s.rebuild_seq_index() {
+                eprintln!("Failed to number stored posts: {}", e);
             }
         }
 
         {
             let mut s = state.lock().unwrap();
-            if let Ok(contents) = std::fs::read_to_string("./labels.json") {
-                if let Ok(labels) = serde_json::from_str::<Vec<types::ModerationLabel>>(&contents) {
-                    for label in labels {
-                        s.label_definitions.insert(label.label, label.description);
+            let index_dir = std::path::Path::new("./data/search-index");
+            let missing = !index_dir.exists();
+            match SearchIndex::open(index_dir) {
+                Ok(mut index) => {
+                    let stale = index.num_docs() != s.memory.len() as u64;
+                    if stale && !missing && !cli.config.reindex && !cli.config.repair {
+                        eprintln!(
+                            "⚠ Search index holds {} posts but {} are stored; restart with --reindex or --repair",
+                            index.num_docs(),
+                            s.memory.len()
+                        );
+                    }
+                    if missing || cli.config.reindex || (cli.config.repair && stale) {
+                        match index.rebuild(&s.memory) {
+                            Ok(count) => println!("✓ Indexed {} posts for search", count),
+                            Err(e) => eprintln!("Failed to rebuild search index: {}", e),
+                        }
                     }
-                    println!(
-                        "✓ Loaded {} label definitions from labels.json",
-                        s.label_definitions.len()
-                    );
-                } else {
-                    eprintln!("Failed to parse labels.json");
+                    s.search_index = Some(index);
                 }
-            } else {
-                eprintln!("labels.json not found, starting with empty label definitions");
-            }
-        }
-    }
-
-    let app = Router::new()
-        .route("/_openherd/outbox", get(handlers::outbox))
-        .route("/_openherd/inbox", post(handlers::inbox))
-        .route("/_openherd/peers", get(handlers::peers))
-        .route("/_openherd/sync", post(handlers::sync))
-        .route(
-            "/_openherd/karma/:code/upvote",
-            patch(handlers::karma_upvote),
-        )
-        .route(
-            "/_openherd/karma/:code/downvote",
-            patch(handlers::karma_downvote),
-        )
-        .route("/_openherd/karma/:code", delete(handlers::karma_revoke))
-        .route("/_openherd/karma/:code/", get(handlers::karma_metadata))
-        .route("/_openherd/karma/lookup", post(handlers::karma_lookup))
-        .route(
-            "/_openherd/moderation/lookup",
-            post(handlers::moderation_lookup),
-        )
-        .route(
-            "/_openherd/moderation/labels",
-            get(handlers::moderation_labels),
-        )
-        .route(
-            "/_openherd/moderation/report",
-            post(handlers::moderation_report),
-        )
-        .route("/_openherd/admin", get(handlers::admin_ui))
-        .route("/_openherd/admin/reports", post(handlers::admin_reports))
-        .route(
-            "/_openherd/admin/accept",
-            post(handlers::admin_accept_report),
-        )
-        .route(
-            "/_openherd/admin/delete/:id",
-            delete(handlers::admin_delete_report),
-        )
-        .route(
-            "/_openherd/admin/karma/codes",
-            post(handlers::admin_generate_karma_codes),
-        )
-        .route(
-            "/_openherd/admin/karma/codes.txt",
-            post(handlers::admin_generate_karma_codes_text),
-        )
-        .route(
-            "/_openherd/admin/moderation/labels",
-            post(handlers::admin_add_label),
-        )
-        .route(
-            "/_openherd/admin/moderation/labels/:label",
-            delete(handlers::admin_delete_label),
-        )
-        .layer(CorsLayer::permissive())
-        .with_state(state.clone());
+                Err(e) => eprintln!(
+                    "Failed to open search index, falling back to substring search: {}",
+                    e
+                ),
+            }
+        }
+
+        {
+            let mut s = state.lock().unwrap();
+            // A missing file just means no labels yet; an unreadable or
+            // malformed one is an operator error we refuse to run past, since
+            // starting with no labels would quietly disable moderation.
+            let labels = match std::fs::read_to_string("./labels.json") {
+                Ok(contents) => {
+                    match serde_json::from_str::<Vec<types::ModerationLabel>>(&contents) {
+                        Ok(labels) => labels,
+                        Err(e) => {
+                            eprintln!("labels.json is malformed, refusing to start: {}", e);
+                            std::process::exit(1);
+                        }
+                    }
+                }
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                    eprintln!("labels.json not found, starting with empty label definitions");
+                    Vec::new()
+                }
+                Err(e) => {
+                    eprintln!("Failed to read labels.json, refusing to start: {}", e);
+                    std::process::exit(1);
+                }
+            };
+            if !labels.is_empty() {
+                let needs_migration = labels.iter().any(|l| l.id.trim().is_empty());
+                for label in labels {
+                    let label = label.with_id();
+                    s.label_definitions.insert(label.id.clone(), label);
+                }
+                println!(
+                    "✓ Loaded {} label definitions from labels.json",
+                    s.label_definitions.len()
+                );
+                if needs_migration {
+                    match s.save_label_definitions() {
+                        Ok(()) => println!("✓ Added label ids to labels.json"),
+                        Err(e) => eprintln!("Failed to migrate labels.json: {}", e),
+                    }
+                }
+            }
+        }
+    }
+
+    {
+        let mut s = state.lock().unwrap();
+        match NodeKey::load_or_generate(&s.db) {
+            Ok(key) => {
+                println!("✓ Node key {}", key.fingerprint());
+                s.node_key = Some(Arc::new(key));
+            }
+            Err(e) => eprintln!("Failed to load node key, pushes will be unsigned: {}", e),
+        }
+    }
+
+    if let Some(path) = &cli.config.label_rules {
+        let mut s = state.lock().unwrap();
+        let mut rules = match rules::load(path) {
+            Ok(rules) => rules,
+            Err(e) => {
+                eprintln!("Failed to load label rules, refusing to start: {}", e);
+                std::process::exit(1);
+            }
+        };
+        for rule in &mut rules {
+            match s.resolve_label(&rule.label) {
+                Some(id) => rule.label = id,
+                None => {
+                    eprintln!("Label rule {} uses unknown label {}", rule.name, rule.label);
+                    std::process::exit(1);
+                }
+            }
+        }
+        println!("✓ Loaded {} label rule(s)", rules.len());
+        s.label_rules = rules;
+    }
+
+    if let Some(path) = &cli.config.trusted_keyring {
+        match Keyring::load(path) {
+            Ok(keyring) if keyring.is_empty() => {
+                eprintln!("Trusted keyring {} has no keys", path.display());
+                std::process::exit(1);
+            }
+            Ok(keyring) => {
+                println!("✓ Trusted keyring: {} key(s)", keyring.len());
+                state.lock().unwrap().keyring = Some(keyring);
+            }
+            Err(e) => {
+                eprintln!("Failed to load trusted keyring: {}", e);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    if cli.config.require_admin_signature && cli.config.admin_signing_keys.is_empty() {
+        eprintln!("--require-admin-signature needs at least one --admin-signing-keys fingerprint");
+        std::process::exit(1);
+    }
+
+    if let Some(path) = &cli.config.content_filter {
+        match ContentFilter::load(path) {
+            Ok(filter) => {
+                println!(
+                    "✓ Content filter: {} term(s), {} pattern(s)",
+                    filter.terms(),
+                    filter.patterns()
+                );
+                state.lock().unwrap().content_filter = filter;
+            }
+            Err(e) => {
+                eprintln!("Failed to load content filter: {}", e);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    let app = routes::router(state.clone());
 
     tokio::spawn(peer_monitor(state.clone()));
+    if cli.config.websub {
+        websub::spawn_notifier(&state);
+    }
+    if cli.config.enable_reports {
+        tokio::spawn(report_janitor(state.clone()));
+    }
+    if cli.config.verify_on_start {
+        tokio::spawn(verify_stored_posts(state.clone()));
+    }
 
-    let port = std::env::var("PORT").unwrap_or_else(|_| "3000".to_string());
-    let addr = format!("0.0.0.0:{}", port);
+    let addr = cli.config.listen_addr();
     let listener = tokio::net::TcpListener::bind(&addr).await.unwrap();
 
     println!("OpenHerd server running on http://{}", addr);
 
-    axum::serve(listener, app).await.unwrap();
+    if cli.config.flush_policy == FlushPolicy::Periodic {
+        let interval = Duration::from_millis(cli.config.flush_interval_ms.max(1));
+        tokio::spawn(periodic_flush(db.clone(), interval));
+    }
+
+    axum::serve(
+        listener,
+        app.into_make_service_with_connect_info::<std::net::SocketAddr>(),
+    )
+    .with_graceful_shutdown(shutdown_signal())
+    .await
+    .unwrap();
+
+    // Whatever the flush policy, a clean exit leaves nothing unflushed.
+    match db.flush_async().await {
+        Ok(_) => println!("✓ Flushed database on shutdown"),
+        Err(e) => eprintln!("Failed to flush database on shutdown: {}", e),
+    }
+}
+
+/// Reads new admin passwords from the terminal until a blank entry.
+fn prompt_new_passwords() -> Vec<String> {
+    let mut passwords = Vec::new();
+    loop {
+        let password = match rpassword::prompt_password("New admin password (blank to finish): ") {
+            Ok(p) => p,
+            Err(e) => {
+                eprintln!("Failed to read password: {}", e);
+                std::process::exit(1);
+            }
+        };
+        if password.is_empty() {
+            return passwords;
+        }
+        match rpassword::prompt_password("Repeat password: ") {
+            Ok(repeat) if repeat == password => passwords.push(password),
+            Ok(_) => eprintln!("Passwords did not match, try again"),
+            Err(e) => {
+                eprintln!("Failed to read password: {}", e);
+                std::process::exit(1);
+            }
+        }
+    }
+}
+
+fn warn_if_not_sled_backend(config: &Config) {
+    if config.admin_backend != AdminBackendKind::Sled {
+        eprintln!(
+            "Note: --admin-backend is {:?}; enrolled passwords only apply with the sled backend",
+            config.admin_backend
+        );
+    }
+}
+
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        let _ = tokio::signal::ctrl_c().await;
+    };
+    #[cfg(unix)]
+    let terminate = async {
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(mut sig) => {
+                sig.recv().await;
+            }
+            Err(_) => std::future::pending::<()>().await,
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {},
+        _ = terminate => {},
+    }
+}
+
+async fn periodic_flush(db: sled::Db, interval: Duration) {
+    loop {
+        tokio::time::sleep(interval).await;
+        if let Err(e) = db.flush_async().await {
+            eprintln!("Periodic DB flush error: {}", e);
+        }
+    }
 }
 
 async fn peer_monitor(state: SharedState) {
-    let client = reqwest::Client::new();
+    let client = reqwest::Client::builder()
+        .timeout(Duration::from_secs(15))
+        .build()
+        .unwrap_or_default();
     loop {
         tokio::time::sleep(Duration::from_secs(120)).await;
 
-        let peers: Vec<String> = {
+        let (full_sync, peers): (bool, Vec<(String, SyncDirection)>) = {
             let s = state.lock().unwrap();
-            s.peers.keys().cloned().collect()
+            let full_sync = s.config.peer_monitor_sync;
+            let peers = s
+                .peers
+                .keys()
+                .filter(|addr| s.config.allow_insecure_peers || !sync::is_insecure_peer(addr))
+                .filter(|addr| !full_sync || s.sync_cooldown(addr).is_none())
+                .map(|addr| (addr.clone(), s.config.sync_direction_for(addr)))
+                .collect();
+            (full_sync, peers)
         };
 
-        for addr in peers {
-            let outbox_url = format!("{}/_openherd/outbox", addr.trim_end_matches('/'));
-            let ok = match client.get(&outbox_url).send().await {
-                Ok(resp) => resp.status().is_success(),
-                Err(_) => false,
-            };
-
-            let mut s = state.lock().unwrap();
-            if ok {
-                if let Some(p) = s.peers.get_mut(&addr) {
-                    p.failures = 0;
-                    p.last_ok = Some(Utc::now());
-                } else {
-                    s.peers.insert(
-                        addr.clone(),
-                        PeerStatus {
-                            failures: 0,
-                            last_ok: Some(Utc::now()),
-                        },
-                    );
-                }
+        for (addr, direction) in peers {
+            if full_sync {
+                monitor_sync(&state, &client, &addr, direction).await;
             } else {
-                if let Some(p) = s.peers.get_mut(&addr) {
-                    p.failures = p.failures.saturating_add(1);
-                    if p.failures >= 5 {
-                        s.peers.remove(&addr);
-                    }
-                } else {
-                }
+                let alive = sync::probe_peer(&client, addr.trim_end_matches('/')).await;
+                state.lock().unwrap().record_probe(&addr, alive);
+            }
+            let mut s = state.lock().unwrap();
+            if s.peer_expired(&addr) {
+                println!(
+                    "Removing peer {}: still failing after its grace period",
+                    addr
+                );
+                s.remove_peer(&addr);
             }
         }
     }
 }
+
+/// One `--peer-monitor-sync` round with `addr`, skipped if neither side
+/// changed since the last.
+async fn monitor_sync(
+    state: &SharedState,
+    client: &reqwest::Client,
+    addr: &str,
+    direction: SyncDirection,
+) {
+    let base = addr.trim_end_matches('/');
+    let remote_change_seq = if direction.pulls() {
+        sync::fetch_change_seq(client, base).await
+    } else {
+        None
+    };
+    let pending = state
+        .lock()
+        .unwrap()
+        .pending_sync(addr, direction, remote_change_seq);
+    let Some(direction) = pending else {
+        return;
+    };
+    let outcome = sync::sync_peer(state, client, base, direction).await;
+    state.lock().unwrap().record_sync(addr, outcome);
+}
+
+async fn report_janitor(state: SharedState) {
+    loop {
+        tokio::time::sleep(Duration::from_secs(3600)).await;
+        let expired = state.lock().unwrap().expire_reports();
+        if expired > 0 {
+            println!("Expired {} unresolved moderation reports", expired);
+        }
+    }
+}
+
+/// Prints what `check_posts` found at startup, one line per post.
+fn log_integrity(report: &store::IntegrityReport, repairing: bool) {
+    if report.is_clean() {
+        println!(
+            "✓ Integrity check passed for {} stored posts",
+            report.checked
+        );
+        return;
+    }
+    let sections = [
+        ("Stored but unreadable", &report.unreadable),
+        ("Stored under another id", &report.misplaced),
+        ("Stored with a duplicate seq", &report.duplicate_seq),
+    ];
+    for (what, ids) in sections {
+        for id in ids {
+            eprintln!("⚠ {}: {}", what, id);
+        }
+    }
+    if !repairing {
+        eprintln!("⚠ Stored posts are damaged; restart with --repair to set them aside");
+    }
+}
+
+/// Scans the whole database and decodes the entries the server loads at
+/// startup, printing a summary. Returns whether everything was readable.
+fn check_db(db: &sled::Db) -> bool {
+    let mut posts = 0;
+    let mut bad_values = Vec::new();
+    let scan = store::scan(db, |k, v| {
+        let ok = if k.starts_with(b"post:") {
+            posts += 1;
+            store::decode_envelope(v).is_ok()
+        } else if k.starts_with(b"peer:") {
+            serde_json::from_slice::<PeerStatus>(v).is_ok()
+        } else if k.starts_with(b"karma_code:") {
+            serde_json::from_slice::<types::KarmaCode>(v).is_ok()
+        } else {
+            true
+        };
+        if !ok {
+            bad_values.push(String::from_utf8_lossy(k).into_owned());
+        }
+    });
+
+    println!("Entries read: {} ({} posts)", scan.entries, posts);
+    println!("Unreadable entries: {}", scan.read_errors);
+    for key in &bad_values {
+        println!("Undecodable value: {}", key);
+    }
+    if let Some(reason) = &scan.aborted {
+        println!("Scan stopped early: {}", reason);
+    }
+    let clean = scan.is_clean() && bad_values.is_empty();
+    println!(
+        "{}",
+        if clean {
+            "✓ Database OK"
+        } else {
+            "✗ Database is damaged"
+        }
+    );
+    clean
+}
+
+/// Re-runs signature validation over every post loaded at startup and
+/// quarantines the ones that fail, to catch tampering with the data directory.
+async fn verify_stored_posts(state: SharedState) {
+    let snapshot: Vec<types::Envelope> = {
+        let s = state.lock().unwrap();
+        s.memory.values().cloned().collect()
+    };
+    let total = snapshot.len();
+    println!("Verifying signatures of {} stored posts", total);
+
+    let failed = tokio::task::spawn_blocking(move || {
+        let mut failed = Vec::new();
+        for (i, env) in snapshot.into_iter().enumerate() {
+            if let Err(e) = validate_envelope(&env) {
+                eprintln!("Post {} failed verification: {}", env.id, e);
+                failed.push(env);
+            }
+            if (i + 1) % 1000 == 0 {
+                println!("Verified {}/{} posts", i + 1, total);
+            }
+        }
+        failed
+    })
+    .await
+    .unwrap_or_default();
+
+    let mut quarantined = 0;
+    {
+        let mut s = state.lock().unwrap();
+        for env in &failed {
+            // Skip posts that were replaced by a fresh import while we verified.
+            let unchanged = s
+                .memory
+                .get(&env.id)
+                .is_some_and(|current| current.data == env.data);
+            if !unchanged {
+                continue;
+            }
+            match s.quarantine_post(&env.id) {
+                Ok(()) => quarantined += 1,
+                Err(e) => eprintln!("Failed to quarantine {}: {}", env.id, e),
+            }
+        }
+        let _ = s.flush_writes();
+        s.commit_search_index();
+    }
+
+    println!(
+        "✓ Verified {} stored posts: {} failed, {} quarantined",
+        total,
+        failed.len(),
+        quarantined
+    );
+}
@@ -0,0 +1,134 @@
+//! Request latency per route, logged when slow and exposed as Prometheus
+//! histograms at `/_openherd/metrics`, alongside per-peer federation lag.
+
+use crate::state::PeerStatus;
+use axum::{
+    extract::{MatchedPath, Request, State},
+    middleware::Next,
+    response::Response,
+};
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Upper bounds of the histogram buckets, in seconds.
+const BUCKETS: [f64; 11] = [
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+#[derive(Default)]
+struct Histogram {
+    /// Observations per bucket, not cumulative; the last slot is `+Inf`.
+    buckets: [u64; BUCKETS.len() + 1],
+    count: u64,
+    sum: f64,
+}
+
+/// Kept outside `AppState`'s lock so timing requests does not contend with
+/// the handlers being timed.
+pub struct RequestMetrics {
+    slow_threshold: Option<Duration>,
+    routes: Mutex<BTreeMap<(String, String), Histogram>>,
+}
+
+impl RequestMetrics {
+    /// `slow_threshold` of `None` disables slow-request logging.
+    pub fn new(slow_threshold: Option<Duration>) -> Self {
+        Self {
+            slow_threshold,
+            routes: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    pub fn observe(&self, method: &str, route: &str, elapsed: Duration) {
+        let secs = elapsed.as_secs_f64();
+        let Ok(mut routes) = self.routes.lock() else {
+            return;
+        };
+        let histogram = routes
+            .entry((method.to_string(), route.to_string()))
+            .or_default();
+        let bucket = BUCKETS
+            .iter()
+            .position(|&le| secs <= le)
+            .unwrap_or(BUCKETS.len());
+        histogram.buckets[bucket] += 1;
+        histogram.count += 1;
+        histogram.sum += secs;
+    }
+
+    /// Prometheus text exposition of every route seen so far.
+    pub fn render(&self) -> String {
+        const NAME: &str = "openherd_request_duration_seconds";
+        let mut out =
+            format!("# HELP {NAME} Time to handle a request, by route.\n# TYPE {NAME} histogram\n");
+        let Ok(routes) = self.routes.lock() else {
+            return out;
+        };
+        for ((method, route), histogram) in routes.iter() {
+            let labels = format!("method=\"{}\",route=\"{}\"", method, route);
+            let mut cumulative = 0;
+            for (i, count) in histogram.buckets.iter().enumerate() {
+                cumulative += count;
+                let le = BUCKETS
+                    .get(i)
+                    .map_or_else(|| "+Inf".to_string(), |b| b.to_string());
+                let _ = writeln!(out, "{NAME}_bucket{{{labels},le=\"{le}\"}} {cumulative}");
+            }
+            let _ = writeln!(out, "{NAME}_sum{{{labels}}} {}", histogram.sum);
+            let _ = writeln!(out, "{NAME}_count{{{labels}}} {}", histogram.count);
+        }
+        out
+    }
+}
+
+/// A single unlabelled gauge.
+pub fn render_gauge(name: &str, help: &str, value: usize) -> String {
+    format!("# HELP {name} {help}\n# TYPE {name} gauge\n{name} {value}\n")
+}
+
+/// A gauge of each peer's `PullPosition::lag` as of its last pull. Peers
+/// never pulled from, or too old to send sequence numbers, are left out.
+pub fn render_peer_lag(peers: &HashMap<String, PeerStatus>) -> String {
+    const NAME: &str = "openherd_peer_lag";
+    let mut out = format!(
+        "# HELP {NAME} Sequence numbers this node is behind a peer's outbox.\n# TYPE {NAME} gauge\n"
+    );
+    let sorted: BTreeMap<_, _> = peers.iter().collect();
+    for (addr, peer) in sorted {
+        if let Some(position) = peer.position {
+            let _ = writeln!(out, "{NAME}{{peer=\"{}\"}} {}", addr, position.lag);
+        }
+    }
+    out
+}
+
+/// Times each request by its route pattern, so `/_openherd/karma/:code`
+/// is one series however many codes are used.
+pub async fn track_requests(
+    State(metrics): State<Arc<RequestMetrics>>,
+    req: Request,
+    next: Next,
+) -> Response {
+    let method = req.method().to_string();
+    let route = req
+        .extensions()
+        .get::<MatchedPath>()
+        .map_or_else(|| "unmatched".to_string(), |p| p.as_str().to_string());
+    let path = req.uri().path().to_string();
+    let started = Instant::now();
+    let response = next.run(req).await;
+    let elapsed = started.elapsed();
+
+    metrics.observe(&method, &route, elapsed);
+    if metrics.slow_threshold.is_some_and(|t| elapsed >= t) {
+        eprintln!(
+            "Slow request: {} {} took {} ms",
+            method,
+            path,
+            elapsed.as_millis()
+        );
+    }
+    response
+}
@@ -0,0 +1,758 @@
+use crate::config::Config;
+use crate::federation;
+use crate::handlers;
+use crate::metrics::{self, RequestMetrics};
+use crate::state::SharedState;
+use crate::wire::GEO_PRECISION_HEADER;
+use axum::{
+    error_handling::HandleErrorLayer,
+    http::{header, HeaderName, HeaderValue, Method},
+    middleware,
+    response::Response,
+    routing::{delete, get, patch, post, MethodRouter},
+    Router,
+};
+use std::sync::Arc;
+use tower::limit::GlobalConcurrencyLimitLayer;
+use tower::ServiceBuilder;
+use tower_http::cors::{AllowOrigin, CorsLayer};
+
+/// CORS for the admin routes: only the configured origins, and only the
+/// methods and headers the admin UI uses.
+fn admin_cors(origins: &[String]) -> CorsLayer {
+    let origins: Vec<HeaderValue> = origins
+        .iter()
+        .filter_map(
+            |o| match HeaderValue::from_str(o.trim().trim_end_matches('/')) {
+                Ok(v) => Some(v),
+                Err(_) => {
+                    eprintln!("Ignoring invalid admin CORS origin: {}", o);
+                    None
+                }
+            },
+        )
+        .collect();
+    CorsLayer::new()
+        .allow_origin(AllowOrigin::list(origins))
+        .allow_methods([Method::GET, Method::POST, Method::DELETE])
+        .allow_headers([
+            header::CONTENT_TYPE,
+            header::AUTHORIZATION,
+            HeaderName::from_static("x-admin-password"),
+            HeaderName::from_static(federation::ADMIN_KEY_HEADER),
+            HeaderName::from_static(federation::DATE_HEADER),
+            HeaderName::from_static(federation::SIGNATURE_HEADER),
+        ])
+}
+
+pub fn router(state: SharedState) -> Router {
+    let (config, request_metrics) = match state.lock() {
+        Ok(s) => (s.config.clone(), s.request_metrics.clone()),
+        Err(_) => (Config::default(), Arc::new(RequestMetrics::new(None))),
+    };
+
+    // Never shed, so monitoring can tell a busy node from a dead one.
+    let probes = Router::new()
+        .route("/_openherd/health", get(handlers::health))
+        .route("/_openherd/metrics", get(handlers::metrics))
+        .layer(CorsLayer::permissive());
+    // The routes that serve posts, whose coordinates `--geo-precision` rounds.
+    let mut located = Router::new()
+        .route("/_openherd/outbox", get(handlers::outbox))
+        .route("/_openherd/outbox.ndjson", get(handlers::outbox_ndjson))
+        .route("/_openherd/outbox/by-ids", post(handlers::outbox_by_ids))
+        .route("/_openherd/search", get(handlers::search))
+        .route("/_openherd/posts/bbox", post(handlers::posts_in_bbox))
+        .route("/_openherd/posts/by-keys", post(handlers::posts_by_keys))
+        .route("/_openherd/post/:id/detail", get(handlers::post_detail))
+        .route("/_openherd/post/:id/quotes", get(handlers::post_quotes));
+    if let Some(decimals) = config.geo_precision {
+        let value = HeaderValue::from(decimals);
+        located = located.layer(middleware::map_response(move |mut response: Response| {
+            let value = value.clone();
+            async move {
+                response
+                    .headers_mut()
+                    .insert(HeaderName::from_static(GEO_PRECISION_HEADER), value);
+                response
+            }
+        }));
+    }
+    let mut public = located
+        .route("/_openherd/health/ready", get(handlers::health_ready))
+        .route("/_openherd/outbox/ids", get(handlers::outbox_ids))
+        .route("/_openherd/node", get(handlers::node_info))
+        .route("/_openherd/capabilities", get(handlers::capabilities))
+        .route("/_openherd/peers", get(handlers::peers))
+        .route("/_openherd/sync", post(handlers::sync))
+        .route("/_openherd/density", get(handlers::density))
+        .route("/_openherd/timespan", get(handlers::timespan))
+        .route("/_openherd/lookup", post(handlers::lookup))
+        .route(
+            "/_openherd/moderation/lookup",
+            post(handlers::moderation_lookup),
+        )
+        .route(
+            "/_openherd/moderation/lookup/definitions",
+            post(handlers::moderation_lookup_definitions),
+        )
+        .route(
+            "/_openherd/moderation/labels",
+            get(handlers::moderation_labels),
+        )
+        .route(
+            "/_openherd/moderation/post-labels",
+            get(handlers::moderation_post_labels),
+        );
+    if config.websub {
+        public = public.route("/_openherd/hub", post(handlers::websub_hub));
+    }
+    if let Some(inbox) = inbox_route(&state, &config) {
+        public = public.route("/_openherd/inbox", inbox);
+    }
+    if !config.mirror {
+        public = public.route("/_openherd/post", post(handlers::submit_post));
+    }
+    if config.enable_karma {
+        public = public.merge(karma_routes(config.mirror));
+    }
+    if !config.reactions.is_empty() {
+        let mut reactions = get(handlers::post_reactions);
+        if !config.mirror {
+            reactions = reactions.post(handlers::add_reaction);
+        }
+        public = public.route("/_openherd/post/:id/reactions", reactions);
+    }
+    if config.enable_reports {
+        public = public
+            .route(
+                "/_openherd/moderation/report-counts",
+                post(handlers::moderation_report_counts),
+            )
+            .route(
+                "/_openherd/moderation/post/:id/reasons",
+                get(handlers::moderation_report_reasons),
+            );
+        if !config.mirror {
+            public = public.route(
+                "/_openherd/moderation/report",
+                post(handlers::moderation_report),
+            );
+        }
+    }
+
+    let mut app = public.layer(CorsLayer::permissive());
+    if config.enable_admin {
+        app = app.merge(admin_routes(&state, &config));
+    }
+    app = app.fallback(handlers::not_found);
+    shed_load(app, config.max_concurrent_requests)
+        .merge(probes)
+        .layer(middleware::from_fn_with_state(
+            request_metrics,
+            metrics::track_requests,
+        ))
+        .with_state(state)
+}
+
+/// Answers requests to `app` beyond `max` in flight with `overloaded`
+/// instead of queueing them. 0 leaves `app` unlimited. Routes merged in
+/// afterwards are not limited.
+fn shed_load<S: Clone + Send + Sync + 'static>(app: Router<S>, max: usize) -> Router<S> {
+    if max == 0 {
+        return app;
+    }
+    // `Router::layer` wraps each route separately, so the limit must be the
+    // global kind to be shared between them.
+    app.layer(
+        ServiceBuilder::new()
+            .layer(HandleErrorLayer::new(handlers::overloaded))
+            .load_shed()
+            .layer(GlobalConcurrencyLimitLayer::new(max)),
+    )
+}
+
+/// The inbox route, signature-guarded when required. A mirror only takes
+/// signed pushes from trusted peers, so with none configured it has no inbox.
+fn inbox_route(state: &SharedState, config: &Config) -> Option<MethodRouter<SharedState>> {
+    if config.mirror && config.inbox_trusted_keys.is_empty() {
+        return None;
+    }
+    let route = post(handlers::inbox);
+    if config.require_signed_inbox || config.mirror {
+        Some(route.route_layer(middleware::from_fn_with_state(
+            state.clone(),
+            handlers::inbox_signature_guard,
+        )))
+    } else {
+        Some(route)
+    }
+}
+
+/// Karma routes; a mirror only serves the read-only ones.
+fn karma_routes(mirror: bool) -> Router<SharedState> {
+    let mut metadata = get(handlers::karma_metadata);
+    let mut karma = Router::new();
+    if !mirror {
+        metadata = metadata.delete(handlers::karma_revoke);
+        karma = karma
+            .route(
+                "/_openherd/karma/:code/upvote",
+                patch(handlers::karma_upvote),
+            )
+            .route(
+                "/_openherd/karma/:code/downvote",
+                patch(handlers::karma_downvote),
+            );
+    }
+    // Metadata is served with and without the trailing slash; older
+    // clients use the slash form.
+    karma
+        .route("/_openherd/karma/:code", metadata)
+        .route("/_openherd/karma/:code/", get(handlers::karma_metadata))
+        .route("/_openherd/karma/lookup", post(handlers::karma_lookup))
+}
+
+fn admin_routes(state: &SharedState, config: &Config) -> Router<SharedState> {
+    let mut admin = Router::new()
+        .route("/_openherd/admin", get(handlers::admin_ui))
+        .route("/_openherd/admin/login", post(handlers::admin_login))
+        .route("/_openherd/admin/logout", post(handlers::admin_logout))
+        .route("/_openherd/admin/peers", get(handlers::admin_peers))
+        .route(
+            "/_openherd/admin/peers/:address",
+            delete(handlers::admin_remove_peer),
+        )
+        .route(
+            "/_openherd/admin/peers/:address/trust",
+            post(handlers::admin_trust_peer).delete(handlers::admin_untrust_peer),
+        )
+        .route(
+            "/_openherd/admin/moderation/labels",
+            post(handlers::admin_add_label),
+        )
+        .route(
+            "/_openherd/admin/moderation/labels/:label",
+            delete(handlers::admin_delete_label),
+        )
+        .route(
+            "/_openherd/admin/import-labels",
+            post(handlers::admin_import_labels),
+        )
+        .route("/_openherd/admin/pins", get(handlers::admin_pinned_posts))
+        .route(
+            "/_openherd/admin/pin/:id",
+            post(handlers::admin_pin_post).delete(handlers::admin_unpin_post),
+        )
+        .route(
+            "/_openherd/admin/content-filter/reload",
+            post(handlers::admin_reload_content_filter),
+        );
+    if config.enable_reports {
+        admin = admin
+            .route("/_openherd/admin/reports", post(handlers::admin_reports))
+            .route(
+                "/_openherd/admin/reports/by-category",
+                post(handlers::admin_reports_by_category),
+            )
+            .route(
+                "/_openherd/admin/reports/archived",
+                get(handlers::admin_archived_reports),
+            )
+            .route(
+                "/_openherd/admin/dead-letters",
+                get(handlers::admin_dead_letters).delete(handlers::admin_clear_dead_letters),
+            )
+            .route(
+                "/_openherd/admin/accept",
+                post(handlers::admin_accept_report),
+            )
+            .route(
+                "/_openherd/admin/delete/:id",
+                delete(handlers::admin_delete_report),
+            );
+    }
+    if config.enable_karma {
+        admin = admin
+            .route(
+                "/_openherd/admin/karma/codes",
+                post(handlers::admin_generate_karma_codes),
+            )
+            .route(
+                "/_openherd/admin/karma/codes.txt",
+                post(handlers::admin_generate_karma_codes_text),
+            )
+            .route(
+                "/_openherd/admin/karma/codes.csv",
+                post(handlers::admin_generate_karma_codes_csv),
+            )
+            .route(
+                "/_openherd/admin/karma/:code/history",
+                get(handlers::admin_karma_history),
+            )
+            .route(
+                "/_openherd/admin/karma/votes",
+                get(handlers::admin_issuer_votes),
+            )
+            .route(
+                "/_openherd/admin/karma/issuer-stats",
+                post(handlers::admin_issuer_stats),
+            )
+            .route(
+                "/_openherd/admin/karma/status",
+                post(handlers::admin_karma_status),
+            )
+            .route(
+                "/_openherd/admin/karma/recompute",
+                post(handlers::admin_recompute_karma),
+            );
+    }
+    // The signature guard runs first: a signed request needs no other
+    // credential to get past the CSRF guard.
+    admin
+        .route_layer(middleware::from_fn(handlers::admin_csrf_guard))
+        .route_layer(middleware::from_fn_with_state(
+            state.clone(),
+            handlers::admin_signature_guard,
+        ))
+        .layer(admin_cors(&config.admin_cors_origins))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::AppState;
+    use axum::body::Body;
+    use axum::http::{Request, StatusCode};
+    use std::sync::{Arc, Mutex};
+    use tower::ServiceExt;
+
+    fn app() -> Router {
+        app_with(Config::default())
+    }
+
+    fn app_with(config: Config) -> Router {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        router(Arc::new(Mutex::new(AppState::new(db, config))))
+    }
+
+    fn preflight(path: &str, origin: &str) -> Request<Body> {
+        Request::options(path)
+            .header("Origin", origin)
+            .header("Access-Control-Request-Method", "POST")
+            .body(Body::empty())
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_admin_form_post_is_rejected() {
+        let form = Request::post("/_openherd/admin/accept")
+            .header("Content-Type", "application/x-www-form-urlencoded")
+            .body(Body::from("report_id=abc&label=spam"))
+            .unwrap();
+        let resp = app().oneshot(form).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+
+        let with_header = Request::post("/_openherd/admin/accept")
+            .header("Content-Type", "application/json")
+            .header("X-Admin-Password", "wrong")
+            .body(Body::from(r#"{"report_id":"abc","label":null}"#))
+            .unwrap();
+        let resp = app().oneshot(with_header).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn test_admin_cors_is_restricted_to_configured_origins() {
+        let config = Config {
+            admin_cors_origins: vec!["https://admin.example.org".to_string()],
+            ..Config::default()
+        };
+        let allowed = app_with(config.clone())
+            .oneshot(preflight(
+                "/_openherd/admin/accept",
+                "https://admin.example.org",
+            ))
+            .await
+            .unwrap();
+        assert_eq!(
+            allowed.headers()["access-control-allow-origin"],
+            "https://admin.example.org"
+        );
+
+        let denied = app_with(config.clone())
+            .oneshot(preflight("/_openherd/admin/accept", "https://evil.example"))
+            .await
+            .unwrap();
+        assert!(!denied.headers().contains_key("access-control-allow-origin"));
+
+        let federation = app_with(config)
+            .oneshot(preflight("/_openherd/inbox", "https://evil.example"))
+            .await
+            .unwrap();
+        assert_eq!(federation.headers()["access-control-allow-origin"], "*");
+    }
+
+    #[tokio::test]
+    async fn test_karma_metadata_with_and_without_trailing_slash() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let mut state = AppState::new(db, Config::default());
+        state.karma_codes.insert(
+            "AAAAA-BBBBB".to_string(),
+            crate::types::KarmaCode {
+                code: "AAAAA-BBBBB".to_string(),
+                issuer: "https://example.org".to_string(),
+                vote_type: None,
+                expires: chrono::Utc::now() + chrono::Duration::days(1),
+                region: None,
+                current_post: None,
+                used_direction: None,
+                history: Vec::new(),
+                weight: 1,
+            },
+        );
+        let app = router(Arc::new(Mutex::new(state)));
+
+        for path in [
+            "/_openherd/karma/AAAAA-BBBBB",
+            "/_openherd/karma/AAAAA-BBBBB/",
+        ] {
+            let resp = app
+                .clone()
+                .oneshot(Request::get(path).body(Body::empty()).unwrap())
+                .await
+                .unwrap();
+            assert_eq!(resp.status(), StatusCode::OK, "{}", path);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_disabled_endpoint_groups_are_not_routed() {
+        let config = Config {
+            enable_karma: false,
+            enable_reports: false,
+            enable_admin: false,
+            ..Config::default()
+        };
+        let get = |path: &str| Request::get(path).body(Body::empty()).unwrap();
+
+        let karma = app_with(config.clone())
+            .oneshot(get("/_openherd/karma/AAAAA-BBBBB"))
+            .await
+            .unwrap();
+        assert_eq!(karma.status(), StatusCode::NOT_FOUND);
+
+        let admin = app_with(config.clone())
+            .oneshot(get("/_openherd/admin"))
+            .await
+            .unwrap();
+        assert_eq!(admin.status(), StatusCode::NOT_FOUND);
+
+        let report = Request::post("/_openherd/moderation/report")
+            .header("Content-Type", "application/json")
+            .body(Body::from("[]"))
+            .unwrap();
+        let resp = app_with(config.clone()).oneshot(report).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+
+        let outbox = app_with(config)
+            .oneshot(get("/_openherd/outbox"))
+            .await
+            .unwrap();
+        assert_eq!(outbox.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_signed_inbox_requires_trusted_node_key() {
+        let trusted = crate::federation::NodeKey::generate().unwrap();
+        let stranger = crate::federation::NodeKey::generate().unwrap();
+        let config = Config {
+            require_signed_inbox: true,
+            inbox_trusted_keys: vec![trusted.fingerprint()],
+            ..Config::default()
+        };
+        let body = serde_json::to_vec(&vec![crate::validation::testing::signed_envelope(
+            "signed push",
+        )])
+        .unwrap();
+        let push = |key: Option<&crate::federation::NodeKey>| {
+            let mut req =
+                Request::post("/_openherd/inbox").header("Content-Type", "application/json");
+            if let Some(key) = key {
+                for (name, value) in key.sign_push(&body).unwrap() {
+                    req = req.header(name, value);
+                }
+            }
+            req.body(Body::from(body.clone())).unwrap()
+        };
+
+        let unsigned = app_with(config.clone()).oneshot(push(None)).await.unwrap();
+        assert_eq!(unsigned.status(), StatusCode::UNAUTHORIZED);
+
+        let untrusted = app_with(config.clone())
+            .oneshot(push(Some(&stranger)))
+            .await
+            .unwrap();
+        assert_eq!(untrusted.status(), StatusCode::FORBIDDEN);
+
+        let signed = app_with(config)
+            .oneshot(push(Some(&trusted)))
+            .await
+            .unwrap();
+        assert_eq!(signed.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_signed_admin_requests() {
+        let admin = crate::federation::NodeKey::generate().unwrap();
+        let stranger = crate::federation::NodeKey::generate().unwrap();
+        let config = Config {
+            admin_signing_keys: vec![admin.fingerprint()],
+            ..Config::default()
+        };
+        let peers = "/_openherd/admin/peers";
+        let signed = |key: &crate::federation::NodeKey, signed_path: &str, path: &str| {
+            let mut req = Request::get(path);
+            for (name, value) in key.sign_admin_request("GET", signed_path, b"").unwrap() {
+                req = req.header(name, value);
+            }
+            req.body(Body::empty()).unwrap()
+        };
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let state = Arc::new(Mutex::new(AppState::new(db, config.clone())));
+        let status = |req: Request<Body>| {
+            let app = router(state.clone());
+            async move { app.oneshot(req).await.unwrap().status() }
+        };
+
+        let request = signed(&admin, peers, peers);
+        let mut replay = Request::get(peers).body(Body::empty()).unwrap();
+        *replay.headers_mut() = request.headers().clone();
+        // The same signature, encoded differently, still verifies.
+        use base64::{engine::general_purpose::STANDARD, Engine};
+        let armored = String::from_utf8(
+            STANDARD
+                .decode(&request.headers()[federation::SIGNATURE_HEADER])
+                .unwrap(),
+        )
+        .unwrap()
+            + "\n";
+        let mut reencoded = Request::get(peers).body(Body::empty()).unwrap();
+        *reencoded.headers_mut() = request.headers().clone();
+        reencoded.headers_mut().insert(
+            federation::SIGNATURE_HEADER,
+            STANDARD.encode(armored).parse().unwrap(),
+        );
+        assert_eq!(status(request).await, StatusCode::OK);
+        assert_eq!(status(replay).await, StatusCode::UNAUTHORIZED);
+        assert_eq!(status(reencoded).await, StatusCode::UNAUTHORIZED);
+        assert_eq!(
+            status(signed(&stranger, peers, peers)).await,
+            StatusCode::FORBIDDEN
+        );
+        let moved = signed(&admin, peers, "/_openherd/admin/reports/archived");
+        assert_eq!(status(moved).await, StatusCode::UNAUTHORIZED);
+        // The token standing in for the password lives for one request.
+        assert!(state.lock().unwrap().admin_tokens.is_empty());
+
+        let required = app_with(Config {
+            require_admin_signature: true,
+            ..config
+        });
+        let unsigned = Request::get(peers)
+            .header("X-Admin-Password", "admin")
+            .body(Body::empty())
+            .unwrap();
+        let resp = required.clone().oneshot(unsigned).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+        // With both required, a signature alone is not enough.
+        let resp = required
+            .oneshot(signed(&admin, peers, peers))
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn test_errors_carry_json_body() {
+        let resp = app()
+            .oneshot(
+                Request::get("/_openherd/karma/NOPE-NOPE")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+        let bytes = axum::body::to_bytes(resp.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let body: crate::error::ErrorBody = serde_json::from_slice(&bytes).unwrap();
+        assert_eq!(body.code, "not_found");
+        assert_eq!(body.message, "unknown karma code");
+    }
+
+    #[tokio::test]
+    async fn test_requests_past_concurrency_limit_are_shed() {
+        // The first request to `/block` holds the only slot until released.
+        let (entered_tx, entered_rx) = tokio::sync::oneshot::channel();
+        let (release_tx, release_rx) = tokio::sync::oneshot::channel::<()>();
+        let gate = Arc::new(Mutex::new(Some((entered_tx, release_rx))));
+        let block = move || {
+            let gate = gate.clone();
+            async move {
+                let (entered, release) = gate.lock().unwrap().take().unwrap();
+                entered.send(()).unwrap();
+                release.await.unwrap();
+            }
+        };
+        let limited = Router::new()
+            .route("/block", get(block))
+            .route("/other", get(|| async {}));
+        let app = shed_load(limited, 1).merge(Router::new().route("/probe", get(|| async {})));
+        let request = |path| Request::get(path).body(Body::empty()).unwrap();
+
+        let first = tokio::spawn(app.clone().oneshot(request("/block")));
+        entered_rx.await.unwrap();
+        let shed = app.clone().oneshot(request("/other")).await.unwrap();
+        assert_eq!(shed.status(), StatusCode::SERVICE_UNAVAILABLE);
+        let probe = app.clone().oneshot(request("/probe")).await.unwrap();
+        assert_eq!(probe.status(), StatusCode::OK);
+
+        release_tx.send(()).unwrap();
+        assert_eq!(first.await.unwrap().unwrap().status(), StatusCode::OK);
+        let after = app.oneshot(request("/other")).await.unwrap();
+        assert_eq!(after.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_rounded_responses_carry_geo_precision() {
+        let get = |path| Request::get(path).body(Body::empty()).unwrap();
+        let rounded = app_with(Config {
+            geo_precision: Some(3),
+            ..Config::default()
+        });
+        let outbox = rounded
+            .clone()
+            .oneshot(get("/_openherd/outbox"))
+            .await
+            .unwrap();
+        assert_eq!(outbox.headers()[GEO_PRECISION_HEADER], "3");
+        let ids = rounded.oneshot(get("/_openherd/outbox/ids")).await.unwrap();
+        assert!(!ids.headers().contains_key(GEO_PRECISION_HEADER));
+
+        let exact = app().oneshot(get("/_openherd/outbox")).await.unwrap();
+        assert!(!exact.headers().contains_key(GEO_PRECISION_HEADER));
+    }
+
+    #[tokio::test]
+    async fn test_unknown_routes_get_json_not_found() {
+        let resp = app()
+            .oneshot(
+                Request::get("/_openherd/outbx?limit=5")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+        let bytes = axum::body::to_bytes(resp.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let body: crate::error::ErrorBody = serde_json::from_slice(&bytes).unwrap();
+        assert_eq!(body.code, "not_found");
+        assert_eq!(body.message, "no route for GET /_openherd/outbx");
+    }
+
+    #[tokio::test]
+    async fn test_mirror_mode_refuses_client_writes() {
+        let config = Config {
+            mirror: true,
+            ..Config::default()
+        };
+        let json_post = |method: Method, path: &str| {
+            Request::builder()
+                .method(method)
+                .uri(path)
+                .header("Content-Type", "application/json")
+                .body(Body::from("[]"))
+                .unwrap()
+        };
+
+        for (method, path) in [
+            (Method::POST, "/_openherd/inbox"),
+            (Method::POST, "/_openherd/post"),
+            (Method::PATCH, "/_openherd/karma/AAAAA-BBBBB/upvote"),
+            (Method::PATCH, "/_openherd/karma/AAAAA-BBBBB/downvote"),
+            (Method::POST, "/_openherd/moderation/report"),
+        ] {
+            let resp = app_with(config.clone())
+                .oneshot(json_post(method, path))
+                .await
+                .unwrap();
+            assert_eq!(resp.status(), StatusCode::NOT_FOUND, "{}", path);
+        }
+        let revoke = app_with(config.clone())
+            .oneshot(json_post(Method::DELETE, "/_openherd/karma/AAAAA-BBBBB"))
+            .await
+            .unwrap();
+        assert_eq!(revoke.status(), StatusCode::METHOD_NOT_ALLOWED);
+
+        let outbox = app_with(config.clone())
+            .oneshot(
+                Request::get("/_openherd/outbox")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(outbox.status(), StatusCode::OK);
+
+        let with_peers = Config {
+            inbox_trusted_keys: vec!["abcdef".to_string()],
+            ..config
+        };
+        let unsigned = app_with(with_peers)
+            .oneshot(json_post(Method::POST, "/_openherd/inbox"))
+            .await
+            .unwrap();
+        assert_eq!(unsigned.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn test_request_timings_are_exposed_per_route() {
+        let app = app();
+        for path in [
+            "/_openherd/karma/AAAAA-BBBBB",
+            "/_openherd/karma/CCCCC-DDDDD",
+        ] {
+            app.clone()
+                .oneshot(Request::get(path).body(Body::empty()).unwrap())
+                .await
+                .unwrap();
+        }
+
+        let resp = app
+            .oneshot(
+                Request::get("/_openherd/metrics")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        let bytes = axum::body::to_bytes(resp.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let text = String::from_utf8(bytes.to_vec()).unwrap();
+        assert!(text.contains("# TYPE openherd_request_duration_seconds histogram"));
+        assert!(
+            text.contains(
+                "openherd_request_duration_seconds_count{method=\"GET\",route=\"/_openherd/karma/:code\"} 2"
+            ),
+            "{}",
+            text
+        );
+        assert!(text.contains("route=\"/_openherd/karma/:code\",le=\"+Inf\"} 2"));
+    }
+}
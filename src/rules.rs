@@ -0,0 +1,80 @@
+//! Auto-labelling rules from `--label-rules`: a JSON array such as
+//!
+//! ```json
+//! [{"name": "link-spam", "label": "spam", "text": "(?i)buy now", "min_reports": 2}]
+//! ```
+//!
+//! A rule matches when every criterion it sets holds: `text` is a regex
+//! searched in the post text, `karma_below` requires net karma under the
+//! value, and `min_reports` requires at least that many distinct reporters.
+//! Rules only label posts that have no label yet, and the first match wins.
+
+use crate::types::Post;
+use regex::Regex;
+use serde::Deserialize;
+use std::path::Path;
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(try_from = "RuleSpec")]
+pub struct LabelRule {
+    pub name: String,
+    /// Label id or name; resolved to an id when the rules are loaded.
+    pub label: String,
+    text: Option<Regex>,
+    karma_below: Option<i32>,
+    min_reports: Option<usize>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RuleSpec {
+    name: String,
+    label: String,
+    #[serde(default)]
+    text: Option<String>,
+    #[serde(default)]
+    karma_below: Option<i32>,
+    #[serde(default)]
+    min_reports: Option<usize>,
+}
+
+impl TryFrom<RuleSpec> for LabelRule {
+    type Error = String;
+
+    fn try_from(spec: RuleSpec) -> Result<Self, String> {
+        if spec.text.is_none() && spec.karma_below.is_none() && spec.min_reports.is_none() {
+            return Err(format!("rule {} has no criteria", spec.name));
+        }
+        let text = spec
+            .text
+            .map(|pattern| Regex::new(&pattern))
+            .transpose()
+            .map_err(|e| format!("rule {}: {}", spec.name, e))?;
+        Ok(Self {
+            name: spec.name,
+            label: spec.label,
+            text,
+            karma_below: spec.karma_below,
+            min_reports: spec.min_reports,
+        })
+    }
+}
+
+impl LabelRule {
+    pub fn matches(&self, post: &Post, karma: i32, reporters: usize) -> bool {
+        self.text.as_ref().is_none_or(|re| re.is_match(&post.text))
+            && self.karma_below.is_none_or(|limit| karma < limit)
+            && self.min_reports.is_none_or(|min| reporters >= min)
+    }
+
+    /// Recorded in `post_label_sources` for labels this rule applied.
+    pub fn source(&self) -> String {
+        format!("rule:{}", self.name)
+    }
+}
+
+pub fn load(path: &Path) -> Result<Vec<LabelRule>, String> {
+    let contents =
+        std::fs::read_to_string(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+    serde_json::from_str(&contents).map_err(|e| format!("{}: {}", path.display(), e))
+}
@@ -0,0 +1,168 @@
+use crate::config::PostDateSource;
+use crate::types::{Envelope, Post};
+use chrono::{DateTime, Utc};
+use std::collections::HashMap;
+use std::path::Path;
+use tantivy::collector::TopDocs;
+use tantivy::directory::MmapDirectory;
+use tantivy::query::QueryParser;
+use tantivy::schema::{Field, Schema, Value, STORED, STRING, TEXT};
+use tantivy::snippet::SnippetGenerator;
+use tantivy::{doc, Index, IndexReader, IndexWriter, ReloadPolicy, TantivyDocument, Term};
+
+const WRITER_HEAP_BYTES: usize = 15_000_000;
+
+/// A ranked search result: the post id, its relevance score and an HTML
+/// snippet with the matched terms wrapped in `<b>`.
+#[derive(Debug, Clone)]
+pub struct IndexHit {
+    pub id: String,
+    pub score: f32,
+    pub snippet: String,
+}
+
+/// Full-text index over post text, kept in step with `memory` by the import
+/// and removal paths on `AppState`.
+pub struct SearchIndex {
+    index: Index,
+    writer: IndexWriter,
+    reader: IndexReader,
+    id_field: Field,
+    text_field: Field,
+}
+
+fn schema() -> (Schema, Field, Field) {
+    let mut builder = Schema::builder();
+    let id_field = builder.add_text_field("id", STRING | STORED);
+    let text_field = builder.add_text_field("text", TEXT | STORED);
+    (builder.build(), id_field, text_field)
+}
+
+impl SearchIndex {
+    /// Opens the index stored in `dir`, creating it if needed.
+    pub fn open(dir: &Path) -> tantivy::Result<Self> {
+        std::fs::create_dir_all(dir)?;
+        let (schema, _, _) = schema();
+        let directory = MmapDirectory::open(dir)?;
+        Self::from_index(Index::open_or_create(directory, schema)?)
+    }
+
+    pub fn in_memory() -> tantivy::Result<Self> {
+        let (schema, _, _) = schema();
+        Self::from_index(Index::create_in_ram(schema))
+    }
+
+    fn from_index(index: Index) -> tantivy::Result<Self> {
+        let (_, id_field, text_field) = schema();
+        let writer = index.writer_with_num_threads(1, WRITER_HEAP_BYTES)?;
+        let reader = index
+            .reader_builder()
+            .reload_policy(ReloadPolicy::Manual)
+            .try_into()?;
+        Ok(Self {
+            index,
+            writer,
+            reader,
+            id_field,
+            text_field,
+        })
+    }
+
+    pub fn num_docs(&self) -> u64 {
+        self.reader.searcher().num_docs()
+    }
+
+    /// Stages `post` for indexing, replacing any previous document with its id.
+    pub fn add(&mut self, post: &Post) -> tantivy::Result<()> {
+        self.remove(&post.id);
+        self.writer.add_document(doc!(
+            self.id_field => post.id.as_str(),
+            self.text_field => post.text.as_str(),
+        ))?;
+        Ok(())
+    }
+
+    pub fn remove(&mut self, id: &str) {
+        self.writer
+            .delete_term(Term::from_field_text(self.id_field, id));
+    }
+
+    /// Makes staged changes durable and visible to searches.
+    pub fn commit(&mut self) -> tantivy::Result<()> {
+        self.writer.commit()?;
+        self.reader.reload()
+    }
+
+    /// Drops every document and indexes `memory` from scratch.
+    pub fn rebuild(&mut self, memory: &HashMap<String, Envelope>) -> tantivy::Result<usize> {
+        self.writer.delete_all_documents()?;
+        let mut count = 0;
+        for env in memory.values() {
+            if let Ok(post) = serde_json::from_str::<Post>(&env.data) {
+                self.add(&post)?;
+                count += 1;
+            }
+        }
+        self.commit()?;
+        Ok(count)
+    }
+
+    pub fn search(
+        &self,
+        query: &str,
+        offset: usize,
+        limit: usize,
+    ) -> tantivy::Result<Vec<IndexHit>> {
+        let searcher = self.reader.searcher();
+        let parser = QueryParser::for_index(&self.index, vec![self.text_field]);
+        let (query, _) = parser.parse_query_lenient(query);
+        let top = searcher.search(
+            &query,
+            &TopDocs::with_limit(limit.max(1)).and_offset(offset),
+        )?;
+        let snippets = SnippetGenerator::create(&searcher, &*query, self.text_field)?;
+
+        let mut hits = Vec::with_capacity(top.len());
+        for (score, address) in top {
+            let doc: TantivyDocument = searcher.doc(address)?;
+            let Some(id) = doc.get_first(self.id_field).and_then(|v| v.as_str()) else {
+                continue;
+            };
+            hits.push(IndexHit {
+                id: id.to_string(),
+                score,
+                snippet: snippets.snippet_from_doc(&doc).to_html(),
+            });
+        }
+        Ok(hits)
+    }
+}
+
+/// Case-insensitive substring scan over decoded post text, newest first.
+/// Used when no index is available and as the baseline in the search bench.
+pub fn naive_search<'a>(
+    memory: &'a HashMap<String, Envelope>,
+    needle: &str,
+    offset: usize,
+    limit: usize,
+    date_source: PostDateSource,
+) -> Vec<&'a Envelope> {
+    let needle = needle.to_lowercase();
+    let mut matches: Vec<(DateTime<Utc>, Post, &Envelope)> = memory
+        .values()
+        .filter_map(|env| {
+            let post: Post = serde_json::from_str(&env.data).ok()?;
+            post.text
+                .to_lowercase()
+                .contains(&needle)
+                .then(|| (date_source.date_of(env, &post), post, env))
+        })
+        .collect();
+    matches.sort_by(|a, b| b.0.cmp(&a.0).then_with(|| a.1.id.cmp(&b.1.id)));
+    matches
+        .into_iter()
+        .skip(offset)
+        .take(limit)
+        .map(|(_, _, env)| env)
+        .collect()
+}
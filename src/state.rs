@@ -1,56 +1,1515 @@
-use crate::types::{Envelope, KarmaCode, ModerationReport};
+use crate::auth::{backend_from_config, AdminBackend};
+use crate::config::{Config, FlushPolicy, ReportOverflow};
+use crate::federation::NodeKey;
+use crate::filter::ContentFilter;
+use crate::geo::GeoIndex;
+use crate::metrics::RequestMetrics;
+use crate::rules::LabelRule;
+use crate::search::SearchIndex;
+use crate::store;
+use crate::types::{
+    ArchivedReport, DeadLetter, Envelope, KarmaCode, ModerationLabel, ModerationReport, PinnedPost,
+    Post, Reaction, Submitter, SyncDirection, ValidationError, WebSubSubscription,
+};
+use crate::validation::{validate_envelope_unverified, validate_envelope_with_keyring, Keyring};
 use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque};
+use std::sync::Arc;
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+/// Consecutive failed syncs after which a peer is quarantined.
+pub const PEER_FAILURE_LIMIT: u8 = 5;
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct PeerStatus {
     pub failures: u8,
     pub last_ok: Option<DateTime<Utc>>,
+    #[serde(default)]
+    pub last_sync: Option<SyncOutcome>,
+    /// When the peer reached `PEER_FAILURE_LIMIT`. Cleared by a successful
+    /// sync; once `peer_removal_grace_secs` have passed, it is removed.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub quarantined_since: Option<DateTime<Utc>>,
+    /// Where the last pull left us in the peer's sequence numbers.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub position: Option<PullPosition>,
+    /// Set by an admin. With `--trust-peer-validation`, envelopes pulled
+    /// from this peer are not signature-checked again here.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub trusted: bool,
+    /// The peer's `X-OpenHerd-Seq` as of our last pull from it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub remote_change_seq: Option<u64>,
+    /// Our `change_seq` as of our last push to it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pushed_change_seq: Option<u64>,
+}
+
+/// Drops times older than `window_secs` from `times` and, if fewer than
+/// `limit` remain, records now and returns true.
+fn take_slot(times: &mut VecDeque<DateTime<Utc>>, limit: usize, window_secs: u64) -> bool {
+    let now = Utc::now();
+    let window = chrono::Duration::seconds(window_secs as i64);
+    while times.front().is_some_and(|t| *t <= now - window) {
+        times.pop_front();
+    }
+    if times.len() >= limit {
+        return false;
+    }
+    times.push_back(now);
+    true
+}
+
+/// Removes `id` from the posts linking to `target` in `index`.
+fn unlink(index: &mut HashMap<String, BTreeSet<String>>, target: &str, id: &str) {
+    if let Some(linking) = index.get_mut(target) {
+        linking.remove(id);
+        if linking.is_empty() {
+            index.remove(target);
+        }
+    }
+}
+
+fn reaction_key(reaction: &Reaction) -> String {
+    format!(
+        "reaction:{}:{}:{}",
+        reaction.post,
+        reaction.id.to_lowercase(),
+        reaction.reaction
+    )
+}
+
+/// What `post_log_sample` logs of a post. Leaves out the text, which may
+/// be personal, and anything else a client wrote beyond its length.
+fn sampled_post_line(envelope: &Envelope, post: &Post) -> String {
+    format!(
+        "Sampled post {} (seq {}): at {:.3},{:.3}, dated {}, {} chars{}{}",
+        post.id,
+        envelope
+            .seq
+            .map_or_else(|| "-".to_string(), |seq| seq.to_string()),
+        post.latitude,
+        post.longitude,
+        post.date.to_rfc3339_opts(chrono::SecondsFormat::Secs, true),
+        post.text.chars().count(),
+        if post.parent.is_some() { ", reply" } else { "" },
+        if post.quote.is_some() { ", quote" } else { "" },
+    )
+}
+
+impl PeerStatus {
+    /// Keeps the pull position and change sequences from whichever halves
+    /// of `outcome` got far enough to report them.
+    fn record_progress(&mut self, outcome: &SyncOutcome) {
+        self.position = outcome.position.or(self.position);
+        self.remote_change_seq = outcome.change_seq.or(self.remote_change_seq);
+        self.pushed_change_seq = outcome.pushed_change_seq.or(self.pushed_change_seq);
+    }
 }
 
-impl Default for PeerStatus {
-    fn default() -> Self {
+/// How far behind a peer's outbox this node is, in the peer's `seq`s.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PullPosition {
+    /// Highest `seq` among the peer's posts we now hold.
+    pub pulled_seq: u64,
+    /// Highest `seq` in the peer's outbox.
+    pub remote_seq: u64,
+    pub lag: u64,
+}
+
+impl PullPosition {
+    pub fn new(pulled_seq: u64, remote_seq: u64) -> Self {
         Self {
-            failures: 0,
-            last_ok: None,
+            pulled_seq,
+            remote_seq,
+            lag: remote_seq.saturating_sub(pulled_seq),
         }
     }
 }
 
+/// What happened the last time this node synced with a peer.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SyncOutcome {
+    pub at: DateTime<Utc>,
+    pub imported: usize,
+    /// Envelopes we already held unchanged, skipped without verification.
+    #[serde(default)]
+    pub skipped: usize,
+    pub pushed: usize,
+    pub error: Option<String>,
+    /// Set when the pull got far enough to read the peer's outbox, even if
+    /// the sync failed afterwards. Peers too old to send `seq` leave it unset.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub position: Option<PullPosition>,
+    /// The peer's `X-OpenHerd-Seq` when the pull read its outbox. Left
+    /// unset if an envelope was refused for a reason that may pass, such as
+    /// a rate limit, so the next sync pulls again.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub change_seq: Option<u64>,
+    /// Our `change_seq` when a push succeeded.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pushed_change_seq: Option<u64>,
+}
+
 pub struct AppState {
+    pub config: Config,
     pub memory: HashMap<String, Envelope>,
     pub db: sled::Db,
+    pub search_index: Option<SearchIndex>,
+    pub geo_index: GeoIndex,
+    /// Post ids by `Envelope::seq`, for cursor reads of the outbox.
+    pub seq_index: BTreeMap<u64, String>,
+    /// Ids of the posts quoting each post id, whether or not we hold the
+    /// quoted post.
+    pub quote_index: HashMap<String, BTreeSet<String>>,
+    /// Ids of the posts replying to each post id, likewise.
+    pub reply_index: HashMap<String, BTreeSet<String>>,
+    /// Bumped whenever a post is stored new or changed, or removed, and
+    /// persisted under `CHANGE_SEQ_DB_KEY`. Unlike `Envelope::seq` it also
+    /// moves on deletes, so peers can tell from it alone whether anything
+    /// happened since they last looked.
+    pub change_seq: u64,
+    /// Offset for sequence numbers from sled's id counter, which starts
+    /// over in a fresh database. `backup::restore` sets it past every
+    /// restored post's `seq`. Read once at startup.
+    seq_base: u64,
+    pub db_write_failures: u64,
+    pub region_violations: u64,
+    /// New or changed posts stored since startup, for `post_log_sample`.
+    stored_posts: u64,
+    /// Recent post times per signing key fingerprint, for the post rate limit.
+    pub post_times: HashMap<String, VecDeque<DateTime<Utc>>>,
+    /// Likewise for posts clients submit, which have a limit of their own.
+    pub client_post_times: HashMap<String, VecDeque<DateTime<Utc>>>,
+    /// Recent reaction times per client address, for the reaction rate limit.
+    pub reaction_times: HashMap<String, VecDeque<DateTime<Utc>>>,
     pub peers: HashMap<String, PeerStatus>,
+    /// Signs this node's inbox pushes; see `federation`.
+    pub node_key: Option<Arc<NodeKey>>,
+    /// Set from `--trusted-keyring`; only its keys may post.
+    pub keyring: Option<Keyring>,
+    /// Loaded from `--content-filter`; empty blocks nothing.
+    pub content_filter: ContentFilter,
 
     pub karma_codes: HashMap<String, KarmaCode>,
+    /// Net karma per post, keyed by the lowercased post id. A post id is
+    /// its key's fingerprint, so this is also each key's reputation.
     pub karma_votes: HashMap<String, i32>,
 
     pub moderation_reports: Vec<ModerationReport>,
     pub post_labels: HashMap<String, String>,
-    pub label_definitions: HashMap<String, String>,
+    /// Where entries of `post_labels` not set by an admin here came from:
+    /// the peer they were imported from, or `rule:<name>`.
+    pub post_label_sources: HashMap<String, String>,
+    pub label_definitions: HashMap<String, ModerationLabel>,
+    /// From `--label-rules`, with labels resolved to ids.
+    pub label_rules: Vec<LabelRule>,
 
-    pub admin_passwords: Vec<String>,
+    pub admin_backend: Arc<dyn AdminBackend>,
+    /// Bearer tokens issued at admin login, mapped to their expiry.
+    pub admin_tokens: HashMap<String, DateTime<Utc>>,
+    /// `admin_replay_key`s of recent signed admin requests, with when they
+    /// were seen, so none can be replayed within the allowed clock skew.
+    pub admin_signatures: HashMap<String, DateTime<Utc>>,
+    /// Entries under `deadletter:` in sled.
+    dead_letter_count: usize,
+    /// Posts that are new or changed, sent as they are stored.
+    pub post_events: tokio::sync::broadcast::Sender<Envelope>,
+    pub request_metrics: Arc<RequestMetrics>,
 }
 
+const DEAD_LETTER_PREFIX: &[u8] = b"deadletter:";
+const CHANGE_SEQ_DB_KEY: &[u8] = b"__change_seq__";
+/// Added to sled's id counter to number posts; see `AppState::seq_base`.
+pub(crate) const SEQ_BASE_DB_KEY: &[u8] = b"__seq_base__";
+const WEBSUB_PREFIX: &str = "websub:";
+
 impl AppState {
-    pub fn new(db: sled::Db) -> Self {
+    pub fn new(db: sled::Db, config: Config) -> Self {
+        let admin_backend = backend_from_config(&config, &db);
+        let dead_letter_count = db.scan_prefix(DEAD_LETTER_PREFIX).count();
+        let stored_u64 = |key: &[u8]| {
+            db.get(key)
+                .ok()
+                .flatten()
+                .and_then(|bytes| <[u8; 8]>::try_from(bytes.as_ref()).ok())
+                .map_or(0, u64::from_be_bytes)
+        };
+        let change_seq = stored_u64(CHANGE_SEQ_DB_KEY);
+        let seq_base = stored_u64(SEQ_BASE_DB_KEY);
+        let slow_request = (config.slow_request_ms > 0)
+            .then(|| std::time::Duration::from_millis(config.slow_request_ms));
         Self {
+            config,
             memory: HashMap::new(),
             db,
+            search_index: None,
+            geo_index: GeoIndex::default(),
+            seq_index: BTreeMap::new(),
+            quote_index: HashMap::new(),
+            reply_index: HashMap::new(),
+            change_seq,
+            seq_base,
+            db_write_failures: 0,
+            region_violations: 0,
+            stored_posts: 0,
+            post_times: HashMap::new(),
+            client_post_times: HashMap::new(),
+            reaction_times: HashMap::new(),
             peers: HashMap::new(),
+            node_key: None,
+            keyring: None,
+            content_filter: ContentFilter::default(),
             karma_codes: HashMap::new(),
             karma_votes: HashMap::new(),
             moderation_reports: Vec::new(),
             post_labels: HashMap::new(),
+            post_label_sources: HashMap::new(),
+            label_rules: Vec::new(),
             label_definitions: HashMap::new(),
-            admin_passwords: Vec::new(),
+            admin_backend,
+            admin_tokens: HashMap::new(),
+            admin_signatures: HashMap::new(),
+            dead_letter_count,
+            post_events: tokio::sync::broadcast::channel(1024).0,
+            request_metrics: Arc::new(RequestMetrics::new(slow_request)),
+        }
+    }
+
+    /// Validates an incoming envelope and applies this node's admission
+    /// policy. Re-imports of an envelope we already hold are not rate limited.
+    /// Validation ties each id to the fingerprint of the key that signed it,
+    /// so no other key can replace a post. Rejections other than rate
+    /// limiting go to the dead-letter store when it is enabled.
+    pub fn admit_envelope(&mut self, envelope: &Envelope) -> Result<Post, ValidationError> {
+        self.admit(envelope, true, Submitter::Peer)
+    }
+
+    /// Like `admit_envelope` for a post a client submits directly, under
+    /// `--client-pow-difficulty` and `--client-post-rate-limit`.
+    pub fn admit_client_envelope(&mut self, envelope: &Envelope) -> Result<Post, ValidationError> {
+        self.admit(envelope, true, Submitter::Client)
+    }
+
+    /// Like `admit_envelope` without verifying the signature; see
+    /// `validate_envelope_unverified`. Only for envelopes from a peer for
+    /// which `skips_verification` holds. Nothing ties the key to the id on
+    /// this path, so an envelope carrying a different key than the post we
+    /// hold under its id fails with `KeyConflict` instead of replacing it.
+    pub fn admit_unverified_envelope(
+        &mut self,
+        envelope: &Envelope,
+    ) -> Result<Post, ValidationError> {
+        self.admit(envelope, false, Submitter::Peer)
+    }
+
+    fn admit(
+        &mut self,
+        envelope: &Envelope,
+        verify: bool,
+        from: Submitter,
+    ) -> Result<Post, ValidationError> {
+        let validate = if verify {
+            validate_envelope_with_keyring
+        } else {
+            validate_envelope_unverified
+        };
+        let admitted = validate(
+            envelope,
+            self.config.pow_difficulty_for(from),
+            &self.config.envelope_limits(),
+            self.keyring.as_ref(),
+        )
+        .and_then(|post| {
+            if !verify {
+                self.check_same_key(envelope)?;
+            }
+            self.content_filter.check(&post)?;
+            self.check_reply_depth(&post)?;
+            Ok(post)
+        });
+        let post = match admitted {
+            Ok(post) => post,
+            Err(e) => {
+                self.dead_letter(envelope, &e);
+                return Err(e);
+            }
+        };
+        let known = self
+            .memory
+            .get(&envelope.id)
+            .is_some_and(|existing| existing.data == envelope.data);
+        if !known {
+            self.check_post_rate(&envelope.id.to_lowercase(), from)?;
+        }
+        Ok(post)
+    }
+
+    fn check_same_key(&self, envelope: &Envelope) -> Result<(), ValidationError> {
+        match self.memory.get(&envelope.id) {
+            Some(existing) if existing.public_key != envelope.public_key => {
+                Err(ValidationError::KeyConflict)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn check_reply_depth(&self, post: &Post) -> Result<(), ValidationError> {
+        let max = self.config.max_reply_depth;
+        if max > 0 && self.reply_depth(post, max) > max {
+            return Err(ValidationError::TooDeep { max });
+        }
+        Ok(())
+    }
+
+    /// How many replies deep `post` is, walking `parent` links through the
+    /// posts we hold. An unknown parent ends the walk, so for a chain we only
+    /// partly hold this is a lower bound; a parent arriving later does not
+    /// re-check its replies. Stops counting past `limit`, which also ends
+    /// walks around a cycle of posts naming each other as parents.
+    pub fn reply_depth(&self, post: &Post, limit: usize) -> usize {
+        let mut depth = 0;
+        let mut parent = post.parent.clone();
+        while let Some(id) = parent {
+            depth += 1;
+            if depth > limit {
+                break;
+            }
+            parent = self
+                .memory
+                .get(&id)
+                .and_then(|env| serde_json::from_str::<Post>(&env.data).ok())
+                .and_then(|p| p.parent);
+        }
+        depth
+    }
+
+    /// Sliding-window limit on posts per signing key. The key fingerprint is
+    /// the envelope id, so this is enforceable without trusting the sender.
+    fn check_post_rate(
+        &mut self,
+        fingerprint: &str,
+        from: Submitter,
+    ) -> Result<(), ValidationError> {
+        let limit = self.config.post_rate_limit_for(from);
+        if limit == 0 {
+            return Ok(());
+        }
+        let limit = self.reputation_rate_limit(limit, self.key_reputation(fingerprint));
+        let window = self.config.post_rate_window_secs;
+        let times = match from {
+            Submitter::Peer => &mut self.post_times,
+            Submitter::Client => &mut self.client_post_times,
+        };
+        let times = times.entry(fingerprint.to_string()).or_default();
+        if !take_slot(times, limit, window) {
+            return Err(ValidationError::RateLimited);
+        }
+        Ok(())
+    }
+
+    /// Sliding-window limit on reactions per client address, over the post
+    /// rate window. Reactions carry no proof of work and keys cost nothing,
+    /// so the address is what holds a sender back.
+    pub fn check_reaction_rate(&mut self, client: &str) -> Result<(), ValidationError> {
+        let limit = self.config.reaction_rate_limit;
+        if limit == 0 {
+            return Ok(());
+        }
+        let window = self.config.post_rate_window_secs;
+        let times = self.reaction_times.entry(client.to_string()).or_default();
+        if !take_slot(times, limit, window) {
+            return Err(ValidationError::RateLimited);
+        }
+        Ok(())
+    }
+
+    /// Net karma of the posts signed by a key. Posts are keyed by their
+    /// signing key's fingerprint, so this is the tally of that id.
+    pub fn key_reputation(&self, fingerprint: &str) -> i32 {
+        self.karma_of(fingerprint)
+    }
+
+    /// Net karma of post `id`, in whatever case the id is written.
+    pub fn karma_of(&self, id: &str) -> i32 {
+        self.karma_votes
+            .get(&id.to_lowercase())
+            .copied()
+            .unwrap_or(0)
+    }
+
+    /// Scales the base post rate limit by a key's reputation: keys in the
+    /// negative get `karma_rate_low_factor`, keys at or above
+    /// `karma_rate_high_threshold` get `karma_rate_high_factor`. Never below 1.
+    fn reputation_rate_limit(&self, base: usize, reputation: i32) -> usize {
+        let factor = if reputation < 0 {
+            self.config.karma_rate_low_factor
+        } else if reputation >= self.config.karma_rate_high_threshold {
+            self.config.karma_rate_high_factor
+        } else {
+            1.0
+        };
+        ((base as f64 * factor).round() as usize).max(1)
+    }
+
+    /// Stores a validated envelope in sled, then memory and the search index.
+    /// Nothing is kept in memory if the sled write fails, so a post is never
+    /// served without being persisted. Callers flush sled and commit the
+    /// index once per batch.
+    pub fn import_envelope(&mut self, mut envelope: Envelope, post: &Post) -> sled::Result<()> {
+        self.stamp_arrival(&mut envelope)?;
+        let id = envelope.id.clone();
+        let bytes = store::encode_envelope(&envelope, self.config.compress_posts)?;
+        if let Err(e) = self.db.insert(store::post_key(&id), bytes) {
+            eprintln!("DB insert error for {}: {}", id, e);
+            self.db_write_failures += 1;
+            return Err(e);
+        }
+        if let Some(index) = self.search_index.as_mut() {
+            if let Err(e) = index.add(post) {
+                eprintln!("Search index error for {}: {}", id, e);
+            }
+        }
+        let date = self.config.post_date_source.date_of(&envelope, post);
+        self.geo_index.insert(post, date);
+        self.index_seq(&envelope);
+        self.index_links(post);
+        self.announce(&envelope);
+        if self.is_change(&envelope) {
+            self.bump_change_seq();
+            self.sample_stored(&envelope, post);
+        }
+        self.memory.insert(id, envelope);
+        self.apply_label_rules(post);
+        Ok(())
+    }
+
+    /// Labels `post` with the first matching rule, unless it already has a
+    /// label from any source.
+    pub fn apply_label_rules(&mut self, post: &Post) {
+        if self.label_rules.is_empty() || self.post_labels.contains_key(&post.id) {
+            return;
+        }
+        let karma = self.karma_of(&post.id);
+        let reporters = self.reporter_count(&post.id);
+        let Some(rule) = self.label_rules.iter().find(|rule| {
+            rule.matches(post, karma, reporters)
+                && self
+                    .label_definitions
+                    .get(&rule.label)
+                    .is_none_or(|d| d.applies_at(post.latitude, post.longitude))
+        }) else {
+            return;
+        };
+        println!("Rule {} labelled {} as {}", rule.name, post.id, rule.label);
+        self.post_label_sources
+            .insert(post.id.clone(), rule.source());
+        self.post_labels.insert(post.id.clone(), rule.label.clone());
+    }
+
+    /// The label on `post_id` as shown to clients. A label scoped to a
+    /// region only shows on posts we hold inside it, since we cannot place
+    /// a post we do not hold.
+    pub fn applied_label(&self, post_id: &str) -> Option<&String> {
+        let slug = self.post_labels.get(post_id)?;
+        let Some(definition) = self.label_definitions.get(slug) else {
+            return Some(slug);
+        };
+        if definition.region.is_none() {
+            return Some(slug);
         }
+        let post: Post = serde_json::from_str(&self.memory.get(post_id)?.data).ok()?;
+        definition
+            .applies_at(post.latitude, post.longitude)
+            .then_some(slug)
     }
 
-    pub fn is_admin(&self, password: &str) -> bool {
-        self.admin_passwords.iter().any(|p| p == password)
+    /// Distinct reporters in the active queue for `post_id`.
+    pub fn reporter_count(&self, post_id: &str) -> usize {
+        self.moderation_reports
+            .iter()
+            .filter(|r| r.post.id == post_id)
+            .map(|r| r.reporter_ip.as_deref().unwrap_or(r.id.as_str()))
+            .collect::<HashSet<_>>()
+            .len()
+    }
+
+    /// Sets `received_at` to now and `seq` to the next sequence number,
+    /// replacing any values a peer sent, unless we already hold this exact
+    /// envelope, which keeps its own.
+    fn stamp_arrival(&self, envelope: &mut Envelope) -> sled::Result<()> {
+        let held = self
+            .memory
+            .get(&envelope.id)
+            .filter(|existing| existing.data == envelope.data);
+        envelope.received_at = Some(
+            held.and_then(|existing| existing.received_at)
+                .unwrap_or_else(Utc::now),
+        );
+        envelope.seq = match held.and_then(|existing| existing.seq) {
+            Some(seq) => Some(seq),
+            None => Some(self.next_seq()?),
+        };
+        Ok(())
+    }
+
+    /// sled ids are monotonic across restarts; shifted past `seq_base`, and
+    /// by one so 0 can mean "from the start" as a cursor.
+    fn next_seq(&self) -> sled::Result<u64> {
+        Ok(self.seq_base + self.db.generate_id()? + 1)
+    }
+
+    /// Whether storing `envelope` adds or changes a post. Call before
+    /// updating `memory`.
+    fn is_change(&self, envelope: &Envelope) -> bool {
+        self.memory
+            .get(&envelope.id)
+            .is_none_or(|existing| existing.data != envelope.data)
+    }
+
+    /// Sends `envelope` on `post_events` if it is new or changed. Call
+    /// before updating `memory`.
+    fn announce(&self, envelope: &Envelope) {
+        if self.post_events.receiver_count() > 0 && self.is_change(envelope) {
+            let _ = self.post_events.send(envelope.clone());
+        }
+    }
+
+    /// Logs every `post_log_sample`th new or changed post.
+    fn sample_stored(&mut self, envelope: &Envelope, post: &Post) {
+        let rate = self.config.post_log_sample;
+        if rate == 0 {
+            return;
+        }
+        self.stored_posts += 1;
+        if self.stored_posts.is_multiple_of(rate) {
+            println!("{}", sampled_post_line(envelope, post));
+        }
+    }
+
+    /// Advances `change_seq` and persists it. A failed write is only
+    /// logged: at worst a restarted node repeats a value peers have seen,
+    /// and the next change moves it on.
+    fn bump_change_seq(&mut self) {
+        self.change_seq += 1;
+        if let Err(e) = self
+            .db
+            .insert(CHANGE_SEQ_DB_KEY, &self.change_seq.to_be_bytes())
+        {
+            eprintln!("DB insert error for change sequence: {}", e);
+            self.db_write_failures += 1;
+        }
+    }
+
+    /// Which halves of a sync with `addr` have anything to do, given the
+    /// peer's current `X-OpenHerd-Seq`: a pull only if it moved since our
+    /// last pull, a push only if our `change_seq` moved since our last
+    /// push. `None` if neither does. Peers that send no seq are always
+    /// pulled from.
+    pub fn pending_sync(
+        &self,
+        addr: &str,
+        direction: SyncDirection,
+        remote_change_seq: Option<u64>,
+    ) -> Option<SyncDirection> {
+        let peer = self.peers.get(addr);
+        let pull = direction.pulls()
+            && (remote_change_seq.is_none()
+                || remote_change_seq != peer.and_then(|p| p.remote_change_seq));
+        let push =
+            direction.pushes() && peer.and_then(|p| p.pushed_change_seq) != Some(self.change_seq);
+        match (pull, push) {
+            (true, true) => Some(SyncDirection::Both),
+            (true, false) => Some(SyncDirection::Pull),
+            (false, true) => Some(SyncDirection::Push),
+            (false, false) => None,
+        }
+    }
+
+    /// Points `seq_index` at `envelope`, dropping the entry for any older
+    /// sequence number of the same post. Call before updating `memory`.
+    fn index_seq(&mut self, envelope: &Envelope) {
+        if let Some(old) = self.memory.get(&envelope.id).and_then(|e| e.seq) {
+            self.seq_index.remove(&old);
+        }
+        if let Some(seq) = envelope.seq {
+            self.seq_index.insert(seq, envelope.id.clone());
+        }
+    }
+
+    /// Points `quote_index` and `reply_index` at what `post` quotes and
+    /// replies to, dropping the entries for whatever the version we hold
+    /// named. Call before updating `memory`.
+    fn index_links(&mut self, post: &Post) {
+        self.unindex_links(&post.id);
+        if let Some(quote) = &post.quote {
+            self.quote_index
+                .entry(quote.clone())
+                .or_default()
+                .insert(post.id.clone());
+        }
+        if let Some(parent) = &post.parent {
+            self.reply_index
+                .entry(parent.clone())
+                .or_default()
+                .insert(post.id.clone());
+        }
+    }
+
+    fn unindex_links(&mut self, id: &str) {
+        let Some(held) = self
+            .memory
+            .get(id)
+            .and_then(|env| serde_json::from_str::<Post>(&env.data).ok())
+        else {
+            return;
+        };
+        if let Some(quoted) = held.quote {
+            unlink(&mut self.quote_index, &quoted, id);
+        }
+        if let Some(parent) = held.parent {
+            unlink(&mut self.reply_index, &parent, id);
+        }
+    }
+
+    /// How many posts we hold quote `post_id`.
+    pub fn quote_count(&self, post_id: &str) -> usize {
+        self.quote_index.get(post_id).map_or(0, BTreeSet::len)
+    }
+
+    /// How many posts we hold reply to `post_id`.
+    pub fn reply_count(&self, post_id: &str) -> usize {
+        self.reply_index.get(post_id).map_or(0, BTreeSet::len)
+    }
+
+    /// Like `import_envelope` for many posts, but applied as one atomic sled
+    /// batch: either every envelope is written or none is.
+    pub fn import_batch(&mut self, mut posts: Vec<(Envelope, Post)>) -> sled::Result<()> {
+        for (envelope, _) in posts.iter_mut() {
+            self.stamp_arrival(envelope)?;
+        }
+        let mut batch = sled::Batch::default();
+        for (envelope, _) in &posts {
+            let bytes = store::encode_envelope(envelope, self.config.compress_posts)?;
+            batch.insert(store::post_key(&envelope.id), bytes);
+        }
+        if let Err(e) = self.db.apply_batch(batch) {
+            eprintln!("DB batch insert error for {} posts: {}", posts.len(), e);
+            self.db_write_failures += posts.len() as u64;
+            return Err(e);
+        }
+        let mut changed = false;
+        for (envelope, post) in posts {
+            if let Some(index) = self.search_index.as_mut() {
+                if let Err(e) = index.add(&post) {
+                    eprintln!("Search index error for {}: {}", post.id, e);
+                }
+            }
+            let date = self.config.post_date_source.date_of(&envelope, &post);
+            self.geo_index.insert(&post, date);
+            self.index_seq(&envelope);
+            self.index_links(&post);
+            self.announce(&envelope);
+            if self.is_change(&envelope) {
+                changed = true;
+                self.sample_stored(&envelope, &post);
+            }
+            self.memory.insert(envelope.id.clone(), envelope);
+            self.apply_label_rules(&post);
+        }
+        if changed {
+            self.bump_change_seq();
+        }
+        Ok(())
+    }
+
+    /// Removes a post from memory, sled and the search and geo indexes, and
+    /// releases the karma votes on it. Every way of deleting a post goes
+    /// through here.
+    pub fn remove_post(&mut self, id: &str) -> Option<Envelope> {
+        self.release_karma_votes(id);
+        self.remove_reactions(id);
+        if let Err(e) = self.unpin_post(id) {
+            eprintln!("DB remove error for pin on {}: {}", id, e);
+        }
+        if let Err(e) = self.db.remove(store::post_key(id)) {
+            eprintln!("DB remove error for {}: {}", id, e);
+        }
+        if let Some(index) = self.search_index.as_mut() {
+            index.remove(id);
+        }
+        self.geo_index.remove(id);
+        self.unindex_links(id);
+        let removed = self.memory.remove(id);
+        if let Some(envelope) = &removed {
+            if let Some(seq) = envelope.seq {
+                self.seq_index.remove(&seq);
+            }
+            self.bump_change_seq();
+        }
+        removed
+    }
+
+    /// Rebuilds `seq_index` from `memory` at startup. Posts stored before
+    /// sequence numbers existed are numbered first, in order of arrival.
+    pub fn rebuild_seq_index(&mut self) -> sled::Result<()> {
+        let mut unnumbered: Vec<(Option<DateTime<Utc>>, String)> = self
+            .memory
+            .values()
+            .filter(|e| e.seq.is_none())
+            .map(|e| (e.received_at, e.id.clone()))
+            .collect();
+        unnumbered.sort();
+        for (_, id) in unnumbered {
+            let seq = self.next_seq()?;
+            let Some(envelope) = self.memory.get_mut(&id) else {
+                continue;
+            };
+            envelope.seq = Some(seq);
+            let bytes = store::encode_envelope(envelope, self.config.compress_posts)?;
+            self.db.insert(store::post_key(&id), bytes)?;
+        }
+        self.seq_index = self
+            .memory
+            .values()
+            .filter_map(|e| Some((e.seq?, e.id.clone())))
+            .collect();
+        Ok(())
+    }
+
+    /// Rebuilds the geo, quote and reply indexes from the posts in `memory`,
+    /// e.g. after loading them from sled at startup.
+    pub fn rebuild_geo_index(&mut self) {
+        self.geo_index.clear();
+        self.quote_index.clear();
+        self.reply_index.clear();
+        let date_source = self.config.post_date_source;
+        for envelope in self.memory.values() {
+            match serde_json::from_str::<Post>(&envelope.data) {
+                Ok(post) => {
+                    let date = date_source.date_of(envelope, &post);
+                    self.geo_index.insert(&post, date);
+                    if let Some(quote) = post.quote {
+                        self.quote_index
+                            .entry(quote)
+                            .or_default()
+                            .insert(post.id.clone());
+                    }
+                    if let Some(parent) = post.parent {
+                        self.reply_index.entry(parent).or_default().insert(post.id);
+                    }
+                }
+                Err(e) => eprintln!("Unreadable post data for {}: {}", envelope.id, e),
+            }
+        }
+    }
+
+    /// The known peer to give up first: most failed syncs, then least
+    /// recently synced. Peers that are not failing are never evicted.
+    fn eviction_candidate(&self) -> Option<String> {
+        self.peers
+            .iter()
+            .filter(|(_, p)| p.failures > 0)
+            .max_by_key(|(_, p)| (p.failures, std::cmp::Reverse(p.last_ok)))
+            .map(|(addr, _)| addr.clone())
+    }
+
+    /// Whether `addr` is known or could be added under `max_peers`.
+    pub fn can_add_peer(&self, addr: &str) -> bool {
+        let max = self.config.max_peers;
+        max == 0
+            || self.peers.contains_key(addr)
+            || self.peers.len() < max
+            || self.eviction_candidate().is_some()
+    }
+
+    /// Evicts failing peers until `addr` fits under `max_peers`. Returns
+    /// false, leaving the peers untouched, if it cannot be made to fit.
+    fn make_room_for_peer(&mut self, addr: &str) -> bool {
+        if !self.can_add_peer(addr) {
+            return false;
+        }
+        let max = self.config.max_peers;
+        while max > 0 && !self.peers.contains_key(addr) && self.peers.len() >= max {
+            let Some(worst) = self.eviction_candidate() else {
+                return false;
+            };
+            println!("Evicting peer {} to make room for {}", worst, addr);
+            self.remove_peer(&worst);
+        }
+        true
+    }
+
+    /// When we last synced with `addr`, if that was within
+    /// `peer_sync_cooldown_secs` and another sync should wait.
+    pub fn sync_cooldown(&self, addr: &str) -> Option<DateTime<Utc>> {
+        let cooldown = self.config.peer_sync_cooldown_secs;
+        if cooldown == 0 {
+            return None;
+        }
+        let last = self.peers.get(addr)?.last_sync.as_ref()?.at;
+        (Utc::now() < last + chrono::Duration::seconds(cooldown as i64)).then_some(last)
+    }
+
+    /// Whether `addr` has been quarantined for longer than
+    /// `peer_removal_grace_secs` and should be removed.
+    pub fn peer_expired(&self, addr: &str) -> bool {
+        let grace = chrono::Duration::seconds(self.config.peer_removal_grace_secs as i64);
+        self.peers
+            .get(addr)
+            .and_then(|p| p.quarantined_since)
+            .is_some_and(|since| Utc::now() >= since + grace)
+    }
+
+    /// Records a sync attempt. A successful sync adds the peer if it is new
+    /// and fits under `max_peers`; a failed one only counts against peers we
+    /// already know.
+    pub fn record_sync(&mut self, addr: &str, outcome: SyncOutcome) {
+        let Some(peer) = self.tally_peer(addr, outcome.error.is_none(), outcome.at) else {
+            return;
+        };
+        peer.record_progress(&outcome);
+        peer.last_sync = Some(outcome);
+        self.persist_peer(addr);
+    }
+
+    /// Records whether `addr` answered the background monitor's liveness
+    /// probe. Counts like a sync, but leaves `last_sync` alone.
+    pub fn record_probe(&mut self, addr: &str, alive: bool) {
+        if self.tally_peer(addr, alive, Utc::now()).is_some() {
+            self.persist_peer(addr);
+        }
+    }
+
+    /// Counts a success or failure at `at` against `addr`, quarantining it
+    /// after `PEER_FAILURE_LIMIT` failures in a row. Returns the peer, or
+    /// `None` if it failed and is unknown or succeeded and does not fit.
+    fn tally_peer(&mut self, addr: &str, ok: bool, at: DateTime<Utc>) -> Option<&mut PeerStatus> {
+        if ok {
+            if !self.make_room_for_peer(addr) {
+                eprintln!("Not adding peer {}: peer limit reached", addr);
+                return None;
+            }
+            let peer = self.peers.entry(addr.to_string()).or_default();
+            if peer.quarantined_since.take().is_some() {
+                println!("Peer {} recovered", addr);
+            }
+            peer.failures = 0;
+            peer.last_ok = Some(at);
+            Some(peer)
+        } else {
+            let peer = self.peers.get_mut(addr)?;
+            peer.failures = peer.failures.saturating_add(1);
+            if peer.failures >= PEER_FAILURE_LIMIT && peer.quarantined_since.is_none() {
+                println!(
+                    "Peer {} quarantined after {} failed checks",
+                    addr, peer.failures
+                );
+                peer.quarantined_since = Some(at);
+            }
+            Some(peer)
+        }
+    }
+
+    /// Whether envelopes pulled from `addr` skip signature verification:
+    /// only when `--trust-peer-validation` is on and an admin trusts it.
+    pub fn skips_verification(&self, addr: &str) -> bool {
+        self.config.trust_peer_validation && self.peers.get(addr).is_some_and(|p| p.trusted)
+    }
+
+    /// Marks a known peer trusted or not, returning false for unknown peers.
+    pub fn set_peer_trusted(&mut self, addr: &str, trusted: bool) -> bool {
+        let Some(peer) = self.peers.get_mut(addr) else {
+            return false;
+        };
+        peer.trusted = trusted;
+        self.persist_peer(addr);
+        true
+    }
+
+    fn persist_peer(&self, addr: &str) {
+        let Some(peer) = self.peers.get(addr) else {
+            return;
+        };
+        let key = format!("peer:{}", addr);
+        match serde_json::to_vec(peer) {
+            Ok(bytes) => {
+                if let Err(e) = self.db.insert(key.as_bytes(), bytes) {
+                    eprintln!("DB insert error for {}: {}", key, e);
+                }
+            }
+            Err(e) => eprintln!("Failed to serialize {}: {}", key, e),
+        }
+    }
+
+    /// Writes a karma code to `karma_code:{code}` so its votes and
+    /// revocations survive a restart.
+    pub fn persist_karma_code(&self, code: &str) {
+        let Some(karma_code) = self.karma_codes.get(code) else {
+            return;
+        };
+        let key = format!("karma_code:{}", code);
+        match serde_json::to_vec(karma_code) {
+            Ok(bytes) => {
+                if let Err(e) = self.db.insert(key.as_bytes(), bytes) {
+                    eprintln!("DB insert error for {}: {}", key, e);
+                }
+            }
+            Err(e) => eprintln!("Failed to serialize {}: {}", key, e),
+        }
+    }
+
+    /// Adds newly generated codes all or nothing: they are written in one
+    /// sled batch and flushed before any is added to `karma_codes`, so a
+    /// failed write leaves neither memory nor sled with part of the batch.
+    pub fn store_karma_codes(&mut self, codes: &[KarmaCode]) -> sled::Result<()> {
+        self.store_karma_codes_with(codes, |db, batch| db.apply_batch(batch))
+    }
+
+    fn store_karma_codes_with(
+        &mut self,
+        codes: &[KarmaCode],
+        apply: impl FnOnce(&sled::Db, sled::Batch) -> sled::Result<()>,
+    ) -> sled::Result<()> {
+        let mut batch = sled::Batch::default();
+        for kc in codes {
+            let key = format!("karma_code:{}", kc.code);
+            let bytes = serde_json::to_vec(kc)
+                .map_err(|e| sled::Error::Unsupported(format!("serialize {}: {}", key, e)))?;
+            batch.insert(key.as_bytes(), bytes);
+        }
+        if let Err(e) = apply(&self.db, batch).and_then(|()| self.flush_writes()) {
+            eprintln!("DB write error for {} karma codes: {}", codes.len(), e);
+            self.db_write_failures += codes.len() as u64;
+            let mut undo = sled::Batch::default();
+            for kc in codes {
+                undo.remove(format!("karma_code:{}", kc.code).as_bytes());
+            }
+            if let Err(e) = self.db.apply_batch(undo) {
+                eprintln!("DB remove error rolling back karma codes: {}", e);
+            }
+            return Err(e);
+        }
+        for kc in codes {
+            self.karma_codes.insert(kc.code.clone(), kc.clone());
+        }
+        Ok(())
+    }
+
+    /// Recomputes `karma_votes` from the codes currently applied to posts.
+    /// Tallies are not stored separately; the codes are the source of truth.
+    pub fn rebuild_karma_votes(&mut self) {
+        self.karma_votes = self.karma_tallies();
+    }
+
+    /// Each post's tally summed from the codes currently applied to it.
+    pub fn karma_tallies(&self) -> HashMap<String, i32> {
+        let mut tallies = HashMap::new();
+        for kc in self.karma_codes.values() {
+            let Some(post) = &kc.current_post else {
+                continue;
+            };
+            *tallies.entry(post.to_lowercase()).or_insert(0) += kc.applied_karma();
+        }
+        tallies
+    }
+
+    /// Frees every karma code voting on `post_id`, as if each vote had been
+    /// revoked, and drops the post's tally so a later revoke cannot adjust
+    /// a post that is gone.
+    fn release_karma_votes(&mut self, post_id: &str) {
+        self.karma_votes.remove(&post_id.to_lowercase());
+        let codes: Vec<String> = self
+            .karma_codes
+            .values()
+            .filter(|kc| kc.current_post.as_deref() == Some(post_id))
+            .map(|kc| kc.code.clone())
+            .collect();
+        let now = Utc::now();
+        for code in codes {
+            if let Some(kc) = self.karma_codes.get_mut(&code) {
+                if let Some(last) = kc.history.last_mut() {
+                    last.revoked_at = Some(now);
+                }
+                kc.current_post = None;
+                kc.used_direction = None;
+            }
+            self.persist_karma_code(&code);
+        }
+    }
+
+    /// Marks a post pinned. Anything that expires or evicts posts must skip
+    /// pinned ones; see `is_pinned`. Returns whether it was newly pinned.
+    pub fn pin_post(&self, post_id: &str) -> sled::Result<bool> {
+        let pin = PinnedPost {
+            post_id: post_id.to_string(),
+            pinned_at: Utc::now(),
+        };
+        let key = format!("pin:{}", post_id);
+        if self.db.contains_key(key.as_bytes())? {
+            return Ok(false);
+        }
+        let bytes = serde_json::to_vec(&pin)
+            .map_err(|e| sled::Error::Unsupported(format!("serialize {}: {}", key, e)))?;
+        self.db.insert(key.as_bytes(), bytes)?;
+        Ok(true)
+    }
+
+    /// Returns whether the post was pinned.
+    pub fn unpin_post(&self, post_id: &str) -> sled::Result<bool> {
+        Ok(self.db.remove(format!("pin:{}", post_id))?.is_some())
+    }
+
+    pub fn is_pinned(&self, post_id: &str) -> bool {
+        self.db
+            .contains_key(format!("pin:{}", post_id))
+            .unwrap_or(false)
+    }
+
+    /// Pinned posts, most recently pinned first.
+    pub fn pinned_posts(&self) -> Vec<PinnedPost> {
+        let mut pins: Vec<PinnedPost> = self
+            .db
+            .scan_prefix(b"pin:")
+            .flatten()
+            .filter_map(|(_, v)| serde_json::from_slice(&v).ok())
+            .collect();
+        pins.sort_by_key(|pin| std::cmp::Reverse(pin.pinned_at));
+        pins
+    }
+
+    /// Whether `reaction` may be stored: it replaces one already held, or
+    /// its post has fewer than `--max-reactions-per-post`.
+    pub fn has_room_for_reaction(&self, reaction: &Reaction) -> sled::Result<bool> {
+        let max = self.config.max_reactions_per_post;
+        if max == 0 || self.db.contains_key(reaction_key(reaction))? {
+            return Ok(true);
+        }
+        let prefix = format!("reaction:{}:", reaction.post);
+        Ok(self.db.scan_prefix(prefix.as_bytes()).take(max).count() < max)
+    }
+
+    /// Stores a validated reaction envelope under
+    /// `reaction:{post}:{author}:{reaction}`, so each key counts once per
+    /// reaction. Returns whether it was new.
+    pub fn add_reaction(&self, envelope: &Envelope, reaction: &Reaction) -> sled::Result<bool> {
+        let key = reaction_key(reaction);
+        let bytes = serde_json::to_vec(envelope)
+            .map_err(|e| sled::Error::Unsupported(format!("serialize {}: {}", key, e)))?;
+        Ok(self.db.insert(key.as_bytes(), bytes)?.is_none())
+    }
+
+    /// Reaction counts for `post_id`, read from sled.
+    pub fn reaction_counts(&self, post_id: &str) -> sled::Result<BTreeMap<String, usize>> {
+        let prefix = format!("reaction:{}:", post_id);
+        let mut counts = BTreeMap::new();
+        for entry in self.db.scan_prefix(prefix.as_bytes()) {
+            let (key, _) = entry?;
+            let key = String::from_utf8_lossy(&key[prefix.len()..]).into_owned();
+            // `{author}:{reaction}`; the author is hex, the reaction may
+            // contain anything.
+            if let Some((_, reaction)) = key.split_once(':') {
+                *counts.entry(reaction.to_string()).or_insert(0) += 1;
+            }
+        }
+        Ok(counts)
+    }
+
+    fn remove_reactions(&self, post_id: &str) {
+        let prefix = format!("reaction:{}:", post_id);
+        for key in self.db.scan_prefix(prefix.as_bytes()).keys().flatten() {
+            if let Err(e) = self.db.remove(&key) {
+                eprintln!("DB remove error for reaction on {}: {}", post_id, e);
+            }
+        }
+    }
+
+    /// Forgets a peer, in memory and in sled.
+    pub fn remove_peer(&mut self, addr: &str) -> Option<PeerStatus> {
+        if let Err(e) = self.db.remove(format!("peer:{}", addr).as_bytes()) {
+            eprintln!("DB remove error for peer {}: {}", addr, e);
+        }
+        self.peers.remove(addr)
+    }
+
+    /// Moves a stored post to `quarantine:{id}` so it is no longer served but
+    /// remains on disk for inspection.
+    pub fn quarantine_post(&mut self, id: &str) -> sled::Result<()> {
+        let Some(envelope) = self.remove_post(id) else {
+            return Ok(());
+        };
+        let bytes = serde_json::to_vec(&envelope)
+            .map_err(|e| sled::Error::Unsupported(format!("serialize {}: {}", id, e)))?;
+        self.db
+            .insert(format!("quarantine:{}", id).as_bytes(), bytes)?;
+        Ok(())
+    }
+
+    /// Flushes sled if the flush policy asks for it after every write;
+    /// otherwise the background flusher or shutdown takes care of it.
+    pub fn flush_writes(&self) -> sled::Result<()> {
+        if self.config.flush_policy == FlushPolicy::EveryWrite {
+            self.db.flush()?;
+        }
+        Ok(())
+    }
+
+    pub fn commit_search_index(&mut self) {
+        if let Some(index) = self.search_index.as_mut() {
+            if let Err(e) = index.commit() {
+                eprintln!("Search index commit error: {}", e);
+            }
+        }
+    }
+
+    /// Writes, flushes, reads back and deletes a reserved key, surfacing
+    /// read-only or full disks that would otherwise only show up in logs.
+    pub fn probe_db(&self) -> sled::Result<()> {
+        const PROBE_KEY: &[u8] = b"__health_probe__";
+        let value = Utc::now().timestamp_nanos_opt().unwrap_or(0).to_be_bytes();
+        self.db.insert(PROBE_KEY, &value)?;
+        self.db.flush()?;
+        let read = self.db.get(PROBE_KEY)?;
+        self.db.remove(PROBE_KEY)?;
+        if read.as_deref() != Some(&value[..]) {
+            return Err(sled::Error::Unsupported(
+                "health probe read back a different value".to_string(),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Moves reports older than `report_max_age_hours` out of the active
+    /// queue, archiving them in sled unless configured to drop them.
+    /// Returns how many reports were removed.
+    pub fn expire_reports(&mut self) -> usize {
+        let max_age = self.config.report_max_age_hours;
+        if max_age == 0 {
+            return 0;
+        }
+        let now = Utc::now();
+        let cutoff = now - chrono::Duration::hours(max_age as i64);
+        let (expired, active): (Vec<_>, Vec<_>) = self
+            .moderation_reports
+            .drain(..)
+            .partition(|r| r.reported_at < cutoff);
+        self.moderation_reports = active;
+        self.archive_reports(&expired, now);
+        expired.len()
+    }
+
+    /// Stores reports leaving the active queue under `archived_report:`,
+    /// unless configured to drop them.
+    fn archive_reports(&self, reports: &[ModerationReport], now: DateTime<Utc>) {
+        if self.config.drop_expired_reports || reports.is_empty() {
+            return;
+        }
+        for report in reports {
+            let archived = ArchivedReport {
+                id: report.id.clone(),
+                post: report.post.clone(),
+                reason: report.reason.clone(),
+                reported_at: report.reported_at,
+                archived_at: now,
+            };
+            let key = format!("archived_report:{}", report.id);
+            match serde_json::to_vec(&archived) {
+                Ok(bytes) => {
+                    if let Err(e) = self.db.insert(key.as_bytes(), bytes) {
+                        eprintln!("DB insert error for {}: {}", key, e);
+                    }
+                }
+                Err(e) => eprintln!("Failed to serialize {}: {}", key, e),
+            }
+        }
+        let _ = self.flush_writes();
+    }
+
+    /// Whether `incoming` more reports fit under `--max-moderation-reports`.
+    /// Under `evict-oldest` the oldest queued reports are logged and
+    /// archived to make room; a batch larger than the whole queue never fits.
+    pub fn make_room_for_reports(&mut self, incoming: usize) -> bool {
+        let max = self.config.max_moderation_reports;
+        let queued = self.moderation_reports.len();
+        if max == 0 || queued + incoming <= max {
+            return true;
+        }
+        if incoming > max || self.config.report_overflow == ReportOverflow::Reject {
+            return false;
+        }
+        let evicted: Vec<ModerationReport> = self
+            .moderation_reports
+            .drain(..queued + incoming - max)
+            .collect();
+        for report in &evicted {
+            eprintln!(
+                "Report queue full: evicting unreviewed report {} on post {}",
+                report.id, report.post.id
+            );
+        }
+        self.archive_reports(&evicted, Utc::now());
+        true
+    }
+
+    /// Archived reports, oldest report first.
+    pub fn archived_reports(&self) -> Vec<ArchivedReport> {
+        let mut reports: Vec<ArchivedReport> = self
+            .db
+            .scan_prefix(b"archived_report:")
+            .flatten()
+            .filter_map(|(_, v)| serde_json::from_slice(&v).ok())
+            .collect();
+        reports.sort_by_key(|r| r.reported_at);
+        reports
+    }
+
+    /// Stores a rejected envelope under `deadletter:{id}`, dropping the oldest
+    /// past `dead_letter_max`. Ids come from sled's monotonic counter, so keys
+    /// sort oldest first.
+    fn dead_letter(&mut self, envelope: &Envelope, error: &ValidationError) {
+        if !self.config.dead_letters || self.config.dead_letter_max == 0 {
+            return;
+        }
+        let now = Utc::now();
+        let letter = DeadLetter {
+            received_at: now,
+            error: error.to_string(),
+            envelope: envelope.clone(),
+        };
+        let key = match self.db.generate_id() {
+            Ok(id) => format!("deadletter:{:020}", id),
+            Err(e) => {
+                eprintln!("Failed to allocate dead letter id: {}", e);
+                return;
+            }
+        };
+        let bytes = match serde_json::to_vec(&letter) {
+            Ok(bytes) => bytes,
+            Err(e) => {
+                eprintln!("Failed to serialize dead letter: {}", e);
+                return;
+            }
+        };
+        if let Err(e) = self.db.insert(key.as_bytes(), bytes) {
+            eprintln!("DB insert error for {}: {}", key, e);
+            return;
+        }
+        self.dead_letter_count += 1;
+        while self.dead_letter_count > self.config.dead_letter_max {
+            match self.db.scan_prefix(DEAD_LETTER_PREFIX).keys().next() {
+                Some(Ok(oldest)) => {
+                    let _ = self.db.remove(oldest);
+                    self.dead_letter_count -= 1;
+                }
+                _ => break,
+            }
+        }
+    }
+
+    /// Stored dead letters, oldest first.
+    pub fn dead_letters(&self) -> Vec<DeadLetter> {
+        self.db
+            .scan_prefix(DEAD_LETTER_PREFIX)
+            .values()
+            .flatten()
+            .filter_map(|v| serde_json::from_slice(&v).ok())
+            .collect()
+    }
+
+    /// Deletes every dead letter, returning how many there were.
+    pub fn clear_dead_letters(&mut self) -> sled::Result<usize> {
+        let mut removed = 0;
+        for key in self.db.scan_prefix(DEAD_LETTER_PREFIX).keys() {
+            self.db.remove(key?)?;
+            removed += 1;
+        }
+        self.dead_letter_count = 0;
+        Ok(removed)
+    }
+
+    pub fn websub_subscribe(&self, subscription: &WebSubSubscription) -> sled::Result<()> {
+        let bytes = serde_json::to_vec(subscription)
+            .map_err(|e| sled::Error::Unsupported(format!("serialize subscription: {}", e)))?;
+        self.db.insert(
+            format!("{}{}", WEBSUB_PREFIX, subscription.callback).as_bytes(),
+            bytes,
+        )?;
+        self.flush_writes()
+    }
+
+    pub fn websub_unsubscribe(&self, callback: &str) -> sled::Result<()> {
+        self.db
+            .remove(format!("{}{}", WEBSUB_PREFIX, callback).as_bytes())?;
+        self.flush_writes()
+    }
+
+    /// Whether `callback` may subscribe under `websub_max_subscriptions`:
+    /// there is room, or it is already subscribed and only renews.
+    pub fn websub_has_room(&self, callback: &str) -> bool {
+        let max = self.config.websub_max_subscriptions;
+        if max == 0 {
+            return true;
+        }
+        let live = self.websub_subscriptions();
+        live.len() < max || live.iter().any(|s| s.callback == callback)
+    }
+
+    /// Live WebSub subscriptions. Expired ones are deleted on the way.
+    pub fn websub_subscriptions(&self) -> Vec<WebSubSubscription> {
+        let now = Utc::now();
+        let mut live = Vec::new();
+        for (key, value) in self.db.scan_prefix(WEBSUB_PREFIX).flatten() {
+            match serde_json::from_slice::<WebSubSubscription>(&value) {
+                Ok(subscription) if subscription.expires > now => live.push(subscription),
+                _ => {
+                    let _ = self.db.remove(key);
+                }
+            }
+        }
+        live
+    }
+
+    pub fn is_admin_token(&self, token: &str) -> bool {
+        self.admin_tokens
+            .get(token)
+            .is_some_and(|expires| *expires > Utc::now())
+    }
+
+    /// Records a signed admin request by its `admin_replay_key`, returning
+    /// false if it has been seen before. Entries are kept for twice the
+    /// clock skew, long enough to outlive the signed date.
+    pub fn note_admin_request(&mut self, replay_key: &str) -> bool {
+        let now = Utc::now();
+        let keep = crate::federation::MAX_CLOCK_SKEW * 2;
+        self.admin_signatures.retain(|_, seen| now - *seen < keep);
+        self.admin_signatures
+            .insert(replay_key.to_string(), now)
+            .is_none()
+    }
+
+    /// Issues a fresh admin bearer token, dropping any that have expired.
+    pub fn issue_admin_token(&mut self) -> (String, DateTime<Utc>) {
+        let now = Utc::now();
+        self.admin_tokens.retain(|_, expires| *expires > now);
+        let token = uuid::Uuid::new_v4().simple().to_string();
+        let ttl = chrono::Duration::seconds(self.config.admin_token_ttl_secs as i64);
+        let expires = now + ttl;
+        self.admin_tokens.insert(token.clone(), expires);
+        (token, expires)
+    }
+
+    /// Resolves a label slug or its display text to the stable slug.
+    pub fn resolve_label(&self, label: &str) -> Option<String> {
+        if self.label_definitions.contains_key(label) {
+            return Some(label.to_string());
+        }
+        self.label_definitions
+            .values()
+            .find(|l| l.label.eq_ignore_ascii_case(label))
+            .map(|l| l.id.clone())
+    }
+
+    pub fn save_label_definitions(&self) -> std::io::Result<()> {
+        let mut labels: Vec<&ModerationLabel> = self.label_definitions.values().collect();
+        labels.sort_by(|a, b| a.id.cmp(&b.id));
+        let json = serde_json::to_string_pretty(&labels)?;
+        std::fs::write("./labels.json", json)
     }
 }
 
 pub type SharedState = std::sync::Arc<std::sync::Mutex<AppState>>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn code(code: &str) -> KarmaCode {
+        KarmaCode {
+            code: code.to_string(),
+            issuer: "test".to_string(),
+            vote_type: None,
+            expires: Utc::now() + chrono::Duration::days(1),
+            region: None,
+            current_post: None,
+            used_direction: None,
+            history: Vec::new(),
+            weight: 1,
+        }
+    }
+
+    #[test]
+    fn test_failed_karma_code_batch_stores_nothing() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let mut state = AppState::new(db.clone(), Config::default());
+        let codes = [code("first"), code("second")];
+
+        let failed = state.store_karma_codes_with(&codes, |db, batch| {
+            db.apply_batch(batch)?;
+            Err(sled::Error::Unsupported("disk full".to_string()))
+        });
+        assert!(failed.is_err());
+        assert!(state.karma_codes.is_empty());
+        assert_eq!(db.scan_prefix("karma_code:").count(), 0);
+        assert_eq!(state.db_write_failures, 2);
+
+        state.store_karma_codes(&codes).unwrap();
+        assert_eq!(state.karma_codes.len(), 2);
+        assert_eq!(db.scan_prefix("karma_code:").count(), 2);
+    }
+
+    #[test]
+    fn test_change_seq_moves_on_changes_and_persists() {
+        use crate::validation::{testing::signed_envelope, validate_envelope};
+
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let mut state = AppState::new(db.clone(), Config::default());
+        let envelope = signed_envelope("hello");
+        let post = validate_envelope(&envelope).unwrap();
+        state.import_envelope(envelope.clone(), &post).unwrap();
+        assert_eq!(state.change_seq, 1);
+        state.import_envelope(envelope.clone(), &post).unwrap();
+        assert_eq!(state.change_seq, 1);
+        state.remove_post(&envelope.id);
+        assert_eq!(state.change_seq, 2);
+        state.remove_post(&envelope.id);
+        assert_eq!(state.change_seq, 2);
+        assert_eq!(AppState::new(db, Config::default()).change_seq, 2);
+
+        let addr = "https://peer.example";
+        assert_eq!(
+            state.pending_sync(addr, SyncDirection::Both, Some(7)),
+            Some(SyncDirection::Both)
+        );
+        state.peers.insert(
+            addr.to_string(),
+            PeerStatus {
+                remote_change_seq: Some(7),
+                pushed_change_seq: Some(2),
+                ..PeerStatus::default()
+            },
+        );
+        assert_eq!(state.pending_sync(addr, SyncDirection::Both, Some(7)), None);
+        assert_eq!(
+            state.pending_sync(addr, SyncDirection::Both, None),
+            Some(SyncDirection::Pull)
+        );
+        state.import_envelope(envelope, &post).unwrap();
+        assert_eq!(
+            state.pending_sync(addr, SyncDirection::Both, Some(7)),
+            Some(SyncDirection::Push)
+        );
+        assert_eq!(state.pending_sync(addr, SyncDirection::Pull, Some(7)), None);
+    }
+
+    #[test]
+    fn test_sampled_post_line_leaves_out_text() {
+        use crate::validation::testing::signed_envelope;
+
+        let envelope = signed_envelope("my secret plans");
+        let post: Post = serde_json::from_str(&envelope.data).unwrap();
+        let line = sampled_post_line(&envelope, &post);
+        assert!(line.contains(&post.id));
+        assert!(line.contains("15 chars"));
+        assert!(!line.contains("secret"));
+    }
+}
@@ -0,0 +1,241 @@
+//! On-disk form of envelopes under `post:` in sled.
+//!
+//! Values are JSON, optionally zstd-compressed. A compressed value starts
+//! with the zstd frame magic, which JSON never does, so entries written
+//! either way stay readable whatever the current setting.
+//!
+//! `scan` reads the whole database without letting damaged entries take
+//! the node down, and `check_posts` finds stored posts that load wrongly.
+
+use crate::types::Envelope;
+use std::collections::HashSet;
+
+const ZSTD_MAGIC: [u8; 4] = [0x28, 0xb5, 0x2f, 0xfd];
+const ZSTD_LEVEL: i32 = 3;
+
+pub fn post_key(id: &str) -> Vec<u8> {
+    format!("post:{}", id).into_bytes()
+}
+
+/// Serializes `envelope` for sled, compressing it when `compress` is set.
+pub fn encode_envelope(envelope: &Envelope, compress: bool) -> sled::Result<Vec<u8>> {
+    let json = serde_json::to_vec(envelope)
+        .map_err(|e| sled::Error::Unsupported(format!("serialize {}: {}", envelope.id, e)))?;
+    if !compress {
+        return Ok(json);
+    }
+    zstd::bulk::compress(&json, ZSTD_LEVEL)
+        .map_err(|e| sled::Error::Unsupported(format!("compress {}: {}", envelope.id, e)))
+}
+
+/// What a full pass over the database found.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct ScanReport {
+    /// Entries read and handed to the visitor.
+    pub entries: usize,
+    /// Entries sled could not read; skipped.
+    pub read_errors: usize,
+    /// Set when sled panicked mid-scan, ending it early.
+    pub aborted: Option<String>,
+}
+
+impl ScanReport {
+    pub fn is_clean(&self) -> bool {
+        self.read_errors == 0 && self.aborted.is_none()
+    }
+}
+
+/// Calls `visit` with every entry sled can read. Unreadable entries are
+/// logged and counted rather than ending the scan, and a panic from a
+/// corrupted tree ends it with `aborted` set instead of taking the process
+/// down, keeping whatever was visited before.
+pub fn scan(db: &sled::Db, mut visit: impl FnMut(&[u8], &[u8])) -> ScanReport {
+    let mut report = ScanReport::default();
+    let mut last_key: Option<sled::IVec> = None;
+    let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+        for item in db.iter() {
+            match item {
+                Ok((key, value)) => {
+                    visit(&key, &value);
+                    report.entries += 1;
+                    last_key = Some(key);
+                }
+                Err(e) => {
+                    report.read_errors += 1;
+                    eprintln!("Unreadable DB entry after {}: {}", describe(&last_key), e);
+                }
+            }
+        }
+    }));
+    if let Err(panic) = result {
+        let message = panic
+            .downcast_ref::<&str>()
+            .map(|s| s.to_string())
+            .or_else(|| panic.downcast_ref::<String>().cloned())
+            .unwrap_or_else(|| "unknown panic".to_string());
+        eprintln!("DB scan aborted after {}: {}", describe(&last_key), message);
+        report.aborted = Some(message);
+    }
+    report
+}
+
+fn describe(key: &Option<sled::IVec>) -> String {
+    match key {
+        Some(key) => format!("key {}", String::from_utf8_lossy(key)),
+        None => "the start".to_string(),
+    }
+}
+
+const POST_PREFIX: &[u8] = b"post:";
+/// Where `set_aside` moves damaged `post:` entries, byte for byte.
+const DAMAGED_PREFIX: &[u8] = b"damaged:";
+
+/// Problems with the stored posts, found before they are loaded.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct IntegrityReport {
+    /// `post:` entries read.
+    pub checked: usize,
+    /// Ids of entries that do not decode.
+    pub unreadable: Vec<String>,
+    /// Ids of entries holding an envelope with another id. Loading serves
+    /// it under that id, without an entry of its own to update or remove.
+    pub misplaced: Vec<String>,
+    /// Ids of posts sharing their `seq` with another post, all but the
+    /// first of each. Only one of each is reachable by outbox cursor.
+    pub duplicate_seq: Vec<String>,
+}
+
+impl IntegrityReport {
+    pub fn is_clean(&self) -> bool {
+        self.unreadable.is_empty() && self.misplaced.is_empty() && self.duplicate_seq.is_empty()
+    }
+}
+
+/// Checks every `post:` entry in `db`. Run before the posts are loaded,
+/// which would otherwise paper over what this finds.
+pub fn check_posts(db: &sled::Db) -> sled::Result<IntegrityReport> {
+    let mut report = IntegrityReport::default();
+    let mut seqs = HashSet::new();
+    for entry in db.scan_prefix(POST_PREFIX) {
+        let (key, value) = entry?;
+        let id = String::from_utf8_lossy(&key[POST_PREFIX.len()..]).into_owned();
+        report.checked += 1;
+        match decode_envelope(&value) {
+            Err(_) => report.unreadable.push(id),
+            Ok(envelope) if envelope.id != id => report.misplaced.push(id),
+            Ok(envelope) => {
+                if envelope.seq.is_some_and(|seq| !seqs.insert(seq)) {
+                    report.duplicate_seq.push(id);
+                }
+            }
+        }
+    }
+    Ok(report)
+}
+
+/// Fixes what `check_posts` found: unreadable and misplaced entries move
+/// under `damaged:` for inspection, and posts with a duplicate `seq` lose
+/// it so they are numbered afresh at startup. Returns the entries changed.
+pub fn repair_posts(
+    db: &sled::Db,
+    report: &IntegrityReport,
+    compress: bool,
+) -> sled::Result<usize> {
+    let mut batch = sled::Batch::default();
+    let mut changed = 0;
+    for id in report.unreadable.iter().chain(&report.misplaced) {
+        let key = post_key(id);
+        if let Some(value) = db.get(&key)? {
+            batch.insert([DAMAGED_PREFIX, key.as_slice()].concat(), value);
+            batch.remove(key);
+            changed += 1;
+        }
+    }
+    for id in &report.duplicate_seq {
+        let key = post_key(id);
+        let Some(value) = db.get(&key)? else {
+            continue;
+        };
+        let Ok(mut envelope) = decode_envelope(&value) else {
+            continue;
+        };
+        envelope.seq = None;
+        batch.insert(key, encode_envelope(&envelope, compress)?);
+        changed += 1;
+    }
+    db.apply_batch(batch)?;
+    db.flush()?;
+    Ok(changed)
+}
+
+/// Reads an envelope written by `encode_envelope`, compressed or not.
+pub fn decode_envelope(bytes: &[u8]) -> Result<Envelope, String> {
+    if bytes.starts_with(&ZSTD_MAGIC) {
+        let json = zstd::stream::decode_all(bytes).map_err(|e| e.to_string())?;
+        serde_json::from_slice(&json).map_err(|e| e.to_string())
+    } else {
+        serde_json::from_slice(bytes).map_err(|e| e.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_scan_survives_a_panicking_visitor() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        for key in ["a", "b", "c"] {
+            db.insert(key, "v").unwrap();
+        }
+        let mut seen = Vec::new();
+        let report = scan(&db, |k, _| {
+            if k == b"c" {
+                panic!("corrupt node");
+            }
+            seen.push(k.to_vec());
+        });
+        assert_eq!(seen, vec![b"a".to_vec(), b"b".to_vec()]);
+        assert_eq!(report.entries, 2);
+        assert_eq!(report.aborted.as_deref(), Some("corrupt node"));
+        assert!(!report.is_clean());
+
+        let clean = scan(&db, |_, _| {});
+        assert_eq!(clean.entries, 3);
+        assert!(clean.is_clean());
+    }
+
+    #[test]
+    fn test_check_and_repair_posts() {
+        use crate::validation::testing::signed_envelope;
+
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let store = |key: &str, envelope: &Envelope| {
+            db.insert(post_key(key), encode_envelope(envelope, false).unwrap())
+                .unwrap();
+        };
+        let mut first = signed_envelope("first");
+        first.seq = Some(1);
+        let mut second = signed_envelope("second");
+        second.seq = Some(1);
+        store(&first.id, &first);
+        store(&second.id, &second);
+        store("elsewhere", &first);
+        db.insert(post_key("garbage"), b"not json".as_slice())
+            .unwrap();
+
+        let report = check_posts(&db).unwrap();
+        assert_eq!(report.checked, 4);
+        assert_eq!(report.unreadable, vec!["garbage".to_string()]);
+        assert_eq!(report.misplaced, vec!["elsewhere".to_string()]);
+        assert_eq!(report.duplicate_seq.len(), 1);
+
+        assert_eq!(repair_posts(&db, &report, false).unwrap(), 3);
+        assert!(check_posts(&db).unwrap().is_clean());
+        assert_eq!(db.scan_prefix(DAMAGED_PREFIX).count(), 2);
+        assert_eq!(
+            db.get(b"damaged:post:garbage").unwrap().unwrap(),
+            b"not json".as_slice()
+        );
+    }
+}
@@ -0,0 +1,262 @@
+use crate::state::{PullPosition, SharedState, SyncOutcome};
+use crate::types::{Envelope, ModerationLabel, SyncDirection};
+use crate::wire::{Format, CHANGE_SEQ_HEADER, MSGPACK};
+use chrono::Utc;
+use reqwest::header::{ACCEPT, CONTENT_TYPE};
+use reqwest::StatusCode as HttpStatus;
+use std::collections::HashMap;
+
+/// Canonical form of a peer address, used as its key in `peers`: an
+/// `http(s)` URL without a trailing slash.
+pub fn normalize_peer_address(address: &str) -> Option<String> {
+    match url::Url::parse(address.trim()) {
+        Ok(url) if url.scheme() == "http" || url.scheme() == "https" => {
+            Some(url.as_str().trim_end_matches('/').to_string())
+        }
+        _ => None,
+    }
+}
+
+/// Whether a normalized peer address would sync over plaintext.
+pub fn is_insecure_peer(base: &str) -> bool {
+    base.starts_with("http://")
+}
+
+/// Fetches a peer's label definitions and its post id -> label slug map.
+pub async fn fetch_peer_labels(
+    client: &reqwest::Client,
+    base: &str,
+) -> Result<(Vec<ModerationLabel>, HashMap<String, String>), String> {
+    let definitions = fetch_json(client, &format!("{}/_openherd/moderation/labels", base)).await?;
+    let post_labels = fetch_json(
+        client,
+        &format!("{}/_openherd/moderation/post-labels", base),
+    )
+    .await?;
+    Ok((definitions, post_labels))
+}
+
+async fn fetch_json<T: serde::de::DeserializeOwned>(
+    client: &reqwest::Client,
+    url: &str,
+) -> Result<T, String> {
+    let resp = client
+        .get(url)
+        .send()
+        .await
+        .map_err(|e| format!("Failed to fetch {}: {}", url, e))?;
+    if resp.status() != HttpStatus::OK {
+        return Err(format!("{} returned status {}", url, resp.status()));
+    }
+    resp.json()
+        .await
+        .map_err(|e| format!("Failed to parse {}: {}", url, e))
+}
+
+/// Whether `base` answers its health endpoint, for the background monitor's
+/// liveness probe.
+pub async fn probe_peer(client: &reqwest::Client, base: &str) -> bool {
+    client
+        .get(format!("{}/_openherd/health", base))
+        .send()
+        .await
+        .is_ok_and(|resp| resp.status().is_success())
+}
+
+/// `base`'s current `X-OpenHerd-Seq`, read from its health endpoint. `None`
+/// if the peer is unreachable or too old to send one.
+pub async fn fetch_change_seq(client: &reqwest::Client, base: &str) -> Option<u64> {
+    let resp = client
+        .get(format!("{}/_openherd/health", base))
+        .send()
+        .await
+        .ok()?;
+    change_seq_of(resp.headers())
+}
+
+fn change_seq_of(headers: &reqwest::header::HeaderMap) -> Option<u64> {
+    headers.get(CHANGE_SEQ_HEADER)?.to_str().ok()?.parse().ok()
+}
+
+/// Pulls `base`'s outbox into this node, then pushes our posts to its inbox,
+/// skipping whichever half `direction` leaves out. `base` must already be a
+/// normalized `http(s)://host` address. The outcome is returned rather than
+/// recorded so callers decide how failures count.
+pub async fn sync_peer(
+    state: &SharedState,
+    client: &reqwest::Client,
+    base: &str,
+    direction: SyncDirection,
+) -> SyncOutcome {
+    let mut outcome = SyncOutcome {
+        at: Utc::now(),
+        imported: 0,
+        skipped: 0,
+        pushed: 0,
+        error: None,
+        position: None,
+        change_seq: None,
+        pushed_change_seq: None,
+    };
+    if let Err(e) = pull_and_push(state, client, base, direction, &mut outcome).await {
+        outcome.error = Some(e);
+    }
+    outcome
+}
+
+async fn pull_and_push(
+    state: &SharedState,
+    client: &reqwest::Client,
+    base: &str,
+    direction: SyncDirection,
+    outcome: &mut SyncOutcome,
+) -> Result<(), String> {
+    // Without a pull we have not seen whether the peer speaks MessagePack.
+    let format = if direction.pulls() {
+        pull(state, client, base, outcome).await?
+    } else {
+        Format::Json
+    };
+    if direction.pushes() {
+        push(state, client, base, format, outcome).await?;
+    }
+    Ok(())
+}
+
+/// Imports `base`'s outbox, returning the format the peer answered in.
+async fn pull(
+    state: &SharedState,
+    client: &reqwest::Client,
+    base: &str,
+    outcome: &mut SyncOutcome,
+) -> Result<Format, String> {
+    let (want_msgpack, skip_known, verify) = {
+        let s = state
+            .lock()
+            .map_err(|_| "State lock poisoned".to_string())?;
+        (
+            s.config.sync_msgpack,
+            s.config.sync_skip_known,
+            !s.skips_verification(base),
+        )
+    };
+    let outbox_url = format!("{}/_openherd/outbox", base);
+    let mut request = client.get(&outbox_url);
+    if want_msgpack {
+        request = request.header(ACCEPT, format!("{}, application/json;q=0.9", MSGPACK));
+    }
+    let resp = request
+        .send()
+        .await
+        .map_err(|e| format!("Failed to fetch remote outbox: {}", e))?;
+    if resp.status() != HttpStatus::OK {
+        return Err(format!("Remote outbox returned status {}", resp.status()));
+    }
+    // Older peers ignore `Accept` and answer in JSON; only push MessagePack
+    // to peers that have shown they speak it.
+    let format = Format::of_body(resp.headers());
+    let change_seq = change_seq_of(resp.headers());
+    let bytes = resp
+        .bytes()
+        .await
+        .map_err(|e| format!("Failed to fetch remote outbox: {}", e))?;
+    let incoming: Vec<Envelope> = format
+        .decode(&bytes)
+        .map_err(|e| format!("Failed to parse remote outbox: {}", e))?;
+
+    // `seq` is the peer's; importing replaces it with ours.
+    let remote_seq = incoming.iter().filter_map(|env| env.seq).max();
+    let mut pulled_seq = 0;
+    // Whether every envelope is in or was refused for good. Otherwise the
+    // peer must be pulled again even if its change seq stays put.
+    let mut complete = true;
+    let mut s = state
+        .lock()
+        .map_err(|_| "State lock poisoned".to_string())?;
+    for env in incoming {
+        let seq = env.seq.unwrap_or(0);
+        let unchanged = s
+            .memory
+            .get(&env.id)
+            .is_some_and(|existing| existing.data == env.data);
+        if unchanged && skip_known {
+            outcome.skipped += 1;
+            pulled_seq = pulled_seq.max(seq);
+            continue;
+        }
+        let admitted = if verify {
+            s.admit_envelope(&env)
+        } else {
+            s.admit_unverified_envelope(&env)
+        };
+        match admitted {
+            Ok(post) => {
+                if s.import_envelope(env, &post).is_ok() {
+                    pulled_seq = pulled_seq.max(seq);
+                    if !unchanged {
+                        outcome.imported += 1;
+                    }
+                } else {
+                    complete = false;
+                }
+            }
+            Err(e) => complete &= !e.is_transient(),
+        }
+    }
+    outcome.position = remote_seq.map(|remote| PullPosition::new(pulled_seq, remote));
+    outcome.change_seq = change_seq.filter(|_| complete);
+    let _ = s.flush_writes();
+    s.commit_search_index();
+    Ok(format)
+}
+
+/// Pushes our posts to `base`'s inbox in `format`, signed with the node key
+/// when we have one.
+async fn push(
+    state: &SharedState,
+    client: &reqwest::Client,
+    base: &str,
+    format: Format,
+    outcome: &mut SyncOutcome,
+) -> Result<(), String> {
+    let (node_key, change_seq, posts_to_send): (_, _, Vec<Envelope>) = {
+        let s = state
+            .lock()
+            .map_err(|_| "State lock poisoned".to_string())?;
+        (
+            s.node_key.clone(),
+            s.change_seq,
+            s.memory.values().take(10_000).cloned().collect(),
+        )
+    };
+
+    let body = format
+        .encode(&posts_to_send)
+        .map_err(|e| format!("Failed to serialize posts: {}", e))?;
+    let inbox_url = format!("{}/_openherd/inbox", base);
+    let mut request = client
+        .post(&inbox_url)
+        .header(CONTENT_TYPE, format.content_type());
+    if let Some(key) = node_key {
+        let headers = key
+            .sign_push(&body)
+            .map_err(|e| format!("Failed to sign push: {}", e))?;
+        for (name, value) in headers {
+            request = request.header(name, value);
+        }
+    }
+    let post_resp = request
+        .body(body)
+        .send()
+        .await
+        .map_err(|e| format!("Failed to push to remote inbox: {}", e))?;
+    if post_resp.status() != HttpStatus::OK {
+        return Err(format!(
+            "Remote inbox returned status {}",
+            post_resp.status()
+        ));
+    }
+    outcome.pushed = posts_to_send.len();
+    outcome.pushed_change_seq = Some(change_seq);
+    Ok(())
+}
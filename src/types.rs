@@ -4,10 +4,25 @@ use serde::{Deserialize, Serialize};
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Envelope {
     pub signature: String,
+    /// Stored in full with every envelope. `id` must equal this key's
+    /// fingerprint, so a key only ever signs one post id and there are no
+    /// repeats to store by reference.
     #[serde(rename = "publicKey")]
     pub public_key: String,
     pub id: String,
     pub data: String,
+    /// When this node first stored the envelope. Set by the receiving node,
+    /// not covered by the signature, and replaced on import from a peer.
+    #[serde(
+        rename = "receivedAt",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub received_at: Option<DateTime<Utc>>,
+    /// This node's insertion sequence number, assigned alongside
+    /// `received_at` and likewise replaced on import from a peer.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub seq: Option<u64>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -18,6 +33,12 @@ pub struct Post {
     pub longitude: f64,
     pub date: DateTime<Utc>,
     pub parent: Option<String>,
+    /// Id of a post this one quotes. Like `parent`, it need not be held here.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub quote: Option<String>,
+    /// Proof-of-work nonce; see `validation::proof_of_work_bits`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub nonce: Option<u64>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -25,15 +46,173 @@ pub struct ApiResponse {
     pub ok: bool,
 }
 
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SearchQuery {
+    pub q: String,
+    #[serde(default)]
+    pub offset: usize,
+    pub limit: Option<usize>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SearchHit {
+    pub envelope: Envelope,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub score: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub snippet: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HealthResponse {
+    pub ok: bool,
+    pub db_writable: bool,
+    /// Post writes that failed since startup.
+    pub db_write_failures: u64,
+    /// Karma votes outside their code's region allowed by `--region-mode warn`.
+    #[serde(default)]
+    pub region_violations: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub message: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct InboxResponse {
+    /// True only when every valid envelope was durably stored.
+    pub ok: bool,
+    pub imported: usize,
+    pub rejected: usize,
+    /// Ids that passed validation but could not be persisted.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub failed: Vec<String>,
+    /// One per stored post, with `--post-receipts`.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub receipts: Vec<PostReceipt>,
+}
+
+/// A node's signed acknowledgement that it stored a post. See `federation`
+/// for what is signed.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PostReceipt {
+    /// The post id.
+    pub id: String,
+    /// Lowercase hex SHA-256 of the envelope's `data`.
+    pub data_sha256: String,
+    pub received_at: DateTime<Utc>,
+    /// Fingerprint of the node key that signed the receipt.
+    pub node: String,
+    /// ASCII-armored detached signature.
+    pub signature: String,
+}
+
+/// This node's identity and what it supports, served at `/_openherd/node`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct NodeInfo {
+    /// Fingerprint of the node key.
+    pub id: Option<String>,
+    /// Armored node public key, for peers' signed-inbox allowlists.
+    pub public_key: Option<String>,
+    pub version: String,
+    pub capabilities: Vec<String>,
+    pub pow_difficulty: u32,
+    pub peers: usize,
+    /// Peer cap; 0 means unlimited.
+    pub max_peers: usize,
+}
+
+/// Configured limits and switches, so clients can adapt to a node instead
+/// of guessing. Byte limits apply to each envelope field; page limits are
+/// the most a single request returns.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Capabilities {
+    pub version: String,
+    /// As in `NodeInfo`.
+    pub capabilities: Vec<String>,
+    /// Media types the outbox can answer in and the inbox reads.
+    pub content_types: Vec<String>,
+    pub mirror: bool,
+    pub pow_difficulty: u32,
+    /// `enforce`, `warn` or `off`, for karma votes outside a code's region.
+    pub region_mode: String,
+    /// Empty when reactions are off.
+    pub reactions: Vec<String>,
+    /// 0 allows any depth.
+    pub max_reply_depth: usize,
+    pub max_post_data_bytes: usize,
+    pub max_public_key_bytes: usize,
+    pub max_signature_bytes: usize,
+    /// Posts per key per `post_rate_window_secs`; 0 means unlimited.
+    pub post_rate_limit: usize,
+    pub post_rate_window_secs: u64,
+    /// What `/_openherd/post` requires of clients, which may differ from
+    /// what the inbox requires of peers.
+    pub client_pow_difficulty: u32,
+    pub client_post_rate_limit: usize,
+    /// Reactions per client address per `post_rate_window_secs`; 0 means
+    /// unlimited.
+    pub reaction_rate_limit: usize,
+    /// 0 means no cap.
+    pub max_reactions_per_post: usize,
+    pub max_outbox_page: usize,
+    pub max_outbox_id_page: usize,
+    pub max_bbox_results: usize,
+    pub max_keys_per_request: usize,
+    pub max_by_keys_results: usize,
+    pub search_min_query_len: usize,
+    pub search_max_results: usize,
+    /// Decimal places served coordinates are rounded to; see
+    /// `GEO_PRECISION_HEADER`. Absent when they are exact.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub geo_precision: Option<u32>,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct SyncRequest {
     pub address: String,
+    /// Sync even within the peer's cooldown. Needs admin credentials.
+    #[serde(default)]
+    pub force: bool,
+    #[serde(default)]
+    pub direction: SyncDirection,
+}
+
+/// Where an envelope being admitted comes from. Clients posting to
+/// `/_openherd/post` have their own proof of work and rate limit settings.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Submitter {
+    Peer,
+    Client,
+}
+
+/// Which halves of a sync run: pulling the peer's outbox into this node,
+/// pushing our posts to the peer's inbox, or both.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum SyncDirection {
+    Pull,
+    Push,
+    #[default]
+    Both,
+}
+
+impl SyncDirection {
+    pub fn pulls(self) -> bool {
+        self != SyncDirection::Push
+    }
+
+    pub fn pushes(self) -> bool {
+        self != SyncDirection::Pull
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct SyncResponse {
     pub ok: bool,
     pub message: String,
+    #[serde(default)]
+    pub imported: usize,
+    #[serde(default)]
+    pub skipped: usize,
 }
 
 #[derive(Debug, thiserror::Error)]
@@ -42,16 +221,186 @@ pub enum ValidationError {
     InvalidSignature,
     #[error("Invalid public key")]
     InvalidPublicKey,
-    #[error("Post ID does not match key fingerprint")]
+    /// The envelope `id` is not the signing key's fingerprint (or is not a
+    /// fingerprint at all).
+    #[error("Envelope id does not match key fingerprint")]
     IdMismatch,
+    /// The key's fingerprint matches the envelope `id`, but the `id` inside
+    /// the signed post data differs.
+    #[error("Post id in data does not match envelope id")]
+    PostIdMismatch,
+    /// The post data and envelope agree on an `id`, but it is not the
+    /// fingerprint of the key that signed them.
+    #[error("Post id in data does not match key fingerprint")]
+    PostKeyMismatch,
+    /// An unverified envelope carries a different key than the post we
+    /// hold under its id.
+    #[error("Post ID already belongs to a different key")]
+    KeyConflict,
+    #[error("Signing key is not in the trusted keyring")]
+    UntrustedKey,
     #[error("Invalid post data: {0}")]
     InvalidPostData(String),
+    #[error("Post rate limit exceeded for key")]
+    RateLimited,
+    #[error("Insufficient proof of work: need {required} leading zero bits")]
+    InsufficientWork { required: u32 },
+    #[error("Post text matches the content filter")]
+    BlockedContent,
+    #[error("Reply is nested deeper than {max} levels")]
+    TooDeep { max: usize },
+    #[error("Envelope {field} exceeds {limit} bytes")]
+    TooLarge { field: &'static str, limit: usize },
     #[error("PGP error: {0}")]
     PgpError(#[from] pgp::errors::Error),
+    #[error("Key generation failed: {0}")]
+    KeyGeneration(String),
     #[error("JSON error: {0}")]
     JsonError(#[from] serde_json::Error),
 }
 
+impl ValidationError {
+    /// Whether the same envelope could be admitted later without changing,
+    /// so a sync that hit this should not be recorded as complete.
+    pub fn is_transient(&self) -> bool {
+        matches!(self, ValidationError::RateLimited)
+    }
+}
+
+/// A lat/lon rectangle. `min_lon > max_lon` means the box crosses the
+/// antimeridian.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BoundingBox {
+    pub min_lat: f64,
+    pub min_lon: f64,
+    pub max_lat: f64,
+    pub max_lon: f64,
+}
+
+/// A WebSub subscription to the outbox, stored under `websub:{callback}`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WebSubSubscription {
+    pub callback: String,
+    pub topic: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub secret: Option<String>,
+    pub expires: DateTime<Utc>,
+}
+
+/// Form body of a WebSub subscribe or unsubscribe request.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HubRequest {
+    #[serde(rename = "hub.mode")]
+    pub mode: String,
+    #[serde(rename = "hub.topic")]
+    pub topic: String,
+    #[serde(rename = "hub.callback")]
+    pub callback: String,
+    #[serde(rename = "hub.lease_seconds")]
+    pub lease_seconds: Option<u64>,
+    #[serde(rename = "hub.secret")]
+    pub secret: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct OutboxQuery {
+    pub after_seq: Option<u64>,
+    pub limit: Option<usize>,
+    /// Skip posts dated before this, by `--post-date-source`.
+    pub since: Option<DateTime<Utc>>,
+    /// Include post dates in `/_openherd/outbox/ids`.
+    #[serde(default)]
+    pub dates: bool,
+    /// Comma-separated `Post` fields, e.g. `id,text,date`. Makes the outbox
+    /// answer with a `PartialOutbox` instead of envelopes.
+    pub fields: Option<String>,
+}
+
+/// Outbox posts cut down to the requested fields. Without the signature
+/// and public key they cannot be verified; `unverified` is always true so
+/// clients cannot mistake them for envelopes. Fetch the full envelopes
+/// (e.g. with `/_openherd/outbox/by-ids`) to verify.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PartialOutbox {
+    pub unverified: bool,
+    pub posts: Vec<serde_json::Map<String, serde_json::Value>>,
+    /// Decimal places `latitude` and `longitude` were rounded to, when the
+    /// node serves rounded coordinates.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub geo_precision: Option<u32>,
+    /// Set when paging with `after_seq`, as in `OutboxPage`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cursor: Option<u64>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OutboxId {
+    pub id: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub date: Option<DateTime<Utc>>,
+}
+
+/// Like `OutboxPage`, with ids in place of envelopes.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OutboxIdPage {
+    pub ids: Vec<OutboxId>,
+    pub cursor: u64,
+}
+
+/// A page of the outbox read by sequence cursor. `cursor` is the sequence
+/// number of the last post looked at, or the requested cursor if there were
+/// none.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OutboxPage {
+    pub envelopes: Vec<Envelope>,
+    pub cursor: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BoundingBoxQuery {
+    #[serde(flatten)]
+    pub bbox: BoundingBox,
+    #[serde(default)]
+    pub offset: usize,
+    pub limit: Option<usize>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PostsByKeysQuery {
+    /// Signing key fingerprints.
+    pub keys: Vec<String>,
+    /// Envelopes to skip, counted across all groups.
+    #[serde(default)]
+    pub offset: usize,
+    pub limit: Option<usize>,
+}
+
+/// One key's posts in `/_openherd/posts/by-keys`, oldest first.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct KeyPosts {
+    pub key: String,
+    pub envelopes: Vec<Envelope>,
+}
+
+/// Time bounds of the posts a node holds, by `--post-date-source`. Both
+/// dates are absent when it holds none.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Timespan {
+    pub oldest: Option<DateTime<Utc>>,
+    pub newest: Option<DateTime<Utc>>,
+    pub count: usize,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DensityQuery {
+    #[serde(default = "default_density_precision")]
+    pub precision: usize,
+}
+
+fn default_density_precision() -> usize {
+    4
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct GeoRegion {
     pub lat: f64,
@@ -71,6 +420,25 @@ pub struct KarmaCode {
     pub current_post: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub used_direction: Option<String>,
+    /// Every post this code has voted on, oldest first.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub history: Vec<KarmaRedemption>,
+    /// How much one vote with this code moves a post's tally.
+    #[serde(default = "default_karma_weight")]
+    pub weight: i32,
+}
+
+fn default_karma_weight() -> i32 {
+    1
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct KarmaRedemption {
+    pub post: String,
+    pub direction: String,
+    pub at: DateTime<Utc>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub revoked_at: Option<DateTime<Utc>>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -81,6 +449,19 @@ pub struct KarmaMetadata {
     pub current_post: Option<String>,
 }
 
+/// An active vote cast with one of an issuer's codes.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct IssuerVote {
+    pub code: String,
+    pub post: String,
+    pub direction: String,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct IssuerQuery {
+    pub issuer: String,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct KarmaLookupRequest {
     pub posts: Vec<String>,
@@ -95,18 +476,117 @@ pub struct KarmaGenerateRequest {
     pub expires: DateTime<Utc>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub region: Option<GeoRegion>,
+    #[serde(default = "default_karma_weight")]
+    pub weight: i32,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ModerationLabel {
+    /// Stable slug stored in `post_labels`; derived from `label` when absent.
+    #[serde(default)]
+    pub id: String,
     pub label: String,
     pub description: String,
+    /// Where the label applies. Unset applies everywhere; set, the label
+    /// only shows on posts this node holds inside the region.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub region: Option<GeoRegion>,
+}
+
+/// Narrows `/_openherd/moderation/labels` to the labels that apply at a
+/// point. Without both coordinates every label is listed.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct LabelsQuery {
+    pub lat: Option<f64>,
+    pub lon: Option<f64>,
+}
+
+impl GeoRegion {
+    /// Whether a point lies within `radius_km` of the centre, by great-circle
+    /// distance.
+    pub fn contains(&self, lat: f64, lon: f64) -> bool {
+        const EARTH_RADIUS_KM: f64 = 6371.0;
+        let (lat1, lat2) = (self.lat.to_radians(), lat.to_radians());
+        let d_lat = lat2 - lat1;
+        let d_lon = (lon - self.lon).to_radians();
+        let a = (d_lat / 2.0).sin().powi(2) + lat1.cos() * lat2.cos() * (d_lon / 2.0).sin().powi(2);
+        let distance = 2.0 * EARTH_RADIUS_KM * a.sqrt().min(1.0).asin();
+        distance <= self.radius_km
+    }
+}
+
+impl KarmaCode {
+    /// Whether this code has voted at least once, even if since revoked.
+    pub fn is_used(&self) -> bool {
+        !self.history.is_empty() || self.current_post.is_some()
+    }
+
+    /// Direction of the vote currently applied, if any. Falls back to the
+    /// latest redemption of `current_post`, then the code's fixed type, for
+    /// codes stored without `used_direction`.
+    pub fn applied_direction(&self) -> Option<&str> {
+        let post = self.current_post.as_deref()?;
+        self.used_direction
+            .as_deref()
+            .or_else(|| {
+                self.history
+                    .iter()
+                    .rev()
+                    .find(|r| r.post == post && r.revoked_at.is_none())
+                    .map(|r| r.direction.as_str())
+            })
+            .or(self.vote_type.as_deref())
+    }
+
+    /// What this code currently adds to its post's tally: the weight, signed
+    /// by the applied direction, or 0 when it is not applied.
+    pub fn applied_karma(&self) -> i32 {
+        match self.applied_direction() {
+            Some("upvote") => self.weight,
+            Some(_) => -self.weight,
+            None => 0,
+        }
+    }
+}
+
+impl ModerationLabel {
+    /// Lowercase ASCII slug of `label`, e.g. `"Hate Speech"` -> `"hate-speech"`.
+    pub fn slug(label: &str) -> String {
+        let mut slug = String::new();
+        for c in label.trim().chars() {
+            if c.is_ascii_alphanumeric() {
+                slug.push(c.to_ascii_lowercase());
+            } else if !slug.is_empty() && !slug.ends_with('-') {
+                slug.push('-');
+            }
+        }
+        slug.trim_end_matches('-').to_string()
+    }
+
+    /// Whether the label applies to a post at `lat`, `lon`.
+    pub fn applies_at(&self, lat: f64, lon: f64) -> bool {
+        self.region
+            .as_ref()
+            .is_none_or(|region| region.contains(lat, lon))
+    }
+
+    /// Fills in a missing `id` from the label text.
+    pub fn with_id(mut self) -> Self {
+        if self.id.trim().is_empty() {
+            self.id = Self::slug(&self.label);
+        }
+        self
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ModerationReport {
     pub post: Envelope,
     pub reason: String,
+    /// Label the reporter thinks applies. Kept only if it names a known label,
+    /// stored as that label's id.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub suggested_label: Option<String>,
     #[serde(skip)]
     pub reported_at: DateTime<Utc>,
     #[serde(skip)]
@@ -115,18 +595,269 @@ pub struct ModerationReport {
     pub id: String,
 }
 
+/// An envelope the inbox or sync rejected, kept with `--dead-letters`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DeadLetter {
+    pub received_at: DateTime<Utc>,
+    pub error: String,
+    pub envelope: Envelope,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PostDetail {
+    pub envelope: Envelope,
+    pub post: Post,
+    pub karma: i32,
+    pub labels: Vec<String>,
+    pub reply_count: usize,
+    #[serde(default)]
+    pub quote_count: usize,
+    pub report_count: usize,
+    /// Peer the label was imported from, if it was not applied here.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub label_source: Option<String>,
+    /// Only in the answer to `/_openherd/post`, with `--post-receipts`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub receipt: Option<PostReceipt>,
+}
+
+/// A post an admin has pinned, stored under `pin:{post_id}`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PinnedPost {
+    pub post_id: String,
+    pub pinned_at: DateTime<Utc>,
+}
+
+/// Signed data of a reaction envelope. As with a post, `id` is the
+/// fingerprint of the key that signed it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Reaction {
+    pub id: String,
+    /// Id of the post reacted to.
+    pub post: String,
+    pub reaction: String,
+    pub date: DateTime<Utc>,
+}
+
+/// A page of the posts held here that quote `post_id`, in `seq` order.
+/// `count` is the total, across all pages; `cursor` is as in `OutboxPage`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PostQuotes {
+    pub post_id: String,
+    pub count: usize,
+    pub quotes: Vec<Envelope>,
+    pub cursor: u64,
+}
+
+/// Paging for `/_openherd/post/:id/quotes`, as for the outbox.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct QuotesQuery {
+    pub after_seq: Option<u64>,
+    pub limit: Option<usize>,
+}
+
+/// How many distinct keys left each reaction on a post.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PostReactions {
+    pub post_id: String,
+    pub counts: std::collections::BTreeMap<String, usize>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct LabelImportRequest {
+    pub address: String,
+    /// Post ids whose proposed label should be merged. Empty only previews.
+    #[serde(default)]
+    pub accept: Vec<String>,
+}
+
+/// A peer's label for a post we hold, differing from our own.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LabelProposal {
+    pub post_id: String,
+    pub label: ModerationLabel,
+    pub current: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LabelImportResponse {
+    pub peer: String,
+    pub proposals: Vec<LabelProposal>,
+    pub merged: usize,
+}
+
+/// Reports in the queue sharing a suggested label.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ReportCategory {
+    /// The suggested label's id, or `uncategorized`.
+    pub category: String,
+    pub count: usize,
+    pub reports: Vec<ModerationReport>,
+}
+
+/// How many distinct reporters gave one reason or category.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ReasonCount {
+    pub reason: String,
+    pub count: usize,
+}
+
+/// Breakdown of one post's active reports, largest group first.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ReportReasons {
+    pub post_id: String,
+    /// Distinct reporters across all reports on the post.
+    pub reporters: usize,
+    /// By suggested label, or `uncategorized`. Public callers only see
+    /// categories that reach the public report threshold.
+    pub categories: Vec<ReasonCount>,
+    /// Free-text reasons, for admins only.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reasons: Option<Vec<ReasonCount>>,
+}
+
+/// An unresolved report moved out of the active queue after the configured
+/// retention period. The reporter's address is not kept.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ArchivedReport {
+    pub id: String,
+    pub post: Envelope,
+    pub reason: String,
+    pub reported_at: DateTime<Utc>,
+    pub archived_at: DateTime<Utc>,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ModerationLookupRequest {
     pub posts: Vec<String>,
 }
 
+/// A post's label joined with its definition, as returned by
+/// `/_openherd/moderation/lookup/definitions`. `label` and `description`
+/// are null when the definition has been deleted since the label was set.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AppliedLabel {
+    pub id: String,
+    pub label: Option<String>,
+    pub description: Option<String>,
+}
+
+/// Karma tally and moderation label for one post, as returned by
+/// `/_openherd/lookup`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PostStatus {
+    pub karma: i32,
+    pub label: Option<String>,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ModerationAction {
     pub report_id: String,
     pub label: Option<String>,
 }
 
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct DryRunQuery {
+    /// Report what would change without changing anything.
+    #[serde(default)]
+    pub dry_run: bool,
+}
+
+/// What an admin accept or delete changed, or with `dry_run` would change.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ModerationImpact {
+    /// Posts whose label is set or cleared, sorted.
+    pub posts: Vec<String>,
+    /// Reports removed from the active queue.
+    pub reports: usize,
+    /// Other reports left queued against the same posts.
+    pub sibling_reports: usize,
+    /// Karma votes currently on `posts`. Labels leave votes in place; this
+    /// is how many voters' posts the change reaches.
+    pub karma_votes: usize,
+    pub applied: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct IssuerStatsRequest {
+    /// May be omitted when the request carries an `Authorization: Bearer` token.
+    #[serde(default)]
+    pub password: String,
+    #[serde(default)]
+    pub offset: usize,
+    pub limit: Option<usize>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct KarmaRecomputeQuery {
+    /// Report the differences without replacing the tally.
+    #[serde(default)]
+    pub dry_run: bool,
+}
+
+/// A post whose stored tally disagreed with its codes.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct KarmaDrift {
+    pub post_id: String,
+    pub before: i32,
+    pub after: i32,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct KarmaRecompute {
+    /// Posts with a non-zero tally after recomputing.
+    pub posts: usize,
+    pub drift: Vec<KarmaDrift>,
+    pub applied: bool,
+}
+
+/// Entries in the content filter after a reload.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ContentFilterStatus {
+    pub terms: usize,
+    pub patterns: usize,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct KarmaStatusRequest {
+    /// May be omitted when the request carries an `Authorization: Bearer` token.
+    #[serde(default)]
+    pub password: String,
+    pub codes: Vec<String>,
+}
+
+/// One entry of `admin_karma_status`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct KarmaCodeStatus {
+    pub code: String,
+    /// Has voted at least once, even if since revoked.
+    pub used: bool,
+    pub expired: bool,
+    #[serde(rename = "currentPost")]
+    pub current_post: Option<String>,
+}
+
+/// How one issuer's karma codes have been used.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct IssuerStats {
+    pub issuer: String,
+    pub issued: usize,
+    /// Codes that have voted at least once, even if since revoked.
+    pub used: usize,
+    pub expired_unused: usize,
+    /// Sum of the votes the issuer's codes currently apply.
+    pub net_karma: i32,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct AdminAuth {
+    /// May be omitted when the request carries an `Authorization: Bearer` token.
+    #[serde(default)]
     pub password: String,
 }
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AdminToken {
+    pub token: String,
+    pub expires: DateTime<Utc>,
+}
@@ -1,15 +1,79 @@
-use crate::types::{Envelope, Post, ValidationError};
-use pgp::types::KeyTrait;
-use pgp::{Deserializable, SignedPublicKey};
+use crate::types::{Envelope, Post, Reaction, ValidationError};
+use pgp::crypto::hash::HashAlgorithm;
+use pgp::types::{KeyTrait, SecretKeyTrait};
+use pgp::{
+    Deserializable, KeyType, Message, SecretKeyParamsBuilder, SignedPublicKey, SignedSecretKey,
+    StandaloneSignature,
+};
+use sha2::{Digest, Sha256};
+use std::collections::HashSet;
+use std::path::Path;
+
+/// Largest accepted lengths, in bytes, of an envelope's fields. Checked
+/// before any PGP parsing so oversized input is cheap to reject.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EnvelopeLimits {
+    pub public_key: usize,
+    pub signature: usize,
+    pub data: usize,
+}
+
+impl Default for EnvelopeLimits {
+    fn default() -> Self {
+        Self {
+            public_key: 16 * 1024,
+            signature: 4 * 1024,
+            data: 16 * 1024,
+        }
+    }
+}
 
 pub fn validate_envelope(envelope: &Envelope) -> Result<Post, ValidationError> {
-    validate_envelope_structure(envelope)?;
+    validate_envelope_with_pow(envelope, 0)
+}
+
+/// Like `validate_envelope`, but also requires the post's proof of work to
+/// reach `difficulty` leading zero bits. A difficulty of 0 skips the check.
+pub fn validate_envelope_with_pow(
+    envelope: &Envelope,
+    difficulty: u32,
+) -> Result<Post, ValidationError> {
+    validate_envelope_with_limits(envelope, difficulty, &EnvelopeLimits::default())
+}
+
+/// Like `validate_envelope_with_pow`, with explicit size limits.
+pub fn validate_envelope_with_limits(
+    envelope: &Envelope,
+    difficulty: u32,
+    limits: &EnvelopeLimits,
+) -> Result<Post, ValidationError> {
+    validate_envelope_with_keyring(envelope, difficulty, limits, None)
+}
+
+/// Like `validate_envelope_with_limits`, and with a keyring also requires
+/// the signing key to be on it. `None` accepts any self-signed post.
+pub fn validate_envelope_with_keyring(
+    envelope: &Envelope,
+    difficulty: u32,
+    limits: &EnvelopeLimits,
+    keyring: Option<&Keyring>,
+) -> Result<Post, ValidationError> {
+    validate_envelope_structure(envelope, limits)?;
 
     let (public_key, _) = SignedPublicKey::from_string(&envelope.public_key)?;
 
-    let fingerprint = hex::encode(public_key.fingerprint());
-    if fingerprint.to_lowercase() != envelope.id.to_lowercase() {
-        return Err(ValidationError::IdMismatch);
+    let fingerprint = fingerprint_of(&public_key);
+    if fingerprint != envelope.id.to_lowercase() {
+        // Say which link broke: if the post names the same id as the
+        // envelope, it was signed with the wrong key.
+        let post_id = serde_json::from_str::<Post>(&envelope.data).map(|p| p.id);
+        return Err(match post_id {
+            Ok(id) if id == envelope.id => ValidationError::PostKeyMismatch,
+            _ => ValidationError::IdMismatch,
+        });
+    }
+    if keyring.is_some_and(|k| !k.contains(&fingerprint)) {
+        return Err(ValidationError::UntrustedKey);
     }
 
     verify_signature(&envelope.signature, &envelope.data, &public_key)?;
@@ -17,31 +81,193 @@ pub fn validate_envelope(envelope: &Envelope) -> Result<Post, ValidationError> {
     let post: Post = serde_json::from_str(&envelope.data)?;
 
     if post.id != envelope.id {
-        return Err(ValidationError::InvalidPostData(
-            "Post ID does not match envelope ID".to_string(),
-        ));
+        return Err(ValidationError::PostIdMismatch);
     }
 
     validate_post(&post)?;
 
+    if difficulty > 0 && proof_of_work_bits(&post) < difficulty {
+        return Err(ValidationError::InsufficientWork {
+            required: difficulty,
+        });
+    }
+
     Ok(post)
 }
 
+/// The checks of `validate_envelope_with_keyring` short of parsing the key
+/// and verifying the signature, for envelopes pulled from a peer trusted to
+/// have verified them. The `id` is taken on trust to be the signing key's
+/// fingerprint, so a keyring is checked against it.
+pub fn validate_envelope_unverified(
+    envelope: &Envelope,
+    difficulty: u32,
+    limits: &EnvelopeLimits,
+    keyring: Option<&Keyring>,
+) -> Result<Post, ValidationError> {
+    validate_envelope_structure(envelope, limits)?;
+    if keyring.is_some_and(|k| !k.contains(&envelope.id)) {
+        return Err(ValidationError::UntrustedKey);
+    }
+
+    let post: Post = serde_json::from_str(&envelope.data)?;
+    if post.id != envelope.id {
+        return Err(ValidationError::PostIdMismatch);
+    }
+
+    validate_post(&post)?;
+
+    if difficulty > 0 && proof_of_work_bits(&post) < difficulty {
+        return Err(ValidationError::InsufficientWork {
+            required: difficulty,
+        });
+    }
+
+    Ok(post)
+}
+
+/// Checks a reaction envelope the way `validate_envelope` checks a post:
+/// the id must be the key's fingerprint and the signature must cover the
+/// data. Whether the reaction or its target is acceptable is up to the node.
+pub fn validate_reaction(
+    envelope: &Envelope,
+    limits: &EnvelopeLimits,
+) -> Result<Reaction, ValidationError> {
+    validate_envelope_structure(envelope, limits)?;
+
+    let (public_key, _) = SignedPublicKey::from_string(&envelope.public_key)?;
+    if fingerprint_of(&public_key) != envelope.id.to_lowercase() {
+        return Err(ValidationError::IdMismatch);
+    }
+    verify_signature(&envelope.signature, &envelope.data, &public_key)?;
+
+    let reaction: Reaction = serde_json::from_str(&envelope.data)?;
+    if reaction.id != envelope.id {
+        return Err(ValidationError::PostIdMismatch);
+    }
+    if reaction.reaction.trim().is_empty() {
+        return Err(ValidationError::InvalidPostData(
+            "Reaction cannot be empty".to_string(),
+        ));
+    }
+    Ok(reaction)
+}
+
+/// Lowercase hex fingerprint of an armored public key.
+pub fn key_fingerprint(armored: &str) -> Result<String, ValidationError> {
+    let (public_key, _) = SignedPublicKey::from_string(armored)?;
+    Ok(fingerprint_of(&public_key))
+}
+
+fn fingerprint_of(public_key: &SignedPublicKey) -> String {
+    hex::encode(public_key.fingerprint()).to_lowercase()
+}
+
+const PUBLIC_KEY_HEADER: &str = "-----BEGIN PGP PUBLIC KEY BLOCK-----";
+
+/// The keys allowed to post on a closed network, by fingerprint.
+#[derive(Debug, Clone, Default)]
+pub struct Keyring {
+    fingerprints: HashSet<String>,
+}
+
+impl Keyring {
+    /// Reads every armored public key in `text`; blocks may be concatenated.
+    pub fn from_armored(text: &str) -> Result<Self, ValidationError> {
+        let mut keyring = Self::default();
+        keyring.add_armored(text)?;
+        Ok(keyring)
+    }
+
+    /// Loads a keyring file, or every file in a keyring directory.
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let mut files = if path.is_dir() {
+            std::fs::read_dir(path)
+                .map_err(|e| format!("{}: {}", path.display(), e))?
+                .filter_map(|entry| entry.ok().map(|e| e.path()))
+                .filter(|p| p.is_file())
+                .collect()
+        } else {
+            vec![path.to_path_buf()]
+        };
+        files.sort();
+
+        let mut keyring = Self::default();
+        for file in files {
+            let text =
+                std::fs::read_to_string(&file).map_err(|e| format!("{}: {}", file.display(), e))?;
+            keyring
+                .add_armored(&text)
+                .map_err(|e| format!("{}: {}", file.display(), e))?;
+        }
+        Ok(keyring)
+    }
+
+    fn add_armored(&mut self, text: &str) -> Result<(), ValidationError> {
+        for block in text.split(PUBLIC_KEY_HEADER).skip(1) {
+            let armored = format!("{}{}", PUBLIC_KEY_HEADER, block);
+            self.fingerprints.insert(key_fingerprint(&armored)?);
+        }
+        Ok(())
+    }
+
+    pub fn contains(&self, fingerprint: &str) -> bool {
+        self.fingerprints.contains(&fingerprint.to_lowercase())
+    }
+
+    pub fn len(&self) -> usize {
+        self.fingerprints.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.fingerprints.is_empty()
+    }
+}
+
+/// Proof-of-work strength of a post: the number of leading zero bits of
+/// SHA-256 over the UTF-8 bytes of `{id}\n{text}\n{nonce}`, with `id` as
+/// in the post, `text` verbatim and `nonce` in decimal. A post without a
+/// nonce scores 0. Clients pick nonces until the score meets the node's
+/// advertised difficulty, then sign the post with that nonce included.
+pub fn proof_of_work_bits(post: &Post) -> u32 {
+    let Some(nonce) = post.nonce else {
+        return 0;
+    };
+    let digest = Sha256::digest(format!("{}\n{}\n{}", post.id, post.text, nonce).as_bytes());
+    let mut bits = 0;
+    for byte in digest {
+        if byte == 0 {
+            bits += 8;
+        } else {
+            bits += byte.leading_zeros();
+            break;
+        }
+    }
+    bits
+}
+
 fn verify_signature(
     signature_armored: &str,
     data: &str,
     public_key: &SignedPublicKey,
 ) -> Result<(), ValidationError> {
-    use pgp::StandaloneSignature;
-
-    let (signature, _) = StandaloneSignature::from_string(signature_armored)?;
-
-    signature.verify(public_key, data.as_bytes())?;
-
-    Ok(())
+    verify_detached(signature_armored, data.as_bytes(), public_key)
 }
 
-fn validate_envelope_structure(envelope: &Envelope) -> Result<(), ValidationError> {
+fn validate_envelope_structure(
+    envelope: &Envelope,
+    limits: &EnvelopeLimits,
+) -> Result<(), ValidationError> {
+    for (field, len, limit) in [
+        ("public_key", envelope.public_key.len(), limits.public_key),
+        ("signature", envelope.signature.len(), limits.signature),
+        ("data", envelope.data.len(), limits.data),
+    ] {
+        if len > limit {
+            return Err(ValidationError::TooLarge { field, limit });
+        }
+    }
+
     if envelope.signature.is_empty() {
         return Err(ValidationError::InvalidSignature);
     }
@@ -97,6 +323,14 @@ fn validate_post(post: &Post) -> Result<(), ValidationError> {
         ));
     }
 
+    if let Some(quote) = &post.quote {
+        if quote.is_empty() || !quote.chars().all(|c| c.is_ascii_hexdigit()) {
+            return Err(ValidationError::InvalidPostData(
+                "Quoted post id must be a hex fingerprint".to_string(),
+            ));
+        }
+    }
+
     let now = chrono::Utc::now();
     let future_tolerance = chrono::Duration::minutes(5);
     if post.date > now + future_tolerance {
@@ -107,3 +341,363 @@ fn validate_post(post: &Post) -> Result<(), ValidationError> {
 
     Ok(())
 }
+
+/// Generates an EdDSA signing key, returning it with its armored public key.
+pub(crate) fn generate_signing_key(
+    user_id: &str,
+) -> Result<(SignedSecretKey, String), ValidationError> {
+    let params = SecretKeyParamsBuilder::default()
+        .key_type(KeyType::EdDSA)
+        .can_certify(true)
+        .can_sign(true)
+        .primary_user_id(user_id.into())
+        .build()
+        .map_err(|e| ValidationError::KeyGeneration(e.to_string()))?;
+    let secret = params.generate()?.sign(String::new)?;
+    let public_key = secret
+        .public_key()
+        .sign(&secret, String::new)?
+        .to_armored_string(None.into())?;
+    Ok((secret, public_key))
+}
+
+/// Armored detached signature over `data`, in the form `verify_signature` checks.
+pub(crate) fn sign_detached(
+    secret: &SignedSecretKey,
+    data: &[u8],
+) -> Result<String, ValidationError> {
+    Ok(Message::new_literal_bytes("", data)
+        .sign(secret, String::new, HashAlgorithm::SHA2_256)?
+        .into_signature()
+        .to_armored_string(None.into())?)
+}
+
+/// Verifies an armored detached signature over raw bytes.
+pub(crate) fn verify_detached(
+    signature_armored: &str,
+    data: &[u8],
+    public_key: &SignedPublicKey,
+) -> Result<(), ValidationError> {
+    let (signature, _) = StandaloneSignature::from_string(signature_armored)?;
+    signature.verify(public_key, data)?;
+    Ok(())
+}
+
+#[cfg(any(test, feature = "test-utils"))]
+pub mod testing {
+    use super::{generate_signing_key, sign_detached};
+    use crate::types::{Envelope, Post};
+    use pgp::types::KeyTrait;
+    use pgp::SignedSecretKey;
+
+    /// Throwaway signing key for building envelopes that pass `validate_envelope`.
+    pub struct TestKey {
+        secret: SignedSecretKey,
+        public_key: String,
+    }
+
+    impl TestKey {
+        pub fn generate() -> Self {
+            let (secret, public_key) =
+                generate_signing_key("openherd test <test@openherd.invalid>")
+                    .expect("key generation");
+            Self { secret, public_key }
+        }
+
+        pub fn fingerprint(&self) -> String {
+            hex::encode(self.secret.fingerprint())
+        }
+
+        pub fn public_key(&self) -> &str {
+            &self.public_key
+        }
+
+        /// Armored detached signature over `data`.
+        pub fn sign(&self, data: &str) -> String {
+            sign_detached(&self.secret, data.as_bytes()).expect("sign data")
+        }
+
+        /// A post whose id matches this key's fingerprint.
+        pub fn post(&self, text: &str) -> Post {
+            Post {
+                id: self.fingerprint(),
+                text: text.to_string(),
+                latitude: 33.7501,
+                longitude: -84.3885,
+                date: chrono::Utc::now(),
+                parent: None,
+                quote: None,
+                nonce: None,
+            }
+        }
+
+        /// Serializes and signs `post` into an envelope keyed by this key.
+        pub fn envelope(&self, post: &Post) -> Envelope {
+            let data = serde_json::to_string(post).expect("serialize post");
+            Envelope {
+                signature: self.sign(&data),
+                public_key: self.public_key.clone(),
+                id: self.fingerprint(),
+                data,
+                received_at: None,
+                seq: None,
+            }
+        }
+    }
+
+    /// Valid envelope for a fresh key carrying `text`.
+    pub fn signed_envelope(text: &str) -> Envelope {
+        let key = TestKey::generate();
+        key.envelope(&key.post(text))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::testing::TestKey;
+    use super::*;
+
+    fn valid() -> (TestKey, Envelope) {
+        let key = TestKey::generate();
+        let envelope = key.envelope(&key.post("hello"));
+        (key, envelope)
+    }
+
+    /// Signs `post` with `key` and validates it.
+    fn validate_post_from(key: &TestKey, post: &Post) -> Result<Post, ValidationError> {
+        validate_envelope(&key.envelope(post))
+    }
+
+    fn post_data_error(result: Result<Post, ValidationError>) -> String {
+        match result {
+            Err(ValidationError::InvalidPostData(msg)) => msg,
+            other => panic!("expected InvalidPostData, got {:?}", other.map(|p| p.id)),
+        }
+    }
+
+    #[test]
+    fn test_valid_envelope_passes() {
+        let (_, envelope) = valid();
+        assert_eq!(validate_envelope(&envelope).unwrap().text, "hello");
+    }
+
+    #[test]
+    fn test_empty_fields_are_rejected() {
+        let (_, envelope) = valid();
+
+        let mut e = envelope.clone();
+        e.signature.clear();
+        assert!(matches!(
+            validate_envelope(&e),
+            Err(ValidationError::InvalidSignature)
+        ));
+
+        let mut e = envelope.clone();
+        e.public_key.clear();
+        assert!(matches!(
+            validate_envelope(&e),
+            Err(ValidationError::InvalidPublicKey)
+        ));
+
+        let mut e = envelope.clone();
+        e.id.clear();
+        assert!(matches!(
+            validate_envelope(&e),
+            Err(ValidationError::IdMismatch)
+        ));
+
+        let mut e = envelope;
+        e.data.clear();
+        assert_eq!(post_data_error(validate_envelope(&e)), "Empty data field");
+    }
+
+    #[test]
+    fn test_missing_armor_markers_are_rejected() {
+        let (_, envelope) = valid();
+
+        let mut e = envelope.clone();
+        e.signature = e.signature.replace("-----BEGIN PGP SIGNATURE-----", "");
+        assert!(matches!(
+            validate_envelope(&e),
+            Err(ValidationError::InvalidSignature)
+        ));
+
+        let mut e = envelope;
+        e.public_key = e
+            .public_key
+            .replace("-----BEGIN PGP PUBLIC KEY BLOCK-----", "");
+        assert!(matches!(
+            validate_envelope(&e),
+            Err(ValidationError::InvalidPublicKey)
+        ));
+    }
+
+    #[test]
+    fn test_non_hex_id_is_rejected() {
+        let (_, mut envelope) = valid();
+        envelope.id = format!("{}zz", &envelope.id[2..]);
+        assert!(matches!(
+            validate_envelope(&envelope),
+            Err(ValidationError::IdMismatch)
+        ));
+    }
+
+    #[test]
+    fn test_id_must_match_key_fingerprint() {
+        let (_, mut envelope) = valid();
+        envelope.id = TestKey::generate().fingerprint();
+        assert!(matches!(
+            validate_envelope(&envelope),
+            Err(ValidationError::IdMismatch)
+        ));
+    }
+
+    #[test]
+    fn test_post_id_must_match_envelope_id() {
+        let key = TestKey::generate();
+        let mut post = key.post("hello");
+        post.id = TestKey::generate().fingerprint();
+        assert!(matches!(
+            validate_post_from(&key, &post),
+            Err(ValidationError::PostIdMismatch)
+        ));
+    }
+
+    #[test]
+    fn test_post_and_envelope_ids_must_match_signing_key() {
+        let key = TestKey::generate();
+        let other = TestKey::generate();
+        // Both ids name `other`, but `key` signed it.
+        let post = other.post("hello");
+        let mut envelope = key.envelope(&post);
+        envelope.id = other.fingerprint();
+        assert!(matches!(
+            validate_envelope(&envelope),
+            Err(ValidationError::PostKeyMismatch)
+        ));
+
+        // The post names the signing key; only the envelope id is wrong.
+        let mut envelope = key.envelope(&key.post("hello"));
+        envelope.id = other.fingerprint();
+        assert!(matches!(
+            validate_envelope(&envelope),
+            Err(ValidationError::IdMismatch)
+        ));
+
+        let messages: HashSet<String> = [
+            ValidationError::IdMismatch,
+            ValidationError::PostIdMismatch,
+            ValidationError::PostKeyMismatch,
+        ]
+        .iter()
+        .map(ToString::to_string)
+        .collect();
+        assert_eq!(messages.len(), 3);
+    }
+
+    #[test]
+    fn test_tampered_data_fails_signature_check() {
+        let (_, mut envelope) = valid();
+        envelope.data = envelope.data.replace("hello", "goodbye");
+        assert!(validate_envelope(&envelope).is_err());
+    }
+
+    #[test]
+    fn test_empty_text_is_rejected() {
+        let key = TestKey::generate();
+        let post = key.post("   ");
+        assert_eq!(
+            post_data_error(validate_post_from(&key, &post)),
+            "Post text cannot be empty"
+        );
+    }
+
+    #[test]
+    fn test_out_of_range_coordinates_are_rejected() {
+        let key = TestKey::generate();
+        for latitude in [-90.5, 91.0] {
+            let mut post = key.post("hello");
+            post.latitude = latitude;
+            assert_eq!(
+                post_data_error(validate_post_from(&key, &post)),
+                "Invalid latitude range"
+            );
+        }
+        for longitude in [-180.5, 181.0] {
+            let mut post = key.post("hello");
+            post.longitude = longitude;
+            assert_eq!(
+                post_data_error(validate_post_from(&key, &post)),
+                "Invalid longitude range"
+            );
+        }
+
+        let mut edge = key.post("hello");
+        edge.latitude = 90.0;
+        edge.longitude = -180.0;
+        assert!(validate_post_from(&key, &edge).is_ok());
+    }
+
+    #[test]
+    fn test_future_dated_posts_are_rejected() {
+        let key = TestKey::generate();
+        let mut post = key.post("hello");
+        post.date = chrono::Utc::now() + chrono::Duration::minutes(10);
+        assert_eq!(
+            post_data_error(validate_post_from(&key, &post)),
+            "Post date cannot be in the future"
+        );
+
+        post.date = chrono::Utc::now() + chrono::Duration::minutes(2);
+        assert!(validate_post_from(&key, &post).is_ok());
+    }
+
+    #[test]
+    fn test_oversized_fields_are_rejected_before_parsing() {
+        let (_, envelope) = valid();
+        let limits = EnvelopeLimits {
+            public_key: envelope.public_key.len(),
+            signature: envelope.signature.len(),
+            data: envelope.data.len(),
+        };
+        assert!(validate_envelope_with_limits(&envelope, 0, &limits).is_ok());
+
+        let mut e = envelope.clone();
+        e.signature.push_str(&"A".repeat(1 << 20));
+        assert!(matches!(
+            validate_envelope(&e),
+            Err(ValidationError::TooLarge {
+                field: "signature",
+                ..
+            })
+        ));
+
+        let tight = EnvelopeLimits {
+            data: envelope.data.len() - 1,
+            ..limits
+        };
+        assert!(matches!(
+            validate_envelope_with_limits(&envelope, 0, &tight),
+            Err(ValidationError::TooLarge { field: "data", .. })
+        ));
+    }
+
+    #[test]
+    fn test_keyring_admits_only_its_keys() {
+        let (member, envelope) = valid();
+        let (other, _) = valid();
+        let armored = format!("{}\n{}", member.public_key(), other.public_key());
+        let keyring = Keyring::from_armored(&armored).unwrap();
+        assert_eq!(keyring.len(), 2);
+
+        let limits = EnvelopeLimits::default();
+        assert!(validate_envelope_with_keyring(&envelope, 0, &limits, Some(&keyring)).is_ok());
+
+        let (_, outsider) = valid();
+        assert!(matches!(
+            validate_envelope_with_keyring(&outsider, 0, &limits, Some(&keyring)),
+            Err(ValidationError::UntrustedKey)
+        ));
+        assert!(validate_envelope_with_keyring(&outsider, 0, &limits, None).is_ok());
+    }
+}
@@ -0,0 +1,308 @@
+//! WebSub hub for this node's outbox. A subscriber asks `/_openherd/hub` to
+//! subscribe a callback to the outbox topic, the hub confirms the intent with
+//! the standard challenge, and from then on every batch of new posts is
+//! POSTed to the callback as a JSON array of envelopes.
+//!
+//! The hub only runs with `--websub`. Callbacks must resolve to public
+//! addresses (see `resolve_callback`), and each request connects to the
+//! address that was checked, without following redirects, so neither DNS
+//! rebinding nor a redirect can point the hub at an internal service.
+
+use crate::state::SharedState;
+use crate::types::{Envelope, HubRequest, WebSubSubscription};
+use chrono::Utc;
+use futures_util::StreamExt;
+use hmac::{Hmac, Mac};
+use rand::{distributions::Alphanumeric, Rng};
+use sha2::Sha256;
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
+use tokio::sync::broadcast::{self, error::TryRecvError};
+use tokio::task::JoinHandle;
+
+/// Path of the only topic this hub serves.
+pub const TOPIC_PATH: &str = "/_openherd/outbox";
+pub const HUB_PATH: &str = "/_openherd/hub";
+
+/// Subscribers notified at once, so one slow callback does not hold up
+/// the rest.
+const DELIVERY_CONCURRENCY: usize = 8;
+
+/// A client that connects to `addr` for `url`'s host and follows no
+/// redirects.
+fn pinned_client(url: &url::Url, addr: SocketAddr) -> reqwest::Client {
+    let mut builder = reqwest::Client::builder()
+        .timeout(Duration::from_secs(15))
+        .redirect(reqwest::redirect::Policy::none());
+    if let Some(host) = url.host_str() {
+        builder = builder.resolve(host, addr);
+    }
+    builder.build().unwrap_or_default()
+}
+
+/// Whether `ip` is reachable on the public internet: not loopback,
+/// private, link-local, shared (CGNAT), unspecified, broadcast or
+/// multicast.
+fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(v4) => {
+            let [a, b, ..] = v4.octets();
+            !(v4.is_loopback()
+                || v4.is_private()
+                || v4.is_link_local()
+                || v4.is_unspecified()
+                || v4.is_broadcast()
+                || v4.is_multicast()
+                || v4.is_documentation()
+                || (a == 100 && (64..128).contains(&b))
+                || a == 0)
+        }
+        IpAddr::V6(v6) => match v6.to_ipv4_mapped() {
+            Some(v4) => is_public(IpAddr::V4(v4)),
+            None => {
+                !(v6.is_loopback()
+                    || v6.is_unspecified()
+                    || v6.is_multicast()
+                    || v6.is_unique_local()
+                    || v6.is_unicast_link_local())
+            }
+        },
+    }
+}
+
+/// Resolves `callback`'s host and returns the address to connect to. Fails
+/// if it names no address or, unless `allow_private`, any address that is
+/// not public.
+pub async fn resolve_callback(callback: &str, allow_private: bool) -> Result<SocketAddr, String> {
+    let url = url::Url::parse(callback).map_err(|e| format!("invalid hub.callback: {}", e))?;
+    let host = url
+        .host_str()
+        .ok_or("hub.callback has no host")?
+        .trim_start_matches('[')
+        .trim_end_matches(']');
+    let port = url.port_or_known_default().unwrap_or(80);
+    let addrs: Vec<SocketAddr> = tokio::net::lookup_host((host, port))
+        .await
+        .map_err(|e| format!("hub.callback host does not resolve: {}", e))?
+        .collect();
+    if !allow_private && addrs.iter().any(|addr| !is_public(addr.ip())) {
+        return Err("hub.callback must resolve to public addresses".to_string());
+    }
+    addrs
+        .into_iter()
+        .next()
+        .ok_or_else(|| "hub.callback host does not resolve".to_string())
+}
+
+/// Confirms a (un)subscription request with its callback, connecting to
+/// `addr` from `resolve_callback`, and applies it if the callback echoes
+/// the challenge. Run in the background: the hub answers `202 Accepted`
+/// before verification.
+pub async fn verify_intent(
+    state: SharedState,
+    request: HubRequest,
+    lease_seconds: u64,
+    addr: SocketAddr,
+) {
+    let challenge: String = rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(32)
+        .map(char::from)
+        .collect();
+    let Ok(mut url) = url::Url::parse(&request.callback) else {
+        return;
+    };
+    {
+        let mut query = url.query_pairs_mut();
+        query
+            .append_pair("hub.mode", &request.mode)
+            .append_pair("hub.topic", &request.topic)
+            .append_pair("hub.challenge", &challenge);
+        if request.mode == "subscribe" {
+            query.append_pair("hub.lease_seconds", &lease_seconds.to_string());
+        }
+    }
+
+    let client = pinned_client(&url, addr);
+    let confirmed = match client.get(url).send().await {
+        Ok(resp) if resp.status().is_success() => {
+            resp.text().await.is_ok_and(|body| body.trim() == challenge)
+        }
+        Ok(resp) => {
+            eprintln!(
+                "WebSub callback {} refused {}: {}",
+                request.callback,
+                request.mode,
+                resp.status()
+            );
+            false
+        }
+        Err(e) => {
+            eprintln!("WebSub callback {} unreachable: {}", request.callback, e);
+            false
+        }
+    };
+    if !confirmed {
+        return;
+    }
+
+    let Ok(s) = state.lock() else {
+        return;
+    };
+    let result = if request.mode == "subscribe" {
+        if !s.websub_has_room(&request.callback) {
+            eprintln!(
+                "Not subscribing WebSub callback {}: subscription limit reached",
+                request.callback
+            );
+            return;
+        }
+        s.websub_subscribe(&WebSubSubscription {
+            callback: request.callback.clone(),
+            topic: request.topic,
+            secret: request.secret,
+            expires: Utc::now() + chrono::Duration::seconds(lease_seconds as i64),
+        })
+    } else {
+        s.websub_unsubscribe(&request.callback)
+    };
+    if let Err(e) = result {
+        eprintln!("Failed to store WebSub subscription: {}", e);
+    }
+}
+
+/// Starts delivering new posts to subscribers. Listens on `post_events`
+/// from the moment it is called, so posts imported right after are not
+/// missed.
+pub fn spawn_notifier(state: &SharedState) -> Option<JoinHandle<()>> {
+    let events = state.lock().ok()?.post_events.subscribe();
+    Some(tokio::spawn(notify_subscribers(state.clone(), events)))
+}
+
+async fn notify_subscribers(state: SharedState, mut events: broadcast::Receiver<Envelope>) {
+    loop {
+        let first = match events.recv().await {
+            Ok(envelope) => envelope,
+            Err(broadcast::error::RecvError::Lagged(missed)) => {
+                eprintln!("WebSub notifier fell behind, {} posts not sent", missed);
+                continue;
+            }
+            Err(broadcast::error::RecvError::Closed) => return,
+        };
+        let mut batch = vec![first];
+        loop {
+            match events.try_recv() {
+                Ok(envelope) => batch.push(envelope),
+                Err(TryRecvError::Lagged(_)) => continue,
+                Err(_) => break,
+            }
+        }
+
+        let (subscriptions, allow_private) = match state.lock() {
+            Ok(s) => (
+                s.websub_subscriptions(),
+                s.config.websub_allow_private_callbacks,
+            ),
+            Err(_) => return,
+        };
+        if subscriptions.is_empty() {
+            continue;
+        }
+        let body = match serde_json::to_vec(&batch) {
+            Ok(body) => body,
+            Err(e) => {
+                eprintln!("Failed to serialize WebSub notification: {}", e);
+                continue;
+            }
+        };
+        futures_util::stream::iter(subscriptions)
+            .for_each_concurrent(DELIVERY_CONCURRENCY, |subscription| {
+                let body = &body;
+                async move { deliver(&subscription, body, allow_private).await }
+            })
+            .await;
+    }
+}
+
+/// POSTs `body` to one subscriber, resolving its callback again so a host
+/// that has since moved to an internal address is not reached.
+async fn deliver(subscription: &WebSubSubscription, body: &[u8], allow_private: bool) {
+    let addr = match resolve_callback(&subscription.callback, allow_private).await {
+        Ok(addr) => addr,
+        Err(e) => {
+            eprintln!("Not notifying {}: {}", subscription.callback, e);
+            return;
+        }
+    };
+    let Ok(url) = url::Url::parse(&subscription.callback) else {
+        return;
+    };
+    let client = pinned_client(&url, addr);
+    let hub = url::Url::parse(&subscription.topic)
+        .and_then(|topic| topic.join(HUB_PATH))
+        .map(String::from)
+        .unwrap_or_default();
+    let mut request = client
+        .post(url)
+        .header(reqwest::header::CONTENT_TYPE, "application/json")
+        .header(
+            reqwest::header::LINK,
+            format!(
+                "<{}>; rel=\"hub\", <{}>; rel=\"self\"",
+                hub, subscription.topic
+            ),
+        );
+    if let Some(secret) = &subscription.secret {
+        request = request.header("X-Hub-Signature", signature(secret, body));
+    }
+    match request.body(body.to_vec()).send().await {
+        Ok(resp) if !resp.status().is_success() => eprintln!(
+            "WebSub subscriber {} answered {}",
+            subscription.callback,
+            resp.status()
+        ),
+        Ok(_) => {}
+        Err(e) => eprintln!(
+            "WebSub subscriber {} unreachable: {}",
+            subscription.callback, e
+        ),
+    }
+}
+
+/// `X-Hub-Signature` value for `body`: `sha256=` and the hex HMAC under the
+/// subscriber's secret.
+pub fn signature(secret: &str, body: &[u8]) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts any key length");
+    mac.update(body);
+    format!("sha256={}", hex::encode(mac.finalize().into_bytes()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_callbacks_must_resolve_to_public_addresses() {
+        for callback in [
+            "http://127.0.0.1:8080/cb",
+            "http://localhost/cb",
+            "http://10.1.2.3/cb",
+            "http://169.254.169.254/latest/meta-data",
+            "http://[::1]/cb",
+            "http://[::ffff:192.168.0.1]/cb",
+            "http://[fd00::1]/cb",
+        ] {
+            assert!(
+                resolve_callback(callback, false).await.is_err(),
+                "{}",
+                callback
+            );
+        }
+        assert!(resolve_callback("http://127.0.0.1:8080/cb", true)
+            .await
+            .is_ok());
+        assert!(is_public("93.184.216.34".parse().unwrap()));
+        assert!(is_public("2606:4700::1".parse().unwrap()));
+    }
+}
@@ -0,0 +1,137 @@
+//! Content negotiation for the federation endpoints.
+//!
+//! `outbox` answers in MessagePack when the request's `Accept` header lists
+//! `application/msgpack`, and `inbox` reads MessagePack when the body's
+//! `Content-Type` is `application/msgpack`. Everything else stays JSON, so
+//! peers that predate this keep working unchanged. A syncing node only pushes
+//! MessagePack to a peer whose outbox already answered in it.
+
+use axum::{
+    async_trait,
+    body::Bytes,
+    extract::{FromRequest, Request},
+    http::{
+        header::{ACCEPT, CONTENT_TYPE, VARY},
+        HeaderMap, HeaderValue, StatusCode,
+    },
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde::{de::DeserializeOwned, Serialize};
+
+pub const MSGPACK: &str = "application/msgpack";
+pub const VERSION_HEADER: &str = "x-openherd-version";
+/// `AppState::change_seq` on `outbox` and `health`; see `AppState::pending_sync`.
+pub const CHANGE_SEQ_HEADER: &str = "x-openherd-seq";
+/// Decimal places coordinates were rounded to under `--geo-precision`; the
+/// envelopes in a response carrying it do not verify.
+pub const GEO_PRECISION_HEADER: &str = "x-openherd-geo-precision";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Json,
+    MsgPack,
+}
+
+fn lists_msgpack(value: Option<&HeaderValue>) -> bool {
+    value
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.split(',').any(|t| t.trim().starts_with(MSGPACK)))
+}
+
+impl Format {
+    /// The format a client asked for in its `Accept` header.
+    pub fn accepted(headers: &HeaderMap) -> Self {
+        if lists_msgpack(headers.get(ACCEPT)) {
+            Format::MsgPack
+        } else {
+            Format::Json
+        }
+    }
+
+    /// The format a body was sent in, per its `Content-Type` header.
+    pub fn of_body(headers: &HeaderMap) -> Self {
+        if lists_msgpack(headers.get(CONTENT_TYPE)) {
+            Format::MsgPack
+        } else {
+            Format::Json
+        }
+    }
+
+    pub fn content_type(self) -> &'static str {
+        match self {
+            Format::Json => "application/json",
+            Format::MsgPack => MSGPACK,
+        }
+    }
+
+    pub fn encode<T: Serialize>(self, value: &T) -> Result<Vec<u8>, String> {
+        match self {
+            Format::Json => serde_json::to_vec(value).map_err(|e| e.to_string()),
+            Format::MsgPack => rmp_serde::to_vec_named(value).map_err(|e| e.to_string()),
+        }
+    }
+
+    pub fn decode<T: DeserializeOwned>(self, bytes: &[u8]) -> Result<T, String> {
+        match self {
+            Format::Json => serde_json::from_slice(bytes).map_err(|e| e.to_string()),
+            Format::MsgPack => rmp_serde::from_slice(bytes).map_err(|e| e.to_string()),
+        }
+    }
+}
+
+/// A response body encoded in the negotiated format.
+pub struct Negotiated<T>(pub Format, pub T);
+
+impl<T: Serialize> IntoResponse for Negotiated<T> {
+    fn into_response(self) -> Response {
+        let Negotiated(format, value) = self;
+        match format.encode(&value) {
+            Ok(body) => (
+                [
+                    (CONTENT_TYPE, format.content_type()),
+                    (VARY, "accept"),
+                    (
+                        axum::http::HeaderName::from_static(VERSION_HEADER),
+                        env!("CARGO_PKG_VERSION"),
+                    ),
+                ],
+                body,
+            )
+                .into_response(),
+            Err(e) => {
+                eprintln!("Failed to encode response: {}", e);
+                StatusCode::INTERNAL_SERVER_ERROR.into_response()
+            }
+        }
+    }
+}
+
+/// A request body in JSON or MessagePack, chosen by its `Content-Type`.
+/// JSON bodies go through axum's `Json` extractor so its rejections are kept.
+pub struct SyncBody<T>(pub T);
+
+#[async_trait]
+impl<T, S> FromRequest<S> for SyncBody<T>
+where
+    T: DeserializeOwned,
+    S: Send + Sync,
+{
+    type Rejection = Response;
+
+    async fn from_request(req: Request, state: &S) -> Result<Self, Self::Rejection> {
+        if Format::of_body(req.headers()) == Format::MsgPack {
+            let bytes = Bytes::from_request(req, state)
+                .await
+                .map_err(IntoResponse::into_response)?;
+            let value = Format::MsgPack
+                .decode(&bytes)
+                .map_err(|e| (StatusCode::BAD_REQUEST, e).into_response())?;
+            return Ok(SyncBody(value));
+        }
+        let Json(value) = Json::<T>::from_request(req, state)
+            .await
+            .map_err(IntoResponse::into_response)?;
+        Ok(SyncBody(value))
+    }
+}